@@ -0,0 +1,768 @@
+use crate::ast::*;
+use crate::check::{
+    exclusive_mandatory, find_shadowed, group_header, indentation_issues, trivial_groups,
+    IndentIssue,
+};
+use crate::document::Draft;
+use crate::location::{rename_target, reverse_resolve};
+use crate::schema::{missing_attributes, SchemaRule};
+use crate::semantic::*;
+use crate::util::*;
+use crate::valueref;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tower_lsp::lsp_types::*;
+use tree_sitter::Node;
+use ustr::Ustr;
+/*
+ * Refactorings only offer commands, the edits are computed once the command is executed
+ * with the arguments the extension filled in, eg. the attribute key the user typed.
+ * Quick fixes need no input and carry their edit directly.
+ */
+pub const ADD_ATTRIBUTE: &str = "uvls.addAttribute";
+pub const SHOW_LOG: &str = "uvls.showLog";
+pub const EFFECTIVE_CONFIG: &str = "uvls/effectiveConfig";
+pub const NEW_MODEL: &str = "uvls/newModel";
+pub const CLEAR_ANALYSIS_CACHE: &str = "uvls.clearAnalysisCache";
+pub const VALIDATE_MODEL: &str = "uvls.validateModel";
+pub const DIFF: &str = "uvls/diff";
+pub const FEATURE_CONSTRAINTS: &str = "uvls/featureConstraints";
+pub const RENAME_PREVIEW: &str = "uvls/renamePreview";
+pub const NORMAL_FORM: &str = "uvls/normalForm";
+pub fn commands() -> Vec<String> {
+    vec![
+        ADD_ATTRIBUTE.into(),
+        SHOW_LOG.into(),
+        EFFECTIVE_CONFIG.into(),
+        NEW_MODEL.into(),
+        CLEAR_ANALYSIS_CACHE.into(),
+        VALIDATE_MODEL.into(),
+        DIFF.into(),
+        FEATURE_CONSTRAINTS.into(),
+        RENAME_PREVIEW.into(),
+        NORMAL_FORM.into(),
+    ]
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AddAttributeArgs {
+    pub uri: Url,
+    pub features: Vec<String>,
+    //Supplied by the extension after prompting the user
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub value: Option<String>,
+}
+#[derive(Serialize, Debug, Default)]
+pub struct AddAttributeResult {
+    pub edited: Vec<String>,
+    //Features already carrying the key
+    pub skipped: Vec<String>,
+}
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RenamePreviewArgs {
+    pub uri: Url,
+    pub position: Position,
+    pub new_name: String,
+}
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RenamedFile {
+    pub uri: Url,
+    pub edits: usize,
+}
+//Summary of the edit a rename would apply, the edit itself is included so a client can apply
+//it once the user confirmed
+#[derive(Serialize, Debug, Clone)]
+pub struct RenamePreview {
+    pub files: Vec<RenamedFile>,
+    pub edits: usize,
+    pub edit: WorkspaceEdit,
+}
+//Features targeted by a selection, either all features of the group whose keyword is
+//selected or several sibling features
+fn selected_features(file: &Document, span: Span) -> Vec<Symbol> {
+    let mut group = None;
+    file.visit_children(Symbol::Root, false, |sym| match sym {
+        Symbol::Group(..) => {
+            if file.span(sym).is_some_and(|s| s.contains(&span.start)) {
+                group = Some(sym);
+            }
+            group.is_none()
+        }
+        _ => group.is_none(),
+    });
+    if let Some(group) = group {
+        return file
+            .direct_children(group)
+            .filter(|sym| matches!(sym, Symbol::Feature(..)))
+            .collect();
+    }
+    let selected: Vec<Symbol> = file
+        .all_features()
+        .filter(|sym| {
+            file.span(*sym)
+                .is_some_and(|s| span.start <= s.start && s.end <= span.end)
+        })
+        .collect();
+    let parent = selected.first().and_then(|sym| file.parent(*sym, false));
+    if selected.len() > 1
+        && selected
+            .iter()
+            .all(|sym| file.parent(*sym, false) == parent)
+    {
+        selected
+    } else {
+        Vec::new()
+    }
+}
+//Rename a shadowing attribute or nested feature to <feature>_<name>, where feature owns the
+//attribute or encloses the nested feature, together with all references through it
+fn rename_shadowing(root: &Snapshot, file_id: FileID, sym: Symbol) -> Option<CodeAction> {
+    let file = root.file(file_id);
+    let name = file.symbol_name(sym)?;
+    let feature = match sym {
+        Symbol::Attribute(..) => file.parent(sym, false)?,
+        _ => file.ancestors(sym).nth(1)?,
+    };
+    let base = format!("{}_{}", file.symbol_name(feature)?, name);
+    if !valid_name(&base) {
+        return None;
+    }
+    let new_name = (1..)
+        .map(|i| match i {
+            1 => base.clone(),
+            _ => format!("{}{}", base, i),
+        })
+        .find(|name| {
+            let path = [Ustr::from(name)];
+            file.lookup(feature, &path, |_| true).next().is_none()
+                && file.lookup(Symbol::Root, &path, |_| true).next().is_none()
+        })?;
+    let target = RootSymbol { file: file_id, sym };
+    Some(CodeAction {
+        title: format!("rename {} to {}", name, new_name),
+        kind: Some(CodeActionKind::QUICKFIX),
+        edit: Some(WorkspaceEdit {
+            changes: Some(rename_edits(root, target, &new_name)?),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+//The edit of textDocument/rename, the preview command computes the same one
+pub fn rename(
+    root: &Snapshot,
+    draft: &Draft,
+    pos: &Position,
+    uri: &Url,
+    new_name: &str,
+) -> Option<WorkspaceEdit> {
+    let target = rename_target(root, draft, pos, uri)?;
+    Some(WorkspaceEdit {
+        changes: Some(rename_edits(root, target, new_name)?),
+        ..Default::default()
+    })
+}
+pub fn rename_preview(edit: WorkspaceEdit) -> RenamePreview {
+    let mut files: Vec<RenamedFile> = edit
+        .changes
+        .iter()
+        .flatten()
+        .map(|(uri, edits)| RenamedFile {
+            uri: uri.clone(),
+            edits: edits.len(),
+        })
+        .collect();
+    files.sort_by(|a, b| a.uri.cmp(&b.uri));
+    RenamePreview {
+        edits: files.iter().map(|f| f.edits).sum(),
+        files,
+        edit,
+    }
+}
+//Plain identifiers only, quoted names would need their references quoted as well
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+//Attributes below target with the number of path segments between them and target
+fn nested_attributes(file: &Document, target: Symbol) -> Vec<(Symbol, usize)> {
+    let mut out = vec![(target, 0)];
+    for sym in file.all_attributes() {
+        let mut below = 0;
+        let mut cur = Some(sym);
+        while let Some(s @ Symbol::Attribute(..)) = cur {
+            below += 1;
+            cur = file.parent(s, false);
+            if cur == Some(target) {
+                out.push((sym, below));
+                break;
+            }
+        }
+    }
+    out
+}
+//Edits renaming a feature or attribute and all references to it, references to nested
+//attributes pass through the renamed segment as well
+pub fn rename_edits(
+    root: &Snapshot,
+    target: RootSymbol,
+    new_name: &str,
+) -> Option<HashMap<Url, Vec<TextEdit>>> {
+    let file = root.file(target.file);
+    let name = file.symbol_name(target.sym)?;
+    let mut changes: HashMap<Url, Vec<TextEdit>> = HashMap::new();
+    changes.entry(file.uri.clone()).or_default().push(TextEdit {
+        range: file.lsp_range(target.sym)?,
+        new_text: new_name.into(),
+    });
+    for (sym, below) in nested_attributes(file, target.sym) {
+        for r in reverse_resolve(root, target.file, sym) {
+            let src = root.file(r.file);
+            let spans = src.path_spans(r.sym);
+            let range = spans
+                .len()
+                .checked_sub(below + 1)
+                .filter(|i| src.path(r.sym)[*i] == name)
+                .and_then(|i| lsp_range(spans[i].clone(), &src.source));
+            if let Some(range) = range {
+                changes.entry(src.uri.clone()).or_default().push(TextEdit {
+                    range,
+                    new_text: new_name.into(),
+                });
+            }
+        }
+    }
+    for (src, span) in valueref::occurrences(root, target) {
+        let src = root.file(src);
+        if let Some(range) = lsp_range(span, &src.source) {
+            changes.entry(src.uri.clone()).or_default().push(TextEdit {
+                range,
+                new_text: new_name.into(),
+            });
+        }
+    }
+    Some(changes)
+}
+//The child of a single child group is mandatory. Below a mandatory group it joins that group
+//when the header line is removed, otherwise the header becomes mandatory
+fn unwrap_group(file: &Document, uri: &Url, group: Symbol) -> Option<CodeAction> {
+    let header = group_header(file, group)?;
+    let range = file.lsp_range(group)?;
+    let siblings: Vec<Symbol> = file
+        .direct_children(file.parent(group, false)?)
+        .take_while(|sym| *sym != group)
+        .filter(|sym| matches!(sym, Symbol::Group(..)))
+        .collect();
+    let edit = match siblings.last().and_then(|sym| file.group_mode(*sym)) {
+        Some(GroupMode::Mandatory) => TextEdit {
+            range: Range {
+                start: Position::new(range.start.line, 0),
+                end: Position::new(range.start.line + 1, 0),
+            },
+            new_text: String::new(),
+        },
+        _ => TextEdit {
+            range,
+            new_text: "mandatory".into(),
+        },
+    };
+    Some(CodeAction {
+        title: format!("remove redundant {} group", header),
+        kind: Some(CodeActionKind::QUICKFIX),
+        edit: Some(WorkspaceEdit {
+            changes: Some([(uri.clone(), vec![edit])].into_iter().collect()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+//Rows from a group header to the last line of its descendants
+fn block_rows(file: &Document, group: Symbol) -> Option<(usize, usize)> {
+    let span = file.span(group)?;
+    let mut blk = file
+        .tree()
+        .root_node()
+        .named_descendant_for_byte_range(span.start, span.end)?;
+    while blk.kind() != "blk" {
+        blk = blk.parent()?;
+    }
+    let (start, end) = (blk.start_position(), blk.end_position());
+    if end.column == 0 && end.row > start.row {
+        Some((start.row, end.row - 1))
+    } else {
+        Some((start.row, end.row))
+    }
+}
+fn indentation(file: &Document, row: usize) -> usize {
+    file.source
+        .line(row)
+        .chars()
+        .take_while(|c| *c == ' ' || *c == '\t')
+        .count()
+}
+//Lines of rows with up to width leading whitespace removed, always ending with a line break
+fn dedent(file: &Document, rows: std::ops::RangeInclusive<usize>, width: usize) -> String {
+    let mut out = String::new();
+    for row in rows {
+        let line = file.source.line(row);
+        let skip = indentation(file, row).min(width);
+        out.extend(line.chars().skip(skip));
+    }
+    if !out.ends_with('\n') {
+        out.push('\n');
+    }
+    out
+}
+//A mandatory group nested in an alternative or or group either moves below the enclosing
+//group, keeping its features mandatory, or its features become regular members
+fn resolve_exclusive_mandatory(
+    file: &Document,
+    uri: &Url,
+    group: Symbol,
+    parent: Symbol,
+) -> Option<Vec<CodeAction>> {
+    let header = group_header(file, parent)?;
+    let (start, end) = block_rows(file, group)?;
+    let (parent_start, parent_end) = block_rows(file, parent)?;
+    let lines = |from: usize, to: usize| Range {
+        start: Position::new(from as u32, 0),
+        end: Position::new(to as u32, 0),
+    };
+    let moved = dedent(
+        file,
+        start..=end,
+        indentation(file, start).saturating_sub(indentation(file, parent_start)),
+    );
+    let move_out = if end == parent_end {
+        vec![TextEdit {
+            range: lines(start, end + 1),
+            new_text: moved,
+        }]
+    } else {
+        vec![
+            TextEdit {
+                range: lines(start, end + 1),
+                new_text: String::new(),
+            },
+            TextEdit {
+                range: lines(parent_end + 1, parent_end + 1),
+                new_text: moved,
+            },
+        ]
+    };
+    let members = if end > start {
+        dedent(
+            file,
+            start + 1..=end,
+            indentation(file, start + 1).saturating_sub(indentation(file, start)),
+        )
+    } else {
+        String::new()
+    };
+    let merge = vec![TextEdit {
+        range: lines(start, end + 1),
+        new_text: members,
+    }];
+    let action = |title: String, edits: Vec<TextEdit>| CodeAction {
+        title,
+        kind: Some(CodeActionKind::QUICKFIX),
+        edit: Some(WorkspaceEdit {
+            changes: Some([(uri.clone(), edits)].into_iter().collect()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    Some(vec![
+        action(
+            format!("move the mandatory group out of the {} group", header),
+            move_out,
+        ),
+        action(
+            format!("make the features members of the {} group", header),
+            merge,
+        ),
+    ])
+}
+fn snap_indentation(uri: &Url, issue: &IndentIssue) -> CodeAction {
+    let nearest = issue.nearest();
+    let edit = TextEdit {
+        range: Range {
+            start: Position::new(issue.row as u32, 0),
+            end: Position::new(issue.row as u32, issue.width as u32),
+        },
+        new_text: " ".repeat(nearest),
+    };
+    CodeAction {
+        title: format!("indent to {} spaces", nearest),
+        kind: Some(CodeActionKind::QUICKFIX),
+        edit: Some(WorkspaceEdit {
+            changes: Some([(uri.clone(), vec![edit])].into_iter().collect()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+pub fn code_actions(
+    root: &Snapshot,
+    uri: &Url,
+    range: Range,
+    schema: &[SchemaRule],
+) -> Vec<CodeActionOrCommand> {
+    let file_id = match root.file_id(uri) {
+        Some(file_id) => file_id,
+        None => return Vec::new(),
+    };
+    let file = root.file(file_id);
+    let span = byte_offset(&range.start, &file.source)..byte_offset(&range.end, &file.source);
+    let mut actions: Vec<CodeActionOrCommand> = find_shadowed(file)
+        .into_iter()
+        .filter(|(sym, _)| {
+            file.span(*sym)
+                .is_some_and(|s| s.start <= span.end && span.start <= s.end)
+        })
+        .filter_map(|(sym, _)| rename_shadowing(root, file_id, sym))
+        .map(CodeActionOrCommand::CodeAction)
+        .collect();
+    let rows = range.start.line as usize..=range.end.line as usize;
+    actions.extend(
+        indentation_issues(file.tree(), &file.source)
+            .iter()
+            .filter(|issue| rows.contains(&issue.row))
+            .map(|issue| CodeActionOrCommand::CodeAction(snap_indentation(uri, issue))),
+    );
+    actions.extend(
+        trivial_groups(file)
+            .into_iter()
+            .filter(|(group, children)| {
+                *children == 1
+                    && file
+                        .span(*group)
+                        .is_some_and(|s| s.start <= span.end && span.start <= s.end)
+            })
+            .filter_map(|(group, _)| unwrap_group(file, uri, group))
+            .map(CodeActionOrCommand::CodeAction),
+    );
+    for (group, parent) in exclusive_mandatory(file) {
+        if !file
+            .span(group)
+            .is_some_and(|s| s.start <= span.end && span.start <= s.end)
+        {
+            continue;
+        }
+        actions.extend(
+            resolve_exclusive_mandatory(file, uri, group, parent)
+                .into_iter()
+                .flatten()
+                .map(CodeActionOrCommand::CodeAction),
+        );
+    }
+    //nested keys would need to be merged into an existing block, those are left to the user
+    for (feature, key, stub) in missing_attributes(schema, file) {
+        if key.contains('.')
+            || !file
+                .span(feature)
+                .is_some_and(|s| s.start <= span.end && span.start <= s.end)
+        {
+            continue;
+        }
+        if let Some(edit) = attribute_edit(file, feature, &stub) {
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: format!(
+                    "add required attribute {} to {}",
+                    key,
+                    file.symbol_name(feature).unwrap_or_default()
+                ),
+                kind: Some(CodeActionKind::QUICKFIX),
+                edit: Some(WorkspaceEdit {
+                    changes: Some([(uri.clone(), vec![edit])].into_iter().collect()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }));
+        }
+    }
+    let features: Vec<String> = selected_features(file, span)
+        .into_iter()
+        .filter_map(|sym| file.symbol_name(sym))
+        .map(|name| name.to_string())
+        .collect();
+    if features.is_empty() {
+        return actions;
+    }
+    let title = format!("add attribute to {} features", features.len());
+    let args = AddAttributeArgs {
+        uri: uri.clone(),
+        features,
+        key: None,
+        value: None,
+    };
+    actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+        title: title.clone(),
+        kind: Some(CodeActionKind::REFACTOR),
+        command: Some(Command {
+            title,
+            command: ADD_ATTRIBUTE.into(),
+            arguments: serde_json::to_value(args).ok().map(|args| vec![args]),
+        }),
+        ..Default::default()
+    }));
+    actions
+}
+//Insert text for a new attribute into the block of a feature, existing attributes are kept
+//and multi line blocks get the attribute on its own line
+fn attribute_edit(file: &Document, feature: Symbol, attribute: &str) -> Option<TextEdit> {
+    let span = file.span(feature)?;
+    let name = file
+        .tree()
+        .root_node()
+        .named_descendant_for_byte_range(span.start, span.end)?;
+    let blk = name.parent()?;
+    let (offset, text) = if let Some(attribs) = blk.child_by_field_name("attribs") {
+        let mut cursor = attribs.walk();
+        let children: Vec<Node> = attribs.named_children(&mut cursor).collect();
+        match children.last() {
+            None => (attribs.start_byte() + 1, attribute.to_string()),
+            Some(last) if last.end_position().row == attribs.end_position().row => {
+                (last.end_byte(), format!(", {}", attribute))
+            }
+            Some(last) => {
+                let line = file.source.byte_to_line(last.start_byte());
+                let line_start = file.source.line_to_byte(line);
+                let indent: String = file
+                    .source
+                    .byte_slice(line_start..last.start_byte())
+                    .chars()
+                    .take_while(|c| c.is_whitespace())
+                    .collect();
+                (last.end_byte(), format!(",\n{}{}", indent, attribute))
+            }
+        }
+    } else {
+        let end = blk
+            .child_by_field_name("cardinality")
+            .map_or(name.end_byte(), |card| card.end_byte());
+        (end, format!(" {{{}}}", attribute))
+    };
+    let pos = lsp_position(offset, &file.source)?;
+    Some(TextEdit {
+        range: Range {
+            start: pos,
+            end: pos,
+        },
+        new_text: text,
+    })
+}
+#[derive(Debug, PartialEq, Eq)]
+pub enum AddAttributeError {
+    InvalidKey,
+    UnknownModel,
+    //Feature whose declaration could not be edited, nothing is applied then
+    NoEdit(String),
+}
+pub fn add_attribute(
+    root: &Snapshot,
+    args: &AddAttributeArgs,
+) -> Result<(WorkspaceEdit, AddAttributeResult), AddAttributeError> {
+    let key = Ustr::from(args.key.as_deref().unwrap_or_default().trim());
+    if key.is_empty() || key.chars().any(|c| c.is_whitespace()) {
+        return Err(AddAttributeError::InvalidKey);
+    }
+    let value = args.value.as_deref().unwrap_or("0");
+    let attribute = if value.is_empty() {
+        key.to_string()
+    } else {
+        format!("{} {}", key, value)
+    };
+    let file = root
+        .file_by_uri(&args.uri)
+        .ok_or(AddAttributeError::UnknownModel)?;
+    let mut result = AddAttributeResult::default();
+    let mut edits = Vec::new();
+    for name in args.features.iter() {
+        let path = [Ustr::from(name)];
+        let feature = match file
+            .lookup(Symbol::Root, &path, |sym| {
+                matches!(sym, Symbol::Feature(..))
+            })
+            .next()
+        {
+            Some(feature) => feature,
+            None => {
+                info!("add attribute: unknown feature {}", name);
+                continue;
+            }
+        };
+        if file
+            .lookup(feature, std::slice::from_ref(&key), |_| true)
+            .next()
+            .is_some()
+        {
+            result.skipped.push(name.clone());
+        } else {
+            let edit = attribute_edit(file, feature, &attribute)
+                .ok_or_else(|| AddAttributeError::NoEdit(name.clone()))?;
+            edits.push(edit);
+            result.edited.push(name.clone());
+        }
+    }
+    Ok((
+        WorkspaceEdit {
+            changes: Some([(args.uri.clone(), edits)].into_iter().collect()),
+            ..Default::default()
+        },
+        result,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::build_document;
+    use crate::parse::parse;
+    use ropey::Rope;
+    use std::sync::Arc;
+    use tokio::time::Instant;
+    fn graph(text: &str) -> (Url, tokio::sync::RwLock<RootGraph>) {
+        let uri = Url::parse("file:///actions.uvl").unwrap();
+        let source = Rope::from_str(text);
+        let tree = parse(&source, None);
+        let doc = build_document(
+            source,
+            tree,
+            uri.clone(),
+            Instant::now(),
+            Default::default(),
+        );
+        let files: hashbrown::HashMap<Url, Arc<Document>> =
+            [(uri.clone(), Arc::new(doc))].into_iter().collect();
+        (uri, tokio::sync::RwLock::new(RootGraph::new(&files, 0)))
+    }
+    //Code actions offered at the end of line
+    fn actions_at(text: &str, line: u32) -> Vec<CodeAction> {
+        actions_with(text, line, &[])
+    }
+    fn actions_with(text: &str, line: u32, schema: &[SchemaRule]) -> Vec<CodeAction> {
+        let (uri, root) = graph(text);
+        let snapshot = root.try_read().unwrap();
+        let pos = Position::new(line, text.lines().nth(line as usize).unwrap().len() as u32);
+        code_actions(&snapshot, &uri, Range::new(pos, pos), schema)
+            .into_iter()
+            .filter_map(|action| match action {
+                CodeActionOrCommand::CodeAction(action) => Some(action),
+                _ => None,
+            })
+            .collect()
+    }
+    fn apply_edit(text: &str, edit: &WorkspaceEdit) -> String {
+        let changes = edit.changes.as_ref().unwrap();
+        let mut edits: Vec<&TextEdit> = changes.values().flatten().collect();
+        edits.sort_by_key(|e| std::cmp::Reverse((e.range.start.line, e.range.start.character)));
+        let mut source = Rope::from_str(text);
+        for edit in edits {
+            let start = source.byte_to_char(byte_offset(&edit.range.start, &source));
+            let end = source.byte_to_char(byte_offset(&edit.range.end, &source));
+            source.remove(start..end);
+            source.insert(start, &edit.new_text);
+        }
+        source.to_string()
+    }
+    //Text after applying the edits of the action titled title
+    fn apply(text: &str, actions: &[CodeAction], title: &str) -> String {
+        let action = actions
+            .iter()
+            .find(|a| a.title.starts_with(title))
+            .unwrap_or_else(|| panic!("no action {} in {:?}", title, actions));
+        apply_edit(text, action.edit.as_ref().unwrap())
+    }
+    #[test]
+    fn exclusive_mandatory_fixes() {
+        let text = "features\n    A\n        alternative\n            B\n            mandatory\n                C\n                D\n            E\n";
+        let actions = actions_at(text, 4);
+        assert_eq!(
+            apply(text, &actions, "move the mandatory group out"),
+            "features\n    A\n        alternative\n            B\n            E\n        mandatory\n            C\n            D\n"
+        );
+        assert_eq!(
+            apply(text, &actions, "make the features members"),
+            "features\n    A\n        alternative\n            B\n            C\n            D\n            E\n"
+        );
+        //the last member of the enclosing group stays in place
+        let text = "features\n    A\n        or\n            B\n            mandatory\n                C\n        optional\n            F\n";
+        let actions = actions_at(text, 4);
+        assert_eq!(
+            apply(text, &actions, "move the mandatory group out of the or group"),
+            "features\n    A\n        or\n            B\n        mandatory\n            C\n        optional\n            F\n"
+        );
+        assert_eq!(
+            apply(text, &actions, "make the features members"),
+            "features\n    A\n        or\n            B\n            C\n        optional\n            F\n"
+        );
+    }
+    #[test]
+    fn add_attribute_edits() {
+        let text = "features\n    Car\n        optional\n            Engine {power 100}\n            Wheel cardinality [1..4]\n            Seat {\n                weight 2\n            }\n            Radio {price 3}\n";
+        let actions = actions_at(text, 2);
+        let action = actions
+            .iter()
+            .find(|a| a.title == "add attribute to 4 features")
+            .unwrap();
+        let command = action.command.as_ref().unwrap();
+        assert_eq!(command.command, ADD_ATTRIBUTE);
+        let mut args: AddAttributeArgs =
+            serde_json::from_value(command.arguments.as_ref().unwrap()[0].clone()).unwrap();
+        assert_eq!(args.features, ["Engine", "Wheel", "Seat", "Radio"]);
+        let (_, root) = graph(text);
+        let snapshot = root.try_read().unwrap();
+        assert_eq!(
+            add_attribute(&snapshot, &args).unwrap_err(),
+            AddAttributeError::InvalidKey
+        );
+        args.key = Some("price".into());
+        args.value = Some("1".into());
+        let (edit, result) = add_attribute(&snapshot, &args).unwrap();
+        assert_eq!(result.edited, ["Engine", "Wheel", "Seat"]);
+        assert_eq!(result.skipped, ["Radio"]);
+        assert_eq!(
+            apply_edit(text, &edit),
+            "features\n    Car\n        optional\n            Engine {power 100, price 1}\n            Wheel cardinality [1..4] {price 1}\n            Seat {\n                weight 2,\n                price 1\n            }\n            Radio {price 3}\n"
+        );
+        args.uri = Url::parse("file:///missing.uvl").unwrap();
+        assert_eq!(
+            add_attribute(&snapshot, &args).unwrap_err(),
+            AddAttributeError::UnknownModel
+        );
+        //sibling selections without a group keyword
+        let (uri, root) = graph(text);
+        let snapshot = root.try_read().unwrap();
+        let actions: Vec<String> = code_actions(
+            &snapshot,
+            &uri,
+            Range::new(Position::new(3, 12), Position::new(4, 36)),
+            &[],
+        )
+        .into_iter()
+        .filter_map(|action| match action {
+            CodeActionOrCommand::CodeAction(action) => Some(action.title),
+            _ => None,
+        })
+        .collect();
+        assert!(actions.contains(&"add attribute to 2 features".to_string()));
+    }
+    #[test]
+    fn required_attribute_fix() {
+        let text = "features\n    Car {cost 1}\n        optional\n            Engine\n";
+        let schema: Vec<SchemaRule> = serde_json::from_str(
+            r#"[{"prefix": "", "attributes": {"cost": {"type": "number", "required": true}}}]"#,
+        )
+        .unwrap();
+        let actions = actions_with(text, 3, &schema);
+        assert_eq!(
+            apply(text, &actions, "add required attribute cost to Engine"),
+            "features\n    Car {cost 1}\n        optional\n            Engine {cost 0}\n"
+        );
+        assert!(!actions.iter().any(|a| a.title.ends_with("to Car")));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::cache::{struct_codec, Codec};
 use crate::check::ErrorInfo;
 use crate::parse::*;
 use crate::util::{lsp_range, node_range};
@@ -6,8 +7,9 @@ use hashbrown::HashMap;
 use log::info;
 use ropey::Rope;
 use std::borrow::{Borrow, Cow};
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
 use std::path::Component;
+use std::sync::OnceLock;
 use tokio::time::Instant;
 use tower_lsp::lsp_types::{DiagnosticSeverity, Url};
 use tree_sitter::{Node, Tree, TreeCursor};
@@ -35,6 +37,23 @@ where
     }
 }
 pub type Span = std::ops::Range<usize>;
+//Syntax level limits from the settings, documents are built again when they change
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Limits {
+    //Constraints with more syntax nodes are skipped from analysis
+    pub max_constraint_size: usize,
+    //Percentage of the file covered by syntax errors above which it is reported as not being
+    //UVL, 0 disables it
+    pub not_uvl_threshold: usize,
+}
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_constraint_size: 5000,
+            not_uvl_threshold: 50,
+        }
+    }
+}
 #[derive(Clone, Debug)]
 pub struct SymbolSpan {
     pub name: Ustr,
@@ -145,6 +164,8 @@ pub struct Group {
 pub struct Reference {
     pub path: Path,
     pub ty: Type,
+    //Feature whose attribute block contains the reference or Root
+    pub scope: Symbol,
 }
 #[derive(Clone, Debug)]
 pub struct Attribute {
@@ -276,6 +297,13 @@ pub enum Constraint {
     Not(Box<Constraint>),
 }
 
+//A constraint or numeric sub expression, references keep their symbol to look up their type
+#[derive(Clone, Debug)]
+pub struct ExprInfo {
+    pub span: Span,
+    pub numeric: bool,
+    pub reference: Option<Symbol>,
+}
 #[derive(Clone, Debug)]
 pub struct ConstraintDecl {
     pub content: Constraint,
@@ -325,6 +353,25 @@ impl Symbol {
     }
 }
 
+//The features declared directly under the features keyword, multiple top level features
+//are treated as mandatory children of an implicit synthetic root
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ModelRoot {
+    #[default]
+    Empty,
+    Feature(Symbol),
+    Synthetic(Vec<Symbol>),
+}
+impl ModelRoot {
+    pub fn features(&self) -> &[Symbol] {
+        match self {
+            Self::Empty => &[],
+            Self::Feature(sym) => std::slice::from_ref(sym),
+            Self::Synthetic(features) => features,
+        }
+    }
+}
+
 #[derive(Default, Debug, Clone)]
 struct TreeMap {
     children: HashMap<Symbol, Vec<Symbol>>,
@@ -350,6 +397,10 @@ struct Ast {
     dirs: Vec<Dir>,
     structure: TreeMap,
     index: HashMap<(Symbol, Ustr, SymbolKind), Symbol>,
+    root: ModelRoot,
+    expressions: Vec<ExprInfo>,
+    //constraints skipped for exceeding Limits::max_constraint_size
+    oversized: Vec<Span>,
 }
 impl Ast {
     pub fn import_prefix(&self, sym: Symbol) -> &[Ustr] {
@@ -454,6 +505,9 @@ impl Ast {
     fn all_references(&self) -> impl Iterator<Item = Symbol> {
         (0..self.references.len()).map(|i| Symbol::Reference(i as u32))
     }
+    fn all_groups(&self) -> impl Iterator<Item = Symbol> {
+        (0..self.groups.len()).map(|i| Symbol::Group(i as u32))
+    }
 
     fn all_constraints(&self) -> impl Iterator<Item = Symbol> {
         (0..self.constraints.len()).map(|i| Symbol::Constraint(i as u32))
@@ -472,6 +526,11 @@ struct VisitorState<'a> {
     cursor: TreeCursor<'a>,
     ast: Ast,
     source: &'a Rope,
+    //Feature owning the constraint currently visited
+    scope: Symbol,
+    //Git conflict blocks, declarations inside are not extracted
+    conflicts: Vec<Span>,
+    limits: Limits,
 }
 impl<'a> VisitorState<'a> {
     fn add_constraint(&mut self, constraint: Constraint, scope: Symbol) -> Symbol {
@@ -484,13 +543,21 @@ impl<'a> VisitorState<'a> {
         sym
     }
     fn add_ref(&mut self, path: Path, req: Type, scope: Symbol) -> Symbol {
-        self.ast.references.push(Reference { path, ty: req });
+        self.ast.references.push(Reference {
+            path,
+            ty: req,
+            scope: Symbol::Root,
+        });
         let sym = Symbol::Reference(self.ast.references.len() as u32 - 1);
         self.push_child(scope, sym);
         sym
     }
     fn add_ref_direct(&mut self, path: Path, req: Type) -> Symbol {
-        self.ast.references.push(Reference { path, ty: req });
+        self.ast.references.push(Reference {
+            path,
+            ty: req,
+            scope: self.scope,
+        });
 
         Symbol::Reference(self.ast.references.len() as u32 - 1)
     }
@@ -535,13 +602,27 @@ impl<'a> VisitorState<'a> {
                     location: self.ast.lsp_range(i, self.source).unwrap(),
                     severity: DiagnosticSeverity::ERROR,
                     weight: 20,
+                    related: Vec::new(),
+                    code: None,
                     msg: format!(
                         "duplicate import already defined in line {}",
                         self.ast.lsp_range(old, self.source).unwrap().start.line
                     ),
+                    tags: Vec::new(),
                 });
             }
         }
+        let roots: Vec<Symbol> = self
+            .ast
+            .children(Symbol::Root)
+            .filter(|sym| matches!(sym, Symbol::Feature(..)))
+            .collect();
+        self.ast.root = match roots.len() {
+            0 => ModelRoot::Empty,
+            1 => ModelRoot::Feature(roots[0]),
+            //reported by check_root_feature
+            _ => ModelRoot::Synthetic(roots),
+        };
         let mut stack = vec![(Symbol::Root, Symbol::Root, 0)];
 
         while let Some((node, scope, depth)) = stack.pop() {
@@ -557,13 +638,19 @@ impl<'a> VisitorState<'a> {
                                 location: self.ast.lsp_range(node, self.source).unwrap(),
                                 severity: DiagnosticSeverity::ERROR,
                                 weight: 20,
+                                related: Vec::new(),
+                                code: None,
                                 msg: "duplicate feature".to_string(),
+                                tags: Vec::new(),
                             });
                             self.errors.push(ErrorInfo {
                                 location: self.ast.lsp_range(old, self.source).unwrap(),
                                 severity: DiagnosticSeverity::ERROR,
                                 weight: 20,
+                                related: Vec::new(),
+                                code: None,
                                 msg: "duplicate feature".to_string(),
+                                tags: Vec::new(),
                             })
                         }
                         node
@@ -578,13 +665,19 @@ impl<'a> VisitorState<'a> {
                                 location: self.ast.lsp_range(node, self.source).unwrap(),
                                 severity: DiagnosticSeverity::ERROR,
                                 weight: 20,
+                                related: Vec::new(),
+                                code: None,
                                 msg: "duplicate attribute".to_string(),
+                                tags: Vec::new(),
                             });
                             self.errors.push(ErrorInfo {
                                 location: self.ast.lsp_range(old, self.source).unwrap(),
                                 severity: DiagnosticSeverity::ERROR,
                                 weight: 20,
+                                related: Vec::new(),
+                                code: None,
                                 msg: "duplicate attribute".to_string(),
+                                tags: Vec::new(),
                             });
                         };
                         self.ast.attributes[i as usize].depth = depth;
@@ -628,6 +721,9 @@ impl<'a> VisitorState<'a> {
     fn goto_next_sibling(&mut self) -> bool {
         self.cursor.goto_next_sibling() && self.skip_extra()
     }
+    fn in_conflict(&self) -> bool {
+        crate::check::in_conflict(&self.conflicts, &self.node().byte_range())
+    }
     fn goto_parent(&mut self) {
         self.cursor.goto_parent();
     }
@@ -637,6 +733,16 @@ impl<'a> VisitorState<'a> {
     fn node(&self) -> Node<'a> {
         self.cursor.node()
     }
+    //Node::parent searches down from the root which is quadratic for deeply nested features,
+    //the cursor already knows the parent
+    fn parent(&self) -> Option<Node<'a>> {
+        let mut cursor = self.cursor.clone();
+        if cursor.goto_parent() {
+            Some(cursor.node())
+        } else {
+            self.node().parent()
+        }
+    }
     fn header(&self) -> Option<Node<'a>> {
         self.node().child_by_field_name("header")
     }
@@ -678,7 +784,10 @@ impl<'a> VisitorState<'a> {
             location: node_range(self.node(), self.source),
             severity: DiagnosticSeverity::ERROR,
             weight: w,
+            related: Vec::new(),
+            code: None,
             msg: error.into(),
+            tags: Vec::new(),
         });
     }
     fn push_error_blk<T: Into<String>>(&mut self, w: u32, error: T) {
@@ -686,7 +795,10 @@ impl<'a> VisitorState<'a> {
             location: node_range(self.header().unwrap(), self.source),
             severity: DiagnosticSeverity::ERROR,
             weight: w,
+            related: Vec::new(),
+            code: None,
             msg: error.into(),
+            tags: Vec::new(),
         });
     }
     fn push_error_node<T: Into<String>>(&mut self, node: Node, w: u32, error: T) {
@@ -694,7 +806,10 @@ impl<'a> VisitorState<'a> {
             location: node_range(node, self.source),
             severity: DiagnosticSeverity::ERROR,
             weight: w,
+            related: Vec::new(),
+            code: None,
             msg: error.into(),
+            tags: Vec::new(),
         });
     }
 }
@@ -737,7 +852,8 @@ impl<'b> SymbolSlice for VisitorState<'b> {
 pub struct Document {
     ast: Ast,
     pub source: Rope,
-    pub tree: Tree,
+    //parsed on first use for documents restored from the model cache
+    tree: OnceLock<Tree>,
     pub timestamp: Instant,
     pub errors: Vec<ErrorInfo>,
     pub path: Vec<Ustr>,
@@ -745,6 +861,22 @@ pub struct Document {
     pub name: Ustr,
 }
 impl Document {
+    pub fn tree(&self) -> &Tree {
+        self.tree
+            .get_or_init(|| crate::parse::parse(&self.source, None))
+    }
+    //Syntax level state for the model cache
+    pub fn stored(&self) -> StoredDocument {
+        StoredDocument {
+            ast: self.ast.clone(),
+            errors: self.errors.clone(),
+        }
+    }
+    //Same encoding as stored() without the copy
+    pub fn encode_stored(&self, out: &mut Vec<u8>) {
+        self.ast.encode(out);
+        self.errors.encode(out);
+    }
     pub fn parent(&self, sym: Symbol, merge_root_features: bool) -> Option<Symbol> {
         if merge_root_features && matches!(sym, Symbol::Feature(..)) {
             Some(Symbol::Root)
@@ -763,6 +895,17 @@ impl Document {
         }
         Symbol::Root
     }
+    //Features enclosing sym, nearest first
+    pub fn ancestors(&self, sym: Symbol) -> impl Iterator<Item = Symbol> + '_ {
+        let mut next = Some(sym);
+        std::iter::from_fn(move || loop {
+            let cur = next?;
+            next = self.parent(cur, false);
+            if matches!(cur, Symbol::Feature(..)) {
+                return Some(cur);
+            }
+        })
+    }
     pub fn all_imports(&self) -> impl Iterator<Item = Symbol> {
         self.ast.all_imports()
     }
@@ -775,9 +918,100 @@ impl Document {
     pub fn all_references(&self) -> impl Iterator<Item = Symbol> {
         self.ast.all_references()
     }
+    pub fn all_groups(&self) -> impl Iterator<Item = Symbol> {
+        self.ast.all_groups()
+    }
     pub fn all_constraints(&self) -> impl Iterator<Item = Symbol> {
         self.ast.all_constraints()
     }
+    //Hash of everything other files can resolve against: namespace, imports, features and
+    //attributes with their types. Constraints are left out and the order of attributes does
+    //not matter. Names are hashed as the resolver compares them, a quoted name keeps its
+    //quotes because "A" and A do not resolve to each other
+    pub fn interface_hash(&self) -> u64 {
+        let join = |names: &[Ustr]| {
+            names
+                .iter()
+                .map(|i| i.as_str())
+                .collect::<Vec<_>>()
+                .join(".")
+        };
+        let mut entries = Vec::new();
+        if let Some(ns) = self.namespace() {
+            entries.push(format!("namespace {}", join(&ns.names)));
+        }
+        for i in self.ast.import.iter() {
+            let alias = i.alias.as_ref().map(|alias| alias.name);
+            entries.push(format!("import {} {:?}", join(&i.path.names), alias));
+        }
+        for sym in self.all_features() {
+            entries.push(format!("feature {:?}", self.symbol_name(sym)));
+        }
+        for sym in self.all_attributes() {
+            let mut path = Vec::new();
+            let mut cur = Some(sym);
+            while let Some(s) = cur {
+                path.extend(self.symbol_name(s));
+                cur = match s {
+                    Symbol::Feature(..) => None,
+                    _ => self.parent(s, false),
+                };
+            }
+            path.reverse();
+            entries.push(format!("attribute {} {:?}", join(&path), self.type_of(sym)));
+        }
+        entries.sort();
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        entries.hash(&mut hasher);
+        hasher.finish()
+    }
+    //Hash of everything the solver encoding reads: the interface, the tree of groups, values
+    //and constraints. Symbols are hashed in declaration order since the encoding names them
+    //by index. Layout and comments do not matter
+    pub fn composition_hash(&self) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        self.interface_hash().hash(&mut hasher);
+        for sym in self
+            .all_features()
+            .chain(self.all_groups())
+            .chain(self.all_attributes())
+            .chain(self.all_references())
+            .chain(self.all_constraints())
+        {
+            sym.hash(&mut hasher);
+            self.parent(sym, false).hash(&mut hasher);
+            self.symbol_name(sym).hash(&mut hasher);
+            match sym {
+                Symbol::Group(..) => format!("{:?}", self.group_mode(sym)).hash(&mut hasher),
+                Symbol::Attribute(..) => format!("{:?}", self.value(sym)).hash(&mut hasher),
+                Symbol::Reference(..) => self.path(sym).hash(&mut hasher),
+                Symbol::Constraint(..) => {
+                    self.scope(sym).hash(&mut hasher);
+                    let text: String = self
+                        .span(sym)
+                        .map(|span| self.source.byte_slice(span).into())
+                        .unwrap_or_default();
+                    text.split_whitespace()
+                        .for_each(|token| token.hash(&mut hasher));
+                }
+                _ => {}
+            }
+        }
+        hasher.finish()
+    }
+    pub fn expressions(&self) -> &[ExprInfo] {
+        &self.ast.expressions
+    }
+    //Constraints that are too large to analyze, they are not part of the tree
+    pub fn oversized_constraints(&self) -> &[Span] {
+        &self.ast.oversized
+    }
+    pub fn model_root(&self) -> &ModelRoot {
+        &self.ast.root
+    }
+    pub fn root_features(&self) -> impl Iterator<Item = Symbol> + '_ {
+        self.ast.root.features().iter().cloned()
+    }
     pub fn group_mode(&self, sym: Symbol) -> Option<GroupMode> {
         match sym {
             Symbol::Group(id) => Some(self.ast.groups[id as usize].mode.clone()),
@@ -797,6 +1031,18 @@ impl Document {
             _ => None,
         }
     }
+    pub fn value_span(&self, sym: Symbol) -> Option<Span> {
+        match sym {
+            Symbol::Attribute(id) => Some(self.ast.attributes[id as usize].value.span.clone()),
+            _ => None,
+        }
+    }
+    pub fn cardinality(&self, sym: Symbol) -> Option<&Cardinality> {
+        match sym {
+            Symbol::Feature(id) => self.ast.features[id as usize].cardinality.as_ref(),
+            _ => None,
+        }
+    }
     pub fn direct_children(&self, sym: Symbol) -> impl Iterator<Item = Symbol> + '_ {
         self.ast
             .structure
@@ -809,9 +1055,24 @@ impl Document {
     pub fn lsp_range(&self, sym: Symbol) -> Option<tower_lsp::lsp_types::Range> {
         self.ast.lsp_range(sym, &self.source)
     }
+    pub fn span(&self, sym: Symbol) -> Option<Span> {
+        self.ast.span(sym)
+    }
+    pub fn symbol_name(&self, sym: Symbol) -> Option<Ustr> {
+        self.ast.name(sym)
+    }
     pub fn namespace(&self) -> Option<&Path> {
         self.ast.namespace.as_ref()
     }
+    pub fn includes(&self) -> &[LanguageLevel] {
+        &self.ast.includes
+    }
+    pub fn alias(&self, sym: Symbol) -> Option<Ustr> {
+        match sym {
+            Symbol::Import(i) => self.ast.import[i as usize].alias.as_ref().map(|a| a.name),
+            _ => None,
+        }
+    }
     pub fn path(&self, sym: Symbol) -> &[Ustr] {
         match sym {
             Symbol::Import(i) => &self.ast.import[i as usize].path.names,
@@ -819,6 +1080,21 @@ impl Document {
             _ => unimplemented!(),
         }
     }
+    pub fn path_spans(&self, sym: Symbol) -> &[Span] {
+        match sym {
+            Symbol::Import(i) => &self.ast.import[i as usize].path.spans,
+            Symbol::Reference(i) => &self.ast.references[i as usize].path.spans,
+            //declarations have no dotted path
+            _ => &[],
+        }
+    }
+    //Scope a reference is resolved from before falling back to the file root
+    pub fn reference_scope(&self, sym: Symbol) -> Symbol {
+        match sym {
+            Symbol::Reference(i) => self.ast.references[i as usize].scope,
+            _ => Symbol::Root,
+        }
+    }
     pub fn import_prefix(&self, sym: Symbol) -> &[Ustr] {
         self.ast.import_prefix(sym)
     }
@@ -1290,7 +1566,8 @@ fn opt_aggreate_op(state: &mut VisitorState) -> Option<AggregateOP> {
     }
 }
 
-fn opt_numeric(state: &mut VisitorState) -> Option<Numeric> {
+//Leaf numeric expressions, the cursor has to be on the expression
+fn opt_numeric_leaf(state: &mut VisitorState) -> Option<Numeric> {
     state.goto_named();
     match state.kind() {
         "path" => {
@@ -1298,30 +1575,6 @@ fn opt_numeric(state: &mut VisitorState) -> Option<Numeric> {
             Some(Numeric::Ref(state.add_ref_direct(path, Type::Number)))
         }
         "number" => Some(Numeric::Number(opt_number(state)?)),
-        "binary_expr" => {
-            let op = state.child_by_name("op").unwrap();
-            visit_children(state, |state| {
-                if let Some(op) = opt_numeric_op(op) {
-                    state.goto_field("lhs");
-                    let lhs = opt_numeric(state)?;
-                    state.goto_field("rhs");
-                    let rhs = opt_numeric(state)?;
-                    Some(Numeric::Binary {
-                        op,
-                        lhs: Box::new(lhs),
-                        rhs: Box::new(rhs),
-                    })
-                } else {
-                    state.push_error_node(
-                        state.node().parent().unwrap(),
-                        40,
-                        "found a constraint, expected a numeric expression",
-                    );
-                    None
-                }
-            })
-        }
-        "nested_expr" => visit_children(state, opt_numeric),
         "aggregate" => {
             let op = opt_aggreate_op(state)?;
             if state.child_by_name("tail").is_some() {
@@ -1392,7 +1645,8 @@ fn opt_equation(node: Node) -> Option<EquationOP> {
     }
 }
 
-fn opt_constraint(state: &mut VisitorState) -> Option<Constraint> {
+//Leaf constraints, the cursor has to be on the expression
+fn opt_constraint_leaf(state: &mut VisitorState) -> Option<Constraint> {
     state.goto_named();
     match state.kind() {
         "path" | "name" => {
@@ -1400,60 +1654,263 @@ fn opt_constraint(state: &mut VisitorState) -> Option<Constraint> {
             Some(Constraint::Ref(state.add_ref_direct(path, Type::Feature)))
         }
         "bool" => Some(Constraint::Constant(visit_children(state, opt_bool))),
-        "unary_expr" => {
-            let op = state.child_by_name("op").unwrap().kind();
-            visit_children(state, |state| {
-                state.goto_field("lhs");
-                match op {
-                    "!" => opt_constraint(state).map(|c| Constraint::Not(Box::new(c))),
-                    _ => None,
-                }
-            })
-        }
-        "nested_expr" => visit_children(state, opt_constraint),
-        "binary_expr" => {
-            let op = state.child_by_name("op").unwrap();
-            visit_children(state, |state| {
-                if let Some(op) = opt_logic_op(op) {
-                    state.goto_field("lhs");
-                    let lhs = opt_constraint(state)?;
-                    state.goto_field("rhs");
-                    let rhs = opt_constraint(state)?;
-                    Some(Constraint::Logic {
-                        op,
-                        lhs: Box::new(lhs),
-                        rhs: Box::new(rhs),
-                    })
-                } else if let Some(op) = opt_equation(op) {
-                    state.goto_field("lhs");
-                    let lhs = opt_numeric(state)?;
-                    state.goto_field("rhs");
-                    let rhs = opt_numeric(state)?;
-                    Some(Constraint::Equation {
-                        op,
-                        lhs: Box::new(lhs),
-                        rhs: Box::new(rhs),
-                    })
-                } else {
-                    state.push_error_node(
-                        state.node().parent().unwrap(),
-                        40,
-                        "expected a constraint found a numeric expression",
-                    );
-                    None
-                }
-            })
-        }
         _ => {
             state.push_error(40, "expected a constraint found a numeric expression");
             None
         }
     }
 }
+//Run f with the cursor placed on node and restore the old cursor afterwards
+fn visit_node<'a, T, F: FnOnce(&mut VisitorState<'a>) -> T>(
+    state: &mut VisitorState<'a>,
+    node: Node<'a>,
+    f: F,
+) -> T {
+    let outer = std::mem::replace(&mut state.cursor, node.walk());
+    let out = f(state);
+    state.cursor = outer;
+    out
+}
+fn first_named(node: Node) -> Option<Node> {
+    let mut cursor = node.walk();
+    let out = node
+        .named_children(&mut cursor)
+        .find(|i| !i.is_extra() && !i.is_error());
+    out
+}
+enum Expr {
+    Constraint(Constraint),
+    Numeric(Numeric),
+}
+enum ExprTask<'a> {
+    Constraint(Node<'a>),
+    Numeric(Node<'a>),
+    Not(Node<'a>),
+    Logic(LogicOP, Node<'a>),
+    Equation(EquationOP, Node<'a>),
+    Binary(NumericOP, Node<'a>),
+}
+//Remember the type of every expression build, constraints are booleans
+fn record_expr(state: &mut VisitorState, node: Node, expr: &Option<Expr>) {
+    let (numeric, reference) = match expr {
+        Some(Expr::Constraint(Constraint::Ref(sym))) => (false, Some(*sym)),
+        Some(Expr::Numeric(Numeric::Ref(sym))) => (true, Some(*sym)),
+        Some(Expr::Constraint(..)) => (false, None),
+        Some(Expr::Numeric(..)) => (true, None),
+        None => return,
+    };
+    state.ast.expressions.push(ExprInfo {
+        span: node.byte_range(),
+        numeric,
+        reference,
+    });
+}
+//Expressions can be nested arbitrarily deep in generated models, so they are build with an
+//explicit stack instead of recursion. Operands are visited left to right and every operand is
+//visited even if a sibling failed to give all errors
+fn opt_expr<'a>(state: &mut VisitorState<'a>, root: ExprTask<'a>) -> Option<Expr> {
+    let mut tasks = vec![root];
+    let mut values: Vec<Option<Expr>> = Vec::new();
+    while let Some(task) = tasks.pop() {
+        match task {
+            ExprTask::Constraint(node) if node.is_error() => values.push(None),
+            ExprTask::Numeric(node) if node.is_error() => values.push(None),
+            ExprTask::Constraint(node) => match node.kind() {
+                "unary_expr" => {
+                    let op = node.child_by_field_name("op").map(|op| op.kind());
+                    match (op, node.child_by_field_name("lhs")) {
+                        (Some("!"), Some(lhs)) => {
+                            tasks.push(ExprTask::Not(node));
+                            tasks.push(ExprTask::Constraint(lhs));
+                        }
+                        _ => values.push(None),
+                    }
+                }
+                "nested_expr" => match first_named(node) {
+                    Some(inner) => tasks.push(ExprTask::Constraint(inner)),
+                    None => values.push(None),
+                },
+                "binary_expr" => {
+                    let op = node.child_by_field_name("op");
+                    let lhs = node.child_by_field_name("lhs");
+                    let rhs = node.child_by_field_name("rhs");
+                    match (op, lhs, rhs) {
+                        (Some(op), Some(lhs), Some(rhs)) => {
+                            if let Some(op) = opt_logic_op(op) {
+                                tasks.push(ExprTask::Logic(op, node));
+                                tasks.push(ExprTask::Constraint(rhs));
+                                tasks.push(ExprTask::Constraint(lhs));
+                            } else if let Some(op) = opt_equation(op) {
+                                tasks.push(ExprTask::Equation(op, node));
+                                tasks.push(ExprTask::Numeric(rhs));
+                                tasks.push(ExprTask::Numeric(lhs));
+                            } else {
+                                state.push_error_node(
+                                    node,
+                                    40,
+                                    "expected a constraint found a numeric expression",
+                                );
+                                values.push(None);
+                            }
+                        }
+                        _ => values.push(None),
+                    }
+                }
+                _ => {
+                    let leaf = visit_node(state, node, opt_constraint_leaf).map(Expr::Constraint);
+                    record_expr(state, node, &leaf);
+                    values.push(leaf);
+                }
+            },
+            ExprTask::Numeric(node) => match node.kind() {
+                "nested_expr" => match first_named(node) {
+                    Some(inner) => tasks.push(ExprTask::Numeric(inner)),
+                    None => values.push(None),
+                },
+                "binary_expr" => {
+                    let op = node.child_by_field_name("op");
+                    let lhs = node.child_by_field_name("lhs");
+                    let rhs = node.child_by_field_name("rhs");
+                    match (op, lhs, rhs) {
+                        (Some(op), Some(lhs), Some(rhs)) => {
+                            if let Some(op) = opt_numeric_op(op) {
+                                tasks.push(ExprTask::Binary(op, node));
+                                tasks.push(ExprTask::Numeric(rhs));
+                                tasks.push(ExprTask::Numeric(lhs));
+                            } else {
+                                state.push_error_node(
+                                    node,
+                                    40,
+                                    "found a constraint, expected a numeric expression",
+                                );
+                                values.push(None);
+                            }
+                        }
+                        _ => values.push(None),
+                    }
+                }
+                _ => {
+                    let leaf = visit_node(state, node, opt_numeric_leaf).map(Expr::Numeric);
+                    record_expr(state, node, &leaf);
+                    values.push(leaf);
+                }
+            },
+            ExprTask::Not(node) => {
+                let out = match values.pop().flatten() {
+                    Some(Expr::Constraint(lhs)) => {
+                        Some(Expr::Constraint(Constraint::Not(Box::new(lhs))))
+                    }
+                    _ => None,
+                };
+                record_expr(state, node, &out);
+                values.push(out);
+            }
+            ExprTask::Logic(op, node) => {
+                let rhs = values.pop().flatten();
+                let lhs = values.pop().flatten();
+                let out = match (lhs, rhs) {
+                    (Some(Expr::Constraint(lhs)), Some(Expr::Constraint(rhs))) => {
+                        Some(Expr::Constraint(Constraint::Logic {
+                            op,
+                            lhs: Box::new(lhs),
+                            rhs: Box::new(rhs),
+                        }))
+                    }
+                    _ => None,
+                };
+                record_expr(state, node, &out);
+                values.push(out);
+            }
+            ExprTask::Equation(op, node) => {
+                let rhs = values.pop().flatten();
+                let lhs = values.pop().flatten();
+                let out = match (lhs, rhs) {
+                    (Some(Expr::Numeric(lhs)), Some(Expr::Numeric(rhs))) => {
+                        Some(Expr::Constraint(Constraint::Equation {
+                            op,
+                            lhs: Box::new(lhs),
+                            rhs: Box::new(rhs),
+                        }))
+                    }
+                    _ => None,
+                };
+                record_expr(state, node, &out);
+                values.push(out);
+            }
+            ExprTask::Binary(op, node) => {
+                let rhs = values.pop().flatten();
+                let lhs = values.pop().flatten();
+                let out = match (lhs, rhs) {
+                    (Some(Expr::Numeric(lhs)), Some(Expr::Numeric(rhs))) => {
+                        Some(Expr::Numeric(Numeric::Binary {
+                            op,
+                            lhs: Box::new(lhs),
+                            rhs: Box::new(rhs),
+                        }))
+                    }
+                    _ => None,
+                };
+                record_expr(state, node, &out);
+                values.push(out);
+            }
+        }
+    }
+    values.pop().flatten()
+}
+fn opt_constraint(state: &mut VisitorState) -> Option<Constraint> {
+    state.goto_named();
+    let node = state.node();
+    match opt_expr(state, ExprTask::Constraint(node))? {
+        Expr::Constraint(c) => Some(c),
+        Expr::Numeric(..) => None,
+    }
+}
+//Number of named syntax nodes under node, counting stops after limit
+fn count_named(node: Node, limit: usize) -> usize {
+    let mut count = 0;
+    let mut cursor = node.walk();
+    'outer: loop {
+        if cursor.node().is_named() {
+            count += 1;
+            if count > limit {
+                break;
+            }
+        }
+        if cursor.goto_first_child() {
+            continue;
+        }
+        loop {
+            if cursor.goto_next_sibling() {
+                continue 'outer;
+            }
+            if !cursor.goto_parent() {
+                break 'outer;
+            }
+        }
+    }
+    count
+}
 fn visit_constraint(state: &mut VisitorState, parent: Symbol) {
+    state.goto_named();
+    let limit = state.limits.max_constraint_size;
+    if count_named(state.node(), limit) > limit {
+        state.errors.push(ErrorInfo {
+            location: node_range(state.node(), state.source),
+            severity: DiagnosticSeverity::WARNING,
+            weight: 30,
+            related: Vec::new(),
+            code: None,
+            msg: "constraint too large to analyze".into(),
+            tags: Vec::new(),
+        });
+        state.ast.oversized.push(state.node().byte_range());
+        return;
+    }
+    state.scope = parent;
     if let Some(cons) = opt_constraint(state) {
         state.add_constraint(cons, parent);
     }
+    state.scope = Symbol::Root;
 }
 fn opt_bool(state: &mut VisitorState) -> bool {
     match state.kind() {
@@ -1509,7 +1966,7 @@ fn visit_attribute_value(state: &mut VisitorState, parent: Symbol) {
     }
 }
 fn visit_constraint_list(state: &mut VisitorState, parent: Symbol) {
-    debug_assert!(state.node().parent().unwrap().kind() == "attribute_constraints");
+    debug_assert!(state.parent().unwrap().kind() == "attribute_constraints");
     loop {
         if state.kind() == "constraint" {
             visit_children_arg(state, parent, visit_constraint);
@@ -1520,7 +1977,7 @@ fn visit_constraint_list(state: &mut VisitorState, parent: Symbol) {
     }
 }
 fn visit_attributes(state: &mut VisitorState, parent: Symbol) {
-    debug_assert!(state.node().parent().unwrap().kind() == "attributes");
+    debug_assert!(state.parent().unwrap().kind() == "attributes");
     loop {
         match state.kind() {
             "attribute_constraints" => {
@@ -1547,7 +2004,7 @@ fn visit_attributes(state: &mut VisitorState, parent: Symbol) {
 }
 
 fn visit_feature(state: &mut VisitorState, parent: Symbol, name: SymbolSpan) {
-    debug_assert!(state.node().parent().unwrap().kind() == "blk");
+    debug_assert!(state.parent().unwrap().kind() == "blk");
     match parent {
         Symbol::Feature(..) => {
             state.push_error(40, "features have to be separated by groups");
@@ -1557,7 +2014,6 @@ fn visit_feature(state: &mut VisitorState, parent: Symbol, name: SymbolSpan) {
     let feature = Feature {
         name,
         cardinality: state
-            .node()
             .parent()
             .unwrap()
             .child_by_field_name("cardinality")
@@ -1583,7 +2039,7 @@ fn visit_feature(state: &mut VisitorState, parent: Symbol, name: SymbolSpan) {
 }
 
 fn visit_ref(state: &mut VisitorState, parent: Symbol, path: Path) {
-    debug_assert!(state.node().parent().unwrap().kind() == "blk");
+    debug_assert!(state.parent().unwrap().kind() == "blk");
     match parent {
         Symbol::Feature(..) => {
             state.push_error(40, "features have to be separated by groups");
@@ -1599,8 +2055,15 @@ fn visit_ref(state: &mut VisitorState, parent: Symbol, path: Path) {
     }
 }
 fn visit_group(state: &mut VisitorState, parent: Symbol, mode: GroupMode) {
-    debug_assert!(state.node().parent().unwrap().kind() == "blk");
+    debug_assert!(state.parent().unwrap().kind() == "blk");
     match parent {
+        //explained by check_exclusive_mandatory
+        Symbol::Group(id)
+            if matches!(mode, GroupMode::Mandatory)
+                && matches!(
+                    state.ast.groups[id as usize].mode,
+                    GroupMode::Alternative | GroupMode::Or
+                ) => {}
         Symbol::Group(..) => {
             state.push_error(40, "groups have to be separated by features");
         }
@@ -1626,8 +2089,11 @@ fn visit_group(state: &mut VisitorState, parent: Symbol, mode: GroupMode) {
     }
 }
 fn visit_blk_decl(state: &mut VisitorState, parent: Symbol) {
-    debug_assert!(state.node().parent().unwrap().kind() == "blk");
+    debug_assert!(state.parent().unwrap().kind() == "blk");
     state.goto_field("header");
+    if state.in_conflict() {
+        return;
+    }
     match state.kind() {
         "name" => {
             let name = opt_name(state).unwrap();
@@ -1665,7 +2131,7 @@ fn visit_blk_decl(state: &mut VisitorState, parent: Symbol) {
     }
 }
 fn visit_features(state: &mut VisitorState) {
-    debug_assert!(state.node().parent().unwrap().kind() == "blk");
+    debug_assert!(state.parent().unwrap().kind() == "blk");
     loop {
         check_no_extra_blk(state, "features");
         if state.kind() == "blk" {
@@ -1678,15 +2144,18 @@ fn visit_features(state: &mut VisitorState) {
 }
 fn visit_constraint_decl(state: &mut VisitorState) {
     loop {
-        check_simple_blk(state, "constraints");
-        match state.kind() {
-            "constraint" | "ref" => visit_children_arg(state, Symbol::Root, visit_constraint),
-            "name" => visit_constraint(state, Symbol::Root),
-            _ => {}
-        }
-        if state.kind() == "ref" {
-            if let Some(alias) = state.child_by_name("alias") {
-                state.push_error_node(alias, 30, "alias not allowed here");
+        //constraints inside a merge conflict are skipped, the others are visited as usual
+        if !state.in_conflict() {
+            check_simple_blk(state, "constraints");
+            match state.kind() {
+                "constraint" | "ref" => visit_children_arg(state, Symbol::Root, visit_constraint),
+                "name" => visit_constraint(state, Symbol::Root),
+                _ => {}
+            }
+            if state.kind() == "ref" {
+                if let Some(alias) = state.child_by_name("alias") {
+                    state.push_error_node(alias, 30, "alias not allowed here");
+                }
             }
         }
         if !state.goto_next_sibling() {
@@ -1774,18 +2243,37 @@ fn visit_top_lvl(state: &mut VisitorState) {
     }
 }
 
-pub fn visit_root(source: Rope, tree: Tree, uri: Url, timestamp: Instant) -> Document {
+pub fn visit_root(
+    source: Rope,
+    tree: Tree,
+    uri: Url,
+    timestamp: Instant,
+    limits: Limits,
+) -> Document {
     let (ast, errors) = {
         let mut state = VisitorState {
             errors: Vec::new(),
             cursor: tree.walk(),
             ast: Default::default(),
             source: &source,
+            scope: Symbol::Root,
+            conflicts: crate::check::conflict_blocks(&source),
+            limits,
         };
         visit_children(&mut state, visit_top_lvl);
         state.connect();
         (state.ast, state.errors)
     };
+    document(ast, errors, source, tree.into(), uri, timestamp)
+}
+fn document(
+    ast: Ast,
+    errors: Vec<ErrorInfo>,
+    source: Rope,
+    tree: OnceLock<Tree>,
+    uri: Url,
+    timestamp: Instant,
+) -> Document {
     let mut path = uri_to_path(&uri).unwrap();
     if let Some(ns) = ast.namespace.as_ref() {
         let len = path.len().saturating_sub(ns.names.len());
@@ -1803,3 +2291,40 @@ pub fn visit_root(source: Rope, tree: Tree, uri: Url, timestamp: Instant) -> Doc
         errors,
     }
 }
+//Document of the model cache, without source and parse tree
+#[derive(Clone, Debug)]
+pub struct StoredDocument {
+    ast: Ast,
+    errors: Vec<ErrorInfo>,
+}
+impl StoredDocument {
+    //source has to be the text the document was built from
+    pub fn restore(self, source: Rope, uri: Url, timestamp: Instant) -> Document {
+        document(
+            self.ast,
+            self.errors,
+            source,
+            OnceLock::new(),
+            uri,
+            timestamp,
+        )
+    }
+}
+struct_codec!(StoredDocument { ast, errors });
+struct_codec!(TreeMap { children, parent });
+struct_codec!(Ast {
+    namespace,
+    includes,
+    import,
+    features,
+    constraints,
+    attributes,
+    references,
+    groups,
+    dirs,
+    structure,
+    index,
+    root,
+    expressions,
+    oversized
+});
@@ -0,0 +1,864 @@
+use crate::ast::{
+    AggregateOP, Attribute, Cardinality, Constraint, ConstraintDecl, Dir, Document, EquationOP,
+    ExprInfo, Feature, Group, GroupMode, Import, LanguageLevel, LanguageLevelSAT, LanguageLevelSMT,
+    Limits, LogicOP, ModelRoot, Numeric, NumericOP, Path as SymbolPath, Reference, Span,
+    StoredDocument, Symbol, SymbolKind, SymbolSpan, Type, Value, ValueDecl,
+};
+use crate::check::ErrorInfo;
+use crate::semantic::Context;
+use hashbrown::HashMap;
+use log::info;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tower_lsp::lsp_types::*;
+use ustr::Ustr;
+/*
+ * Optional on disk cache of files owned by the OS, keyed by path and modification time. For
+ * every file it keeps the syntax level document, the extracted model with its syntax errors,
+ * and the diagnostics last published for it. On a cold start unchanged files are restored from
+ * the cache instead of parsed, their diagnostics are published right away and replaced once
+ * the real check finishes. Parse trees can not be serialized, so a restored file is only
+ * parsed when a request needs its tree.
+ *
+ * The document depends on the settings limiting the syntax checks, every entry records a
+ * fingerprint of them and is ignored when they changed.
+ *
+ * The cache is written every SAVE_INTERVAL when it changed and on shutdown. The format is a
+ * little endian binary dump: magic, version, entry count and for every entry path,
+ * modification time, settings fingerprint, diagnostics and the document if there is one.
+ */
+const MAGIC: &[u8; 4] = b"UVLC";
+const VERSION: u8 = 6;
+const FILE_NAME: &str = "models.bin";
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+//A document checked in this session or one restored from disk
+#[derive(Debug, Clone)]
+enum Model {
+    Checked(Arc<Document>),
+    Stored(Arc<StoredDocument>),
+}
+#[derive(Debug, Clone)]
+struct Entry {
+    modified: SystemTime,
+    limits: u64,
+    errors: Vec<ErrorInfo>,
+    model: Option<Model>,
+}
+#[derive(Debug, Default)]
+pub struct ModelCache {
+    file: PathBuf,
+    entries: HashMap<PathBuf, Entry>,
+    //fingerprint of the settings new entries are built with
+    limits: u64,
+    dirty: bool,
+}
+//Fingerprint of the limits the stored documents and syntax errors were built with
+pub fn fingerprint(limits: &Limits) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    limits.hash(&mut hasher);
+    hasher.finish()
+}
+//Cache directory of a workspace, one per workspace root under the user cache directory
+pub fn cache_dir(root: &Path) -> PathBuf {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir);
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    root.hash(&mut hasher);
+    base.join("uvls").join(format!("{:016x}", hasher.finish()))
+}
+impl ModelCache {
+    //Reads the file system, call it off the runtime
+    pub fn open(dir: &Path, limits: u64) -> Self {
+        let file = dir.join(FILE_NAME);
+        let mut entries = std::fs::read(&file)
+            .ok()
+            .and_then(|data| decode(&data))
+            .unwrap_or_else(|| {
+                info!("no usable model cache at {:?}", file);
+                HashMap::new()
+            });
+        let count = entries.len();
+        entries.retain(|path, _| path.exists());
+        ModelCache {
+            file,
+            dirty: entries.len() != count,
+            entries,
+            limits,
+        }
+    }
+    //Cached diagnostics, entries for an older version of the file or built with other
+    //settings are ignored
+    pub fn lookup(&self, path: &Path, modified: SystemTime) -> Option<&[ErrorInfo]> {
+        self.entry(path, modified).map(|e| e.errors.as_slice())
+    }
+    //The document of an unchanged file, its source is the one on disk
+    pub fn restore(&self, path: &Path, modified: SystemTime) -> Option<StoredDocument> {
+        match self.entry(path, modified)?.model.as_ref()? {
+            Model::Checked(doc) => Some(doc.stored()),
+            Model::Stored(stored) => Some(stored.as_ref().clone()),
+        }
+    }
+    fn entry(&self, path: &Path, modified: SystemTime) -> Option<&Entry> {
+        self.entries
+            .get(path)
+            .filter(|e| e.modified == modified && e.limits == self.limits)
+    }
+    //Entries recorded with other settings are ignored from now on and replaced once their
+    //files are checked again
+    pub fn set_limits(&mut self, limits: u64) {
+        self.limits = limits;
+    }
+    pub fn record(
+        &mut self,
+        path: PathBuf,
+        modified: SystemTime,
+        errors: &[ErrorInfo],
+        doc: Arc<Document>,
+    ) {
+        self.entries.insert(
+            path,
+            Entry {
+                modified,
+                limits: self.limits,
+                errors: errors.to_vec(),
+                model: Some(Model::Checked(doc)),
+            },
+        );
+        self.dirty = true;
+    }
+    pub fn clear(&mut self) -> usize {
+        let cleared = self.entries.len();
+        self.entries.clear();
+        self.dirty = true;
+        cleared
+    }
+    //Entries to write if anything changed since the last save, documents are shared
+    fn changes(&mut self) -> Option<(PathBuf, HashMap<PathBuf, Entry>)> {
+        if !self.dirty {
+            return None;
+        }
+        self.dirty = false;
+        Some((self.file.clone(), self.entries.clone()))
+    }
+}
+//Write the cache if it changed, the lock is only held to collect the entries. Blocks on the
+//file system, call it off the runtime
+pub fn save(cache: &parking_lot::Mutex<Option<ModelCache>>) -> std::io::Result<()> {
+    let (file, entries) = match cache.lock().as_mut().and_then(ModelCache::changes) {
+        Some(changes) => changes,
+        None => return Ok(()),
+    };
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&file, encode(&entries))
+}
+//Save the cache every SAVE_INTERVAL until shutdown, a crash loses at most one interval
+pub async fn save_periodically(ctx: Arc<Context>) {
+    let mut interval = tokio::time::interval(SAVE_INTERVAL);
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = ctx.shutdown.cancelled() => return,
+            _ = interval.tick() => {
+                let ctx = ctx.clone();
+                let saved = tokio::task::spawn_blocking(move || save(&ctx.model_cache)).await;
+                if let Ok(Err(e)) = saved {
+                    info!("failed to save the model cache: {}", e);
+                }
+            }
+        }
+    }
+}
+
+//Binary encoding of cached values, decoding fails on truncated or foreign data
+pub trait Codec: Sized {
+    fn encode(&self, out: &mut Vec<u8>);
+    fn decode(r: &mut Reader) -> Option<Self>;
+}
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.data.len() < n {
+            return None;
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Some(head)
+    }
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+    fn str(&mut self) -> Option<&'a str> {
+        let len = self.u32()? as usize;
+        std::str::from_utf8(self.take(len)?).ok()
+    }
+    //Variant tag of an enum
+    pub fn tag(&mut self) -> Option<u8> {
+        self.u8()
+    }
+}
+//Codec of a struct from its fields in declaration order
+macro_rules! struct_codec {
+    ($ty:ident { $($field:ident),+ }) => {
+        impl crate::cache::Codec for $ty {
+            fn encode(&self, out: &mut Vec<u8>) {
+                $(crate::cache::Codec::encode(&self.$field, out);)+
+            }
+            fn decode(r: &mut crate::cache::Reader) -> Option<Self> {
+                Some($ty {
+                    $($field: crate::cache::Codec::decode(r)?,)+
+                })
+            }
+        }
+    };
+}
+pub(crate) use struct_codec;
+//Codec of an enum without fields, variants are stored by their position in the list
+macro_rules! unit_codec {
+    ($ty:ident: $($variant:ident),+) => {
+        impl Codec for $ty {
+            fn encode(&self, out: &mut Vec<u8>) {
+                let tags = [$(matches!(self, $ty::$variant)),+];
+                out.push(tags.iter().position(|t| *t).unwrap_or_default() as u8);
+            }
+            fn decode(r: &mut Reader) -> Option<Self> {
+                [$($ty::$variant),+].into_iter().nth(r.tag()? as usize)
+            }
+        }
+    };
+}
+impl Codec for u8 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self);
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        r.u8()
+    }
+}
+impl Codec for u32 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        r.u32()
+    }
+}
+impl Codec for u64 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        r.u64()
+    }
+}
+impl Codec for usize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u64).encode(out);
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        r.u64()?.try_into().ok()
+    }
+}
+impl Codec for bool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        match r.u8()? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+impl Codec for f64 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.to_bits().encode(out);
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        Some(f64::from_bits(r.u64()?))
+    }
+}
+impl Codec for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.len() as u32).encode(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        r.str().map(String::from)
+    }
+}
+impl Codec for Ustr {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.len() as u32).encode(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        r.str().map(Ustr::from)
+    }
+}
+impl Codec for Url {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.to_string().encode(out);
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        Url::parse(r.str()?).ok()
+    }
+}
+impl Codec for Span {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.start.encode(out);
+        self.end.encode(out);
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        Some(usize::decode(r)?..usize::decode(r)?)
+    }
+}
+impl<T: Codec> Codec for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.len() as u32).encode(out);
+        for i in self.iter() {
+            i.encode(out);
+        }
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        (0..r.u32()?).map(|_| T::decode(r)).collect()
+    }
+}
+impl<T: Codec> Codec for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.is_some().encode(out);
+        if let Some(v) = self {
+            v.encode(out);
+        }
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        if bool::decode(r)? {
+            Some(Some(T::decode(r)?))
+        } else {
+            Some(None)
+        }
+    }
+}
+impl<T: Codec> Codec for Box<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_ref().encode(out);
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        T::decode(r).map(Box::new)
+    }
+}
+impl<A: Codec, B: Codec> Codec for (A, B) {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+        self.1.encode(out);
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        Some((A::decode(r)?, B::decode(r)?))
+    }
+}
+impl<A: Codec, B: Codec, C: Codec> Codec for (A, B, C) {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+        self.1.encode(out);
+        self.2.encode(out);
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        Some((A::decode(r)?, B::decode(r)?, C::decode(r)?))
+    }
+}
+impl<K: Codec + Hash + Eq, V: Codec> Codec for HashMap<K, V> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.len() as u32).encode(out);
+        for (k, v) in self.iter() {
+            k.encode(out);
+            v.encode(out);
+        }
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        (0..r.u32()?)
+            .map(|_| Some((K::decode(r)?, V::decode(r)?)))
+            .collect()
+    }
+}
+impl Codec for SystemTime {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let since = self.duration_since(UNIX_EPOCH).unwrap_or_default();
+        since.as_secs().encode(out);
+        since.subsec_nanos().encode(out);
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        UNIX_EPOCH.checked_add(Duration::new(r.u64()?, r.u32()?))
+    }
+}
+struct_codec!(Position { line, character });
+struct_codec!(Range { start, end });
+struct_codec!(Location { uri, range });
+struct_codec!(DiagnosticRelatedInformation { location, message });
+impl Codec for DiagnosticSeverity {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(match *self {
+            DiagnosticSeverity::ERROR => 1,
+            DiagnosticSeverity::WARNING => 2,
+            DiagnosticSeverity::INFORMATION => 3,
+            _ => 4,
+        });
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        match r.u8()? {
+            1 => Some(DiagnosticSeverity::ERROR),
+            2 => Some(DiagnosticSeverity::WARNING),
+            3 => Some(DiagnosticSeverity::INFORMATION),
+            4 => Some(DiagnosticSeverity::HINT),
+            _ => None,
+        }
+    }
+}
+impl Codec for DiagnosticTag {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(match *self {
+            DiagnosticTag::UNNECESSARY => 1,
+            _ => 2,
+        });
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        match r.u8()? {
+            1 => Some(DiagnosticTag::UNNECESSARY),
+            2 => Some(DiagnosticTag::DEPRECATED),
+            _ => None,
+        }
+    }
+}
+struct_codec!(ErrorInfo {
+    location,
+    severity,
+    weight,
+    msg,
+    related,
+    code,
+    tags
+});
+
+unit_codec!(Type: String, Number, Vector, Attributes, Feature, Void, Namespace, Alias, Dir, Aggregate);
+unit_codec!(LanguageLevelSAT: Any, GroupCardinality);
+unit_codec!(LanguageLevelSMT: Any, FeatureCardinality, Aggregate);
+unit_codec!(NumericOP: Add, Sub, Div, Mul);
+unit_codec!(LogicOP: And, Or, Implies, Equiv);
+unit_codec!(AggregateOP: Avg, Sum);
+unit_codec!(EquationOP: Greater, Smaller, Equal);
+unit_codec!(SymbolKind: Feature, Constraint, Attribute, Reference, Group, Import, LangLvl, Dir, Root);
+struct_codec!(SymbolSpan { name, span });
+struct_codec!(SymbolPath { names, spans });
+struct_codec!(Feature { name, cardinality });
+struct_codec!(Import { path, alias });
+struct_codec!(Group { mode, span });
+struct_codec!(Reference { path, ty, scope });
+struct_codec!(Attribute { name, value, depth });
+struct_codec!(Dir { name, depth });
+struct_codec!(ValueDecl { value, span });
+struct_codec!(ExprInfo {
+    span,
+    numeric,
+    reference
+});
+struct_codec!(ConstraintDecl { content, span });
+impl Codec for Symbol {
+    fn encode(&self, out: &mut Vec<u8>) {
+        SymbolKind::from(self).encode(out);
+        if *self != Symbol::Root {
+            self.offset().encode(out);
+        }
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        Some(match SymbolKind::decode(r)? {
+            SymbolKind::Root => Symbol::Root,
+            SymbolKind::Feature => Symbol::Feature(r.u32()?),
+            SymbolKind::Constraint => Symbol::Constraint(r.u32()?),
+            SymbolKind::Attribute => Symbol::Attribute(r.u32()?),
+            SymbolKind::Reference => Symbol::Reference(r.u32()?),
+            SymbolKind::Group => Symbol::Group(r.u32()?),
+            SymbolKind::Import => Symbol::Import(r.u32()?),
+            SymbolKind::LangLvl => Symbol::LangLvl(r.u32()?),
+            SymbolKind::Dir => Symbol::Dir(r.u32()?),
+        })
+    }
+}
+impl Codec for Cardinality {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Cardinality::From(min) => {
+                out.push(0);
+                min.encode(out);
+            }
+            Cardinality::Range(min, max) => {
+                out.push(1);
+                min.encode(out);
+                max.encode(out);
+            }
+            Cardinality::Max(max) => {
+                out.push(2);
+                max.encode(out);
+            }
+            Cardinality::Any => out.push(3),
+        }
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        match r.tag()? {
+            0 => Some(Cardinality::From(usize::decode(r)?)),
+            1 => Some(Cardinality::Range(usize::decode(r)?, usize::decode(r)?)),
+            2 => Some(Cardinality::Max(usize::decode(r)?)),
+            3 => Some(Cardinality::Any),
+            _ => None,
+        }
+    }
+}
+impl Codec for GroupMode {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            GroupMode::Or => out.push(0),
+            GroupMode::Alternative => out.push(1),
+            GroupMode::Optional => out.push(2),
+            GroupMode::Mandatory => out.push(3),
+            GroupMode::Cardinality(card) => {
+                out.push(4);
+                card.encode(out);
+            }
+        }
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        match r.tag()? {
+            0 => Some(GroupMode::Or),
+            1 => Some(GroupMode::Alternative),
+            2 => Some(GroupMode::Optional),
+            3 => Some(GroupMode::Mandatory),
+            4 => Some(GroupMode::Cardinality(Cardinality::decode(r)?)),
+            _ => None,
+        }
+    }
+}
+impl Codec for ModelRoot {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            ModelRoot::Empty => out.push(0),
+            ModelRoot::Feature(sym) => {
+                out.push(1);
+                sym.encode(out);
+            }
+            ModelRoot::Synthetic(features) => {
+                out.push(2);
+                features.encode(out);
+            }
+        }
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        match r.tag()? {
+            0 => Some(ModelRoot::Empty),
+            1 => Some(ModelRoot::Feature(Symbol::decode(r)?)),
+            2 => Some(ModelRoot::Synthetic(Vec::decode(r)?)),
+            _ => None,
+        }
+    }
+}
+impl Codec for LanguageLevel {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            LanguageLevel::SAT(minor) => {
+                out.push(0);
+                minor.encode(out);
+            }
+            LanguageLevel::SMT(minor) => {
+                out.push(1);
+                minor.encode(out);
+            }
+        }
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        match r.tag()? {
+            0 => Some(LanguageLevel::SAT(Codec::decode(r)?)),
+            1 => Some(LanguageLevel::SMT(Codec::decode(r)?)),
+            _ => None,
+        }
+    }
+}
+impl Codec for Value {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Void => out.push(0),
+            Value::Number(n) => {
+                out.push(1);
+                n.encode(out);
+            }
+            Value::String(s) => {
+                out.push(2);
+                s.encode(out);
+            }
+            Value::Vector => out.push(3),
+            Value::Bool(b) => {
+                out.push(4);
+                b.encode(out);
+            }
+            Value::Attributes => out.push(5),
+        }
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        match r.tag()? {
+            0 => Some(Value::Void),
+            1 => Some(Value::Number(f64::decode(r)?)),
+            2 => Some(Value::String(String::decode(r)?)),
+            3 => Some(Value::Vector),
+            4 => Some(Value::Bool(bool::decode(r)?)),
+            5 => Some(Value::Attributes),
+            _ => None,
+        }
+    }
+}
+//Expressions nest as deep as the constraints, the stack grows like in the visitor
+impl Codec for Constraint {
+    fn encode(&self, out: &mut Vec<u8>) {
+        stacker::maybe_grow(32 * 1024, 1024 * 1024, || match self {
+            Constraint::Constant(value) => {
+                out.push(0);
+                value.encode(out);
+            }
+            Constraint::Equation { op, lhs, rhs } => {
+                out.push(1);
+                op.encode(out);
+                lhs.encode(out);
+                rhs.encode(out);
+            }
+            Constraint::Logic { op, lhs, rhs } => {
+                out.push(2);
+                op.encode(out);
+                lhs.encode(out);
+                rhs.encode(out);
+            }
+            Constraint::Ref(sym) => {
+                out.push(3);
+                sym.encode(out);
+            }
+            Constraint::Not(c) => {
+                out.push(4);
+                c.encode(out);
+            }
+        })
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        stacker::maybe_grow(32 * 1024, 1024 * 1024, || match r.tag()? {
+            0 => Some(Constraint::Constant(bool::decode(r)?)),
+            1 => Some(Constraint::Equation {
+                op: Codec::decode(r)?,
+                lhs: Codec::decode(r)?,
+                rhs: Codec::decode(r)?,
+            }),
+            2 => Some(Constraint::Logic {
+                op: Codec::decode(r)?,
+                lhs: Codec::decode(r)?,
+                rhs: Codec::decode(r)?,
+            }),
+            3 => Some(Constraint::Ref(Symbol::decode(r)?)),
+            4 => Some(Constraint::Not(Codec::decode(r)?)),
+            _ => None,
+        })
+    }
+}
+impl Codec for Numeric {
+    fn encode(&self, out: &mut Vec<u8>) {
+        stacker::maybe_grow(32 * 1024, 1024 * 1024, || match self {
+            Numeric::Number(n) => {
+                out.push(0);
+                n.encode(out);
+            }
+            Numeric::Ref(sym) => {
+                out.push(1);
+                sym.encode(out);
+            }
+            Numeric::Binary { op, rhs, lhs } => {
+                out.push(2);
+                op.encode(out);
+                rhs.encode(out);
+                lhs.encode(out);
+            }
+            Numeric::Aggregate { op, context, query } => {
+                out.push(3);
+                op.encode(out);
+                context.encode(out);
+                query.encode(out);
+            }
+        })
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        stacker::maybe_grow(32 * 1024, 1024 * 1024, || match r.tag()? {
+            0 => Some(Numeric::Number(f64::decode(r)?)),
+            1 => Some(Numeric::Ref(Symbol::decode(r)?)),
+            2 => Some(Numeric::Binary {
+                op: Codec::decode(r)?,
+                rhs: Codec::decode(r)?,
+                lhs: Codec::decode(r)?,
+            }),
+            3 => Some(Numeric::Aggregate {
+                op: Codec::decode(r)?,
+                context: Codec::decode(r)?,
+                query: Codec::decode(r)?,
+            }),
+            _ => None,
+        })
+    }
+}
+impl Codec for Model {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Model::Checked(doc) => doc.encode_stored(out),
+            Model::Stored(stored) => stored.encode(out),
+        }
+    }
+    fn decode(r: &mut Reader) -> Option<Self> {
+        StoredDocument::decode(r).map(|stored| Model::Stored(Arc::new(stored)))
+    }
+}
+struct_codec!(Entry {
+    modified,
+    limits,
+    errors,
+    model
+});
+
+fn encode(entries: &HashMap<PathBuf, Entry>) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    (entries.len() as u32).encode(&mut out);
+    for (path, entry) in entries.iter() {
+        path.to_string_lossy().to_string().encode(&mut out);
+        entry.encode(&mut out);
+    }
+    out
+}
+//A cache from another version or a truncated write is discarded as a whole
+fn decode(data: &[u8]) -> Option<HashMap<PathBuf, Entry>> {
+    let mut r = Reader { data };
+    if r.take(4)? != MAGIC || r.u8()? != VERSION {
+        return None;
+    }
+    (0..r.u32()?)
+        .map(|_| Some((PathBuf::from(r.str()?), Entry::decode(&mut r)?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::build_document;
+    use crate::parse::parse;
+    use ropey::Rope;
+    use tokio::time::Instant;
+    #[test]
+    fn roundtrip() {
+        let dir = std::env::temp_dir().join(format!("uvls-cache-test-{}", std::process::id()));
+        let model = dir.join("model.uvl");
+        std::fs::create_dir_all(&dir).unwrap();
+        let text = "namespace Car\nfeatures\n    Car {cost 10, name 'car'}\n        alternative\n            A\n            B\n        [1..2]\n            C\nconstraints\n    A => !(B | C)\n    sum(cost) > 3\n    Car.cost + 2 == 12.5\n";
+        std::fs::write(&model, text).unwrap();
+        let uri = Url::from_file_path(&model).unwrap();
+        let modified = std::fs::metadata(&model).unwrap().modified().unwrap();
+        let source = Rope::from_str(text);
+        let doc = Arc::new(build_document(
+            source.clone(),
+            parse(&source, None),
+            uri.clone(),
+            Instant::now(),
+            Default::default(),
+        ));
+        let errors = vec![ErrorInfo {
+            location: Range {
+                start: Position::new(1, 4),
+                end: Position::new(1, 5),
+            },
+            severity: DiagnosticSeverity::WARNING,
+            weight: 10,
+            msg: "unused feature".into(),
+            related: vec![DiagnosticRelatedInformation {
+                location: Location {
+                    uri: uri.clone(),
+                    range: Range::default(),
+                },
+                message: "declared here".into(),
+            }],
+            code: Some("grammar/unsupported/typed-features".into()),
+            tags: vec![DiagnosticTag::UNNECESSARY],
+        }];
+        let cache = parking_lot::Mutex::new(Some(ModelCache::open(&dir, 1)));
+        if let Some(cache) = cache.lock().as_mut() {
+            cache.record(model.clone(), modified, &errors, doc.clone());
+            //entries of deleted files are dropped when the cache is opened
+            cache.record(dir.join("deleted.uvl"), modified, &errors, doc.clone());
+        }
+        save(&cache).unwrap();
+        //nothing changed since
+        assert!(cache.lock().as_mut().unwrap().changes().is_none());
+        let mut cache = ModelCache::open(&dir, 1);
+        assert_eq!(cache.entries.len(), 1);
+        let cached = cache.lookup(&model, modified).unwrap();
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[0].location, errors[0].location);
+        assert_eq!(cached[0].severity, errors[0].severity);
+        assert_eq!(cached[0].msg, errors[0].msg);
+        assert_eq!(cached[0].related, errors[0].related);
+        assert_eq!(cached[0].code, errors[0].code);
+        assert_eq!(cached[0].tags, errors[0].tags);
+        //the restored document is the parsed one without parsing
+        let stored = cache.restore(&model, modified).unwrap();
+        let restored = stored.restore(source, uri, Instant::now());
+        let features = |doc: &Document| {
+            doc.all_features()
+                .map(|f| (doc.symbol_name(f), doc.lsp_range(f), doc.parent(f, false)))
+                .collect::<Vec<_>>()
+        };
+        let references = |doc: &Document| {
+            doc.all_references()
+                .map(|r| (doc.path(r).to_vec(), doc.span(r)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(features(&restored), features(&doc));
+        assert_eq!(references(&restored), references(&doc));
+        assert_eq!(restored.model_root(), doc.model_root());
+        assert_eq!(restored.path, doc.path);
+        assert_eq!(
+            format!("{:?}", restored.errors),
+            format!("{:?}", doc.errors)
+        );
+        //a newer file invalidates the entry
+        let newer = modified + Duration::from_secs(1);
+        assert!(cache.lookup(&model, newer).is_none());
+        assert!(cache.restore(&model, newer).is_none());
+        //so do other syntax limits
+        cache.set_limits(2);
+        assert!(cache.lookup(&model, modified).is_none());
+        assert!(cache.restore(&model, modified).is_none());
+        assert!(ModelCache::open(&dir, 2).lookup(&model, modified).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+        //garbage is not a cache
+        assert!(decode(b"UVLC\x01\xff").is_none());
+        //neither is a truncated write of the current version
+        let data = encode(&cache.entries);
+        assert_eq!(data[4], VERSION);
+        assert!(decode(&data).is_some());
+        assert!(decode(&data[..data.len() - 1]).is_none());
+        let mut header = MAGIC.to_vec();
+        header.push(VERSION);
+        header.extend_from_slice(&1u32.to_le_bytes());
+        assert!(decode(&header).is_none());
+    }
+}
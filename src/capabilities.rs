@@ -0,0 +1,92 @@
+use tower_lsp::lsp_types::*;
+/*
+ * What the client declared in initialize. Handlers branch on it to answer with shapes the
+ * client accepts instead of assuming full support, everything not declared is unsupported.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientSupport {
+    //Snippets in completion items, otherwise plain text is inserted
+    pub snippets: bool,
+    //Nested DocumentSymbols, otherwise a flat list of SymbolInformation
+    pub hierarchical_symbols: bool,
+    //semanticTokens/full/delta requests, otherwise full responses only
+    pub token_deltas: bool,
+    //Dynamic registration of file watchers, otherwise files are polled
+    pub watch_files: bool,
+    //Markdown in hovers, otherwise plain text
+    pub markdown_hover: bool,
+}
+impl ClientSupport {
+    pub fn new(caps: &ClientCapabilities) -> Self {
+        let doc = caps.text_document.as_ref();
+        ClientSupport {
+            snippets: doc
+                .and_then(|doc| doc.completion.as_ref())
+                .and_then(|completion| completion.completion_item.as_ref())
+                .and_then(|item| item.snippet_support)
+                .unwrap_or(false),
+            hierarchical_symbols: doc
+                .and_then(|doc| doc.document_symbol.as_ref())
+                .and_then(|symbols| symbols.hierarchical_document_symbol_support)
+                .unwrap_or(false),
+            token_deltas: matches!(
+                doc.and_then(|doc| doc.semantic_tokens.as_ref())
+                    .and_then(|tokens| tokens.requests.full.as_ref()),
+                Some(SemanticTokensFullOptions::Delta { delta: Some(true) })
+            ),
+            watch_files: caps
+                .workspace
+                .as_ref()
+                .and_then(|workspace| workspace.did_change_watched_files.as_ref())
+                .and_then(|watched| watched.dynamic_registration)
+                .unwrap_or(false),
+            markdown_hover: doc
+                .and_then(|doc| doc.hover.as_ref())
+                .and_then(|hover| hover.content_format.as_ref())
+                .is_some_and(|formats| formats.contains(&MarkupKind::Markdown)),
+        }
+    }
+    pub fn semantic_tokens_full(&self) -> SemanticTokensFullOptions {
+        if self.token_deltas {
+            SemanticTokensFullOptions::Delta { delta: Some(true) }
+        } else {
+            SemanticTokensFullOptions::Bool(true)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn minimal_client() {
+        let minimal = ClientSupport::new(&ClientCapabilities::default());
+        assert_eq!(minimal, ClientSupport::default());
+        assert_eq!(
+            minimal.semantic_tokens_full(),
+            SemanticTokensFullOptions::Bool(true)
+        );
+        let caps: ClientCapabilities = serde_json::from_value(serde_json::json!({
+            "textDocument": {
+                "completion": {"completionItem": {"snippetSupport": true}},
+                "documentSymbol": {"hierarchicalDocumentSymbolSupport": true},
+                "hover": {"contentFormat": ["markdown", "plaintext"]},
+                "semanticTokens": {
+                    "requests": {"full": {"delta": true}},
+                    "tokenTypes": [],
+                    "tokenModifiers": [],
+                    "formats": ["relative"]
+                }
+            },
+            "workspace": {"didChangeWatchedFiles": {"dynamicRegistration": true}}
+        }))
+        .unwrap();
+        let full = ClientSupport::new(&caps);
+        assert!(full.snippets && full.hierarchical_symbols && full.token_deltas);
+        assert!(full.watch_files && full.markdown_hover);
+        assert_eq!(
+            full.semantic_tokens_full(),
+            SemanticTokensFullOptions::Delta { delta: Some(true) }
+        );
+    }
+}
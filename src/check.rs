@@ -2,9 +2,10 @@ use std::sync::Arc;
 use tokio::select;
 use tokio::sync::mpsc;
 
+use crate::ast::{Cardinality, Document, GroupMode, Limits, Span, Symbol};
 use crate::semantic::*;
 use crate::util::*;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use log::info;
 use ropey::Rope;
 use tokio::time::Instant;
@@ -30,63 +31,100 @@ pub struct ErrorInfo {
     pub severity: DiagnosticSeverity,
     pub weight: u32,
     pub msg: String,
+    //Other declarations involved, eg. the shadowed one
+    pub related: Vec<DiagnosticRelatedInformation>,
+    //Stable identifier clients can match on
+    pub code: Option<String>,
+    //Eg. unnecessary, clients fade dead declarations instead of underlining them
+    pub tags: Vec<DiagnosticTag>,
 }
 
 impl ErrorInfo {
+    //Only errors block linking and analysis, everything else is advisory
+    pub fn is_error(&self) -> bool {
+        self.severity == DiagnosticSeverity::ERROR
+    }
+    //Severity as shown by the command line
+    pub fn severity_name(&self) -> &'static str {
+        match self.severity {
+            DiagnosticSeverity::ERROR => "error",
+            DiagnosticSeverity::WARNING => "warning",
+            DiagnosticSeverity::INFORMATION => "info",
+            _ => "hint",
+        }
+    }
     fn diagnostic(self) -> Diagnostic {
         Diagnostic {
             range: self.location,
             severity: Some(self.severity),
             message: self.msg,
+            code: self.code.map(NumberOrString::String),
+            related_information: if self.related.is_empty() {
+                None
+            } else {
+                Some(self.related)
+            },
+            tags: if self.tags.is_empty() {
+                None
+            } else {
+                Some(self.tags)
+            },
             ..Default::default()
         }
     }
 }
-pub async fn publish(client: &Client, uri: &Url, err: &[ErrorInfo]) {
-    if let Some(max) = err.iter().max_by_key(|e| e.weight) {
-        client
-            .publish_diagnostics(
-                uri.clone(),
-                err.iter()
-                    .rev()
-                    .filter(|e| e.weight == max.weight)
-                    .map(|i| i.clone().diagnostic())
-                    .collect(),
-                None,
-            )
-            .await;
-    } else {
-        client.publish_diagnostics(uri.clone(), vec![], None).await;
+//Errors with the highest weight mask the others, hints and information are always shown
+pub fn visible(err: &[ErrorInfo]) -> Vec<&ErrorInfo> {
+    match err.iter().max_by_key(|e| e.weight) {
+        Some(max) => err
+            .iter()
+            .rev()
+            .filter(|e| e.weight == max.weight || e.severity > DiagnosticSeverity::WARNING)
+            .collect(),
+        None => Vec::new(),
     }
 }
-//Walk the syntax tree and only go "down" if F is true
-fn ts_filterd_visit<F: FnMut(Node) -> bool>(root: Node, mut f: F) {
-    let mut reached_root = false;
-    let mut cursor = root.walk();
-    if !cursor.goto_first_child() {
-        return;
+//Publication order, diagnostics at the same position are ordered by severity and code so
+//clients and the check mode see the same order on every run
+pub fn publication_order(e: &ErrorInfo) -> (u32, u32, DiagnosticSeverity, Option<&str>) {
+    (
+        e.location.start.line,
+        e.location.start.character,
+        e.severity,
+        e.code.as_deref(),
+    )
+}
+//At most max visible diagnostics, 0 for no limit, the heaviest are kept and the dropped ones
+//are summarized in a trailing diagnostic at the start of the file
+pub fn capped(err: &[ErrorInfo], max: usize) -> Vec<Diagnostic> {
+    let mut visible = visible(err);
+    let mut dropped = 0;
+    if max != 0 && visible.len() > max {
+        visible.sort_by_key(|e| (std::cmp::Reverse(e.weight), e.severity));
+        dropped = visible.len() - max;
+        visible.truncate(max);
     }
-    while !reached_root {
-        if f(cursor.node()) && cursor.goto_first_child() {
-            continue;
-        }
-        if cursor.goto_next_sibling() {
-            continue;
-        }
-        loop {
-            if !cursor.goto_parent() {
-                reached_root = true;
-                break;
-            }
-            if cursor.node() == root {
-                reached_root = true;
-                break;
-            }
-            if cursor.goto_next_sibling() {
-                break;
-            }
-        }
+    visible.sort_by(|a, b| publication_order(a).cmp(&publication_order(b)));
+    let mut diagnostics: Vec<Diagnostic> = visible
+        .into_iter()
+        .map(|i| i.clone().diagnostic())
+        .collect();
+    if dropped == 0 {
+        return diagnostics;
     }
+    diagnostics.push(Diagnostic {
+        range: Range::default(),
+        severity: Some(DiagnosticSeverity::INFORMATION),
+        message: format!("and {} more diagnostics", dropped),
+        code: Some(NumberOrString::String("diagnostics/truncated".into())),
+        ..Default::default()
+    });
+    diagnostics
+}
+pub async fn publish(client: &Client, uri: &Url, err: &[ErrorInfo], max: usize) {
+    client
+        .publish_diagnostics(uri.clone(), capped(err, max), None)
+        .await;
 }
 //Check if line breaks are correct eg. inside parenthesis
 //This is necessary because the treesitter grammer allows 2 features on the same line under certain
@@ -96,36 +134,47 @@ pub fn check_sanity(tree: &Tree, source: &Rope) -> Vec<ErrorInfo> {
     let mut cursor = QueryCursor::new();
     let mut error = Vec::new();
     let mut lines = HashMap::new();
+    let conflicts = conflict_blocks(source);
     for i in cursor.matches(
         &TS.queries.check_sanity,
         tree.root_node(),
         node_source(source),
     ) {
         let node = i.captures[0].node;
+        //reported as a merge conflict by check_errors
+        if in_conflict(&conflicts, &node.byte_range()) {
+            continue;
+        }
         //We have to check if line breaks in expression are contained by a parenthesis
         if node.kind() == "expr" {
             if node.start_position().row == node.end_position().row {
                 continue;
             } else {
-                let mut ok_lines = Vec::new();
+                //Rows covered by the outermost parenthesis, they are visited in order and
+                //never overlap so the ranges stay sorted
+                let mut ok_lines: Vec<std::ops::Range<usize>> = Vec::new();
                 ts_filterd_visit(node, |node| {
                     if node.kind() == "nested_expr" {
-                        for i in node.start_position().row..node.end_position().row {
-                            ok_lines.push(i);
-                        }
+                        ok_lines.push(node.start_position().row..node.end_position().row);
                         false
                     } else {
                         true
                     }
                 });
+                let mut ok_lines = ok_lines.iter().peekable();
                 for i in node.start_position().row..node.end_position().row {
-                    if !ok_lines.iter().any(|k| *k == i) {
+                    while ok_lines.next_if(|k| k.end <= i).is_some() {}
+                    if !ok_lines.peek().is_some_and(|k| k.contains(&i)) {
                         error.push(ErrorInfo {
                             weight: 100,
+                            related: Vec::new(),
+                            code: None,
                             location: node_range(node, source),
                             severity: DiagnosticSeverity::ERROR,
                             msg: "line breaks are only allowed inside parenthesis".to_string(),
+                            tags: Vec::new(),
                         });
+                        break;
                     }
                 }
             }
@@ -134,17 +183,23 @@ pub fn check_sanity(tree: &Tree, source: &Rope) -> Vec<ErrorInfo> {
             if node.start_position().row != node.end_position().row {
                 error.push(ErrorInfo {
                     weight: 100,
+                    related: Vec::new(),
+                    code: None,
                     location: node_range(node, source),
                     severity: DiagnosticSeverity::ERROR,
                     msg: "line breaks are only allowed inside parenthesis".to_string(),
+                    tags: Vec::new(),
                 });
             }
             if lines.insert(node.start_position().row, node).is_some() {
                 error.push(ErrorInfo {
                     weight: 100,
+                    related: Vec::new(),
+                    code: None,
                     location: node_range(node, source),
                     severity: DiagnosticSeverity::ERROR,
                     msg: "features have to be in diffrent lines".to_string(),
+                    tags: Vec::new(),
                 });
             }
         } else {
@@ -152,9 +207,12 @@ pub fn check_sanity(tree: &Tree, source: &Rope) -> Vec<ErrorInfo> {
             if node.start_position().row != node.end_position().row {
                 error.push(ErrorInfo {
                     weight: 100,
+                    related: Vec::new(),
+                    code: None,
                     location: node_range(node, source),
                     severity: DiagnosticSeverity::ERROR,
                     msg: "multiline strings are not supported".to_string(),
+                    tags: Vec::new(),
                 });
             }
         }
@@ -163,7 +221,117 @@ pub fn check_sanity(tree: &Tree, source: &Rope) -> Vec<ErrorInfo> {
     error
 }
 
-pub fn classify_error(root: Node, source: &Rope) -> ErrorInfo {
+//Version of the embedded tree-sitter-uvl grammar
+pub static GRAMMAR_VERSION: &str = "UVL 1.0";
+//Syntax the embedded grammar does not know yet, matched on the tokens of the lines of an
+//error region. Comments and strings are left out so text in them never matches.
+//To support a new pattern add an entry here, the example is checked by the tests
+pub struct NewerSyntax {
+    pub id: &'static str,
+    pub feature: &'static str,
+    pub introduced: &'static str,
+    pub example: &'static str,
+    pub matches: fn(&[Token]) -> bool,
+}
+//Kind and text of a leaf node
+pub type Token = (&'static str, String);
+//A name directly followed by an opening parenthesis
+fn call(tokens: &[Token], functions: &[&str]) -> bool {
+    tokens.windows(2).any(|pair| {
+        pair[0].0 == "name" && functions.contains(&pair[0].1.as_str()) && pair[1].0 == "("
+    })
+}
+pub static NEWER_SYNTAX: &[NewerSyntax] = &[
+    NewerSyntax {
+        id: "typed-features",
+        feature: "typed features",
+        introduced: "UVL 2.0",
+        example: "features\n    Car\n        optional\n            Integer Price\n",
+        matches: |tokens| match tokens {
+            [("name", ty), ("name", _), ..] => {
+                matches!(ty.as_str(), "Integer" | "Real" | "String" | "Boolean")
+            }
+            _ => false,
+        },
+    },
+    NewerSyntax {
+        id: "type-level",
+        feature: "Type-level language level",
+        introduced: "UVL 2.0",
+        example: "include\n    Type-level.*\nfeatures\n    Car\n",
+        //unknown levels may be split into a name, the dash and level
+        matches: |tokens| {
+            tokens.iter().any(|(_, text)| text == "Type-level")
+                || tokens.windows(3).any(|t| match t {
+                    [("name", ty), ("-", _), (_, level)] => ty == "Type" && level == "level",
+                    _ => false,
+                })
+        },
+    },
+    NewerSyntax {
+        id: "string-functions",
+        feature: "string functions",
+        introduced: "UVL 2.0",
+        example: "features\n    Name\nconstraints\n    len(Name) > 3\n",
+        matches: |tokens| call(tokens, &["len"]),
+    },
+    NewerSyntax {
+        id: "rounding-functions",
+        feature: "rounding functions",
+        introduced: "UVL 2.0",
+        example: "features\n    Price\nconstraints\n    floor(Price) == 3\n",
+        matches: |tokens| call(tokens, &["floor", "ceil"]),
+    },
+];
+//Leaves of the rows below root grouped by line
+fn line_tokens(root: Node, source: &Rope, rows: std::ops::Range<usize>) -> Vec<Vec<Token>> {
+    let mut lines: Vec<Vec<Token>> = vec![Vec::new(); rows.len()];
+    ts_filterd_visit(root, |node| {
+        if node.end_position().row < rows.start
+            || node.start_position().row >= rows.end
+            || node.is_extra()
+            || node.kind() == "string"
+        {
+            return false;
+        }
+        if node.child_count() == 0 {
+            let line = node.start_position().row.checked_sub(rows.start);
+            if let Some(line) = line.and_then(|line| lines.get_mut(line)) {
+                line.push((node.kind(), source.byte_slice(node.byte_range()).into()));
+            }
+        }
+        true
+    });
+    lines
+}
+pub fn newer_syntax(
+    root: Node,
+    source: &Rope,
+    rows: std::ops::Range<usize>,
+) -> Option<&'static NewerSyntax> {
+    line_tokens(root, source, rows)
+        .iter()
+        .find_map(|line| NEWER_SYNTAX.iter().find(|syntax| (syntax.matches)(line)))
+}
+fn unsupported_syntax(syntax: &NewerSyntax, location: Range) -> ErrorInfo {
+    ErrorInfo {
+        location,
+        severity: DiagnosticSeverity::ERROR,
+        weight: 80,
+        related: Vec::new(),
+        code: Some(format!("grammar/unsupported/{}", syntax.id)),
+        msg: format!(
+            "this syntax requires a newer UVLS version (feature: {}, introduced in grammar {})",
+            syntax.feature, syntax.introduced
+        ),
+        tags: Vec::new(),
+    }
+}
+pub fn classify_error(tree: &Tree, root: Node, source: &Rope) -> ErrorInfo {
+    let rows = root.start_position().row..root.end_position().row + 1;
+    if let Some(syntax) = newer_syntax(tree.root_node(), source, rows) {
+        return unsupported_syntax(syntax, node_range(root, source));
+    }
     let err_source = source.byte_slice(root.byte_range());
     if root.start_position().row == root.end_position().row {
         let err_raw: String = err_source.into();
@@ -183,7 +351,10 @@ pub fn classify_error(root: Node, source: &Rope) -> ErrorInfo {
                 location: node_range(root, source),
                 severity: DiagnosticSeverity::ERROR,
                 weight: 80,
+                related: Vec::new(),
+                code: None,
                 msg: "missing lhs or rhs expression".into(),
+                tags: Vec::new(),
             };
         }
     }
@@ -191,27 +362,712 @@ pub fn classify_error(root: Node, source: &Rope) -> ErrorInfo {
         location: node_range(root, source),
         severity: DiagnosticSeverity::ERROR,
         weight: 80,
+        related: Vec::new(),
+        code: None,
         msg: "unknown syntax error".into(),
+        tags: Vec::new(),
     }
 }
-pub fn check_errors(tree: &Tree, source: &Rope) -> Vec<ErrorInfo> {
+//Byte ranges of git conflict blocks, from the <<<<<<< line to the end of the >>>>>>> line.
+//An unterminated block extends to the end of the file
+pub fn conflict_blocks(source: &Rope) -> Vec<Span> {
+    let mut out = Vec::new();
+    let mut start = None;
+    let mut offset = 0;
+    let marker =
+        |line: &ropey::RopeSlice, m: char| line.chars().take(7).filter(|c| *c == m).count() == 7;
+    for line in source.lines() {
+        let len = line.len_bytes();
+        if marker(&line, '<') {
+            start = start.or(Some(offset));
+        } else if marker(&line, '>') {
+            if let Some(start) = start.take() {
+                out.push(start..offset + len);
+            }
+        }
+        offset += len;
+    }
+    if let Some(start) = start {
+        out.push(start..source.len_bytes());
+    }
+    out
+}
+pub fn in_conflict(conflicts: &[Span], span: &Span) -> bool {
+    conflicts
+        .iter()
+        .any(|c| c.start < span.end.max(span.start + 1) && span.start < c.end)
+}
+pub fn check_errors(tree: &Tree, source: &Rope, limits: Limits) -> Vec<ErrorInfo> {
+    let conflicts = conflict_blocks(source);
+    //Errors inside a conflict block are replaced by the conflict error, the rest of the file
+    //is reported as usual
+    let mut conflict_err: Vec<ErrorInfo> = conflicts
+        .iter()
+        .filter_map(|c| {
+            Some(ErrorInfo {
+                location: lsp_range(c.clone(), source)?,
+                severity: DiagnosticSeverity::ERROR,
+                weight: 80,
+                related: Vec::new(),
+                code: Some("syntax/merge-conflict".into()),
+                msg: "unresolved merge conflict".into(),
+                tags: Vec::new(),
+            })
+        })
+        .collect();
     let mut err: Vec<ErrorInfo> = Vec::new();
+    let mut error_bytes = 0;
     ts_filterd_visit(tree.root_node(), |i| {
-        if i.is_missing() {
+        if in_conflict(&conflicts, &i.byte_range()) && (i.is_missing() || i.is_error()) {
+            false
+        } else if i.is_missing() {
             err.push(ErrorInfo {
                 location: node_range(i, source),
                 severity: DiagnosticSeverity::ERROR,
                 weight: 80,
+                related: Vec::new(),
+                code: None,
                 msg: format!("missing {}", i.kind()),
+                tags: Vec::new(),
             });
             false
         } else if i.is_error() {
-            err.push(classify_error(i, source));
+            error_bytes += i.byte_range().len();
+            err.push(classify_error(tree, i, source));
             false
         } else {
             true
         }
     });
+    let threshold = limits.not_uvl_threshold;
+    let len = source.len_bytes();
+    if threshold > 0 && len > 0 && error_bytes * 100 > len * threshold {
+        //The component stays a syntax error so references are not checked either
+        err = vec![ErrorInfo {
+            location: Range::default(),
+            severity: DiagnosticSeverity::ERROR,
+            weight: 80,
+            related: Vec::new(),
+            code: Some("syntax/not-uvl".into()),
+            msg: format!(
+                "most of this file could not be parsed, it may not be a UVL model ({}% syntax errors)",
+                error_bytes * 100 / len
+            ),
+            tags: Vec::new(),
+        }];
+    }
+    err.append(&mut conflict_err);
+    err
+}
+//Declarations hiding another declaration from bare references: attributes hiding an
+//attribute of an enclosing feature or a feature of the same name and nested features hiding
+//an import, references starting with that name resolve to both.
+//Returns (shadowing, shadowed) pairs
+pub fn find_shadowed(file: &Document) -> Vec<(Symbol, Symbol)> {
+    let mut out = Vec::new();
+    for attrib in file.all_attributes() {
+        let (feature, name) = match (file.parent(attrib, false), file.symbol_name(attrib)) {
+            (Some(feature @ Symbol::Feature(..)), Some(name)) => (feature, name),
+            _ => continue,
+        };
+        let path = [name];
+        let shadowed = file
+            .ancestors(feature)
+            .skip(1)
+            .find_map(|outer| {
+                file.lookup(outer, &path, |sym| matches!(sym, Symbol::Attribute(..)))
+                    .next()
+            })
+            .or_else(|| {
+                file.lookup(Symbol::Root, &path, |sym| {
+                    matches!(sym, Symbol::Feature(..))
+                })
+                .next()
+            });
+        if let Some(shadowed) = shadowed {
+            out.push((attrib, shadowed));
+        }
+    }
+    for feature in file.all_features() {
+        let name = match file.symbol_name(feature) {
+            Some(name) if file.ancestors(feature).nth(1).is_some() => name,
+            _ => continue,
+        };
+        //the first segment of an import is a directory when the path has several
+        let shadowed = file
+            .all_imports()
+            .find(|import| file.import_prefix(*import).first() == Some(&name));
+        if let Some(shadowed) = shadowed {
+            out.push((feature, shadowed));
+        }
+    }
+    out
+}
+pub fn check_shadowing(file: &Document) -> Vec<ErrorInfo> {
+    find_shadowed(file)
+        .into_iter()
+        .filter_map(|(sym, shadowed)| {
+            let range = file.lsp_range(shadowed)?;
+            let kind = match shadowed {
+                Symbol::Feature(..) => "feature",
+                Symbol::Attribute(..) => "attribute",
+                _ => "import",
+            };
+            Some(ErrorInfo {
+                location: file.lsp_range(sym)?,
+                severity: DiagnosticSeverity::WARNING,
+                weight: 10,
+                msg: format!(
+                    "{} shadows the {} declared in line {}",
+                    file.symbol_name(sym)?,
+                    kind,
+                    range.start.line + 1
+                ),
+                related: vec![DiagnosticRelatedInformation {
+                    location: Location {
+                        uri: file.uri.clone(),
+                        range,
+                    },
+                    message: format!("shadowed {}", kind),
+                }],
+                code: None,
+                tags: Vec::new(),
+            })
+        })
+        .collect()
+}
+//Groups without children and alternative or or groups with a single child, which is
+//mandatory then. Returns (group, number of children) pairs
+pub fn trivial_groups(file: &Document) -> Vec<(Symbol, usize)> {
+    file.all_groups()
+        .filter_map(|group| {
+            let children = file
+                .direct_children(group)
+                .filter(|sym| matches!(sym, Symbol::Feature(..) | Symbol::Reference(..)))
+                .count();
+            match file.group_mode(group)? {
+                _ if children == 0 => Some((group, 0)),
+                GroupMode::Alternative | GroupMode::Or if children == 1 => Some((group, 1)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+pub fn group_header(file: &Document, group: Symbol) -> Option<String> {
+    let header: String = file.source.byte_slice(file.span(group)?).into();
+    Some(header.trim().to_string())
+}
+pub fn check_groups(file: &Document) -> Vec<ErrorInfo> {
+    trivial_groups(file)
+        .into_iter()
+        .filter_map(|(group, children)| {
+            let header = group_header(file, group)?;
+            let (severity, msg, code) = if children == 0 {
+                (
+                    DiagnosticSeverity::WARNING,
+                    format!("{} group without children", header),
+                    "group/empty",
+                )
+            } else {
+                (
+                    DiagnosticSeverity::HINT,
+                    format!(
+                        "{} group with a single child, the child is mandatory",
+                        header
+                    ),
+                    "group/single-child",
+                )
+            };
+            Some(ErrorInfo {
+                location: file.lsp_range(group)?,
+                severity,
+                weight: 10,
+                msg,
+                related: Vec::new(),
+                code: Some(code.into()),
+                tags: Vec::new(),
+            })
+        })
+        .collect()
+}
+//Mandatory groups nested in an alternative or or group, the intent is a mandatory member of
+//an exclusive group. Returns (mandatory, enclosing) pairs
+pub fn exclusive_mandatory(file: &Document) -> Vec<(Symbol, Symbol)> {
+    file.all_groups()
+        .filter_map(|group| {
+            let parent = file.parent(group, false)?;
+            match (file.group_mode(group)?, file.group_mode(parent)?) {
+                (GroupMode::Mandatory, GroupMode::Alternative | GroupMode::Or) => {
+                    Some((group, parent))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+//A model declares one root feature. Several top level features are mandatory children of an
+//implicit root, see ModelRoot, this is pointed out on each of them with the others as related
+//information. A missing root is an error on the features keyword or the start of the file,
+//blank files are not reported while they are being created
+pub fn check_root_feature(file: &Document) -> Vec<ErrorInfo> {
+    let roots = file.model_root().features();
+    let diagnostic = |location: Range, severity, msg: String, code: &str, related| ErrorInfo {
+        location,
+        severity,
+        weight: if severity == DiagnosticSeverity::ERROR {
+            40
+        } else {
+            0
+        },
+        related,
+        code: Some(code.into()),
+        msg,
+        tags: Vec::new(),
+    };
+    match roots {
+        [] => {
+            if file.source.chars().all(char::is_whitespace) {
+                return Vec::new();
+            }
+            let mut cursor = file.tree().walk();
+            let features = file
+                .tree()
+                .root_node()
+                .children(&mut cursor)
+                .filter_map(|blk| blk.child_by_field_name("header"))
+                .find(|header| header.kind() == "features");
+            let location = features
+                .map(|header| node_range(header, &file.source))
+                .unwrap_or_default();
+            vec![diagnostic(
+                location,
+                DiagnosticSeverity::ERROR,
+                "missing root feature".into(),
+                "model/missing-root",
+                Vec::new(),
+            )]
+        }
+        [_] => Vec::new(),
+        _ => roots
+            .iter()
+            .filter_map(|&sym| {
+                let related = roots
+                    .iter()
+                    .filter(|&&other| other != sym)
+                    .filter_map(|&other| {
+                        Some(DiagnosticRelatedInformation {
+                            location: Location {
+                                uri: file.uri.clone(),
+                                range: file.lsp_range(other)?,
+                            },
+                            message: format!("competing root feature {}", file.symbol_name(other)?),
+                        })
+                    })
+                    .collect();
+                Some(diagnostic(
+                    file.lsp_range(sym)?,
+                    DiagnosticSeverity::INFORMATION,
+                    format!(
+                        "{} root features, they are treated as mandatory children of an implicit root",
+                        roots.len()
+                    ),
+                    "model/implicit-root",
+                    related,
+                ))
+            })
+            .collect(),
+    }
+}
+pub fn check_exclusive_mandatory(file: &Document) -> Vec<ErrorInfo> {
+    exclusive_mandatory(file)
+        .into_iter()
+        .filter_map(|(group, parent)| {
+            let header = group_header(file, parent)?;
+            let explanation = match file.group_mode(parent)? {
+                GroupMode::Alternative => "selects exactly one of its children",
+                _ => "selects any nonempty subset of its children",
+            };
+            Some(ErrorInfo {
+                location: file.lsp_range(group)?,
+                severity: DiagnosticSeverity::ERROR,
+                weight: 40,
+                msg: format!(
+                    "mandatory features can not be members of an {} group since it {}. Groups have to be separated by features",
+                    header, explanation
+                ),
+                related: vec![DiagnosticRelatedInformation {
+                    location: Location {
+                        uri: file.uri.clone(),
+                        range: file.lsp_range(parent)?,
+                    },
+                    message: format!("enclosing {} group", header),
+                }],
+                code: Some("group/mandatory-in-exclusive".into()),
+                tags: Vec::new(),
+            })
+        })
+        .collect()
+}
+//Cardinality groups that select fewer children than they have mandatory members, the
+//features of mandatory groups nested directly in them. Mandatory members of alternative
+//groups are already reported by check_exclusive_mandatory. Returns (group, maximum,
+//mandatory members)
+pub fn overfull_groups(file: &Document) -> Vec<(Symbol, usize, Vec<Symbol>)> {
+    file.all_groups()
+        .filter_map(|group| {
+            let max = match file.group_mode(group)? {
+                GroupMode::Cardinality(Cardinality::Max(max) | Cardinality::Range(_, max)) => max,
+                _ => return None,
+            };
+            let mandatory: Vec<Symbol> = file
+                .direct_children(group)
+                .filter(|sym| matches!(file.group_mode(*sym), Some(GroupMode::Mandatory)))
+                .flat_map(|sym| file.direct_children(sym))
+                .filter(|sym| matches!(sym, Symbol::Feature(..) | Symbol::Reference(..)))
+                .collect();
+            if mandatory.len() > max {
+                Some((group, max, mandatory))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+pub fn check_overfull_groups(file: &Document) -> Vec<ErrorInfo> {
+    overfull_groups(file)
+        .into_iter()
+        .filter_map(|(group, max, mandatory)| {
+            let header = group_header(file, group)?;
+            let related = mandatory
+                .iter()
+                .filter_map(|&sym| {
+                    Some(DiagnosticRelatedInformation {
+                        location: Location {
+                            uri: file.uri.clone(),
+                            range: file.lsp_range(sym)?,
+                        },
+                        message: "mandatory member".into(),
+                    })
+                })
+                .collect();
+            Some(ErrorInfo {
+                location: file.lsp_range(group)?,
+                severity: DiagnosticSeverity::ERROR,
+                weight: 40,
+                msg: format!(
+                    "{} group selects at most {} of its children but {} are mandatory, the group is unsatisfiable",
+                    header,
+                    max,
+                    mandatory.len()
+                ),
+                related,
+                code: Some("group/mandatory-overflow".into()),
+                tags: Vec::new(),
+            })
+        })
+        .collect()
+}
+//Closest declared name within a few edits, longer names tolerate more typos
+fn nearest_name<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = (name.chars().count() / 3).max(2);
+    candidates
+        .map(|c| (strsim::levenshtein(name, c), c))
+        .filter(|(distance, _)| *distance <= limit)
+        .min()
+        .map(|(_, c)| c)
+}
+//A reference like Feature.attr where Feature resolves but declares no attr. Reported on the
+//first unknown segment instead of as an unresolved reference, with the declared attributes of
+//the feature as related information
+pub fn unknown_attribute(
+    root: &RootGraph,
+    file_id: FileID,
+    reference: Symbol,
+) -> Option<ErrorInfo> {
+    let file = root.file(file_id);
+    let path = file.path(reference);
+    let scope = file.reference_scope(reference);
+    let (owner, missing) = (1..path.len()).rev().find_map(|k| {
+        root.resolve_scoped(file_id, scope, &path[..k])
+            .find(|dst| matches!(dst.sym, Symbol::Feature(..) | Symbol::Attribute(..)))
+            .map(|dst| (dst, k))
+    })?;
+    let dst = root.file(owner.file);
+    let declared: Vec<Symbol> = dst
+        .direct_children(owner.sym)
+        .filter(|sym| matches!(sym, Symbol::Attribute(..)))
+        .collect();
+    let name = path[missing];
+    let owner_name = path[..missing]
+        .iter()
+        .map(|n| n.as_str())
+        .collect::<Vec<_>>()
+        .join(".");
+    let mut msg = format!("{} has no attribute {}", owner_name, name);
+    let nearest = nearest_name(
+        name.as_str(),
+        declared
+            .iter()
+            .filter_map(|sym| dst.symbol_name(*sym))
+            .map(|name| name.as_str()),
+    );
+    if let Some(nearest) = nearest {
+        msg = format!("{}, did you mean {}?", msg, nearest);
+    }
+    let related = if declared.is_empty() {
+        vec![DiagnosticRelatedInformation {
+            location: Location {
+                uri: dst.uri.clone(),
+                range: dst.lsp_range(owner.sym)?,
+            },
+            message: format!("{} declares no attributes", owner_name),
+        }]
+    } else {
+        declared
+            .iter()
+            .filter_map(|sym| {
+                Some(DiagnosticRelatedInformation {
+                    location: Location {
+                        uri: dst.uri.clone(),
+                        range: dst.lsp_range(*sym)?,
+                    },
+                    message: format!("declared attribute {}", dst.symbol_name(*sym)?),
+                })
+            })
+            .collect()
+    };
+    Some(ErrorInfo {
+        location: lsp_range(file.path_spans(reference)[missing].clone(), &file.source)?,
+        severity: DiagnosticSeverity::ERROR,
+        weight: 30,
+        msg,
+        related,
+        code: Some("reference/unknown-attribute".into()),
+        tags: Vec::new(),
+    })
+}
+//Start of the first top level section of a kind
+fn section_start(file: &Document, kind: &str) -> Option<usize> {
+    let root = file.tree().root_node();
+    let mut cursor = root.walk();
+    let start = root
+        .named_children(&mut cursor)
+        .filter(|node| node.kind() == "blk")
+        .find(|node| {
+            node.child_by_field_name("header")
+                .is_some_and(|header| header.kind() == kind)
+        })
+        .map(|node| node.start_byte());
+    start
+}
+//Inside the feature tree and the constraints a reference may point anywhere in the file, so
+//references to features declared further down are fine. Only sections out of order create
+//genuine forward references: constraints before the features or an alias used before the
+//imports section declaring it
+pub fn check_forward_references(file: &Document) -> Vec<ErrorInfo> {
+    let features = section_start(file, "features");
+    let mut out = Vec::new();
+    for r in file.all_references() {
+        let (span, location) = match (file.span(r), file.lsp_range(r)) {
+            (Some(span), Some(location)) => (span, location),
+            _ => continue,
+        };
+        let path = file.path(r);
+        let import = file.all_imports().find(|i| {
+            let prefix = file.import_prefix(*i);
+            path.len() > prefix.len() && path.starts_with(prefix)
+        });
+        let (decl, msg) = if let Some(import) = import {
+            let msg = format!(
+                "{} is used before the import declaring it, imports have to precede features and constraints",
+                file.import_prefix(import)
+                    .iter()
+                    .map(|i| i.as_str())
+                    .collect::<Vec<_>>()
+                    .join(".")
+            );
+            (import, msg)
+        } else if let Some(feature) = file
+            .lookup(Symbol::Root, &path[..1], |sym| {
+                matches!(sym, Symbol::Feature(..))
+            })
+            .next()
+        {
+            if features.is_some_and(|start| start < span.start) {
+                continue;
+            }
+            let msg = format!(
+                "{} is used before the features section declaring it, constraints have to follow the features",
+                path[0]
+            );
+            (feature, msg)
+        } else {
+            continue;
+        };
+        let decl_span = match file.span(decl) {
+            Some(decl_span) if decl_span.start > span.start => decl_span,
+            _ => continue,
+        };
+        out.push(ErrorInfo {
+            location,
+            severity: DiagnosticSeverity::ERROR,
+            weight: 50,
+            msg,
+            related: lsp_range(decl_span, &file.source)
+                .map(|range| DiagnosticRelatedInformation {
+                    location: Location {
+                        uri: file.uri.clone(),
+                        range,
+                    },
+                    message: "declared here".into(),
+                })
+                .into_iter()
+                .collect(),
+            code: None,
+            tags: Vec::new(),
+        });
+    }
+    out
+}
+//A line whose indentation matches none of the open levels, it sits between lower and upper
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndentIssue {
+    pub row: usize,
+    pub width: usize,
+    pub lower: usize,
+    pub upper: usize,
+}
+impl IndentIssue {
+    pub fn nearest(&self) -> usize {
+        if self.width - self.lower <= self.upper - self.width {
+            self.lower
+        } else {
+            self.upper
+        }
+    }
+}
+//Lines continuing something from a previous line eg. attribute blocks or expressions in
+//parenthesis, their indentation is not significant. Every line after the first one of such a
+//node continues it, its last line only if the line starts before the node ends. Collected in
+//one pass, walking up from every line is quadratic in the nesting depth
+struct Continuations {
+    inner: HashSet<usize>,
+    //last rows with the highest end byte of a node ending there
+    last: HashMap<usize, usize>,
+}
+impl Continuations {
+    fn new(tree: &Tree) -> Self {
+        let mut out = Continuations {
+            inner: HashSet::new(),
+            last: HashMap::new(),
+        };
+        ts_filterd_visit(tree.root_node(), |node| {
+            let (start, end) = (node.start_position().row, node.end_position().row);
+            if !matches!(node.kind(), "blk" | "source_file" | "ERROR") && start < end {
+                out.inner.extend(start + 1..end);
+                let last = out.last.entry(end).or_insert(0);
+                *last = (*last).max(node.end_byte());
+            }
+            true
+        });
+        out
+    }
+    fn contains(&self, offset: usize, row: usize) -> bool {
+        self.inner.contains(&row) || self.last.get(&row).is_some_and(|end| offset < *end)
+    }
+}
+//Significant lines as (row, indentation width, trailing whitespace)
+fn indented_lines(tree: &Tree, source: &Rope) -> Vec<(usize, usize, bool)> {
+    let mut out = Vec::new();
+    let continuations = Continuations::new(tree);
+    for (row, line) in source.lines().enumerate() {
+        let line = line.to_string();
+        let content = line.trim_end_matches(['\n', '\r']);
+        let text = content.trim_start();
+        if text.is_empty() || text.starts_with("//") {
+            continue;
+        }
+        let width = content.len() - text.len();
+        if continuations.contains(source.line_to_byte(row) + width, row) {
+            continue;
+        }
+        out.push((row, width, text.len() != text.trim_end().len()));
+    }
+    out
+}
+//Simulate the indentation ladder, going back to a width which was never opened changes
+//the tree shape depending on how the grammar rounds it
+pub fn indentation_issues(tree: &Tree, source: &Rope) -> Vec<IndentIssue> {
+    let mut out = Vec::new();
+    let mut levels = vec![0];
+    for (row, width, _) in indented_lines(tree, source) {
+        let mut popped = None;
+        while levels.last().is_some_and(|top| *top > width) {
+            popped = levels.pop();
+        }
+        match (levels.last().copied(), popped) {
+            (Some(top), _) if top == width => {}
+            (Some(top), Some(upper)) => {
+                let issue = IndentIssue {
+                    row,
+                    width,
+                    lower: top,
+                    upper,
+                };
+                if issue.nearest() == upper {
+                    levels.push(upper);
+                }
+                out.push(issue);
+            }
+            _ => levels.push(width),
+        }
+    }
+    out
+}
+pub fn check_indentation(tree: &Tree, source: &Rope) -> Vec<ErrorInfo> {
+    let issues = indentation_issues(tree, source);
+    let mut err = Vec::new();
+    let lines = indented_lines(tree, source);
+    for issue in issues.iter() {
+        err.push(ErrorInfo {
+            location: Range {
+                start: Position::new(issue.row as u32, 0),
+                end: Position::new(issue.row as u32, issue.width as u32),
+            },
+            severity: DiagnosticSeverity::WARNING,
+            weight: 10,
+            related: Vec::new(),
+            code: None,
+            msg: format!(
+                "indentation of {} does not match an enclosing level, nearest valid levels are {} and {}",
+                issue.width, issue.lower, issue.upper
+            ),
+            tags: Vec::new(),
+        });
+        //trailing whitespace makes the previous line look fine while this one is ambiguous
+        let prev = lines.iter().take_while(|(row, ..)| *row < issue.row).last();
+        if let Some(&(row, _, true)) = prev {
+            let line = source.line(row).to_string();
+            let content = line.trim_end_matches(['\n', '\r']);
+            let end = content.encode_utf16().count() as u32;
+            let start = content.trim_end().encode_utf16().count() as u32;
+            err.push(ErrorInfo {
+                location: Range {
+                    start: Position::new(row as u32, start),
+                    end: Position::new(row as u32, end),
+                },
+                severity: DiagnosticSeverity::WARNING,
+                weight: 10,
+                related: Vec::new(),
+                code: None,
+                msg: format!(
+                    "trailing whitespace, the depth of line {} is ambiguous",
+                    issue.row + 1
+                ),
+                tags: Vec::new(),
+            });
+        }
+    }
     err
 }
 
@@ -229,20 +1085,23 @@ async fn maybe_publish(
     uri: Url,
     mut err: Vec<ErrorInfo>,
     timestamp: u64,
+    max: usize,
 ) {
     if let Some(old) = source_map.get_mut(&uri) {
 
         if old.timestamp < timestamp {
-            publish(client, &uri, &err).await;
+            publish(client, &uri, &err, max).await;
             old.timestamp = timestamp;
             old.error = err;
         } else if old.timestamp == timestamp {
             old.timestamp = timestamp;
             old.error.append(&mut err);
-            publish(client, &uri, &old.error).await;
+            old.error
+                .sort_by(|a, b| publication_order(a).cmp(&publication_order(b)));
+            publish(client, &uri, &old.error, max).await;
         }
     } else {
-        publish(client, &uri, &err).await;
+        publish(client, &uri, &err, max).await;
         source_map.insert(
             uri,
             DiagnosticState {
@@ -259,8 +1118,13 @@ pub async fn diagnostic_handler(ctx: Arc<Context>, mut rx: mpsc::Receiver<Diagno
         select! {
             _ = ctx.shutdown.cancelled() => return,
             Some(mut update) = rx.recv()=>{
-                for (uri,err) in update.error_state.drain(){
-                    maybe_publish(&ctx.client,&mut source_map,uri,err,update.timestamp).await
+                for (uri,mut err) in update.error_state.drain(){
+                    //library models are only used for resolution
+                    if ctx.settings.read().is_library(&uri) {
+                        err.clear();
+                    }
+                    let max = ctx.settings.read().max_diagnostics;
+                    maybe_publish(&ctx.client,&mut source_map,uri,err,update.timestamp,max).await
 
                 }
 
@@ -269,3 +1133,468 @@ pub async fn diagnostic_handler(ctx: Arc<Context>, mut rx: mpsc::Receiver<Diagno
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn forward_reference_errors(text: &str) -> Vec<String> {
+        let source = Rope::from_str(text);
+        let doc = crate::document::build_document(
+            source.clone(),
+            crate::parse::parse(&source, None),
+            Url::parse("file:///forward.uvl").unwrap(),
+            Instant::now(),
+            Default::default(),
+        );
+        check_forward_references(&doc)
+            .into_iter()
+            .map(|e| e.msg)
+            .collect()
+    }
+    #[test]
+    fn unknown_attribute_segment() {
+        let uri = Url::parse("file:///attributes.uvl").unwrap();
+        let source = Rope::from_str("features\n    Car {cost 10, weight 2}\n        optional\n            Bus\nconstraints\n    Car.cots > 1\n    Bus.price > 1\n    Tram.cost > 1\n");
+        let doc = crate::document::build_document(
+            source.clone(),
+            crate::parse::parse(&source, None),
+            uri.clone(),
+            Instant::now(),
+            Default::default(),
+        );
+        let files = [(uri.clone(), Arc::new(doc))].into_iter().collect();
+        let root = RootGraph::new(&files, 0);
+        let err = root.reference_errors(root.file_id(&uri).unwrap());
+        let found: Vec<(String, Range, usize)> = err
+            .into_iter()
+            .map(|e| (e.msg, e.location, e.related.len()))
+            .collect();
+        let range = |line, start, end| Range {
+            start: Position::new(line, start),
+            end: Position::new(line, end),
+        };
+        assert_eq!(
+            found,
+            vec![
+                (
+                    "Car has no attribute cots, did you mean cost?".to_string(),
+                    range(5, 8, 12),
+                    2
+                ),
+                ("Bus has no attribute price".to_string(), range(6, 8, 13), 1),
+                ("unresolved reference".to_string(), range(7, 4, 13), 0),
+            ]
+        );
+    }
+    #[test]
+    fn forward_references() {
+        //constraints and attribute constraints may refer to features declared further down
+        assert!(forward_reference_errors(
+            "features\n    A {constraint B.x > 1}\n        optional\n            B {x 2}\nconstraints\n    A => B\n"
+        )
+        .is_empty());
+        let errors = forward_reference_errors(
+            "constraints\n    A => B\nfeatures\n    A\n        optional\n            B\n",
+        );
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("A is used before the features section"));
+        let errors = forward_reference_errors(
+            "features\n    A\n        optional\n            s.B\nimports\n    sub as s\n",
+        );
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("s is used before the import"));
+    }
+    #[test]
+    fn mandatory_in_alternative() {
+        let source = Rope::from_str("features\n    A\n        alternative\n            B\n            mandatory\n                C\n            D\n");
+        let doc = crate::document::build_document(
+            source.clone(),
+            crate::parse::parse(&source, None),
+            Url::parse("file:///exclusive.uvl").unwrap(),
+            Instant::now(),
+            Default::default(),
+        );
+        let errors: Vec<&ErrorInfo> = doc
+            .errors
+            .iter()
+            .filter(|e| e.location.start.line == 4)
+            .collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].code.as_deref(),
+            Some("group/mandatory-in-exclusive")
+        );
+        assert!(errors[0].msg.contains("exactly one"));
+        assert_eq!(
+            errors[0].related[0].location.range.start,
+            Position::new(2, 8)
+        );
+    }
+    #[test]
+    fn mandatory_overflow() {
+        let overflow = |text: &str| {
+            let source = Rope::from_str(text);
+            let doc = crate::document::build_document(
+                source.clone(),
+                crate::parse::parse(&source, None),
+                Url::parse("file:///overflow.uvl").unwrap(),
+                Instant::now(),
+                Default::default(),
+            );
+            visible(&doc.errors)
+                .into_iter()
+                .filter(|e| e.is_error())
+                .map(|e| {
+                    (
+                        e.code.clone().unwrap(),
+                        e.location.start.line,
+                        e.related.len(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        //reported once as a mandatory member of an exclusive group
+        assert_eq!(
+            overflow("features\n    A\n        alternative\n            mandatory\n                B\n                C\n"),
+            vec![("group/mandatory-in-exclusive".to_string(), 3, 1)]
+        );
+        assert_eq!(
+            overflow("features\n    A\n        [0..0]\n            mandatory\n                B\n            C\n"),
+            vec![("group/mandatory-overflow".to_string(), 2, 1)]
+        );
+        assert_eq!(
+            overflow("features\n    A\n        [0..1]\n            mandatory\n                B\n                C\n"),
+            vec![("group/mandatory-overflow".to_string(), 2, 2)]
+        );
+        assert_eq!(
+            overflow("features\n    A\n        [1..2]\n            mandatory\n                B\n                C\n            D\n"),
+            vec![]
+        );
+    }
+    #[test]
+    fn conflicts_and_foreign_files() {
+        let source = Rope::from_str("features\n    Car\n        optional\n<<<<<<< HEAD\n            Engine\n=======\n            Motor\n>>>>>>> branch\n            Radio\n");
+        assert_eq!(
+            conflict_blocks(&source),
+            vec![Span {
+                start: 34,
+                end: 107
+            }]
+        );
+        let doc = crate::document::build_document(
+            source.clone(),
+            crate::parse::parse(&source, None),
+            Url::parse("file:///conflict.uvl").unwrap(),
+            Instant::now(),
+            Default::default(),
+        );
+        let codes: Vec<_> = visible(&doc.errors)
+            .into_iter()
+            .map(|e| (e.code.clone(), e.location.start.line))
+            .collect();
+        assert_eq!(codes, vec![(Some("syntax/merge-conflict".into()), 3)]);
+        assert!(doc
+            .all_features()
+            .all(|f| doc.symbol_name(f).map(|n| n.as_str()) != Some("Motor")));
+        //errors and constraints outside the block are unaffected
+        let source = Rope::from_str("features\n    Car\n        optional\n            Engine\n            Radio\nconstraints\n    Engine => Car\n<<<<<<< HEAD\n    Radio => Car\n=======\n    Radio => Engine\n>>>>>>> branch\n    Radio =>\n");
+        let doc = crate::document::build_document(
+            source.clone(),
+            crate::parse::parse(&source, None),
+            Url::parse("file:///conflict.uvl").unwrap(),
+            Instant::now(),
+            Default::default(),
+        );
+        let mut lines: Vec<_> = visible(&doc.errors)
+            .into_iter()
+            .map(|e| e.location.start.line)
+            .collect();
+        lines.sort();
+        lines.dedup();
+        assert_eq!(lines, vec![7, 12]);
+        assert!(doc
+            .all_constraints()
+            .any(|c| doc.lsp_range(c).is_some_and(|r| r.start.line == 6)));
+        let source = Rope::from_str("{\"name\": \"build\", \"on\": {\"push\": [\"main\"]}}\n");
+        let err = check_errors(
+            &crate::parse::parse(&source, None),
+            &source,
+            Limits::default(),
+        );
+        assert_eq!(err.len(), 1);
+        assert_eq!(err[0].code.as_deref(), Some("syntax/not-uvl"));
+        let disabled = Limits {
+            not_uvl_threshold: 0,
+            ..Default::default()
+        };
+        let err = check_errors(&crate::parse::parse(&source, None), &source, disabled);
+        assert!(err
+            .iter()
+            .all(|e| e.code.as_deref() != Some("syntax/not-uvl")));
+    }
+    #[test]
+    fn root_features() {
+        let roots = |text: &str| {
+            let source = Rope::from_str(text);
+            let doc = crate::document::build_document(
+                source.clone(),
+                crate::parse::parse(&source, None),
+                Url::parse("file:///roots.uvl").unwrap(),
+                Instant::now(),
+                Default::default(),
+            );
+            check_root_feature(&doc)
+                .into_iter()
+                .map(|e| {
+                    let code = e.code.unwrap();
+                    (code, e.severity, e.location.start.line, e.related.len())
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            roots("namespace Empty\nfeatures\n"),
+            vec![(
+                "model/missing-root".to_string(),
+                DiagnosticSeverity::ERROR,
+                1,
+                0
+            )]
+        );
+        assert_eq!(
+            roots("features\n    A\n    B\n    C\n"),
+            vec![
+                (
+                    "model/implicit-root".to_string(),
+                    DiagnosticSeverity::INFORMATION,
+                    1,
+                    2
+                ),
+                (
+                    "model/implicit-root".to_string(),
+                    DiagnosticSeverity::INFORMATION,
+                    2,
+                    2
+                ),
+                (
+                    "model/implicit-root".to_string(),
+                    DiagnosticSeverity::INFORMATION,
+                    3,
+                    2
+                )
+            ]
+        );
+        assert!(roots("features\n    A\n        optional\n            B\n").is_empty());
+        assert!(roots("\n").is_empty());
+    }
+    #[test]
+    fn trivial_group_smells() {
+        let source = Rope::from_str("features\n    A\n        mandatory\n            B\n        alternative\n            C\n        or\n            D\n            E\n        optional\n");
+        let doc = crate::document::build_document(
+            source.clone(),
+            crate::parse::parse(&source, None),
+            Url::parse("file:///groups.uvl").unwrap(),
+            Instant::now(),
+            Default::default(),
+        );
+        let smells: Vec<(u32, Option<String>)> = check_groups(&doc)
+            .into_iter()
+            .map(|e| (e.location.start.line, e.code))
+            .collect();
+        assert_eq!(
+            smells,
+            vec![
+                (4, Some("group/single-child".into())),
+                (9, Some("group/empty".into()))
+            ]
+        );
+    }
+    #[test]
+    fn newer_syntax_patterns() {
+        let found = |text: &str| {
+            let source = Rope::from_str(text);
+            let tree = crate::parse::parse(&source, None);
+            newer_syntax(tree.root_node(), &source, 0..source.len_lines()).map(|i| i.id)
+        };
+        for syntax in NEWER_SYNTAX {
+            assert_eq!(found(syntax.example), Some(syntax.id));
+            let err = unsupported_syntax(syntax, Range::default());
+            assert!(err.msg.contains(syntax.feature));
+            assert!(err.msg.contains(syntax.introduced));
+            assert_eq!(err.code, Some(format!("grammar/unsupported/{}", syntax.id)));
+        }
+        assert!(found("features\n    Root\n        optional\n            A {price 3}\n").is_none());
+        //names, comments and strings which only look like newer syntax
+        let quoted = "features\n    Root {note 'len(x)'}\n        optional\n            Integer\n";
+        assert!(found(quoted).is_none());
+        assert!(found("features\n    Root // floor(Price) == 3, Type-level\n").is_none());
+    }
+    //"line: message (line of the shadowed declaration)" of every shadowing warning
+    fn shadowing(text: &str) -> Vec<String> {
+        let source = Rope::from_str(text);
+        let doc = crate::document::build_document(
+            source.clone(),
+            crate::parse::parse(&source, None),
+            Url::parse("file:///shadow.uvl").unwrap(),
+            Instant::now(),
+            Default::default(),
+        );
+        check_shadowing(&doc)
+            .into_iter()
+            .map(|e| {
+                let shadowed = e.related[0].location.range.start.line;
+                format!("{}: {} ({})", e.location.start.line, e.msg, shadowed)
+            })
+            .collect()
+    }
+    #[test]
+    fn shadowed_attributes() {
+        let text =
+            "features\n    Car {cost 10}\n        optional\n            Engine {cost 5, Car 1}\n";
+        assert_eq!(
+            shadowing(text),
+            [
+                "3: cost shadows the attribute declared in line 2 (1)",
+                "3: Car shadows the feature declared in line 2 (1)",
+            ]
+        );
+        let text = "features\n    Car {cost 10}\n        optional\n            Engine {weight 5}\n";
+        assert!(shadowing(text).is_empty());
+    }
+    #[test]
+    fn shadowed_features() {
+        let text = "imports\n    engine\n    parts.wheel as wheels\n    lib.brakes\nfeatures\n    Car\n        optional\n            engine\n            wheels\n            lib\n            wheel\n";
+        assert_eq!(
+            shadowing(text),
+            [
+                "7: engine shadows the import declared in line 2 (1)",
+                "8: wheels shadows the import declared in line 3 (2)",
+                "9: lib shadows the import declared in line 4 (3)",
+            ]
+        );
+        //the root feature is referenced by its name anyway
+        assert!(shadowing("imports\n    engine\nfeatures\n    engine\n").is_empty());
+    }
+    #[test]
+    fn crlf_ranges() {
+        let source = Rope::from_str("features\r\n    A\r\n    A\r\n   B\r\n");
+        let doc = crate::document::build_document(
+            source.clone(),
+            crate::parse::parse(&source, None),
+            Url::parse("file:///crlf.uvl").unwrap(),
+            Instant::now(),
+            Default::default(),
+        );
+        let ranges: Vec<Range> = doc
+            .errors
+            .iter()
+            .filter(|e| e.msg.starts_with("duplicate feature"))
+            .map(|e| e.location)
+            .collect();
+        assert!(ranges.contains(&Range {
+            start: Position::new(2, 4),
+            end: Position::new(2, 5)
+        }));
+        assert!(doc.errors.iter().any(|e| e.location
+            == Range {
+                start: Position::new(3, 0),
+                end: Position::new(3, 3)
+            }));
+    }
+    #[test]
+    fn indentation_ladder() {
+        let source = Rope::from_str(
+            "features\n  Root \n    optional\n      A\n   B\n    mandatory\n        C\n",
+        );
+        let tree = crate::parse::parse(&source, None);
+        let issues = indentation_issues(&tree, &source);
+        assert_eq!(
+            issues,
+            vec![IndentIssue {
+                row: 4,
+                width: 3,
+                lower: 2,
+                upper: 4,
+            }]
+        );
+        assert_eq!(issues[0].nearest(), 2);
+    }
+    #[test]
+    fn indentation_below_syntax_errors() {
+        let source = Rope::from_str(
+            "features\n  Root\n    optional\n      A\n   B\nconstraints\n    A =>\n",
+        );
+        let doc = crate::document::build_document(
+            source.clone(),
+            crate::parse::parse(&source, None),
+            Url::parse("file:///indent.uvl").unwrap(),
+            Instant::now(),
+            Default::default(),
+        );
+        assert!(doc
+            .errors
+            .iter()
+            .any(|e| e.msg.starts_with("indentation of 3")));
+        let shown = visible(&doc.errors);
+        assert!(shown
+            .iter()
+            .any(|e| e.location.start.line == 6 && e.severity == DiagnosticSeverity::ERROR));
+        assert!(shown.iter().all(|e| !e.msg.starts_with("indentation")));
+    }
+    #[test]
+    fn diagnostic_cap() {
+        let error = |line: u32, weight: u32| ErrorInfo {
+            location: Range {
+                start: Position::new(line, 0),
+                end: Position::new(line, 1),
+            },
+            severity: DiagnosticSeverity::ERROR,
+            weight,
+            msg: format!("error {}", line),
+            related: Vec::new(),
+            code: None,
+            tags: Vec::new(),
+        };
+        let mut err: Vec<ErrorInfo> = (0..10).map(|line| error(line, 30)).collect();
+        err.push(error(10, 10));
+        assert_eq!(capped(&err, 0).len(), 10);
+        assert_eq!(capped(&err, 10).len(), 10);
+        let diagnostics = capped(&err, 4);
+        assert_eq!(diagnostics.len(), 5);
+        assert!(diagnostics[..4].iter().all(|d| d.message != "error 10"));
+        assert_eq!(diagnostics[4].message, "and 6 more diagnostics");
+        //published by position whatever order the checks produced
+        let lines: Vec<u32> = capped(&err, 0).iter().map(|d| d.range.start.line).collect();
+        assert_eq!(lines, (0..10).collect::<Vec<u32>>());
+        //hints are never masked but dropped before heavier errors
+        err.push(ErrorInfo {
+            severity: DiagnosticSeverity::HINT,
+            ..error(11, 0)
+        });
+        let diagnostics = capped(&err, 10);
+        assert!(diagnostics[..10].iter().all(|d| d.message != "error 11"));
+        assert_eq!(diagnostics[10].message, "and 1 more diagnostics");
+    }
+    #[test]
+    fn constraint_limits() {
+        //the limit is an argument of the build, documents built with other limits are untouched
+        let source = Rope::from_str(
+            "features\n    A\n        optional\n            B\nconstraints\n    A & B | !A\n",
+        );
+        let build = |max_constraint_size| {
+            let doc = crate::document::build_document(
+                source.clone(),
+                crate::parse::parse(&source, None),
+                Url::parse("file:///limits.uvl").unwrap(),
+                Instant::now(),
+                Limits {
+                    max_constraint_size,
+                    ..Default::default()
+                },
+            );
+            doc.oversized_constraints().len()
+        };
+        assert_eq!(build(3), 1);
+        assert_eq!(build(5000), 0);
+        assert_eq!(build(3), 1);
+    }
+}
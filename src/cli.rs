@@ -0,0 +1,596 @@
+use crate::ast::Document;
+use crate::check::{visible, ErrorInfo};
+use crate::document::build_document;
+use crate::export::export;
+use crate::parse::parse;
+use crate::semantic::{check_documents, RootGraph};
+use crate::util::char_offset;
+use hashbrown::HashMap;
+use ropey::Rope;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::time::Instant;
+use tower_lsp::lsp_types::*;
+/*
+ * Headless check for CI: uvls --check [paths] [--json] [--baseline <file> [--write-baseline]]
+ * All models below the paths, the working directory by default, are checked like in the
+ * editor and the visible diagnostics are printed sorted by file and position, as text or as
+ * JSON. The solver is not run. With a baseline, diagnostics recorded in it are suppressed and
+ * entries that no longer occur are listed so the baseline can be shrunk. The exit code is 1 if
+ * a new error remains.
+ *
+ * Baseline entries do not store line numbers. A diagnostic is anchored on the headers of its
+ * enclosing blocks and its own line, eg. features > Car > optional > Engine or
+ * constraints > Car => Missing, and its column after the indentation, so edits elsewhere in
+ * the file keep the entry valid.
+ *
+ * uvls --check - [--root <dir>] reads a single model from stdin instead. It is checked as the
+ * file <stdin>.uvl in the root, the working directory by default, whose models are loaded to
+ * resolve imports but not reported.
+ *
+ * uvls parse <file> [--format json|uvl] prints the syntax tree of one model in the format
+ * documented in export.rs, or the model printed back as UVL. The models in the directory of
+ * the file are loaded as well to resolve its imports and references.
+ */
+const USAGE: &str = "usage: uvls --check [paths | - [--root <dir>]] [--json] [--baseline <file> [--write-baseline]]";
+//File name of the model read from stdin
+const STDIN_NAME: &str = "<stdin>.uvl";
+const PARSE_USAGE: &str = "usage: uvls parse <file> [--format json|uvl]";
+const BASELINE_VERSION: u32 = 2;
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Fingerprint {
+    pub file: String,
+    pub anchor: String,
+    pub column: usize,
+    pub code: String,
+    //FNV-1a of the message, stable between builds unlike the std hasher
+    pub message: String,
+}
+#[derive(Serialize, Deserialize, Debug)]
+struct Baseline {
+    version: u32,
+    entries: Vec<Fingerprint>,
+}
+#[derive(Debug)]
+struct Finding {
+    fingerprint: Fingerprint,
+    position: (u32, u32),
+    severity: &'static str,
+    msg: String,
+    text: String,
+    error: bool,
+}
+#[derive(Serialize)]
+struct JsonDiagnostic<'a> {
+    line: u32,
+    column: u32,
+    severity: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    code: &'a str,
+    message: &'a str,
+}
+#[derive(Serialize)]
+struct JsonFile<'a> {
+    path: &'a str,
+    diagnostics: Vec<JsonDiagnostic<'a>>,
+}
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    files: Vec<JsonFile<'a>>,
+    stale: &'a [Fingerprint],
+}
+struct Options {
+    paths: Vec<PathBuf>,
+    baseline: Option<PathBuf>,
+    write: bool,
+    json: bool,
+    stdin: bool,
+    root: Option<PathBuf>,
+}
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut opts = Options {
+        paths: Vec::new(),
+        baseline: None,
+        write: false,
+        json: false,
+        stdin: false,
+        root: None,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--baseline" => {
+                let file = args.next().ok_or("--baseline expects a file")?;
+                opts.baseline = Some(file.into());
+            }
+            "--write-baseline" => opts.write = true,
+            "--json" => opts.json = true,
+            "--root" => {
+                let dir = args.next().ok_or("--root expects a directory")?;
+                opts.root = Some(dir.into());
+            }
+            "-" => opts.stdin = true,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => opts.paths.push(arg.into()),
+        }
+    }
+    if opts.write && opts.baseline.is_none() {
+        return Err("--write-baseline needs --baseline <file>".into());
+    }
+    if opts.stdin && !opts.paths.is_empty() {
+        return Err("- can not be combined with paths".into());
+    }
+    if opts.root.is_some() && !opts.stdin {
+        return Err("--root is only used with -".into());
+    }
+    if opts.paths.is_empty() {
+        opts.paths
+            .push(opts.root.clone().unwrap_or_else(|| ".".into()));
+    }
+    Ok(opts)
+}
+fn load(paths: &[PathBuf]) -> HashMap<Url, Arc<Document>> {
+    let mut documents = HashMap::new();
+    for e in paths
+        .iter()
+        .flat_map(walkdir::WalkDir::new)
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_file())
+        .filter(|e| e.path().extension().is_some_and(|ext| ext == "uvl"))
+    {
+        let path = match std::fs::canonicalize(e.path()) {
+            Ok(path) => path,
+            Err(_) => continue,
+        };
+        let (text, uri) = match (std::fs::read_to_string(&path), Url::from_file_path(&path)) {
+            (Ok(text), Ok(uri)) => (text, uri),
+            _ => {
+                eprintln!("failed to read {}", e.path().display());
+                continue;
+            }
+        };
+        let source = Rope::from_str(&text);
+        let tree = parse(&source, None);
+        let doc = build_document(
+            source,
+            tree,
+            uri.clone(),
+            Instant::now(),
+            Default::default(),
+        );
+        documents.insert(uri, Arc::new(doc));
+    }
+    documents
+}
+//Add the model read from stdin as a file of the root, replacing one with the same name
+fn load_stdin(
+    documents: &mut HashMap<Url, Arc<Document>>,
+    root: &Path,
+    text: &str,
+) -> Result<Url, String> {
+    let root = std::fs::canonicalize(root).map_err(|e| format!("{}: {}", root.display(), e))?;
+    let uri = Url::from_file_path(root.join(STDIN_NAME))
+        .map_err(|_| format!("{}: invalid root", root.display()))?;
+    let source = Rope::from_str(text);
+    let tree = parse(&source, None);
+    let doc = build_document(
+        source,
+        tree,
+        uri.clone(),
+        Instant::now(),
+        Default::default(),
+    );
+    documents.insert(uri.clone(), Arc::new(doc));
+    Ok(uri)
+}
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+//Headers of all blocks containing pos from the outermost one followed by the line of pos,
+//which tells apart constraints and leaf features in the same block, and the column after the
+//indentation
+fn anchor(doc: &Document, pos: &Position) -> (String, usize) {
+    let source = &doc.source;
+    let offset = char_offset(pos, source);
+    let row = source.char_to_line(offset);
+    let indent = source
+        .line(row)
+        .chars()
+        .take_while(|c| c.is_whitespace())
+        .count();
+    let column = (offset - source.line_to_char(row)).saturating_sub(indent);
+    let byte = source.char_to_byte(offset);
+    let mut headers = Vec::new();
+    let mut node = doc.tree().root_node().descendant_for_byte_range(byte, byte);
+    while let Some(n) = node {
+        let header = n.child_by_field_name("header");
+        if let Some(header) = header.filter(|_| n.kind() == "blk") {
+            let text: String = source.byte_slice(header.byte_range()).into();
+            headers.push(text.split_whitespace().collect::<Vec<_>>().join(" "));
+        }
+        node = n.parent();
+    }
+    headers.reverse();
+    let line: String = source.line(row).into();
+    let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+    if !line.is_empty() && headers.last() != Some(&line) {
+        headers.push(line);
+    }
+    (headers.join(" > "), column)
+}
+fn display_path(uri: &Url) -> String {
+    let path = uri.to_file_path().unwrap_or_default();
+    let relative = std::env::current_dir()
+        .and_then(std::fs::canonicalize)
+        .ok()
+        .and_then(|cwd| path.strip_prefix(cwd).ok().map(Path::to_path_buf));
+    relative
+        .unwrap_or(path)
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+fn findings(
+    documents: &HashMap<Url, Arc<Document>>,
+    err: &HashMap<Url, Vec<ErrorInfo>>,
+) -> Vec<Finding> {
+    let mut out = Vec::new();
+    for (uri, err) in err.iter() {
+        let doc = &documents[uri];
+        let file = display_path(uri);
+        for e in visible(err) {
+            let (anchor, column) = anchor(doc, &e.location.start);
+            let severity = e.severity_name();
+            let code = e.code.clone().unwrap_or_default();
+            let mut text = format!(
+                "{}:{}:{}: {}: {}",
+                file,
+                e.location.start.line + 1,
+                e.location.start.character + 1,
+                severity,
+                e.msg
+            );
+            if !code.is_empty() {
+                text = format!("{} [{}]", text, code);
+            }
+            out.push(Finding {
+                fingerprint: Fingerprint {
+                    file: file.clone(),
+                    anchor,
+                    column,
+                    code,
+                    message: format!("{:016x}", fnv1a(&e.msg)),
+                },
+                position: (e.location.start.line, e.location.start.character),
+                severity,
+                msg: e.msg.clone(),
+                text,
+                error: e.is_error(),
+            });
+        }
+    }
+    //the text orders findings at the same position by severity, message and code
+    out.sort_by(|a, b| {
+        let key = |f: &Finding| (f.fingerprint.file.clone(), f.position, f.text.clone());
+        key(a).cmp(&key(b))
+    });
+    out
+}
+//Findings grouped by file, files and findings keep the sorted order
+fn json_report(findings: &[&Finding], stale: &[Fingerprint]) -> String {
+    let mut files: Vec<JsonFile> = Vec::new();
+    for f in findings {
+        let diagnostic = JsonDiagnostic {
+            line: f.position.0 + 1,
+            column: f.position.1 + 1,
+            severity: f.severity,
+            code: &f.fingerprint.code,
+            message: &f.msg,
+        };
+        match files.last_mut() {
+            Some(file) if file.path == f.fingerprint.file => file.diagnostics.push(diagnostic),
+            _ => files.push(JsonFile {
+                path: &f.fingerprint.file,
+                diagnostics: vec![diagnostic],
+            }),
+        }
+    }
+    serde_json::to_string_pretty(&JsonReport { files, stale }).unwrap_or_default()
+}
+//Findings not covered by the baseline and baseline entries that no longer occur, every entry
+//covers one finding
+fn compare<'a>(
+    findings: &'a [Finding],
+    baseline: &[Fingerprint],
+) -> (Vec<&'a Finding>, Vec<Fingerprint>) {
+    let mut remaining: HashMap<&Fingerprint, usize> = HashMap::new();
+    for e in baseline {
+        *remaining.entry(e).or_default() += 1;
+    }
+    let new = findings
+        .iter()
+        .filter(|f| match remaining.get_mut(&f.fingerprint) {
+            Some(n) if *n > 0 => {
+                *n -= 1;
+                false
+            }
+            _ => true,
+        })
+        .collect();
+    let stale = baseline
+        .iter()
+        .filter(|e| match remaining.get_mut(e) {
+            Some(n) if *n > 0 => {
+                *n -= 1;
+                true
+            }
+            _ => false,
+        })
+        .cloned()
+        .collect();
+    (new, stale)
+}
+fn read_baseline(path: &Path) -> Result<Vec<Fingerprint>, String> {
+    let data = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let baseline: Baseline =
+        serde_json::from_str(&data).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if baseline.version != BASELINE_VERSION {
+        return Err(format!(
+            "{}: unsupported baseline version {}",
+            path.display(),
+            baseline.version
+        ));
+    }
+    Ok(baseline.entries)
+}
+fn write_baseline(path: &Path, findings: &[Finding]) -> Result<(), String> {
+    let mut entries: Vec<Fingerprint> = findings.iter().map(|f| f.fingerprint.clone()).collect();
+    entries.sort();
+    let baseline = Baseline {
+        version: BASELINE_VERSION,
+        entries,
+    };
+    let data = serde_json::to_string_pretty(&baseline).map_err(|e| e.to_string())?;
+    std::fs::write(path, data).map_err(|e| format!("{}: {}", path.display(), e))
+}
+//Exit code of the check mode: 0 no new errors, 1 new errors, 2 invalid usage
+pub fn run(args: &[String]) -> i32 {
+    let opts = match parse_args(args) {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return 2;
+        }
+    };
+    let mut documents = load(&opts.paths);
+    let mut stdin = None;
+    if opts.stdin {
+        let mut text = String::new();
+        if let Err(e) = std::io::stdin().read_to_string(&mut text) {
+            eprintln!("stdin: {}", e);
+            return 2;
+        }
+        match load_stdin(&mut documents, &opts.paths[0], &text) {
+            Ok(uri) => stdin = Some(uri),
+            Err(e) => {
+                eprintln!("{}", e);
+                return 2;
+            }
+        }
+    }
+    let mut err = check_documents(&documents);
+    //only the piped model is reported, the root just provides its imports
+    if let Some(uri) = stdin {
+        err.retain(|k, _| *k == uri);
+        documents.retain(|k, _| *k == uri);
+    }
+    let findings = findings(&documents, &err);
+    match (&opts.baseline, opts.write) {
+        (Some(path), true) => {
+            if let Err(e) = write_baseline(path, &findings) {
+                eprintln!("{}", e);
+                return 2;
+            }
+            println!(
+                "wrote {} baseline entries to {}",
+                findings.len(),
+                path.display()
+            );
+            0
+        }
+        (baseline, _) => {
+            let baseline = match baseline.as_deref().map(read_baseline) {
+                Some(Ok(baseline)) => baseline,
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    return 2;
+                }
+                None => Vec::new(),
+            };
+            let (new, stale) = compare(&findings, &baseline);
+            if opts.json {
+                println!("{}", json_report(&new, &stale));
+                return i32::from(new.iter().any(|f| f.error));
+            }
+            for f in new.iter() {
+                println!("{}", f.text);
+            }
+            for e in stale.iter() {
+                println!("no longer occurs: {} {} [{}]", e.file, e.anchor, e.code);
+            }
+            println!(
+                "checked {} files: {} new, {} in baseline, {} stale baseline entries",
+                documents.len(),
+                new.len(),
+                findings.len() - new.len(),
+                stale.len()
+            );
+            i32::from(new.iter().any(|f| f.error))
+        }
+    }
+}
+#[derive(Debug, PartialEq, Eq)]
+enum Format {
+    Json,
+    Uvl,
+}
+fn parse_model_args(args: &[String]) -> Result<(PathBuf, Format), String> {
+    let mut file = None;
+    let mut format = Format::Json;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                format = match args.next().map(String::as_str) {
+                    Some("json") => Format::Json,
+                    Some("uvl") => Format::Uvl,
+                    _ => return Err("--format expects json or uvl".into()),
+                }
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ if file.is_some() => return Err("expected a single file".into()),
+            _ => file = Some(PathBuf::from(arg)),
+        }
+    }
+    Ok((file.ok_or("missing file")?, format))
+}
+//Exit code of the parse mode: 0 the model has no errors, 1 it has errors, 2 invalid usage
+pub fn parse_model(args: &[String]) -> i32 {
+    let (file, format) = match parse_model_args(args) {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("{}\n{}", e, PARSE_USAGE);
+            return 2;
+        }
+    };
+    let path = match std::fs::canonicalize(&file) {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("{}: {}", file.display(), e);
+            return 2;
+        }
+    };
+    let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    let documents = load(&[dir]);
+    let uri = match Url::from_file_path(&path) {
+        Ok(uri) if documents.contains_key(&uri) => uri,
+        _ => {
+            eprintln!("{}: not a uvl model", file.display());
+            return 2;
+        }
+    };
+    let err = check_documents(&documents);
+    let root = RootGraph::new(&documents, 0);
+    let file_id = root.file_id(&uri).unwrap();
+    let model = export(
+        &root,
+        file_id,
+        true,
+        err.get(&uri).map(Vec::as_slice).unwrap_or_default(),
+    );
+    match format {
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&model).unwrap_or_default()
+        ),
+        Format::Uvl => print!("{}", model.to_uvl_text()),
+    }
+    i32::from(model.diagnostics.iter().any(|d| d.severity == "error"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn findings_of(text: &str) -> Vec<Finding> {
+        let uri = Url::parse("file:///ci/model.uvl").unwrap();
+        let source = Rope::from_str(text);
+        let tree = parse(&source, None);
+        let doc = build_document(
+            source,
+            tree,
+            uri.clone(),
+            Instant::now(),
+            Default::default(),
+        );
+        let documents = [(uri, Arc::new(doc))].into_iter().collect();
+        findings(&documents, &check_documents(&documents))
+    }
+    #[test]
+    fn baseline_ignores_shifts() {
+        let model = "features\n    Car\n        optional\n            Engine\nconstraints\n    Car => Missing\n";
+        let baseline: Vec<Fingerprint> = findings_of(model)
+            .into_iter()
+            .map(|f| f.fingerprint)
+            .collect();
+        assert_eq!(baseline.len(), 1);
+        //new lines above move the diagnostic down
+        let shifted = "features\n    Car\n        optional\n            Wheel\n            Engine\nconstraints\n    Wheel => Car\n    Car => Missing\n";
+        let found = findings_of(shifted);
+        let (new, stale) = compare(&found, &baseline);
+        assert!(new.is_empty());
+        assert!(stale.is_empty());
+        let worse = "features\n    Car\n        optional\n            Engine\nconstraints\n    Car => Missing\n    Engine => Gone\n";
+        let found = findings_of(worse);
+        let (new, _) = compare(&found, &baseline);
+        assert_eq!(new.len(), 1);
+        assert!(new[0].text.contains(":7:"));
+        let fixed = "features\n    Car\n        optional\n            Engine\nconstraints\n    Car => Engine\n";
+        let found = findings_of(fixed);
+        let (new, stale) = compare(&found, &baseline);
+        assert!(new.is_empty());
+        assert_eq!(stale, baseline);
+    }
+    #[test]
+    fn distinct_constraints() {
+        let model =
+            "features\n    Car\nconstraints\n    Car => Missing\n    Car => Missing | Car\n";
+        let found = findings_of(model);
+        assert_eq!(found.len(), 2);
+        assert_ne!(found[0].fingerprint, found[1].fingerprint);
+        assert_eq!(found[0].fingerprint.anchor, "constraints > Car => Missing");
+        //one of two identical errors fixed leaves the other one in the baseline
+        let baseline: Vec<Fingerprint> = found.into_iter().map(|f| f.fingerprint).collect();
+        let fixed = "features\n    Car\nconstraints\n    Car => Car\n    Car => Missing | Car\n";
+        let found = findings_of(fixed);
+        let (new, stale) = compare(&found, &baseline);
+        assert!(new.is_empty());
+        assert_eq!(stale, baseline[..1].to_vec());
+    }
+    #[test]
+    fn stdin_resolves_imports() {
+        let opts = parse_args(&["-".into(), "--root".into(), "fixtures/check".into()]).unwrap();
+        assert!(opts.stdin);
+        let mut documents = load(&opts.paths);
+        let model =
+            "imports\n    a\nfeatures\n    Top\nconstraints\n    Top => a.A\n    Top => a.Gone\n";
+        let uri = load_stdin(&mut documents, &opts.paths[0], model).unwrap();
+        let mut err = check_documents(&documents);
+        err.retain(|k, _| *k == uri);
+        documents.retain(|k, _| *k == uri);
+        let found = findings(&documents, &err);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].text.contains("<stdin>.uvl:7:"));
+        assert!(parse_args(&["-".into(), "model.uvl".into()]).is_err());
+    }
+    #[test]
+    fn golden_json() {
+        //paths are shown relative to the working directory, the crate root under cargo test
+        let report = || {
+            let documents = load(&["fixtures/check".into()]);
+            let findings = findings(&documents, &check_documents(&documents));
+            let new: Vec<&Finding> = findings.iter().collect();
+            json_report(&new, &[])
+        };
+        let first = report();
+        for _ in 0..3 {
+            assert_eq!(report(), first);
+        }
+        assert_eq!(
+            first,
+            include_str!("../fixtures/check/expected.json").trim_end()
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use crate::ast::*;
 use crate::completion::find_section;
 use crate::semantic::{RootGraph, Snapshot};
-use crate::util::{node_range, TS};
+use crate::util::{line_len_utf16, node_range, ts_filterd_visit, TS};
 use log::info;
 use ropey::Rope;
 use std::collections::HashSet;
@@ -18,47 +18,149 @@ use tree_sitter::{Node, QueryCursor, Tree};
 struct AbsToken {
     range: Range,
     kind: u32,
+    modifiers: u32,
 }
 struct FileState {
     state: Vec<SemanticToken>,
 }
-pub fn token_types() -> Vec<SemanticTokenType> {
-    vec![
-        SemanticTokenType::KEYWORD,
-        SemanticTokenType::OPERATOR,
-        SemanticTokenType::NAMESPACE,
-        SemanticTokenType::ENUM_MEMBER,
-        SemanticTokenType::CLASS,
-        SemanticTokenType::COMMENT,
-        SemanticTokenType::ENUM,
-        SemanticTokenType::INTERFACE,
-        SemanticTokenType::FUNCTION,
-        SemanticTokenType::MACRO,
-        SemanticTokenType::PARAMETER,
-        SemanticTokenType::NUMBER,
-    ]
+//Captures of the highlight query and the tokens they are reported as, the legend is generated
+//from this table. To add a token type or modifier add a row here
+struct TokenKind {
+    capture: &'static str,
+    ty: SemanticTokenType,
+    modifiers: &'static [SemanticTokenModifier],
+    //types used in order when the client does not know ty, the token is omitted otherwise
+    fallback: &'static [SemanticTokenType],
 }
-fn token_index(name: &str) -> u32 {
-    match name {
-        "keyword" => 0,
-        "operator" => 1,
-        "namespace" => 2,
-        "enumMember" => 3,
-        "class" => 4,
-        "comment" => 5,
-        "enum" => 6,
-        "interface" => 7,
-        "function" => 8,
-        "macro" => 9,
-        "parameter" => 10,
-        "number" => 11,
-        _ => 0,
+static TOKEN_KINDS: &[TokenKind] = &[
+    TokenKind {
+        capture: "keyword",
+        ty: SemanticTokenType::KEYWORD,
+        modifiers: &[],
+        fallback: &[],
+    },
+    TokenKind {
+        capture: "operator",
+        ty: SemanticTokenType::OPERATOR,
+        modifiers: &[],
+        fallback: &[],
+    },
+    TokenKind {
+        capture: "enumMember",
+        ty: SemanticTokenType::ENUM_MEMBER,
+        modifiers: &[],
+        fallback: &[SemanticTokenType::PROPERTY, SemanticTokenType::VARIABLE],
+    },
+    TokenKind {
+        capture: "comment",
+        ty: SemanticTokenType::COMMENT,
+        modifiers: &[],
+        fallback: &[],
+    },
+    TokenKind {
+        capture: "function",
+        ty: SemanticTokenType::FUNCTION,
+        modifiers: &[],
+        fallback: &[SemanticTokenType::KEYWORD],
+    },
+    TokenKind {
+        capture: "macro",
+        ty: SemanticTokenType::MACRO,
+        modifiers: &[],
+        fallback: &[SemanticTokenType::KEYWORD],
+    },
+    TokenKind {
+        capture: "parameter",
+        ty: SemanticTokenType::PARAMETER,
+        modifiers: &[],
+        fallback: &[SemanticTokenType::VARIABLE],
+    },
+    TokenKind {
+        capture: "number",
+        ty: SemanticTokenType::NUMBER,
+        modifiers: &[],
+        fallback: &[],
+    },
+    TokenKind {
+        capture: "string",
+        ty: SemanticTokenType::STRING,
+        modifiers: &[],
+        fallback: &[],
+    },
+];
+//Legend agreed with the client and the token type and modifier bitset of each row of
+//TOKEN_KINDS in it
+#[derive(Debug, Clone)]
+pub struct Legend {
+    pub types: Vec<SemanticTokenType>,
+    pub modifiers: Vec<SemanticTokenModifier>,
+    kinds: Vec<Option<(u32, u32)>>,
+}
+impl Legend {
+    //Everything the client declares is supported, without a declaration the full table is used
+    pub fn new(client: Option<&SemanticTokensClientCapabilities>) -> Self {
+        let known_type = |ty: &SemanticTokenType| {
+            client.is_none_or(|c| c.token_types.is_empty() || c.token_types.contains(ty))
+        };
+        let known_modifier = |m: &SemanticTokenModifier| {
+            client.is_none_or(|c| c.token_modifiers.is_empty() || c.token_modifiers.contains(m))
+        };
+        let mut types: Vec<SemanticTokenType> = Vec::new();
+        let mut modifiers: Vec<SemanticTokenModifier> = Vec::new();
+        let kinds = TOKEN_KINDS
+            .iter()
+            .map(|kind| {
+                let ty = std::iter::once(&kind.ty)
+                    .chain(kind.fallback.iter())
+                    .find(|ty| known_type(ty))?;
+                let index = match types.iter().position(|i| i == ty) {
+                    Some(i) => i,
+                    None => {
+                        types.push(ty.clone());
+                        types.len() - 1
+                    }
+                };
+                let mut bitset = 0;
+                for m in kind.modifiers.iter().filter(|m| known_modifier(m)) {
+                    let bit = match modifiers.iter().position(|i| i == m) {
+                        Some(i) => i,
+                        None => {
+                            modifiers.push(m.clone());
+                            modifiers.len() - 1
+                        }
+                    };
+                    bitset |= 1 << bit;
+                }
+                Some((index as u32, bitset))
+            })
+            .collect();
+        Legend {
+            types,
+            modifiers,
+            kinds,
+        }
+    }
+    pub fn lsp(&self) -> SemanticTokensLegend {
+        SemanticTokensLegend {
+            token_types: self.types.clone(),
+            token_modifiers: self.modifiers.clone(),
+        }
+    }
+    //Token type and modifiers of a capture, None if the client can not show it
+    fn kind(&self, capture: &str) -> Option<(u32, u32)> {
+        let row = TOKEN_KINDS.iter().position(|k| k.capture == capture)?;
+        self.kinds[row]
+    }
+}
+impl Default for Legend {
+    fn default() -> Self {
+        Legend::new(None)
     }
 }
 
 pub enum ColorUpdate {
     File(Tree),
-    Root(RootGraph),
+    Root(Box<RootGraph>),
 }
 fn fast_lsp_range(
     node: Node,
@@ -137,28 +239,43 @@ impl FileState {
         source: &Rope,
         _file: &Document,
         utf16_line: &HashSet<usize>,
+        errors: &[Span],
         token: &mut Vec<AbsToken>,
     ) {
         let _section = find_section(origin);
         let mut cursor = QueryCursor::new();
 
-        let captures = TS.queries.highlight.capture_names();
         for i in cursor.matches(
             &TS.queries.highlight,
             origin,
             crate::util::node_source(source),
         ) {
             for c in i.captures {
-                let kind = captures[c.index as usize].as_str();
+                //captures inside error recovery nodes are mostly garbage
+                let span = c.node.byte_range();
+                if errors
+                    .iter()
+                    .any(|e| e.start < span.end && span.start < e.end)
+                {
+                    continue;
+                }
+                //the capture is mapped to the legend once all tokens are collected
                 let range = fast_lsp_range(c.node, source, utf16_line);
                 token.push(AbsToken {
                     range,
-                    kind: token_index(kind),
+                    kind: c.index,
+                    modifiers: 0,
                 });
             }
         }
     }
-    fn new(origin: &Url, tree: Tree, source: &ropey::Rope, root: &Snapshot) -> Self {
+    fn new(
+        origin: &Url,
+        tree: Tree,
+        source: &ropey::Rope,
+        root: &Snapshot,
+        legend: &Legend,
+    ) -> Self {
         let mut token = vec![];
 
         let time = Instant::now();
@@ -174,18 +291,55 @@ impl FileState {
                 }
             }
         }
+        let mut errors = Vec::new();
+        ts_filterd_visit(tree.root_node(), |node| {
+            if node.is_error() {
+                errors.push(node.byte_range());
+                false
+            } else {
+                true
+            }
+        });
         let mut sections = tree.walk();
         let file = root.file_by_uri(origin).unwrap();
         //iterate captures and create colors token, we currently allow diffrent color for diffrent
         //sections (currently unsed)
         sections.goto_first_child();
         loop {
-            Self::color_section(sections.node(), root, source, file, &utf16_line, &mut token);
+            if !sections.node().is_error() {
+                Self::color_section(
+                    sections.node(),
+                    root,
+                    source,
+                    file,
+                    &utf16_line,
+                    &errors,
+                    &mut token,
+                );
+            }
             if !sections.goto_next_sibling() {
                 break;
             }
 
         }
+        let captures: Vec<_> = TS
+            .queries
+            .highlight
+            .capture_names()
+            .iter()
+            .map(|name| legend.kind(name))
+            .collect();
+        let mut token: Vec<AbsToken> = token
+            .into_iter()
+            .filter_map(|t| {
+                let (kind, modifiers) = captures[t.kind as usize]?;
+                Some(AbsToken {
+                    kind,
+                    modifiers,
+                    ..t
+                })
+            })
+            .collect();
         token.sort_by_key(|a| (a.range.start.line, a.range.start.character));
         token.dedup();
         let mut filtered = Vec::new();
@@ -218,7 +372,7 @@ impl FileState {
                         },
                         length: len,
                         token_type: i.kind,
-                        token_modifiers_bitset: 0,
+                        token_modifiers_bitset: i.modifiers,
                     })
                 } else {
                     filtered.push(SemanticToken {
@@ -226,7 +380,7 @@ impl FileState {
                         delta_start: next_col,
                         length: len,
                         token_type: i.kind,
-                        token_modifiers_bitset: 0,
+                        token_modifiers_bitset: i.modifiers,
                     })
                 }
             } else {
@@ -242,29 +396,29 @@ impl FileState {
                         } else {
                             next_col
                         },
-                        length: source.line(i.range.start.line as usize).len_utf16_cu() as u32
+                        length: line_len_utf16(source, i.range.start.line as usize) as u32
                             - next_col,
                         token_type: i.kind,
-                        token_modifiers_bitset: 0,
+                        token_modifiers_bitset: i.modifiers,
                     })
                 } else {
                     filtered.push(SemanticToken {
                         delta_line: next_line,
                         delta_start: next_col,
-                        length: source.line(i.range.start.line as usize).len_utf16_cu() as u32
+                        length: line_len_utf16(source, i.range.start.line as usize) as u32
                             - next_col,
                         token_type: i.kind,
-                        token_modifiers_bitset: 0,
+                        token_modifiers_bitset: i.modifiers,
                     })
                 }
-                if i.range.start.line - i.range.end.line > 2 {
+                if i.range.end.line - i.range.start.line > 1 {
                     for l in i.range.start.line + 1..i.range.end.line {
                         filtered.push(SemanticToken {
                             delta_line: 1,
                             delta_start: 0,
-                            length: source.line(l as usize).len_utf16_cu() as u32,
+                            length: line_len_utf16(source, l as usize) as u32,
                             token_type: i.kind,
-                            token_modifiers_bitset: 0,
+                            token_modifiers_bitset: i.modifiers,
                         })
                     }
                 }
@@ -273,7 +427,7 @@ impl FileState {
                     delta_start: 0,
                     length: i.range.end.character,
                     token_type: i.kind,
-                    token_modifiers_bitset: 0,
+                    token_modifiers_bitset: i.modifiers,
                 })
             }
             last = Some(i.clone());
@@ -286,13 +440,19 @@ impl FileState {
 }
 pub struct State {
     files: dashmap::DashMap<Url, FileState>,
+    legend: parking_lot::RwLock<Legend>,
 }
 impl State {
     pub fn new() -> Self {
         State {
             files: Default::default(),
+            legend: Default::default(),
         }
     }
+    //Negotiated on initialize, before any tokens are requested
+    pub fn set_legend(&self, legend: Legend) {
+        *self.legend.write() = legend;
+    }
     pub fn get(
         &self,
         root: Snapshot,
@@ -300,7 +460,7 @@ impl State {
         tree: Tree,
         source: ropey::Rope,
     ) -> SemanticTokens {
-        let state = FileState::new(&uri, tree, &source, &root);
+        let state = FileState::new(&uri, tree, &source, &root, &self.legend.read());
         let out = state.state.clone();
         self.files.insert(uri, state);
 
@@ -317,13 +477,13 @@ impl State {
         source: ropey::Rope,
     ) -> SemanticTokensFullDeltaResult {
         if let Some(old) = self.files.get(&uri) {
-            let state = FileState::new(&uri, tree, &source, &root);
+            let state = FileState::new(&uri, tree, &source, &root, &self.legend.read());
             let diff = old.diff(&state);
             self.files.insert(uri.clone(), state);
             diff
         } else {
             info!("Start color");
-            let state = FileState::new(&uri, tree, &source, &root);
+            let state = FileState::new(&uri, tree, &source, &root, &self.legend.read());
             let out = state.state.clone();
             self.files.insert(uri.clone(), state);
 
@@ -335,3 +495,37 @@ impl State {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn minimal_client_legend() {
+        let full = Legend::default();
+        assert_eq!(full.types.len(), TOKEN_KINDS.len());
+        assert_eq!(full.kind("string"), Some((8, 0)));
+        let client: SemanticTokensClientCapabilities = serde_json::from_value(serde_json::json!({
+            "requests": {"full": true},
+            "tokenTypes": ["comment", "variable", "keyword"],
+            "tokenModifiers": [],
+            "formats": ["relative"]
+        }))
+        .unwrap();
+        let minimal = Legend::new(Some(&client));
+        assert_eq!(
+            minimal.types,
+            [
+                SemanticTokenType::KEYWORD,
+                SemanticTokenType::VARIABLE,
+                SemanticTokenType::COMMENT
+            ]
+        );
+        assert_eq!(minimal.kind("keyword"), Some((0, 0)));
+        assert_eq!(minimal.kind("macro"), Some((0, 0)));
+        assert_eq!(minimal.kind("enumMember"), Some((1, 0)));
+        assert_eq!(minimal.kind("parameter"), Some((1, 0)));
+        assert_eq!(minimal.kind("comment"), Some((2, 0)));
+        assert_eq!(minimal.kind("number"), None);
+        assert_eq!(minimal.kind("operator"), None);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::ast::*;
 use crate::document::Draft;
+use crate::settings::Settings;
 use crate::util::*;
 use crate::{parse, semantic::*};
 use compact_str::CompactString;
@@ -144,29 +145,38 @@ pub enum Section {
     Unknown,
     Attribute,
 }
-pub fn find_section(node: Node) -> Section {
-    match node.kind() {
-        "blk" => match node.child_by_field_name("header").unwrap().kind() {
-            "constraints" => Section::Constraints,
-            "include" => Section::Include,
-            "imports" => Section::Imports,
-            "features" => Section::Features,
-            _ => find_section(node.parent().unwrap()),
-        },
-        "source_file" => Section::TopLevel,
-        "attribute_constraint" | "attribute_constraints" => Section::Constraints,
-        "binary_expr" | "unary_expr" | "nested_expr" => Section::Constraints,
-        "attribute_value" => Section::Attribute,
-        _ => {
-            if let Some(p) = node.parent() {
-                find_section(p)
-            } else {
-                Section::Unknown
-            }
+pub fn find_section(mut node: Node) -> Section {
+    //a loop since features can be nested thousands of levels deep
+    loop {
+        match node.kind() {
+            "blk" => match node.child_by_field_name("header").unwrap().kind() {
+                "constraints" => return Section::Constraints,
+                "include" => return Section::Include,
+                "imports" => return Section::Imports,
+                "features" => return Section::Features,
+                _ => {}
+            },
+            "source_file" => return Section::TopLevel,
+            "attribute_constraint" | "attribute_constraints" => return Section::Constraints,
+            "binary_expr" | "unary_expr" | "nested_expr" => return Section::Constraints,
+            "attribute_value" => return Section::Attribute,
+            _ => {}
         }
+        node = match node.parent() {
+            Some(p) => p,
+            None => return Section::Unknown,
+        };
     }
 }
 
+//Tree-sitter columns count bytes, cursor columns are converted to chars first
+fn char_point(source: &Rope, row: usize, col: usize) -> Point {
+    let byte = source.char_to_byte((source.line_to_char(row) + col).min(source.len_chars()));
+    Point {
+        row,
+        column: byte.saturating_sub(source.line_to_byte(row)),
+    }
+}
 fn node_at(node: Node, pos: Point) -> Node {
     let mut next = pos;
     next.column += 1;
@@ -176,76 +186,84 @@ fn node_at(node: Node, pos: Point) -> Node {
 pub fn containes(range: Range, pos: &Position) -> bool {
     range.start.character <= pos.character && range.end.character > pos.character
 }
-pub fn estimate_expr(node: Node, pos: &Position, source: &Rope) -> CompletionEnv {
-    if node.is_error() && node.start_position().row == node.end_position().row {
-        let err_raw: String = source.byte_slice(node.byte_range()).into();
-        if err_raw.contains("=>")
-            || err_raw.contains("<=>")
-            || err_raw.contains('&')
-            || err_raw.contains('|')
-        {
-            return CompletionEnv::Constraint;
-        }
-        if err_raw.contains('+')
-            || err_raw.contains('-')
-            || err_raw.contains('*')
-            || err_raw.contains('/')
-            || err_raw.contains('>')
-            || err_raw.contains('<')
-            || err_raw.contains("==")
-        {
-            return CompletionEnv::Numeric;
-        }
-    }
-    match node.kind() {
-        "number" => CompletionEnv::Numeric,
-        "aggregate" => {
-            let mut cursor = node.walk();
-            cursor.goto_first_child();
-            let mut arg_offset = -1;
-            let mut args = Vec::new();
-            loop {
-                if containes(
-                    lsp_range(cursor.node().byte_range(), source).unwrap(),
-                    &Position {
-                        character: pos.character - 1,
-                        line: pos.line,
-                    },
-                ) {
-                    arg_offset = args.len() as isize;
-                }
-                if cursor.field_name().map(|i| i == "arg").unwrap_or(false) {
-                    args.push(parse::parse_path(cursor.node(), source));
+pub fn estimate_expr(mut node: Node, pos: &Position, source: &Rope) -> CompletionEnv {
+    //Parenthesis and paths take the type of the expression containing them, they can be
+    //nested arbitrarily deep
+    loop {
+        if node.is_error() && node.start_position().row == node.end_position().row {
+            let err_raw: String = source.byte_slice(node.byte_range()).into();
+            if err_raw.contains("=>")
+                || err_raw.contains("<=>")
+                || err_raw.contains('&')
+                || err_raw.contains('|')
+            {
+                return CompletionEnv::Constraint;
+            }
+            if err_raw.contains('+')
+                || err_raw.contains('-')
+                || err_raw.contains('*')
+                || err_raw.contains('/')
+                || err_raw.contains('>')
+                || err_raw.contains('<')
+                || err_raw.contains("==")
+            {
+                return CompletionEnv::Numeric;
+            }
+        }
+        let env = match node.kind() {
+            "number" => CompletionEnv::Numeric,
+            "aggregate" => {
+                let mut cursor = node.walk();
+                cursor.goto_first_child();
+                let mut arg_offset = -1;
+                let mut args = Vec::new();
+                loop {
+                    if containes(
+                        lsp_range(cursor.node().byte_range(), source).unwrap(),
+                        &Position {
+                            character: pos.character - 1,
+                            line: pos.line,
+                        },
+                    ) {
+                        arg_offset = args.len() as isize;
+                    }
+                    if cursor.field_name().map(|i| i == "arg").unwrap_or(false) {
+                        args.push(parse::parse_path(cursor.node(), source));
+                    }
+                    info!("{:?}", cursor.node().kind());
+                    if !cursor.goto_next_sibling() {
+                        break;
+                    }
                 }
-                info!("{:?}", cursor.node().kind());
-                if !cursor.goto_next_sibling() {
-                    break;
+                info!("args {:?} offset {}", &args, arg_offset);
+                if arg_offset == 0 && args.len() > 1 {
+                    CompletionEnv::Constraint
+                } else if args.len() == 1 && arg_offset == 0 {
+                    CompletionEnv::Aggregate { context: None }
+                } else if arg_offset >= 1 {
+                    CompletionEnv::Aggregate {
+                        context: args[0].clone(),
+                    }
+                } else {
+                    CompletionEnv::Aggregate { context: None }
                 }
             }
-            info!("args {:?} offset {}", &args, arg_offset);
-            if arg_offset == 0 && args.len() > 1 {
-                CompletionEnv::Constraint
-            } else if args.len() == 1 && arg_offset == 0 {
-                CompletionEnv::Aggregate { context: None }
-            } else if arg_offset >= 1 {
-                CompletionEnv::Aggregate {
-                    context: args[0].clone(),
+            "binary_expr" => {
+                let op: String = source
+                    .byte_slice(node.child_by_field_name("op").unwrap().byte_range())
+                    .into();
+                match op.as_str() {
+                    "=>" | "&" | "|" | "<=>" => CompletionEnv::Constraint,
+                    _ => CompletionEnv::Numeric,
                 }
-            } else {
-                CompletionEnv::Aggregate { context: None }
             }
-        }
-        "binary_expr" => {
-            let op: String = source
-                .byte_slice(node.child_by_field_name("op").unwrap().byte_range())
-                .into();
-            match op.as_str() {
-                "=>" | "&" | "|" | "<=>" => CompletionEnv::Constraint,
-                _ => CompletionEnv::Numeric,
+            "nested_expr" | "path" => {
+                node = node.parent().unwrap();
+                continue;
             }
-        }
-        "nested_expr" | "path" => estimate_expr(node.parent().unwrap(), pos, source),
-        _ => CompletionEnv::Constraint,
+            _ => CompletionEnv::Constraint,
+        };
+        return env;
     }
 }
 
@@ -263,68 +281,46 @@ fn position_to_node<'a>(
     tree: &'a Tree,
     pos: &Position,
 ) -> (CompletionOffset, Node<'a>) {
-    let line = source.line(pos.line as usize);
-    let rel_char = line.utf16_cu_to_char(pos.character as usize);
+    let base_offset = source.line_to_char((pos.line as usize).min(source.len_lines() - 1));
+    let rel_char = char_offset(pos, source) - base_offset;
     if rel_char == 0 {
-        (CompletionOffset::SameLine, tree.root_node())
-    } else {
-        let base_offset = source.line_to_char(pos.line as usize);
-        if !source.char(base_offset + rel_char - 1).is_whitespace() {
-            (
-                CompletionOffset::Continous,
-                node_at(
-                    tree.root_node(),
-                    Point {
-                        row: pos.line as usize,
-                        column: rel_char - 1,
-                    },
-                ),
-            )
-        } else {
-            for i in (0..rel_char - 1).rev() {
-                if !source.char(base_offset + i).is_whitespace() {
-                    return (
-                        CompletionOffset::SameLine,
-                        node_at(
-                            tree.root_node(),
-                            Point {
-                                row: pos.line as usize,
-                                column: i,
-                            },
-                        ),
-                    );
-                }
-            }
-            for i in (0..pos.line as usize).rev() {
-                if !source
-                    .char(source.line_to_char(i) + rel_char - 1)
-                    .is_whitespace()
-                {
-                    return (
-                        CompletionOffset::Cut,
-                        node_at(
-                            tree.root_node(),
-                            Point {
-                                row: i,
-                                column: rel_char - 1,
-                            },
-                        ),
-                    );
-                }
-            }
-
-            (
+        return (CompletionOffset::SameLine, tree.root_node());
+    }
+    if !source.char(base_offset + rel_char - 1).is_whitespace() {
+        return (
+            CompletionOffset::Continous,
+            node_at(
+                tree.root_node(),
+                char_point(source, pos.line as usize, rel_char - 1),
+            ),
+        );
+    }
+    for i in (0..rel_char - 1).rev() {
+        if !source.char(base_offset + i).is_whitespace() {
+            return (
+                CompletionOffset::SameLine,
+                node_at(tree.root_node(), char_point(source, pos.line as usize, i)),
+            );
+        }
+    }
+    for i in (0..pos.line as usize).rev() {
+        if !source
+            .char(source.line_to_char(i) + rel_char - 1)
+            .is_whitespace()
+        {
+            return (
                 CompletionOffset::Cut,
-                node_at(
-                    tree.root_node(),
-                    Point {
-                        row: pos.line as usize,
-                        column: rel_char - 1,
-                    },
-                ),
-            )
+                node_at(tree.root_node(), char_point(source, i, rel_char - 1)),
+            );
         }
     }
+    (
+        CompletionOffset::Cut,
+        node_at(
+            tree.root_node(),
+            char_point(source, pos.line as usize, rel_char - 1),
+        ),
+    )
 }
 fn estimate_env(node: Node, source: &Rope, pos: &Position) -> Option<CompletionEnv> {
     if node.is_extra() && !node.is_error() {
@@ -381,6 +377,10 @@ struct CompletionQuery {
     postfix_range: Range,
     env: CompletionEnv,
     offset: CompletionOffset,
+    //Feature whose attribute block contains the cursor, paths are shortened relative to it
+    scope: Symbol,
+    //Client accepts snippets, otherwise their plain text is inserted
+    snippets: bool,
 }
 impl CompletionQuery {
     fn text_edit(&self, text: TextOP) -> TextEdit {
@@ -389,6 +389,39 @@ impl CompletionQuery {
                 new_text: text.into(),
                 range: self.postfix_range,
             },
+            TextOP::Snippet(snippet, plain) => TextEdit {
+                new_text: if self.snippets { snippet } else { plain }.into(),
+                range: self.postfix_range,
+            },
+            TextOP::Block(range, snippet, plain) => TextEdit {
+                new_text: if self.snippets { snippet } else { plain }.into(),
+                range,
+            },
+            TextOP::Import(text, _) => TextEdit {
+                new_text: text.into(),
+                range: self.postfix_range,
+            },
+        }
+    }
+    fn additional_edits(&self, text: &TextOP) -> Option<Vec<TextEdit>> {
+        match text {
+            TextOP::Import(_, import) => Some(vec![import.clone()]),
+            _ => None,
+        }
+    }
+    fn insert_format(&self, text: &TextOP) -> Option<InsertTextFormat> {
+        match text {
+            TextOP::Snippet(..) | TextOP::Block(..) if self.snippets => {
+                Some(InsertTextFormat::SNIPPET)
+            }
+            _ => Some(InsertTextFormat::PLAIN_TEXT),
+        }
+    }
+    //Blocks carry their own indentation, the client must not add the cursor line's
+    fn insert_mode(&self, text: &TextOP) -> Option<InsertTextMode> {
+        match text {
+            TextOP::Block(..) if self.snippets => Some(InsertTextMode::AS_IS),
+            _ => None,
         }
     }
 }
@@ -403,6 +436,36 @@ pub fn longest_path<'a>(node: Node<'a>, source: &Rope) -> Option<(Path, Node<'a>
         parse::parse_or_lang_lvl(node, source).map(|p| (p, node))
     }
 }
+//Name of the feature whose attribute block contains node
+fn enclosing_feature(mut node: Node, source: &Rope) -> Option<Ustr> {
+    while let Some(parent) = node.parent() {
+        if node.kind() == "attributes" && parent.kind() == "blk" {
+            let header = parent.child_by_field_name("header")?;
+            return match header.kind() {
+                "name" => parse::parse_name(header, source).map(|name| name.name),
+                _ => None,
+            };
+        }
+        node = parent;
+    }
+    None
+}
+fn estimate_scope(pos: &Position, draft: &Draft, file: &Document) -> Symbol {
+    match draft {
+        Draft::Tree { source, tree, .. } => {
+            let (_, edit_node) = position_to_node(source, tree, pos);
+            enclosing_feature(edit_node, source)
+                .and_then(|name| {
+                    file.lookup(Symbol::Root, &[name], |sym| {
+                        matches!(sym, Symbol::Feature(..))
+                    })
+                    .next()
+                })
+                .unwrap_or(Symbol::Root)
+        }
+        _ => Symbol::Root,
+    }
+}
 //"smart" completion, find context arround the cursor
 fn estimate_context(pos: &Position, draft: &Draft) -> Option<CompletionQuery> {
     match draft {
@@ -420,6 +483,8 @@ fn estimate_context(pos: &Position, draft: &Draft) -> Option<CompletionQuery> {
                         prefix: path.names,
                         env: estimate_env(path_node, source, pos)
                             .unwrap_or(CompletionEnv::SomeName),
+                        scope: Symbol::Root,
+                        snippets: false,
                     })
                 } else {
                     Some(CompletionQuery {
@@ -429,6 +494,8 @@ fn estimate_context(pos: &Position, draft: &Draft) -> Option<CompletionQuery> {
                         prefix: path.names[..path.names.len() - 1].to_vec(),
                         env: estimate_env(path_node, source, pos)
                             .unwrap_or(CompletionEnv::SomeName),
+                        scope: Symbol::Root,
+                        snippets: false,
                     })
                 }
             } else {
@@ -441,6 +508,8 @@ fn estimate_context(pos: &Position, draft: &Draft) -> Option<CompletionQuery> {
                         end: *pos,
                     },
                     env: estimate_env(edit_node, source, pos).unwrap_or(CompletionEnv::SomeName),
+                    scope: Symbol::Root,
+                    snippets: false,
                 })
             }
         }
@@ -476,6 +545,12 @@ impl From<Type> for CompletionKind {
 #[derive(PartialEq, Debug)]
 enum TextOP {
     Put(CompactString),
+    //Snippet and the plain text used for clients without snippet support
+    Snippet(CompactString, CompactString),
+    //Multi line snippet replacing range instead of the postfix, indented by us
+    Block(Range, CompactString, CompactString),
+    //Reference together with the import it needs
+    Import(CompactString, TextEdit),
 }
 //A completion option send to the editor
 #[derive(PartialEq, Debug)]
@@ -536,18 +611,270 @@ fn add_keywords<const I: usize>(
         });
     }
 }
-fn add_top_lvl_keywords(query: &str, top: &mut TopN<CompletionOpt>, w: f32) {
-    add_keywords(
+fn add_snippet(query: &str, top: &mut TopN<CompletionOpt>, w: f32, name: &str, op: TextOP) {
+    top.push(CompletionOpt {
+        op,
+        lable: name.into(),
+        rank: if query.is_empty() {
+            w
+        } else {
+            strsim::jaro_winkler(query, name) as f32 * w
+        },
+        name: name.into(),
+        kind: CompletionKind::Keyword,
+    });
+}
+fn escape_snippet(text: &str, choice: bool) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        if matches!(c, '\\' | '$' | '}') || (choice && matches!(c, ',' | '|')) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+//Edit adding path to the imports of file, a missing imports section is created in front of
+//the features
+fn import_edit(file: &Document, path: &str) -> Option<TextEdit> {
+    let source = &file.source;
+    let last = file
+        .all_imports()
+        .filter_map(|sym| file.span(sym))
+        .map(|span| span.end)
+        .max();
+    let root = file.tree().root_node();
+    let mut cursor = root.walk();
+    let sections: Vec<(&str, usize)> = root
+        .named_children(&mut cursor)
+        .filter(|blk| blk.kind() == "blk")
+        .filter_map(|blk| {
+            blk.child_by_field_name("header")
+                .map(|header| (header.kind(), blk.start_position().row))
+        })
+        .collect();
+    let (pos, text) = if let Some(end) = last {
+        let row = source.byte_to_line(end);
+        let text = format!("{}{}\n", line_indent(source, row), path);
+        (Position::new(row as u32 + 1, 0), text)
+    } else if let Some((_, row)) = sections.iter().find(|(kind, _)| *kind == "imports") {
+        (Position::new(*row as u32 + 1, 0), format!("    {}\n", path))
+    } else if let Some((_, row)) = sections
+        .iter()
+        .find(|(kind, _)| matches!(*kind, "features" | "constraints"))
+    {
+        let text = format!("imports\n    {}\n\n", path);
+        (Position::new(*row as u32, 0), text)
+    } else {
+        let newline = if source.len_chars() > 0 && source.char(source.len_chars() - 1) != '\n' {
+            "\n"
+        } else {
+            ""
+        };
+        let text = format!("{}imports\n    {}\n", newline, path);
+        (lsp_position(source.len_bytes(), source)?, text)
+    };
+    Some(TextEdit {
+        range: Range {
+            start: pos,
+            end: pos,
+        },
+        new_text: text,
+    })
+}
+//Features of files that are not imported yet, inserted as qualified reference together with
+//the import. A typed name which already resolves needs no import. Files are preferred when
+//other unresolved references of the origin would resolve in them.
+fn add_import_completions(
+    snapshot: &RootGraph,
+    origin: FileID,
+    query: &CompletionQuery,
+    top: &mut TopN<CompletionOpt>,
+) {
+    let typed = [Ustr::from(&query.postfix)];
+    if snapshot.resolve(origin, &typed).next().is_some() {
+        return;
+    }
+    let file = snapshot.file(origin);
+    let imported: HashSet<FileID> = snapshot.fs.imports(origin).map(|(_, f)| f).collect();
+    let unresolved: Vec<&[Ustr]> = file
+        .all_references()
+        .filter(|&sym| {
+            snapshot
+                .resolve_sym(RootSymbol { file: origin, sym })
+                .is_none()
+        })
+        .map(|sym| file.path(sym))
+        .filter(|path| *path != typed)
+        .collect();
+    for (path, _, node) in snapshot.fs.sub_files(origin, &[]) {
+        let target = match node {
+            FSNode::File(target) if target != origin && !imported.contains(&target) => target,
+            _ => continue,
+        };
+        let edit = match import_edit(file, &path) {
+            Some(edit) => edit,
+            None => continue,
+        };
+        let candidate = snapshot.file(target);
+        let used = unresolved
+            .iter()
+            .filter(|path| {
+                candidate
+                    .lookup(Symbol::Root, path, |_| true)
+                    .next()
+                    .is_some()
+            })
+            .count();
+        for sym in candidate.all_features() {
+            let name = match candidate.symbol_name(sym) {
+                Some(name) => name,
+                None => continue,
+            };
+            let text: CompactString = format!("{}.{}", path, name).into();
+            let op = TextOP::Import(text.clone(), edit.clone());
+            let mut opt = CompletionOpt::new(CompletionKind::Feature, name, text, 2, op, query);
+            opt.rank *= 1.0 + 0.1 * used.min(10) as f32;
+            top.push(opt);
+        }
+    }
+}
+//Top level sections in their legal order
+static SECTIONS: [&str; 5] = ["namespace", "include", "imports", "features", "constraints"];
+//Sections which can be added at row without duplicating an existing section or breaking
+//the order
+fn missing_sections(tree: &Tree, row: usize) -> Vec<&'static str> {
+    let root = tree.root_node();
+    let mut cursor = root.walk();
+    let mut existing = [false; SECTIONS.len()];
+    let mut before = None;
+    let mut after = None;
+    for blk in root.named_children(&mut cursor) {
+        let section = blk
+            .child_by_field_name("header")
+            .filter(|_| blk.kind() == "blk")
+            .and_then(|header| SECTIONS.iter().position(|s| *s == header.kind()));
+        if let Some(i) = section {
+            existing[i] = true;
+            if blk.start_position().row < row {
+                before = before.max(Some(i));
+            } else {
+                after = Some(after.map_or(i, |k: usize| k.min(i)));
+            }
+        }
+    }
+    let start = before.map_or(0, |i| i + 1);
+    let end = after.unwrap_or(SECTIONS.len());
+    (start..end)
+        .filter(|i| !existing[*i])
+        .map(|i| SECTIONS[i])
+        .collect()
+}
+//Path of the file relative to the common directory of all known files
+fn dotted_path(snapshot: &RootGraph, origin: FileID) -> String {
+    let path = &snapshot.file(origin).path;
+    let common = snapshot
+        .file_paths()
+        .iter()
+        .map(|p| {
+            p.iter()
+                .zip(path.iter())
+                .take_while(|(i, k)| i == k)
+                .count()
+                .min(p.len().saturating_sub(1))
+        })
+        .min()
+        .unwrap_or(0)
+        .min(path.len().saturating_sub(1));
+    path[common..].iter().map(|i| i.as_str()).join(".")
+}
+fn add_top_lvl_keywords(
+    snapshot: &RootGraph,
+    origin: FileID,
+    tree: &Tree,
+    query: &CompletionQuery,
+    top: &mut TopN<CompletionOpt>,
+    w: f32,
+) {
+    for (i, section) in missing_sections(tree, query.postfix_range.start.line as usize)
+        .into_iter()
+        .enumerate()
+    {
+        //prefer the sections in their legal order
+        let w = w - i as f32 * 0.01;
+        let op = if section == "namespace" {
+            let path = dotted_path(snapshot, origin);
+            TextOP::Snippet(
+                format!("namespace ${{1:{}}}", escape_snippet(&path, false)).into(),
+                format!("namespace {}", path).into(),
+            )
+        } else {
+            TextOP::Put(section.into())
+        };
+        add_snippet(&query.postfix, top, w, section, op);
+    }
+}
+//Skeleton for the first feature of a model, the nested lines continue the indentation of
+//the cursor line
+fn add_root_feature(
+    source: &Rope,
+    pos: &Position,
+    query: &str,
+    top: &mut TopN<CompletionOpt>,
+    w: f32,
+) -> Option<()> {
+    let (start, _) = typed_prefix(source, pos);
+    let indent = line_indent(source, pos.line as usize);
+    let unit = match indent.as_str() {
+        "" => "    ",
+        i if i.starts_with('\t') => "\t",
+        i => i,
+    };
+    let range = Range {
+        start: lsp_position(source.char_to_byte(start), source)?,
+        end: *pos,
+    };
+    add_snippet(
         query,
         top,
         w,
-        [
-            "namespace".into(),
-            "features".into(),
-            "imports".into(),
-            "constraints".into(),
-            "include".into(),
-        ],
+        "root feature",
+        TextOP::Block(
+            range,
+            format!(
+                "${{1:Root}}\n{}{}optional\n{}{}{}${{2:Feature}}",
+                indent, unit, indent, unit, unit
+            )
+            .into(),
+            "Root".into(),
+        ),
+    );
+    Some(())
+}
+//Constraint template referencing existing features
+fn add_constraint_template(file: &Document, query: &str, top: &mut TopN<CompletionOpt>, w: f32) {
+    let names: Vec<Ustr> = file
+        .all_features()
+        .filter_map(|sym| file.symbol_name(sym))
+        .unique()
+        .take(10)
+        .collect();
+    if names.is_empty() {
+        return;
+    }
+    let choice = names
+        .iter()
+        .map(|name| escape_snippet(name, true))
+        .join(",");
+    add_snippet(
+        query,
+        top,
+        w,
+        "constraint",
+        TextOP::Snippet(
+            format!("${{1|{}|}} => ${{2|{}|}}", choice, choice).into(),
+            format!("{} => {}", names[0], names.get(1).unwrap_or(&names[0])).into(),
+        ),
     );
 }
 
@@ -564,6 +891,83 @@ fn add_group_keywords(query: &str, top: &mut TopN<CompletionOpt>, w: f32) {
         ],
     );
 }
+//Leading whitespace of a line
+fn line_indent(source: &Rope, line: usize) -> String {
+    source
+        .line(line)
+        .chars()
+        .take_while(|c| *c == ' ' || *c == '\t')
+        .collect()
+}
+fn indent_width(indent: &str) -> usize {
+    indent.chars().map(|c| if c == '\t' { 4 } else { 1 }).sum()
+}
+//Char offset and text of the word in front of the cursor, brackets included
+fn typed_prefix(source: &Rope, pos: &Position) -> (usize, String) {
+    let line_start = source.line_to_char(pos.line as usize);
+    let end = char_offset(pos, source);
+    let mut start = end;
+    while start > line_start && !source.char(start - 1).is_whitespace() {
+        start -= 1;
+    }
+    (start, source.slice(start..end).into())
+}
+//Cardinality group with two children, the children are indented two levels below the
+//parent feature. Only the text typed in front of the cursor is replaced, so a partially
+//typed bracket is completed as well.
+fn add_cardinality_group(
+    source: &Rope,
+    pos: &Position,
+    top: &mut TopN<CompletionOpt>,
+    w: f32,
+) -> Option<()> {
+    let line = pos.line as usize;
+    let (start, typed) = typed_prefix(source, pos);
+    let indent = line_indent(source, line);
+    let parent = (0..line)
+        .rev()
+        .filter(|i| source.line(*i).chars().any(|c| !c.is_whitespace()))
+        .map(|i| line_indent(source, i))
+        .find(|i| indent_width(i) < indent_width(&indent));
+    let parent = parent.unwrap_or_default();
+    //prefer the unit between the parent and the group line, otherwise follow the parent
+    let unit = match indent.strip_prefix(parent.as_str()) {
+        Some(unit) if !unit.is_empty() => unit.to_string(),
+        _ if parent.contains('\t') => "\t".into(),
+        _ => "    ".into(),
+    };
+    let child = format!("{}{}{}", parent, unit, unit);
+    let range = Range {
+        start: lsp_position(source.char_to_byte(start), source)?,
+        end: *pos,
+    };
+    let label = "[n..m] group";
+    top.push(CompletionOpt {
+        op: TextOP::Block(
+            range,
+            format!(
+                "[${{1:1}}..${{2:*}}]\n{}${{3:Feature}}\n{}${{4:Feature}}",
+                child, child
+            )
+            .into(),
+            "[1..*]".into(),
+        ),
+        lable: label.into(),
+        rank: if typed.is_empty() || typed.starts_with('[') {
+            w
+        } else {
+            strsim::jaro_winkler(&typed, label) as f32 * w
+        },
+        //clients filter by the replaced text, a typed bracket has to match
+        name: if typed.starts_with('[') {
+            typed.as_str().into()
+        } else {
+            label.into()
+        },
+        kind: CompletionKind::Keyword,
+    });
+    Some(())
+}
 fn add_lang_lvl_major_keywords(query: &str, top: &mut TopN<CompletionOpt>, w: f32) {
     add_keywords(query, top, w, ["SMT-level".into(), "SAT-level".into()]);
 }
@@ -702,7 +1106,9 @@ fn completion_symbol_local(
         {
             return true;
         }
-        let text = make_path(prefix.iter().chain(sym_prefix.iter()));
+        let path: Vec<Ustr> = prefix.iter().chain(sym_prefix.iter()).cloned().collect();
+        let text = shorten_path(snapshot, origin, root.file, sym, &path, query)
+            .unwrap_or_else(|| make_path(path.iter()));
         top.push(CompletionOpt::new(
             ty.into(),
             *sym_prefix.last().unwrap(),
@@ -714,6 +1120,28 @@ fn completion_symbol_local(
         true
     });
 }
+//Shortest tail of a qualified feature or attribute path that still resolves to the same
+//symbol from the cursor's scope, eg. price instead of Car.price inside Car. Paths stay
+//qualified when the tail refers to something else, eg. parts.Engine next to a local Engine.
+//Completions after a typed prefix are relative to it and never shortened
+fn shorten_path(
+    snapshot: &RootGraph,
+    origin: FileID,
+    file: FileID,
+    sym: Symbol,
+    path: &[Ustr],
+    query: &CompletionQuery,
+) -> Option<CompactString> {
+    if !query.prefix.is_empty() {
+        return None;
+    }
+    let target = RootSymbol { file, sym };
+    (1..path.len())
+        .rev()
+        .map(|start| &path[start..])
+        .find(|short| snapshot.resolve_scoped(origin, query.scope, short).next() == Some(target))
+        .map(|short| make_path(short.iter()))
+}
 fn path_len(path: &[Ustr]) -> usize {
     path.iter().map(|i| i.len()).sum()
 }
@@ -835,15 +1263,61 @@ fn encode_float(f: f32) -> u32 {
     }
 }
 
+pub fn trigger_characters(settings: &Settings) -> Vec<String> {
+    let mut triggers: Vec<String> = vec![".".into(), "[".into()];
+    for i in settings.completion_triggers.iter() {
+        if i.chars().count() == 1 && !triggers.contains(i) {
+            triggers.push(i.clone());
+        }
+    }
+    triggers
+}
+//[ opens a cardinality, paths make no sense there
+fn cardinality_completions(draft: &Draft, pos: &Position) -> CompletionList {
+    let mut items = Vec::new();
+    if let Draft::Tree { source, tree, .. } = draft {
+        let (_, node) = position_to_node(source, tree, pos);
+        if find_section(node) == Section::Features {
+            let offset = byte_offset(pos, source);
+            let closed = offset < source.len_bytes() && source.byte(offset) == b']';
+            for (i, interval) in ["1..*", "0..1", "1..1", "0..*"].into_iter().enumerate() {
+                items.push(CompletionItem {
+                    label: interval.into(),
+                    insert_text: Some(if closed {
+                        interval.into()
+                    } else {
+                        format!("{}]", interval)
+                    }),
+                    sort_text: Some(format!("{}", i)),
+                    kind: Some(CompletionItemKind::VALUE),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    CompletionList {
+        items,
+        is_incomplete: false,
+    }
+}
 pub fn compute_completions(
     snapshot: Snapshot,
     draft: &Draft,
     pos: TextDocumentPositionParams,
+    trigger: Option<&str>,
+    snippets: bool,
 ) -> CompletionList {
     info!("Starting completion");
+    if trigger == Some("[") {
+        return cardinality_completions(draft, &pos.position);
+    }
     let timer = Instant::now();
     let origin = snapshot.file_id(&pos.text_document.uri).unwrap();
-    let ctx = estimate_context(&pos.position, draft);
+    let ctx = estimate_context(&pos.position, draft).map(|mut ctx| {
+        ctx.scope = estimate_scope(&pos.position, draft, snapshot.file(origin));
+        ctx.snippets = snippets;
+        ctx
+    });
     info!("Stat completion: {:#?}", ctx);
     if let Some(ctx) = ctx {
         let mut top: TopN<CompletionOpt> = TopN::new(MAX_N);
@@ -851,10 +1325,43 @@ pub fn compute_completions(
         match &ctx.env {
             CompletionEnv::GroupMode => {
                 add_group_keywords(&ctx.postfix, &mut top, 2.0);
+                if let Draft::Tree { source, .. } = draft {
+                    add_cardinality_group(source, &pos.position, &mut top, 1.9);
+                }
+            }
+            CompletionEnv::Toplevel => {
+                if let Draft::Tree { tree, .. } = draft {
+                    add_top_lvl_keywords(&snapshot, origin, tree, &ctx, &mut top, 2.0);
+                }
             }
-            CompletionEnv::Toplevel => add_top_lvl_keywords(&ctx.postfix, &mut top, 2.0),
             CompletionEnv::SomeName => {}
             CompletionEnv::Constraint | CompletionEnv::Numeric | CompletionEnv::Feature => {
+                let file = snapshot.file(origin);
+                if let Draft::Tree { source, .. } = draft {
+                    //a partially typed group cardinality does not parse as a group
+                    if ctx.env == CompletionEnv::Feature
+                        && typed_prefix(source, &pos.position).1.starts_with('[')
+                    {
+                        add_cardinality_group(source, &pos.position, &mut top, 3.0);
+                    }
+                }
+                match (&ctx.env, &ctx.offset) {
+                    (_, CompletionOffset::SameLine) => {}
+                    (CompletionEnv::Feature, _) if file.all_features().next().is_none() => {
+                        if let Draft::Tree { source, .. } = draft {
+                            add_root_feature(source, &pos.position, &ctx.postfix, &mut top, 3.0);
+                        }
+                    }
+                    (CompletionEnv::Constraint, _)
+                        if ctx.scope == Symbol::Root
+                            && !file
+                                .direct_children(Symbol::Root)
+                                .any(|sym| matches!(sym, Symbol::Constraint(..))) =>
+                    {
+                        add_constraint_template(file, &ctx.postfix, &mut top, 3.0);
+                    }
+                    _ => {}
+                }
                 match (&ctx.env, &ctx.offset) {
                     //heuristic to provide nearly correct predictions, to
                     //make it more accurate we need to respect
@@ -881,6 +1388,12 @@ pub fn compute_completions(
                         completion_symbol(&snapshot, origin, &ctx, &mut top);
                     }
                 }
+                if ctx.prefix.is_empty()
+                    && !ctx.postfix.is_empty()
+                    && matches!(ctx.env, CompletionEnv::Constraint | CompletionEnv::Feature)
+                {
+                    add_import_completions(&snapshot, origin, &ctx, &mut top);
+                }
                 is_incomplete = true
             }
             CompletionEnv::Import => {
@@ -959,6 +1472,9 @@ pub fn compute_completions(
             .filter(|opt| opt.kind != CompletionKind::DontCare)
             .map(|opt| CompletionItem {
                 label: opt.lable.into(),
+                insert_text_format: ctx.insert_format(&opt.op),
+                insert_text_mode: ctx.insert_mode(&opt.op),
+                additional_text_edits: ctx.additional_edits(&opt.op),
                 text_edit: Some(CompletionTextEdit::Edit(ctx.text_edit(opt.op))),
                 sort_text: Some(format!("{:X}", encode_float(-opt.rank))),
                 filter_text: Some(opt.name.as_str().into()),
@@ -993,6 +1509,286 @@ pub fn compute_completions(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::document::build_document;
+    use std::sync::Arc;
+    //Labels offered after the first occurence of needle
+    fn labels_after(text: &str, needle: &str) -> Vec<String> {
+        complete(text, needle, None)
+    }
+    fn complete(text: &str, needle: &str, trigger: Option<&str>) -> Vec<String> {
+        complete_items(text, needle, trigger)
+            .into_iter()
+            .map(|i| i.label)
+            .collect()
+    }
+    fn complete_items(text: &str, needle: &str, trigger: Option<&str>) -> Vec<CompletionItem> {
+        complete_in(text, &[], needle, trigger, true)
+    }
+    //Completions in /scope.uvl next to other files given by name and content
+    fn complete_in(
+        text: &str,
+        others: &[(&str, &str)],
+        needle: &str,
+        trigger: Option<&str>,
+        snippets: bool,
+    ) -> Vec<CompletionItem> {
+        let source = Rope::from_str(text);
+        let uri = Url::parse("file:///scope.uvl").unwrap();
+        let tree = parse::parse(&source, None);
+        let doc = build_document(
+            source.clone(),
+            tree.clone(),
+            uri.clone(),
+            Instant::now(),
+            Default::default(),
+        );
+        let mut files = hashbrown::HashMap::new();
+        files.insert(uri.clone(), Arc::new(doc));
+        for (name, text) in others {
+            let uri = Url::parse(&format!("file:///{}", name)).unwrap();
+            let source = Rope::from_str(text);
+            let tree = parse::parse(&source, None);
+            let doc = build_document(
+                source,
+                tree,
+                uri.clone(),
+                Instant::now(),
+                Default::default(),
+            );
+            files.insert(uri, Arc::new(doc));
+        }
+        let root = tokio::sync::RwLock::new(RootGraph::new(&files, 0));
+        let (line, column) = text
+            .lines()
+            .enumerate()
+            .find_map(|(i, l)| l.find(needle).map(|c| (i, &l[..c + needle.len()])))
+            .map(|(i, l)| (i, l.encode_utf16().count()))
+            .unwrap();
+        let draft = Draft::Tree {
+            source,
+            tree,
+            revision: Instant::now(),
+        };
+        compute_completions(
+            root.try_read().unwrap(),
+            &draft,
+            TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri },
+                position: Position::new(line as u32, column as u32),
+            },
+            trigger,
+            snippets,
+        )
+        .items
+    }
+    fn group_edit(text: &str, needle: &str) -> TextEdit {
+        let item = complete_items(text, needle, None)
+            .into_iter()
+            .find(|i| i.label == "[n..m] group")
+            .unwrap();
+        assert_eq!(item.insert_text, None);
+        assert_eq!(item.insert_text_mode, Some(InsertTextMode::AS_IS));
+        match item.text_edit {
+            Some(CompletionTextEdit::Edit(edit)) => edit,
+            _ => panic!("expected a text edit"),
+        }
+    }
+    #[test]
+    fn import_feature() {
+        let others = [
+            ("parts.uvl", "features\n    Parts\n        alternative\n            Engine\n            Battery\n"),
+            ("spare.uvl", "features\n    Spare\n        optional\n            Engine\n"),
+        ];
+        let text = "features\n    Car\n        optional\n            Engi\nconstraints\n    Car => Battery\n";
+        let items: Vec<CompletionItem> = complete_in(text, &others, "Engi", None, true)
+            .into_iter()
+            .filter(|i| i.additional_text_edits.is_some())
+            .collect();
+        //parts is preferred since Battery already refers to it
+        let labels: Vec<&str> = items.iter().map(|i| i.label.as_str()).collect();
+        let parts = labels.iter().position(|l| *l == "parts.Engine").unwrap();
+        let spare = labels.iter().position(|l| *l == "spare.Engine").unwrap();
+        assert!(parts < spare);
+        let item = &items[parts];
+        assert_eq!(
+            item.additional_text_edits.as_deref(),
+            Some(
+                &[TextEdit {
+                    range: Range::new(Position::new(0, 0), Position::new(0, 0)),
+                    new_text: "imports\n    parts\n\n".into(),
+                }][..]
+            )
+        );
+        match &item.text_edit {
+            Some(CompletionTextEdit::Edit(edit)) => {
+                assert_eq!(edit.new_text, "parts.Engine");
+                assert_eq!(
+                    edit.range,
+                    Range::new(Position::new(3, 12), Position::new(3, 16))
+                );
+            }
+            _ => panic!("expected a text edit"),
+        }
+        //imported files are completed through their import
+        let text = "imports\n    parts\nfeatures\n    Car\n        optional\n            Engi\n";
+        let items = complete_in(text, &others, "Engi", None, true);
+        assert!(!items
+            .iter()
+            .any(|i| i.label == "parts.Engine" && i.additional_text_edits.is_some()));
+        let spare = items.iter().find(|i| i.label == "spare.Engine").unwrap();
+        assert_eq!(
+            spare.additional_text_edits.as_ref().unwrap()[0].range.start,
+            Position::new(2, 0)
+        );
+    }
+    #[test]
+    fn plain_text_client() {
+        let text = "features\n    Car\n        opt\n";
+        let items = complete_in(text, &[], "opt", None, false);
+        let group = items.iter().find(|i| i.label == "[n..m] group").unwrap();
+        for i in items.iter() {
+            assert_eq!(i.insert_text_format, Some(InsertTextFormat::PLAIN_TEXT));
+            assert_eq!(i.insert_text_mode, None);
+        }
+        match &group.text_edit {
+            Some(CompletionTextEdit::Edit(edit)) => assert_eq!(edit.new_text, "[1..*]"),
+            _ => panic!("expected a text edit"),
+        }
+    }
+    #[test]
+    fn root_feature() {
+        let edit = |text: &str, needle: &str| {
+            let item = complete_items(text, needle, None)
+                .into_iter()
+                .find(|i| i.label == "root feature")
+                .unwrap();
+            assert_eq!(item.insert_text_mode, Some(InsertTextMode::AS_IS));
+            match item.text_edit {
+                Some(CompletionTextEdit::Edit(edit)) => edit,
+                _ => panic!("expected a text edit"),
+            }
+        };
+        let root = edit("features\n    \n", "    ");
+        assert_eq!(
+            root.range,
+            Range::new(Position::new(1, 4), Position::new(1, 4))
+        );
+        assert_eq!(
+            root.new_text,
+            "${1:Root}\n        optional\n            ${2:Feature}"
+        );
+        let root = edit("features\n\t\n", "\t");
+        assert_eq!(root.new_text, "${1:Root}\n\t\toptional\n\t\t\t${2:Feature}");
+    }
+    #[test]
+    fn cardinality_group() {
+        let edit = group_edit("features\n    Car\n        opt\n", "opt");
+        assert_eq!(
+            edit.range,
+            Range::new(Position::new(2, 8), Position::new(2, 11))
+        );
+        assert_eq!(
+            edit.new_text,
+            "[${1:1}..${2:*}]\n            ${3:Feature}\n            ${4:Feature}"
+        );
+        let edit = group_edit("features\n\tCar\n\t\topt\n", "opt");
+        assert_eq!(
+            edit.range,
+            Range::new(Position::new(2, 2), Position::new(2, 5))
+        );
+        assert_eq!(
+            edit.new_text,
+            "[${1:1}..${2:*}]\n\t\t\t${3:Feature}\n\t\t\t${4:Feature}"
+        );
+        //only the partially typed bracket is replaced
+        let edit = group_edit("features\n  Car\n    optional\n      A\n    [1..\n", "[1..");
+        assert_eq!(
+            edit.range,
+            Range::new(Position::new(4, 4), Position::new(4, 8))
+        );
+        assert!(edit.new_text.ends_with("\n      ${4:Feature}"));
+    }
+    #[test]
+    fn scoped_paths() {
+        let labels = labels_after(
+            "features\n    Car {weight 100, price 10}\n        optional\n            Engine {price 5, constraint p > 3}\n",
+            "constraint p",
+        );
+        //unambiguous, resolved through the enclosing feature and its ancestors
+        assert!(labels.iter().any(|l| l == "price"));
+        assert!(labels.iter().any(|l| l == "weight"));
+        assert!(!labels.iter().any(|l| l == "Engine.price"));
+        //price alone refers to Engine.price here
+        assert!(labels.iter().any(|l| l == "Car.price"));
+    }
+    #[test]
+    fn nested_scoped_paths() {
+        let labels = labels_after(
+            "features\n    Car {dims {width 2}}\n        optional\n            Engine {constraint w > 3}\n",
+            "constraint w",
+        );
+        assert!(labels.iter().any(|l| l == "dims.width"));
+        assert!(!labels.iter().any(|l| l == "Car.dims.width"));
+    }
+    #[test]
+    fn feature_paths() {
+        let others = [(
+            "parts.uvl",
+            "features\n    Parts\n        optional\n            Engine\n            Battery\n",
+        )];
+        let text = "imports\n    parts\nfeatures\n    Car {constraint Eng}\n        optional\n            Engine\n";
+        let labels: Vec<String> = complete_in(text, &others, "constraint Eng", None, true)
+            .into_iter()
+            .map(|i| i.label)
+            .collect();
+        //Engine alone is the local feature, the imported one has to stay qualified
+        assert!(labels.iter().any(|l| l == "Engine"));
+        assert!(labels.iter().any(|l| l == "parts.Engine"));
+        assert!(labels.iter().any(|l| l == "parts.Battery"));
+        assert!(!labels.iter().any(|l| l == "Battery"));
+    }
+    #[test]
+    fn unscoped_paths() {
+        let labels = labels_after(
+            "features\n    Car {weight 100, price 10}\nconstraints\n    p > 3\n",
+            "    p",
+        );
+        assert!(labels.iter().any(|l| l == "Car.price"));
+        assert!(!labels.iter().any(|l| l == "price"));
+    }
+    #[test]
+    fn bracket_trigger() {
+        let labels = complete(
+            "features\n    Car cardinality [\n",
+            "cardinality [",
+            Some("["),
+        );
+        assert!(labels.iter().any(|l| l == "1..*"));
+        assert!(!labels.iter().any(|l| l == "Car"));
+        let labels = complete(
+            "features\n    Car\nconstraints\n    Car & [\n",
+            "& [",
+            Some("["),
+        );
+        assert!(labels.is_empty());
+        //configured triggers complete paths as usual
+        let labels = complete(
+            "features\n    Car\nconstraints\n    C\n",
+            "    C",
+            Some("C"),
+        );
+        assert!(labels.iter().any(|l| l == "Car"));
+    }
+    #[test]
+    fn section_order() {
+        let source = Rope::from_str("namespace Test\n\nconstraints\n    A\n");
+        let tree = parse::parse(&source, None);
+        assert_eq!(
+            missing_sections(&tree, 1),
+            vec!["include", "imports", "features"]
+        );
+        assert!(missing_sections(&tree, 4).is_empty());
+    }
     #[test]
     fn test_top_n() {
         let mut top = TopN::new(10);
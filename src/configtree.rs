@@ -0,0 +1,353 @@
+use crate::ast::*;
+use crate::semantic::{FileID, RootGraph, RootSymbol, Snapshot};
+use crate::smt::{ComposedCache, WarmSolver, SOLVER_POOL};
+use hashbrown::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+use tower_lsp::lsp_types::*;
+/*
+ * Feature tree of a model with the state of every feature in a partial configuration, for
+ * clients rendering a configurator. The configuration is propagated with the same solver as
+ * configuration sessions, a feature is selected or deselected when the configuration decides
+ * it, forced when the model and the configuration leave only one value and undecided
+ * otherwise. Imported models are expanded below the reference importing them. A configuration
+ * contradicting the model is reported as a conflict, the states are then the ones of the
+ * empty configuration.
+ */
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TreeParams {
+    pub text_document: TextDocumentIdentifier,
+    #[serde(default)]
+    pub configuration: Vec<Decision>,
+}
+#[derive(Deserialize, Debug)]
+pub struct Decision {
+    //document declaring the feature, the requested document if omitted
+    pub uri: Option<Url>,
+    pub feature: String,
+    pub selected: bool,
+}
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum DecisionState {
+    Selected,
+    Deselected,
+    Undecided,
+    Forced,
+}
+#[derive(Serialize, Debug)]
+pub struct TreeFeature {
+    pub feature: String,
+    pub location: Location,
+    pub state: DecisionState,
+    //value of a decided or forced feature
+    pub value: Option<bool>,
+    pub groups: Vec<TreeGroup>,
+    #[serde(skip)]
+    sym: RootSymbol,
+}
+#[derive(Serialize, Debug)]
+pub struct TreeGroup {
+    //or, alternative, optional, mandatory or cardinality
+    pub kind: String,
+    pub range: Range,
+    pub features: Vec<TreeFeature>,
+}
+#[derive(Serialize, Debug)]
+pub struct ConfigurationTree {
+    pub conflict: bool,
+    pub features: Vec<TreeFeature>,
+}
+
+fn group_kind(mode: GroupMode) -> &'static str {
+    match mode {
+        GroupMode::Or => "or",
+        GroupMode::Alternative => "alternative",
+        GroupMode::Optional => "optional",
+        GroupMode::Mandatory => "mandatory",
+        GroupMode::Cardinality(..) => "cardinality",
+    }
+}
+//Undecided tree below sym, references are followed once so import cycles end
+fn feature(root: &RootGraph, sym: RootSymbol, seen: &mut HashSet<RootSymbol>) -> TreeFeature {
+    let file = root.file(sym.file);
+    let groups = file
+        .direct_children(sym.sym)
+        .filter_map(|g| {
+            let kind = group_kind(file.group_mode(g)?);
+            let features = file
+                .direct_children(g)
+                .filter_map(|c| {
+                    let dst = match c {
+                        Symbol::Feature(..) => RootSymbol {
+                            file: sym.file,
+                            sym: c,
+                        },
+                        Symbol::Reference(..) => root
+                            .resolve_sym(RootSymbol {
+                                file: sym.file,
+                                sym: c,
+                            })
+                            .filter(|dst| matches!(dst.sym, Symbol::Feature(..)))?,
+                        _ => return None,
+                    };
+                    if !seen.insert(dst) {
+                        return None;
+                    }
+                    Some(stacker::maybe_grow(32 * 1024, 1024 * 1024, || {
+                        feature(root, dst, seen)
+                    }))
+                })
+                .collect();
+            Some(TreeGroup {
+                kind: kind.into(),
+                range: file.lsp_range(g).unwrap_or_default(),
+                features,
+            })
+        })
+        .collect();
+    TreeFeature {
+        feature: file.symbol_name(sym.sym).unwrap_or_default().to_string(),
+        location: Location {
+            uri: file.uri.clone(),
+            range: file.lsp_range(sym.sym).unwrap_or_default(),
+        },
+        state: DecisionState::Undecided,
+        value: None,
+        groups,
+        sym,
+    }
+}
+pub fn tree(root: &RootGraph, file_id: FileID) -> Vec<TreeFeature> {
+    let mut seen = HashSet::new();
+    let mut out = Vec::new();
+    for sym in root.file(file_id).root_features() {
+        let sym = RootSymbol { file: file_id, sym };
+        if seen.insert(sym) {
+            out.push(feature(root, sym, &mut seen));
+        }
+    }
+    out
+}
+//Features named by the configuration
+fn decisions(
+    root: &RootGraph,
+    uri: &Url,
+    configuration: &[Decision],
+) -> Result<Vec<(RootSymbol, bool)>, String> {
+    configuration
+        .iter()
+        .map(|d| {
+            let uri = d.uri.as_ref().unwrap_or(uri);
+            let file_id = root
+                .file_id(uri)
+                .ok_or_else(|| format!("unknown document {}", uri))?;
+            let file = root.file(file_id);
+            file.all_features()
+                .find(|f| {
+                    file.symbol_name(*f)
+                        .is_some_and(|n| n.as_str() == d.feature)
+                })
+                .map(|sym| (RootSymbol { file: file_id, sym }, d.selected))
+                .ok_or_else(|| format!("unknown feature {}", d.feature))
+        })
+        .collect()
+}
+fn assign(
+    features: &mut [TreeFeature],
+    decided: &HashMap<RootSymbol, bool>,
+    forced: &HashMap<RootSymbol, bool>,
+) {
+    for f in features.iter_mut() {
+        (f.state, f.value) = match (decided.get(&f.sym), forced.get(&f.sym)) {
+            (Some(true), _) => (DecisionState::Selected, Some(true)),
+            (Some(false), _) => (DecisionState::Deselected, Some(false)),
+            (None, Some(value)) => (DecisionState::Forced, Some(*value)),
+            (None, None) => (DecisionState::Undecided, None),
+        };
+        for g in f.groups.iter_mut() {
+            assign(&mut g.features, decided, forced);
+        }
+    }
+}
+//The snapshot is released before waiting for a solver slot
+pub async fn configuration_tree(
+    root: Snapshot<'_>,
+    params: &TreeParams,
+    composed: &ComposedCache,
+) -> Result<ConfigurationTree, String> {
+    let uri = &params.text_document.uri;
+    let file_id = root.file_id(uri).ok_or("unknown document")?;
+    let comp = root
+        .components()
+        .iter()
+        .find(|comp| comp.members.contains(&file_id))
+        .ok_or("the model is not analyzed yet")?;
+    let decisions = decisions(&root, uri, &params.configuration)?;
+    let mut features = tree(&root, file_id);
+    let input = WarmSolver::input(&root, comp, composed)
+        .await
+        .map_err(|e| e.to_string())?;
+    let revision = root.revision;
+    drop(root);
+    let cancel = CancellationToken::new();
+    let _permit = SOLVER_POOL
+        .acquire(uri.to_string(), revision, true, &cancel)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut solver = WarmSolver::start(input, &cancel)
+        .await
+        .map_err(|e| e.to_string())?;
+    let propagated = solver
+        .propagate(&decisions, &cancel)
+        .await
+        .map_err(|e| e.to_string())?;
+    let (conflict, decided, forced) = match propagated {
+        Some(forced) => (false, decisions.into_iter().collect(), forced),
+        None => {
+            let forced = solver
+                .propagate(&[], &cancel)
+                .await
+                .map_err(|e| e.to_string())?
+                .ok_or("the model has no valid configuration")?;
+            (true, HashMap::new(), forced)
+        }
+    };
+    assign(&mut features, &decided, &forced);
+    Ok(ConfigurationTree { conflict, features })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::build_document;
+    use crate::parse::parse;
+    use crate::semantic::link_documents;
+    use ropey::Rope;
+    use std::sync::Arc;
+    use tokio::time::Instant;
+    fn models() -> HashMap<Url, Arc<Document>> {
+        [
+            ("file:///car.uvl", "imports\n    engine\nfeatures\n    Car\n        mandatory\n            engine.Engine\n        optional\n            Radio\n"),
+            ("file:///engine.uvl", "features\n    Engine\n        alternative\n            Petrol\n            Electric\n"),
+        ]
+        .into_iter()
+        .map(|(uri, text)| {
+            let uri = Url::parse(uri).unwrap();
+            let source = Rope::from_str(text);
+            let doc = build_document(source.clone(), parse(&source, None), uri.clone(), Instant::now(), Default::default());
+            (uri, Arc::new(doc))
+        })
+        .collect()
+    }
+    #[test]
+    fn imported_subtrees() {
+        let car = Url::parse("file:///car.uvl").unwrap();
+        let engine = Url::parse("file:///engine.uvl").unwrap();
+        let root = RootGraph::new(&models(), 0);
+        let mut features = tree(&root, root.file_id(&car).unwrap());
+        let decided = decisions(
+            &root,
+            &car,
+            &[Decision {
+                uri: Some(engine.clone()),
+                feature: "Electric".into(),
+                selected: true,
+            }],
+        )
+        .unwrap();
+        let petrol = decisions(
+            &root,
+            &engine,
+            &[Decision {
+                uri: None,
+                feature: "Petrol".into(),
+                selected: false,
+            }],
+        )
+        .unwrap();
+        let forced = [(features[0].sym, true), (petrol[0].0, false)]
+            .into_iter()
+            .collect();
+        assign(&mut features, &decided.into_iter().collect(), &forced);
+        let car_node = &features[0];
+        assert_eq!(car_node.state, DecisionState::Forced);
+        let engine_node = &car_node.groups[0].features[0];
+        assert_eq!(engine_node.location.uri, engine);
+        assert_eq!(engine_node.state, DecisionState::Undecided);
+        let states: Vec<_> = engine_node.groups[0]
+            .features
+            .iter()
+            .map(|f| (f.feature.as_str(), f.state, f.value))
+            .collect();
+        assert_eq!(
+            states,
+            [
+                ("Petrol", DecisionState::Forced, Some(false)),
+                ("Electric", DecisionState::Selected, Some(true))
+            ]
+        );
+        assert_eq!(car_node.groups[1].kind, "optional");
+    }
+    #[tokio::test]
+    #[ignore = "needs z3"]
+    async fn propagation() {
+        let (root, _) = link_documents(&models());
+        let root = tokio::sync::RwLock::new(root);
+        let composed = ComposedCache::default();
+        let car = Url::parse("file:///car.uvl").unwrap();
+        let engine = Url::parse("file:///engine.uvl").unwrap();
+        let request = |configuration: Vec<(&str, bool)>| TreeParams {
+            text_document: TextDocumentIdentifier { uri: car.clone() },
+            configuration: configuration
+                .into_iter()
+                .map(|(feature, selected)| Decision {
+                    uri: Some(engine.clone()),
+                    feature: feature.into(),
+                    selected,
+                })
+                .collect(),
+        };
+        let states = |tree: &ConfigurationTree| {
+            let engine = &tree.features[0].groups[0].features[0];
+            engine.groups[0]
+                .features
+                .iter()
+                .map(|f| (f.feature.clone(), f.state, f.value))
+                .collect::<Vec<_>>()
+        };
+        let tree = configuration_tree(
+            root.read().await,
+            &request(vec![("Electric", true)]),
+            &composed,
+        )
+        .await
+        .unwrap();
+        assert!(!tree.conflict);
+        assert_eq!(tree.features[0].state, DecisionState::Forced);
+        assert_eq!(
+            states(&tree),
+            [
+                ("Petrol".into(), DecisionState::Forced, Some(false)),
+                ("Electric".into(), DecisionState::Selected, Some(true))
+            ]
+        );
+        //both alternatives contradict the model, the states are the ones of no decisions
+        let tree = configuration_tree(
+            root.read().await,
+            &request(vec![("Electric", true), ("Petrol", true)]),
+            &composed,
+        )
+        .await
+        .unwrap();
+        assert!(tree.conflict);
+        assert_eq!(
+            states(&tree),
+            [
+                ("Petrol".into(), DecisionState::Undecided, None),
+                ("Electric".into(), DecisionState::Undecided, None)
+            ]
+        );
+    }
+}
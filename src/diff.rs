@@ -0,0 +1,603 @@
+use crate::ast::*;
+use hashbrown::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use tower_lsp::lsp_types::*;
+use ustr::Ustr;
+/*
+ * Structural diff of two versions of a model for reviewing changes, the old version is
+ * either another file or text supplied by the extension, eg. from git. Features are matched
+ * by name since names are unique within a model, constraints by a normalized text where
+ * operands of commutative operators are sorted. A removed and an added feature with the same
+ * attributes and children, or the same parent if they have neither, are reported as a rename.
+ * Locations point into the new version, changes only visible in the old one have none.
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DiffArgs {
+    pub uri: Url,
+    #[serde(default)]
+    pub old_uri: Option<Url>,
+    #[serde(default)]
+    pub old_text: Option<String>,
+    #[serde(default)]
+    pub markdown: bool,
+}
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Renamed,
+    Moved,
+    GroupChanged,
+    CardinalityChanged,
+    AttributeAdded,
+    AttributeRemoved,
+    AttributeChanged,
+    ConstraintAdded,
+    ConstraintRemoved,
+}
+#[derive(Serialize, Debug, Clone)]
+pub struct Change {
+    pub kind: ChangeKind,
+    //Feature, attribute path or normalized constraint
+    pub name: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+    pub location: Option<Location>,
+}
+#[derive(Serialize, Debug)]
+pub struct ModelDiff {
+    pub uri: Url,
+    pub changes: Vec<Change>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub markdown: Option<String>,
+}
+#[derive(Debug, PartialEq)]
+struct FeatureInfo {
+    sym: Symbol,
+    parent: Option<Ustr>,
+    group: Option<String>,
+    cardinality: Option<String>,
+    attributes: Vec<(String, String)>,
+    children: Vec<Ustr>,
+}
+fn cardinality_text(card: &Cardinality) -> String {
+    match card {
+        Cardinality::From(min) => format!("[{}..*]", min),
+        Cardinality::Range(min, max) => format!("[{}..{}]", min, max),
+        Cardinality::Max(max) => format!("[{}]", max),
+        Cardinality::Any => "[*]".into(),
+    }
+}
+fn group_text(mode: &GroupMode) -> String {
+    match mode {
+        GroupMode::Or => "or".into(),
+        GroupMode::Alternative => "alternative".into(),
+        GroupMode::Optional => "optional".into(),
+        GroupMode::Mandatory => "mandatory".into(),
+        GroupMode::Cardinality(card) => cardinality_text(card),
+    }
+}
+//Attribute path below its feature, eg. price.currency
+fn attribute_path(file: &Document, sym: Symbol) -> String {
+    let mut path = Vec::new();
+    let mut cur = Some(sym);
+    while let Some(s @ Symbol::Attribute(..)) = cur {
+        path.extend(file.symbol_name(s).map(|name| name.to_string()));
+        cur = file.parent(s, false);
+    }
+    path.reverse();
+    path.join(".")
+}
+fn features(file: &Document) -> (Vec<Ustr>, HashMap<Ustr, FeatureInfo>) {
+    let mut order = Vec::new();
+    let mut info = HashMap::new();
+    for sym in file.all_features() {
+        let name = match file.symbol_name(sym) {
+            Some(name) => name,
+            None => continue,
+        };
+        if info.contains_key(&name) {
+            continue;
+        }
+        let mut children: Vec<Ustr> = file
+            .direct_children(sym)
+            .filter(|g| matches!(g, Symbol::Group(..)))
+            .flat_map(|g| file.direct_children(g))
+            .filter_map(|c| file.symbol_name(c))
+            .collect();
+        children.sort();
+        order.push(name);
+        info.insert(
+            name,
+            FeatureInfo {
+                sym,
+                parent: file.ancestors(sym).nth(1).and_then(|p| file.symbol_name(p)),
+                group: file
+                    .parent(sym, false)
+                    .and_then(|g| file.group_mode(g))
+                    .map(|mode| group_text(&mode)),
+                cardinality: file.cardinality(sym).map(cardinality_text),
+                attributes: Vec::new(),
+                children,
+            },
+        );
+    }
+    for sym in file.all_attributes() {
+        //nested attributes are compared by their leaves
+        if matches!(file.value(sym), Some(Value::Attributes) | None) {
+            continue;
+        }
+        let owner = match file.ancestors(sym).next() {
+            Some(owner) => owner,
+            None => continue,
+        };
+        let feature = match file.symbol_name(owner).and_then(|name| info.get_mut(&name)) {
+            Some(feature) if feature.sym == owner => feature,
+            _ => continue,
+        };
+        let value: String = file
+            .value_span(sym)
+            .map(|span| file.source.byte_slice(span).into())
+            .unwrap_or_default();
+        let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+        feature.attributes.push((attribute_path(file, sym), value));
+    }
+    for feature in info.values_mut() {
+        feature.attributes.sort();
+    }
+    (order, info)
+}
+fn reference_text(file: &Document, sym: Symbol) -> String {
+    match sym {
+        Symbol::Reference(..) => file
+            .path(sym)
+            .iter()
+            .map(|name| name.as_str())
+            .collect::<Vec<_>>()
+            .join("."),
+        _ => file
+            .symbol_name(sym)
+            .map(|name| name.to_string())
+            .unwrap_or_default(),
+    }
+}
+fn binary(lhs: String, op: &str, rhs: String, commutative: bool) -> String {
+    if commutative && rhs < lhs {
+        format!("({} {} {})", rhs, op, lhs)
+    } else {
+        format!("({} {} {})", lhs, op, rhs)
+    }
+}
+fn aggregate_text(
+    file: &Document,
+    op: &AggregateOP,
+    context: &Option<Symbol>,
+    query: &Path,
+) -> String {
+    let op = match op {
+        AggregateOP::Sum => "sum",
+        AggregateOP::Avg => "avg",
+    };
+    let query: Vec<&str> = query.names.iter().map(|name| name.as_str()).collect();
+    match context {
+        Some(context) => {
+            format!(
+                "{}({}, {})",
+                op,
+                reference_text(file, *context),
+                query.join(".")
+            )
+        }
+        None => format!("{}({})", op, query.join(".")),
+    }
+}
+enum TextTask<'a> {
+    Constraint(&'a Constraint),
+    Numeric(&'a Numeric),
+    Not,
+    Binary(&'static str, bool),
+}
+//Fully parenthesized text of a constraint, a > b is written as b < a.
+//Constraints nest arbitrarily deep, so operands are written with an explicit stack
+fn constraint_text(file: &Document, constraint: &Constraint) -> String {
+    let mut tasks = vec![TextTask::Constraint(constraint)];
+    let mut values: Vec<String> = Vec::new();
+    while let Some(task) = tasks.pop() {
+        match task {
+            TextTask::Constraint(Constraint::Constant(value)) => values.push(value.to_string()),
+            TextTask::Constraint(Constraint::Ref(sym)) => values.push(reference_text(file, *sym)),
+            TextTask::Constraint(Constraint::Not(inner)) => {
+                tasks.extend([TextTask::Not, TextTask::Constraint(inner)]);
+            }
+            TextTask::Constraint(Constraint::Logic { op, lhs, rhs }) => {
+                let (op, commutative) = match op {
+                    LogicOP::And => ("&", true),
+                    LogicOP::Or => ("|", true),
+                    LogicOP::Implies => ("=>", false),
+                    LogicOP::Equiv => ("<=>", true),
+                };
+                tasks.extend([
+                    TextTask::Binary(op, commutative),
+                    TextTask::Constraint(rhs),
+                    TextTask::Constraint(lhs),
+                ]);
+            }
+            TextTask::Constraint(Constraint::Equation { op, lhs, rhs }) => {
+                let (op, commutative, lhs, rhs) = match op {
+                    EquationOP::Equal => ("==", true, lhs, rhs),
+                    EquationOP::Smaller => ("<", false, lhs, rhs),
+                    EquationOP::Greater => ("<", false, rhs, lhs),
+                };
+                tasks.extend([
+                    TextTask::Binary(op, commutative),
+                    TextTask::Numeric(rhs),
+                    TextTask::Numeric(lhs),
+                ]);
+            }
+            TextTask::Numeric(Numeric::Binary { op, lhs, rhs }) => {
+                let (op, commutative) = match op {
+                    NumericOP::Add => ("+", true),
+                    NumericOP::Mul => ("*", true),
+                    NumericOP::Sub => ("-", false),
+                    NumericOP::Div => ("/", false),
+                };
+                tasks.extend([
+                    TextTask::Binary(op, commutative),
+                    TextTask::Numeric(rhs),
+                    TextTask::Numeric(lhs),
+                ]);
+            }
+            TextTask::Numeric(Numeric::Number(n)) => values.push(n.to_string()),
+            TextTask::Numeric(Numeric::Ref(sym)) => values.push(reference_text(file, *sym)),
+            TextTask::Numeric(Numeric::Aggregate { op, context, query }) => {
+                values.push(aggregate_text(file, op, context, query));
+            }
+            TextTask::Not => {
+                let inner = values.pop().unwrap();
+                values.push(format!("!{}", inner));
+            }
+            TextTask::Binary(op, commutative) => {
+                let rhs = values.pop().unwrap();
+                let lhs = values.pop().unwrap();
+                values.push(binary(lhs, op, rhs, commutative));
+            }
+        }
+    }
+    values.pop().unwrap_or_default()
+}
+//Constraints too large to analyze are not in the tree, they are compared by their source
+//text with whitespace collapsed
+fn constraints(file: &Document) -> Vec<(String, Option<Range>)> {
+    let analyzed = file.all_constraints().filter_map(|sym| {
+        let text = constraint_text(file, file.constraint(sym)?);
+        Some((text, file.lsp_range(sym)))
+    });
+    let oversized = file.oversized_constraints().iter().map(|span| {
+        let text: String = file.source.byte_slice(span.clone()).into();
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        (text, crate::util::lsp_range(span.clone(), &file.source))
+    });
+    analyzed.chain(oversized).collect()
+}
+struct Builder<'a> {
+    new: &'a Document,
+    changes: Vec<Change>,
+}
+impl<'a> Builder<'a> {
+    fn push(
+        &mut self,
+        kind: ChangeKind,
+        name: String,
+        old: Option<String>,
+        new: Option<String>,
+        sym: Option<Symbol>,
+    ) {
+        let range = sym.and_then(|sym| self.new.lsp_range(sym));
+        self.push_range(kind, name, old, new, range);
+    }
+    fn push_range(
+        &mut self,
+        kind: ChangeKind,
+        name: String,
+        old: Option<String>,
+        new: Option<String>,
+        range: Option<Range>,
+    ) {
+        let location = range.map(|range| Location {
+            uri: self.new.uri.clone(),
+            range,
+        });
+        self.changes.push(Change {
+            kind,
+            name,
+            old,
+            new,
+            location,
+        });
+    }
+    fn compare(&mut self, name: Ustr, old: &FeatureInfo, new: &FeatureInfo, renamed: &[Ustr]) {
+        let sym = Some(new.sym);
+        let old_parent = old
+            .parent
+            .map(|p| renamed_to(renamed, p).unwrap_or(p).to_string());
+        let new_parent = new.parent.map(|p| p.to_string());
+        if old_parent != new_parent {
+            let name = name.to_string();
+            self.push(ChangeKind::Moved, name, old_parent, new_parent, sym);
+        }
+        if old.group != new.group {
+            let (before, after) = (old.group.clone(), new.group.clone());
+            self.push(
+                ChangeKind::GroupChanged,
+                name.to_string(),
+                before,
+                after,
+                sym,
+            );
+        }
+        if old.cardinality != new.cardinality {
+            let (before, after) = (old.cardinality.clone(), new.cardinality.clone());
+            self.push(
+                ChangeKind::CardinalityChanged,
+                name.to_string(),
+                before,
+                after,
+                sym,
+            );
+        }
+        let old_attributes: HashMap<&String, &String> =
+            old.attributes.iter().map(|(k, v)| (k, v)).collect();
+        let new_attributes: HashMap<&String, &String> =
+            new.attributes.iter().map(|(k, v)| (k, v)).collect();
+        for (path, value) in new.attributes.iter() {
+            let qualified = format!("{}.{}", name, path);
+            match old_attributes.get(path) {
+                None => {
+                    let value = Some(value.clone());
+                    self.push(ChangeKind::AttributeAdded, qualified, None, value, sym);
+                }
+                Some(old) if *old != value => {
+                    let (before, after) = (Some(old.to_string()), Some(value.clone()));
+                    self.push(ChangeKind::AttributeChanged, qualified, before, after, sym);
+                }
+                _ => {}
+            }
+        }
+        for (path, value) in old.attributes.iter() {
+            if !new_attributes.contains_key(path) {
+                let qualified = format!("{}.{}", name, path);
+                let value = Some(value.clone());
+                self.push(ChangeKind::AttributeRemoved, qualified, value, None, sym);
+            }
+        }
+    }
+}
+//renamed holds pairs of old and new names
+fn renamed_to(renamed: &[Ustr], old: Ustr) -> Option<Ustr> {
+    renamed
+        .chunks(2)
+        .find(|pair| pair[0] == old)
+        .map(|pair| pair[1])
+}
+fn same_shape(old: &FeatureInfo, new: &FeatureInfo) -> bool {
+    old.attributes == new.attributes
+        && old.children == new.children
+        && (!old.attributes.is_empty() || !old.children.is_empty() || old.parent == new.parent)
+}
+pub fn diff(old: &Document, new: &Document, markdown: bool) -> ModelDiff {
+    let (old_order, old_features) = features(old);
+    let (new_order, new_features) = features(new);
+    let added: Vec<Ustr> = new_order
+        .iter()
+        .filter(|name| !old_features.contains_key(*name))
+        .cloned()
+        .collect();
+    let mut renamed = Vec::new();
+    let mut matched = HashSet::new();
+    for name in old_order
+        .iter()
+        .filter(|name| !new_features.contains_key(*name))
+    {
+        let candidates: Vec<Ustr> = added
+            .iter()
+            .filter(|a| !matched.contains(*a))
+            .filter(|a| same_shape(&old_features[name], &new_features[*a]))
+            .cloned()
+            .collect();
+        if let [target] = candidates[..] {
+            matched.insert(target);
+            renamed.extend([*name, target]);
+        }
+    }
+    let mut builder = Builder {
+        new,
+        changes: Vec::new(),
+    };
+    for name in new_order.iter() {
+        let info = &new_features[name];
+        let previous = renamed
+            .chunks(2)
+            .find(|pair| pair[1] == *name)
+            .map(|pair| pair[0]);
+        match (old_features.get(name), previous) {
+            (Some(old), _) => builder.compare(*name, old, info, &renamed),
+            (None, Some(previous)) => {
+                let (before, after) = (Some(previous.to_string()), Some(name.to_string()));
+                let sym = Some(info.sym);
+                builder.push(ChangeKind::Renamed, name.to_string(), before, after, sym);
+                builder.compare(*name, &old_features[&previous], info, &renamed);
+            }
+            (None, None) => {
+                let parent = info.parent.map(|p| p.to_string());
+                let sym = Some(info.sym);
+                builder.push(ChangeKind::Added, name.to_string(), None, parent, sym);
+            }
+        }
+    }
+    for name in old_order.iter() {
+        if !new_features.contains_key(name) && renamed_to(&renamed, *name).is_none() {
+            let parent = old_features[name].parent.map(|p| p.to_string());
+            builder.push(ChangeKind::Removed, name.to_string(), parent, None, None);
+        }
+    }
+    //constraints are compared as multisets
+    let mut remaining: HashMap<String, usize> = HashMap::new();
+    for (text, _) in constraints(old) {
+        *remaining.entry(text).or_default() += 1;
+    }
+    for (text, range) in constraints(new) {
+        match remaining.get_mut(&text) {
+            Some(n) if *n > 0 => *n -= 1,
+            _ => builder.push_range(ChangeKind::ConstraintAdded, text, None, None, range),
+        }
+    }
+    for (text, _) in constraints(old) {
+        if let Some(n) = remaining.get_mut(&text).filter(|n| **n > 0) {
+            *n -= 1;
+            builder.push(ChangeKind::ConstraintRemoved, text, None, None, None);
+        }
+    }
+    let changes = builder.changes;
+    ModelDiff {
+        uri: new.uri.clone(),
+        markdown: markdown.then(|| render(&changes)),
+        changes,
+    }
+}
+fn render(changes: &[Change]) -> String {
+    if changes.is_empty() {
+        return "No structural changes".into();
+    }
+    let value = |v: &Option<String>| v.clone().unwrap_or_else(|| "none".into());
+    let mut out = String::from("### Model changes\n\n");
+    for c in changes {
+        let line = match c.kind {
+            ChangeKind::Added => format!("added feature `{}`", c.name),
+            ChangeKind::Removed => format!("removed feature `{}`", c.name),
+            ChangeKind::Renamed => format!("renamed `{}` to `{}`", value(&c.old), c.name),
+            ChangeKind::Moved => format!(
+                "moved `{}` from `{}` to `{}`",
+                c.name,
+                value(&c.old),
+                value(&c.new)
+            ),
+            ChangeKind::GroupChanged => {
+                format!("`{}` group {} -> {}", c.name, value(&c.old), value(&c.new))
+            }
+            ChangeKind::CardinalityChanged => format!(
+                "`{}` cardinality {} -> {}",
+                c.name,
+                value(&c.old),
+                value(&c.new)
+            ),
+            ChangeKind::AttributeAdded => {
+                format!("added attribute `{}` = `{}`", c.name, value(&c.new))
+            }
+            ChangeKind::AttributeRemoved => format!("removed attribute `{}`", c.name),
+            ChangeKind::AttributeChanged => {
+                format!("`{}` `{}` -> `{}`", c.name, value(&c.old), value(&c.new))
+            }
+            ChangeKind::ConstraintAdded => format!("added constraint `{}`", c.name),
+            ChangeKind::ConstraintRemoved => format!("removed constraint `{}`", c.name),
+        };
+        out.push_str(&format!("- {}\n", line));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::build_document;
+    use crate::parse::parse;
+    use ropey::Rope;
+    use tokio::time::Instant;
+    fn model(text: &str) -> Document {
+        let source = Rope::from_str(text);
+        let tree = parse(&source, None);
+        let uri = Url::parse("file:///diff.uvl").unwrap();
+        build_document(source, tree, uri, Instant::now(), Default::default())
+    }
+    #[test]
+    fn structural_changes() {
+        let old = model("features\n    Car\n        mandatory\n            Engine {power 100}\n        optional\n            Radio\n            Gps\nconstraints\n    Radio => Engine\n    Gps | Radio\n");
+        let new = model("features\n    Car\n        mandatory\n            Motor {power 120}\n        alternative\n            Radio\n            Navigation\nconstraints\n    Radio | Navigation\n    Radio => Motor\n");
+        let result = diff(&old, &new, true);
+        let changes: Vec<(ChangeKind, &str)> = result
+            .changes
+            .iter()
+            .map(|c| (c.kind, c.name.as_str()))
+            .collect();
+        //Motor differs in its attribute value from Engine, so it is no rename
+        assert!(changes.contains(&(ChangeKind::Added, "Motor")));
+        assert!(changes.contains(&(ChangeKind::Removed, "Engine")));
+        assert!(changes.contains(&(ChangeKind::Renamed, "Navigation")));
+        assert!(changes.contains(&(ChangeKind::GroupChanged, "Radio")));
+        //Radio | Gps became Radio | Navigation, Radio => Engine became Radio => Motor
+        assert!(changes.contains(&(ChangeKind::ConstraintAdded, "(Navigation | Radio)")));
+        assert!(changes.contains(&(ChangeKind::ConstraintRemoved, "(Radio => Engine)")));
+        let renamed = &result.changes[changes
+            .iter()
+            .position(|c| c.0 == ChangeKind::Renamed)
+            .unwrap()];
+        assert_eq!(renamed.old.as_deref(), Some("Gps"));
+        assert_eq!(
+            renamed.location.as_ref().unwrap().range.start,
+            Position::new(6, 12)
+        );
+        assert!(result
+            .markdown
+            .unwrap()
+            .contains("- renamed `Gps` to `Navigation`"));
+        //formatting and operand order are no changes
+        let reordered = model("features\n    Car\n        mandatory\n            Motor {power   120}\n        alternative\n            Navigation\n            Radio\nconstraints\n    Radio => Motor\n    Navigation | Radio\n");
+        assert!(diff(&new, &reordered, false).changes.is_empty());
+    }
+    #[test]
+    fn large_constraints() {
+        let mut header = String::from("features\n    Car\n        optional\n");
+        for i in 0..1000 {
+            header.push_str(&format!("            F{}\n", i));
+        }
+        let nested = |lhs: &str, rhs: &str| {
+            let operands: String = (0..800).map(|i| format!("(F{} & ", i)).collect();
+            format!("    {}{} > {}{}\n", operands, lhs, rhs, ")".repeat(800))
+        };
+        let oversized = |sep: &str| {
+            let operands: Vec<String> = (0..20000).map(|i| format!("F{}", i % 1000)).collect();
+            format!("    {}\n", operands.join(sep))
+        };
+        let old = format!(
+            "{}constraints\n{}{}",
+            header,
+            nested("F0", "F1"),
+            oversized(" | ")
+        );
+        let old = model(&old);
+        assert_eq!(old.oversized_constraints().len(), 1);
+        //a > b is written as b < a and whitespace is collapsed in skipped constraints
+        let same = format!(
+            "{}constraints\n{}{}",
+            header,
+            nested("F1", "F0").replace('>', "<"),
+            oversized("  |  ")
+        );
+        assert!(diff(&old, &model(&same), false).changes.is_empty());
+        let changed = format!(
+            "{}constraints\n{}{}",
+            header,
+            nested("F0", "F1"),
+            oversized(" & ")
+        );
+        let changes = diff(&old, &model(&changed), false).changes;
+        let kinds: Vec<ChangeKind> = changes.iter().map(|c| c.kind).collect();
+        assert_eq!(
+            kinds,
+            [ChangeKind::ConstraintAdded, ChangeKind::ConstraintRemoved]
+        );
+        let location = changes[0].location.as_ref().unwrap();
+        assert_eq!(location.range.start, Position::new(1005, 4));
+    }
+}
@@ -1,7 +1,9 @@
+use crate::util::char_offset;
 use crate::{ast, check};
 use crate::{parse, semantic};
 use hashbrown::HashMap;
 use log::info;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::SystemTime;
 use tokio::sync::watch;
@@ -21,28 +23,12 @@ pub fn update_text(
     for e in changes.content_changes.iter() {
         if let Some(range) = e.range {
             info!("apply change");
-            let start_line = range.start.line as usize;
-            let end_line = range.end.line as usize;
-
-            let start_col = range.start.character as usize;
-            let end_col = range.end.character as usize;
-
-            let start_col8 = source.line(start_line).utf16_cu_to_char(start_col);
-            let end_col8 = if end_line < source.len_lines() {
-                source.line(end_line).utf16_cu_to_char(end_col)
-            } else {
-                0
-            };
-
-            let start_char = source.line_to_char(start_line) + start_col8;
-            let end_char = if end_line < source.len_lines() {
-                source.line_to_char(end_line) + end_col8
-            } else {
-                source.len_chars()
-            };
-
+            let start_char = char_offset(&range.start, source);
+            let end_char = char_offset(&range.end, source);
             let start_byte = source.char_to_byte(start_char);
             let end_byte = source.char_to_byte(end_char);
+            let start_line = source.byte_to_line(start_byte);
+            let end_line = source.byte_to_line(end_byte);
             let start_col_byte = start_byte - source.line_to_byte(start_line);
             let end_col_byte = end_byte - source.line_to_byte(end_line);
             source.remove(start_char..end_char);
@@ -143,11 +129,38 @@ impl DocumentState {
 pub struct AsyncDraft {
     content: watch::Receiver<Draft>,
     pub state: DocumentState,
+    //set for drafts restored from the model cache, their tree is parsed on first use
+    lazy: Option<Arc<LazyTree>>,
+}
+struct LazyTree {
+    tx: watch::Sender<Draft>,
+    started: AtomicBool,
+}
+impl LazyTree {
+    fn start(self: &Arc<Self>) {
+        if self.started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let lazy = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let (source, revision) = match &*lazy.tx.borrow() {
+                Draft::Source { source, revision } => (source.clone(), *revision),
+                _ => return,
+            };
+            let tree = parse::parse(&source, None);
+            let _ = lazy.tx.send(Draft::Tree {
+                source,
+                tree,
+                revision,
+            });
+        });
+    }
 }
 
 impl AsyncDraft {
     //Wait until a state is reached, or timeout
     pub async fn sync(&mut self, sync: DraftSync, deadline: tokio::time::Instant) -> Option<Draft> {
+        self.need(sync);
         select! {
             () = tokio::time::sleep_until(deadline) =>
                 Some(self.content.borrow().clone()),
@@ -156,8 +169,17 @@ impl AsyncDraft {
     }
 
     pub async fn wait(&mut self, sync: DraftSync) -> Option<Draft> {
+        self.need(sync);
         Self::wait_for(&mut self.content, sync).await
     }
+    pub fn revision(&self) -> Instant {
+        self.content.borrow().revision()
+    }
+    fn need(&self, sync: DraftSync) {
+        if let (Some(lazy), true) = (self.lazy.as_ref(), sync >= DraftSync::Tree) {
+            lazy.start();
+        }
+    }
     pub async fn wait_for(draft: &mut watch::Receiver<Draft>, sync: DraftSync) -> Option<Draft> {
         loop {
             if draft.borrow_and_update().sync() >= sync {
@@ -187,7 +209,8 @@ impl AsyncDraft {
             revision,
             source: source.clone(),
         });
-        let doc = parse_document( revision, uri, tx, source, None);
+        let limits = semantic.settings.read().limits();
+        let doc = parse_document(revision, uri, tx, source, None, limits);
         drop(permit);
         semantic
             .documents
@@ -206,13 +229,66 @@ impl AsyncDraft {
         let (tx, rx) = watch::channel(Draft::Unavailable { revision });
         spawn(Self::open_raw(tx, revision, text, state, uri, semantic));
 
-        Self { state, content: rx }
+        Self {
+            state,
+            content: rx,
+            lazy: None,
+        }
+    }
+    //Parse on the calling thread, the caller hands the document to the semantic layer
+    pub fn open_blocking(
+        text: String,
+        state: DocumentState,
+        uri: Url,
+        limits: ast::Limits,
+    ) -> (Self, ast::Document) {
+        let revision = Instant::now();
+        let (tx, rx) = watch::channel(Draft::Unavailable { revision });
+        let doc = parse_document(revision, uri, tx, Rope::from_str(&text), None, limits);
+        (
+            Self {
+                state,
+                content: rx,
+                lazy: None,
+            },
+            doc,
+        )
+    }
+    //Draft and document of an unchanged file from the model cache, nothing is parsed
+    pub fn open_stored(
+        text: String,
+        state: DocumentState,
+        uri: Url,
+        stored: ast::StoredDocument,
+    ) -> (Self, ast::Document) {
+        let revision = Instant::now();
+        let source = Rope::from_str(&text);
+        let (tx, rx) = watch::channel(Draft::Source {
+            revision,
+            source: source.clone(),
+        });
+        let doc = stored.restore(source, uri, revision);
+        let lazy = LazyTree {
+            tx,
+            started: AtomicBool::new(false),
+        };
+        (
+            Self {
+                state,
+                content: rx,
+                lazy: Some(Arc::new(lazy)),
+            },
+            doc,
+        )
     }
     pub fn update(
         &mut self,
         params: DidChangeTextDocumentParams,
         semantic: Arc<semantic::Context>,
     ) {
+        //the edit is applied to the tree of the old draft
+        self.need(DraftSync::Tree);
+        self.lazy = None;
         let revision = Instant::now();
         let (tx, rx) = watch::channel(Draft::Unavailable { revision });
         let mut old_rx = std::mem::replace(&mut self.content, rx);
@@ -233,12 +309,14 @@ impl AsyncDraft {
                 revision,
                 source: source.clone(),
             });
+            let limits = semantic.settings.read().limits();
             let doc = parse_document(
                 revision,
                 uri,
                 tx,
                 source,
                 if parse_whole { None } else { Some(old_tree) },
+                limits,
             );
             drop(permit);
             semantic
@@ -269,6 +347,11 @@ impl DocumentStore {
         self.ast.insert(doc.uri.clone(), Arc::new(doc));
         self.revision += 1;
     }
+    pub fn update_all(&mut self, docs: Vec<ast::Document>) {
+        for doc in docs {
+            self.update(doc);
+        }
+    }
     pub fn delete(&mut self, name: &Url, timestamp: Instant) {
         if self
             .file_revision
@@ -291,6 +374,7 @@ fn parse_document(
     draft: watch::Sender<Draft>,
     source: Rope,
     old_tree: Option<Tree>,
+    limits: ast::Limits,
 ) -> ast::Document {
     let tree = parse::parse(&source, old_tree.as_ref());
     let _ = draft.send(Draft::Tree {
@@ -298,8 +382,33 @@ fn parse_document(
         tree: tree.clone(),
         source: source.clone(),
     });
-    let mut doc = ast::visit_root(source.clone(), tree.clone(), uri, revision);
+    build_document(source, tree, uri, revision, limits)
+}
+//Create the ast and run all syntax level checks
+pub fn build_document(
+    source: Rope,
+    tree: Tree,
+    uri: Url,
+    revision: Instant,
+    limits: ast::Limits,
+) -> ast::Document {
+    let mut doc = ast::visit_root(source.clone(), tree.clone(), uri, revision, limits);
     doc.errors.append(&mut check::check_sanity(&tree, &source));
-    doc.errors.append(&mut check::check_errors(&tree, &source));
+    doc.errors
+        .append(&mut check::check_errors(&tree, &source, limits));
+    doc.errors
+        .append(&mut check::check_indentation(&tree, &source));
+    let mut shadowed = check::check_shadowing(&doc);
+    doc.errors.append(&mut shadowed);
+    let mut forward = check::check_forward_references(&doc);
+    doc.errors.append(&mut forward);
+    let mut groups = check::check_groups(&doc);
+    doc.errors.append(&mut groups);
+    let mut mandatory = check::check_exclusive_mandatory(&doc);
+    doc.errors.append(&mut mandatory);
+    let mut overfull = check::check_overfull_groups(&doc);
+    doc.errors.append(&mut overfull);
+    let mut roots = check::check_root_feature(&doc);
+    doc.errors.append(&mut roots);
     doc
 }
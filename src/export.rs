@@ -0,0 +1,839 @@
+use crate::ast::*;
+use crate::check::{visible, ErrorInfo};
+use crate::semantic::*;
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use tower_lsp::lsp_types::{Range, TextDocumentIdentifier, Url};
+/*
+ * Serialization of the syntax tree for external tools, used by uvls parse <file> and the
+ * uvls/syntaxModel request. The request takes { "textDocument": { "uri": string },
+ * "resolved": bool } and answers with the same document as the command line. The format is
+ * part of the public interface and versioned by schemaVersion, fields are only ever added
+ * within a version.
+ *
+ * {
+ *   "schemaVersion": 1,
+ *   "uri": string,
+ *   "namespace": [string] | null,
+ *   "includes": ["SAT-level" | "SMT-level.feature-cardinality" | ...],
+ *   "imports": [{ "path": [string], "alias": string | null, "range": Range,
+ *                 "resolved": string | null }],
+ *   "features": [Feature],
+ *   "constraints": [{ "range": Range, "expr": Expr }],
+ *   "diagnostics": [{ "range": Range, "severity": "error" | "warning" | "info" | "hint",
+ *                     "message": string, "code": string | null }]
+ * }
+ * Feature = { "name": string, "range": Range, "qualifiedName": string | null,
+ *             "cardinality": Cardinality | null, "attributes": [Attribute],
+ *             "constraints": [{ "range": Range, "expr": Expr }], "groups": [Group] }
+ * Group = { "kind": "or" | "alternative" | "optional" | "mandatory" | "cardinality",
+ *           "cardinality": Cardinality | null, "range": Range, "children": [Child] }
+ * Child = { "node": "feature", ...Feature }
+ *       | { "node": "reference", "path": [string], "range": Range, "resolved": Resolved | null }
+ * Cardinality = { "min": number | null, "max": number | null }, [*] has neither and [n] only max
+ * Attribute = { "name": string, "range": Range, "value": Value, "attributes": [Attribute] }
+ * Value = { "type": "void" | "attributes" } | { "type": "number", "value": number }
+ *       | { "type": "string" | "vector", "value": string } | { "type": "bool", "value": bool }
+ * Expr = { "kind": "constant", "value": bool } | { "kind": "number", "value": number }
+ *      | { "kind": "ref", "path": [string], "range": Range, "resolved": Resolved | null }
+ *      | { "kind": "not", "operand": Expr } | { "kind": "logic" | "equation" | "binary",
+ *        "op": "&" | "|" | "=>" | "<=>" | "<" | ">" | "==" | "+" | "-" | "*" | "/",
+ *        "lhs": Expr, "rhs": Expr }
+ *      | { "kind": "aggregate", "op": "sum" | "avg", "context": Expr | null, "query": [string] }
+ * Resolved = { "uri": string, "qualifiedName": string }
+ *
+ * Strings are stored without their quotes, vectors as their source text since their content
+ * is not analyzed. Names keep quotes because "A" and A are different names. Parts of the file
+ * that failed to parse are missing and explained by the diagnostics. Resolved names are only
+ * filled in on request, a qualified name is the module path of the declaring file followed
+ * by the feature and attribute names.
+ */
+pub const SCHEMA_VERSION: u32 = 1;
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SyntaxModelParams {
+    pub text_document: TextDocumentIdentifier,
+    #[serde(default)]
+    pub resolved: bool,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SyntaxModel {
+    pub schema_version: u32,
+    pub uri: Url,
+    pub namespace: Option<Vec<String>>,
+    pub includes: Vec<String>,
+    pub imports: Vec<ImportNode>,
+    pub features: Vec<FeatureNode>,
+    pub constraints: Vec<ConstraintNode>,
+    pub diagnostics: Vec<DiagnosticNode>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportNode {
+    pub path: Vec<String>,
+    pub alias: Option<String>,
+    pub range: Range,
+    pub resolved: Option<Url>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FeatureNode {
+    pub name: String,
+    pub range: Range,
+    pub qualified_name: Option<String>,
+    pub cardinality: Option<CardinalityNode>,
+    pub attributes: Vec<AttributeNode>,
+    pub constraints: Vec<ConstraintNode>,
+    pub groups: Vec<GroupNode>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CardinalityNode {
+    pub min: Option<usize>,
+    pub max: Option<usize>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GroupNode {
+    pub kind: String,
+    pub cardinality: Option<CardinalityNode>,
+    pub range: Range,
+    pub children: Vec<ChildNode>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "node", rename_all = "camelCase")]
+pub enum ChildNode {
+    Feature(FeatureNode),
+    Reference {
+        path: Vec<String>,
+        range: Range,
+        resolved: Option<ResolvedName>,
+    },
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttributeNode {
+    pub name: String,
+    pub range: Range,
+    pub value: ValueNode,
+    pub attributes: Vec<AttributeNode>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", content = "value", rename_all = "camelCase")]
+pub enum ValueNode {
+    Void,
+    Number(f64),
+    String(String),
+    Vector(String),
+    Bool(bool),
+    Attributes,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConstraintNode {
+    pub range: Range,
+    pub expr: ExprNode,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum ExprNode {
+    Constant {
+        value: bool,
+    },
+    Number {
+        value: f64,
+    },
+    Ref {
+        path: Vec<String>,
+        range: Range,
+        resolved: Option<ResolvedName>,
+    },
+    Not {
+        operand: Box<ExprNode>,
+    },
+    Logic {
+        op: String,
+        lhs: Box<ExprNode>,
+        rhs: Box<ExprNode>,
+    },
+    Equation {
+        op: String,
+        lhs: Box<ExprNode>,
+        rhs: Box<ExprNode>,
+    },
+    Binary {
+        op: String,
+        lhs: Box<ExprNode>,
+        rhs: Box<ExprNode>,
+    },
+    Aggregate {
+        op: String,
+        context: Option<Box<ExprNode>>,
+        query: Vec<String>,
+    },
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolvedName {
+    pub uri: Url,
+    pub qualified_name: String,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DiagnosticNode {
+    pub range: Range,
+    pub severity: String,
+    pub message: String,
+    pub code: Option<String>,
+}
+fn names(path: &[ustr::Ustr]) -> Vec<String> {
+    path.iter().map(|name| name.to_string()).collect()
+}
+fn cardinality_node(card: &Cardinality) -> CardinalityNode {
+    let (min, max) = match card {
+        Cardinality::From(min) => (Some(*min), None),
+        Cardinality::Range(min, max) => (Some(*min), Some(*max)),
+        Cardinality::Max(max) => (None, Some(*max)),
+        Cardinality::Any => (None, None),
+    };
+    CardinalityNode { min, max }
+}
+fn language_level(lvl: &LanguageLevel) -> String {
+    let (major, minor): (&str, Vec<&str>) = match lvl {
+        LanguageLevel::SAT(minor) => (
+            "SAT-level",
+            minor
+                .iter()
+                .map(|m| match m {
+                    LanguageLevelSAT::Any => "*",
+                    LanguageLevelSAT::GroupCardinality => "group-cardinality",
+                })
+                .collect(),
+        ),
+        LanguageLevel::SMT(minor) => (
+            "SMT-level",
+            minor
+                .iter()
+                .map(|m| match m {
+                    LanguageLevelSMT::Any => "*",
+                    LanguageLevelSMT::FeatureCardinality => "feature-cardinality",
+                    LanguageLevelSMT::Aggregate => "aggregate-function",
+                })
+                .collect(),
+        ),
+    };
+    std::iter::once(major)
+        .chain(minor)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+enum ExprTask<'a> {
+    Constraint(&'a Constraint),
+    Numeric(&'a Numeric),
+    Not,
+    Logic(&'static str),
+    Equation(&'static str),
+    Binary(&'static str),
+}
+struct Exporter<'a> {
+    root: &'a RootGraph,
+    file_id: FileID,
+    file: &'a Document,
+    resolved: bool,
+}
+impl<'a> Exporter<'a> {
+    fn range(&self, sym: Symbol) -> Range {
+        self.file.lsp_range(sym).unwrap_or_default()
+    }
+    //Module path of the declaring file followed by the feature and attribute names
+    fn qualified_name(&self, dst: RootSymbol) -> String {
+        let file = self.root.file(dst.file);
+        let mut out = names(&file.path);
+        if matches!(dst.sym, Symbol::Attribute(..)) {
+            let feature = file
+                .ancestors(dst.sym)
+                .next()
+                .and_then(|f| file.symbol_name(f));
+            out.extend(feature.map(|name| name.to_string()));
+        }
+        out.extend(
+            file.prefix(dst.sym)
+                .iter()
+                .rev()
+                .map(|name| name.to_string()),
+        );
+        out.join(".")
+    }
+    fn resolve(&self, sym: Symbol) -> Option<ResolvedName> {
+        if !self.resolved {
+            return None;
+        }
+        let dst = self.root.resolve_sym(RootSymbol {
+            file: self.file_id,
+            sym,
+        })?;
+        Some(ResolvedName {
+            uri: self.root.file(dst.file).uri.clone(),
+            qualified_name: self.qualified_name(dst),
+        })
+    }
+    fn value(&self, sym: Symbol) -> ValueNode {
+        match self.file.value(sym) {
+            Some(Value::Number(num)) => ValueNode::Number(*num),
+            Some(Value::String(text)) => {
+                //only the enclosing quotes, an escaped quote may end the content
+                let end = text.len().saturating_sub(1);
+                let inner = text.get(1..end).unwrap_or_default();
+                ValueNode::String(inner.to_string())
+            }
+            Some(Value::Vector) => ValueNode::Vector(
+                self.file
+                    .value_span(sym)
+                    .map(|span| self.file.source.byte_slice(span).to_string())
+                    .unwrap_or_default(),
+            ),
+            Some(Value::Bool(value)) => ValueNode::Bool(*value),
+            Some(Value::Attributes) => ValueNode::Attributes,
+            _ => ValueNode::Void,
+        }
+    }
+    fn attribute(&self, sym: Symbol) -> AttributeNode {
+        AttributeNode {
+            name: self.file.symbol_name(sym).unwrap_or_default().to_string(),
+            range: self.range(sym),
+            value: self.value(sym),
+            attributes: self
+                .file
+                .direct_children(sym)
+                .filter(|c| matches!(c, Symbol::Attribute(..)))
+                .map(|c| self.attribute(c))
+                .collect(),
+        }
+    }
+    fn reference(&self, sym: Symbol) -> ExprNode {
+        ExprNode::Ref {
+            path: names(self.file.path(sym)),
+            range: self.range(sym),
+            resolved: self.resolve(sym),
+        }
+    }
+    //Constraints nest arbitrarily deep, operands are build with an explicit stack and the
+    //operators pop them once both are done
+    fn expr(&self, constraint: &Constraint) -> ExprNode {
+        let mut tasks = vec![ExprTask::Constraint(constraint)];
+        let mut values: Vec<ExprNode> = Vec::new();
+        while let Some(task) = tasks.pop() {
+            match task {
+                ExprTask::Constraint(Constraint::Constant(value)) => {
+                    values.push(ExprNode::Constant { value: *value });
+                }
+                ExprTask::Constraint(Constraint::Ref(sym)) => values.push(self.reference(*sym)),
+                ExprTask::Constraint(Constraint::Not(operand)) => {
+                    tasks.extend([ExprTask::Not, ExprTask::Constraint(operand)]);
+                }
+                ExprTask::Constraint(Constraint::Logic { op, lhs, rhs }) => {
+                    let op = match op {
+                        LogicOP::And => "&",
+                        LogicOP::Or => "|",
+                        LogicOP::Implies => "=>",
+                        LogicOP::Equiv => "<=>",
+                    };
+                    tasks.extend([
+                        ExprTask::Logic(op),
+                        ExprTask::Constraint(rhs),
+                        ExprTask::Constraint(lhs),
+                    ]);
+                }
+                ExprTask::Constraint(Constraint::Equation { op, lhs, rhs }) => {
+                    let op = match op {
+                        EquationOP::Greater => ">",
+                        EquationOP::Smaller => "<",
+                        EquationOP::Equal => "==",
+                    };
+                    tasks.extend([
+                        ExprTask::Equation(op),
+                        ExprTask::Numeric(rhs),
+                        ExprTask::Numeric(lhs),
+                    ]);
+                }
+                ExprTask::Numeric(Numeric::Number(value)) => {
+                    values.push(ExprNode::Number { value: *value });
+                }
+                ExprTask::Numeric(Numeric::Ref(sym)) => values.push(self.reference(*sym)),
+                ExprTask::Numeric(Numeric::Binary { op, lhs, rhs }) => {
+                    let op = match op {
+                        NumericOP::Add => "+",
+                        NumericOP::Sub => "-",
+                        NumericOP::Mul => "*",
+                        NumericOP::Div => "/",
+                    };
+                    tasks.extend([
+                        ExprTask::Binary(op),
+                        ExprTask::Numeric(rhs),
+                        ExprTask::Numeric(lhs),
+                    ]);
+                }
+                ExprTask::Numeric(Numeric::Aggregate { op, context, query }) => {
+                    values.push(ExprNode::Aggregate {
+                        op: match op {
+                            AggregateOP::Sum => "sum",
+                            AggregateOP::Avg => "avg",
+                        }
+                        .into(),
+                        context: context.map(|sym| Box::new(self.reference(sym))),
+                        query: names(&query.names),
+                    });
+                }
+                ExprTask::Not => {
+                    let operand = Box::new(values.pop().unwrap());
+                    values.push(ExprNode::Not { operand });
+                }
+                ExprTask::Logic(op) | ExprTask::Equation(op) | ExprTask::Binary(op) => {
+                    let rhs = Box::new(values.pop().unwrap());
+                    let lhs = Box::new(values.pop().unwrap());
+                    let op = op.into();
+                    values.push(match task {
+                        ExprTask::Logic(..) => ExprNode::Logic { op, lhs, rhs },
+                        ExprTask::Equation(..) => ExprNode::Equation { op, lhs, rhs },
+                        _ => ExprNode::Binary { op, lhs, rhs },
+                    });
+                }
+            }
+        }
+        values.pop().unwrap()
+    }
+    fn constraints(&self, parent: Symbol) -> Vec<ConstraintNode> {
+        self.file
+            .direct_children(parent)
+            .filter_map(|sym| {
+                Some(ConstraintNode {
+                    range: self.range(sym),
+                    expr: self.expr(self.file.constraint(sym)?),
+                })
+            })
+            .collect()
+    }
+    fn group(&self, sym: Symbol, done: &mut HashMap<Symbol, FeatureNode>) -> Option<GroupNode> {
+        let (kind, cardinality) = match self.file.group_mode(sym)? {
+            GroupMode::Or => ("or", None),
+            GroupMode::Alternative => ("alternative", None),
+            GroupMode::Optional => ("optional", None),
+            GroupMode::Mandatory => ("mandatory", None),
+            GroupMode::Cardinality(card) => ("cardinality", Some(cardinality_node(&card))),
+        };
+        let children = self
+            .file
+            .direct_children(sym)
+            .filter_map(|c| match c {
+                Symbol::Feature(..) => done.remove(&c).map(ChildNode::Feature),
+                Symbol::Reference(..) => Some(ChildNode::Reference {
+                    path: names(self.file.path(c)),
+                    range: self.range(c),
+                    resolved: self.resolve(c),
+                }),
+                _ => None,
+            })
+            .collect();
+        Some(GroupNode {
+            kind: kind.into(),
+            cardinality,
+            range: self.range(sym),
+            children,
+        })
+    }
+    //The features below sym have to be in done already
+    fn feature(&self, sym: Symbol, done: &mut HashMap<Symbol, FeatureNode>) -> FeatureNode {
+        let qualified_name = if self.resolved {
+            Some(self.qualified_name(RootSymbol {
+                file: self.file_id,
+                sym,
+            }))
+        } else {
+            None
+        };
+        FeatureNode {
+            name: self.file.symbol_name(sym).unwrap_or_default().to_string(),
+            range: self.range(sym),
+            qualified_name,
+            cardinality: self.file.cardinality(sym).map(cardinality_node),
+            attributes: self
+                .file
+                .direct_children(sym)
+                .filter(|c| matches!(c, Symbol::Attribute(..)))
+                .map(|c| self.attribute(c))
+                .collect(),
+            constraints: self.constraints(sym),
+            groups: self
+                .file
+                .direct_children(sym)
+                .filter_map(|c| self.group(c, done))
+                .collect(),
+        }
+    }
+    //Features are finished bottom up without recursion, models can nest thousands of levels
+    fn feature_tree(&self, sym: Symbol) -> FeatureNode {
+        let mut order = Vec::new();
+        self.file.visit_children(sym, false, |child| {
+            if matches!(child, Symbol::Feature(..)) {
+                order.push(child);
+            }
+            true
+        });
+        let mut done = HashMap::new();
+        for &child in order.iter().rev() {
+            let node = self.feature(child, &mut done);
+            done.insert(child, node);
+        }
+        self.feature(sym, &mut done)
+    }
+}
+//Serialize a file of the root graph, references and imports are resolved if requested
+pub fn export(
+    root: &RootGraph,
+    file_id: FileID,
+    resolved: bool,
+    diagnostics: &[ErrorInfo],
+) -> SyntaxModel {
+    let file = root.file(file_id);
+    let exporter = Exporter {
+        root,
+        file_id,
+        file,
+        resolved,
+    };
+    let imported: Vec<(Symbol, FileID)> = if resolved {
+        root.fs.imports(file_id).collect()
+    } else {
+        Vec::new()
+    };
+    SyntaxModel {
+        schema_version: SCHEMA_VERSION,
+        uri: file.uri.clone(),
+        namespace: file.namespace().map(|ns| names(&ns.names)),
+        includes: file.includes().iter().map(language_level).collect(),
+        imports: file
+            .all_imports()
+            .map(|sym| ImportNode {
+                path: names(file.path(sym)),
+                alias: file.alias(sym).map(|alias| alias.to_string()),
+                range: exporter.range(sym),
+                resolved: imported
+                    .iter()
+                    .find(|(import, _)| *import == sym)
+                    .map(|(_, dst)| root.file(*dst).uri.clone()),
+            })
+            .collect(),
+        features: file
+            .direct_children(Symbol::Root)
+            .filter(|sym| matches!(sym, Symbol::Feature(..)))
+            .map(|sym| exporter.feature_tree(sym))
+            .collect(),
+        constraints: exporter.constraints(Symbol::Root),
+        diagnostics: visible(diagnostics)
+            .into_iter()
+            .map(|e| DiagnosticNode {
+                range: e.location,
+                severity: e.severity_name().into(),
+                message: e.msg.clone(),
+                code: e.code.clone(),
+            })
+            .collect(),
+    }
+}
+fn cardinality_text(card: &CardinalityNode) -> String {
+    match (card.min, card.max) {
+        (Some(min), Some(max)) => format!("[{}..{}]", min, max),
+        (Some(min), None) => format!("[{}..*]", min),
+        (None, Some(max)) => format!("[{}]", max),
+        (None, None) => "[*]".into(),
+    }
+}
+//Strings keep the escapes they were written with, only quotes left from a "..." string have
+//to be escaped inside '...'
+fn quoted(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                out.push(c);
+                out.extend(chars.next());
+            }
+            '\'' => out.push_str("\\'"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+fn value_text(value: &ValueNode) -> Option<String> {
+    match value {
+        ValueNode::Number(num) => Some(num.to_string()),
+        ValueNode::String(text) => Some(format!("'{}'", quoted(text))),
+        ValueNode::Vector(text) => Some(text.clone()),
+        ValueNode::Bool(value) => Some(value.to_string()),
+        ValueNode::Void | ValueNode::Attributes => None,
+    }
+}
+fn attribute_text(attribute: &AttributeNode) -> String {
+    let value = match &attribute.value {
+        ValueNode::Attributes => Some(attributes_text(&attribute.attributes, &[])),
+        value => value_text(value),
+    };
+    match value {
+        Some(value) => format!("{} {}", attribute.name, value),
+        None => attribute.name.clone(),
+    }
+}
+fn attributes_text(attributes: &[AttributeNode], constraints: &[ConstraintNode]) -> String {
+    let entries: Vec<String> = attributes
+        .iter()
+        .map(attribute_text)
+        .chain(
+            constraints
+                .iter()
+                .map(|c| format!("constraint {}", expr_text(&c.expr))),
+        )
+        .collect();
+    format!("{{{}}}", entries.join(", "))
+}
+enum TextTask<'a> {
+    Expr(&'a ExprNode),
+    Operand(&'a ExprNode),
+    Parenthesis,
+    Not,
+    Binary(&'a str),
+    Aggregate(&'a str, &'a [String]),
+}
+fn compound(expr: &ExprNode) -> bool {
+    matches!(
+        expr,
+        ExprNode::Logic { .. } | ExprNode::Equation { .. } | ExprNode::Binary { .. }
+    )
+}
+//Operands are parenthesized unless they are atoms, so the text does not rely on precedence.
+//Expressions nest arbitrarily deep, so the text is build with an explicit stack
+fn expr_text(expr: &ExprNode) -> String {
+    let mut tasks = vec![TextTask::Expr(expr)];
+    let mut values: Vec<String> = Vec::new();
+    while let Some(task) = tasks.pop() {
+        match task {
+            TextTask::Operand(expr) if compound(expr) => {
+                tasks.extend([TextTask::Parenthesis, TextTask::Expr(expr)]);
+            }
+            TextTask::Operand(expr) | TextTask::Expr(expr) => match expr {
+                ExprNode::Constant { value } => values.push(value.to_string()),
+                ExprNode::Number { value } => values.push(value.to_string()),
+                ExprNode::Ref { path, .. } => values.push(path.join(".")),
+                ExprNode::Not { operand } => {
+                    tasks.extend([TextTask::Not, TextTask::Operand(operand)]);
+                }
+                ExprNode::Logic { op, lhs, rhs }
+                | ExprNode::Equation { op, lhs, rhs }
+                | ExprNode::Binary { op, lhs, rhs } => tasks.extend([
+                    TextTask::Binary(op),
+                    TextTask::Operand(rhs),
+                    TextTask::Operand(lhs),
+                ]),
+                ExprNode::Aggregate { op, context, query } => match context {
+                    Some(context) => {
+                        tasks.extend([TextTask::Aggregate(op, query), TextTask::Expr(context)]);
+                    }
+                    None => values.push(format!("{}({})", op, query.join("."))),
+                },
+            },
+            TextTask::Parenthesis => {
+                let inner = values.pop().unwrap();
+                values.push(format!("({})", inner));
+            }
+            TextTask::Not => {
+                let operand = values.pop().unwrap();
+                values.push(format!("!{}", operand));
+            }
+            TextTask::Binary(op) => {
+                let rhs = values.pop().unwrap();
+                let lhs = values.pop().unwrap();
+                values.push(format!("{} {} {}", lhs, op, rhs));
+            }
+            TextTask::Aggregate(op, query) => {
+                let context = values.pop().unwrap();
+                values.push(format!("{}({}, {})", op, context, query.join(".")));
+            }
+        }
+    }
+    values.pop().unwrap_or_default()
+}
+enum TextItem<'a> {
+    Feature(&'a FeatureNode, usize),
+    Group(&'a GroupNode, usize),
+    Reference(&'a [String], usize),
+}
+fn feature_text(out: &mut String, feature: &FeatureNode, depth: usize) {
+    let mut stack = vec![TextItem::Feature(feature, depth)];
+    while let Some(item) = stack.pop() {
+        match item {
+            TextItem::Feature(feature, depth) => {
+                let _ = write!(out, "{}{}", "    ".repeat(depth), feature.name);
+                if let Some(card) = feature.cardinality.as_ref() {
+                    let _ = write!(out, " cardinality {}", cardinality_text(card));
+                }
+                if !feature.attributes.is_empty() || !feature.constraints.is_empty() {
+                    let _ = write!(
+                        out,
+                        " {}",
+                        attributes_text(&feature.attributes, &feature.constraints)
+                    );
+                }
+                out.push('\n');
+                let groups = feature.groups.iter().rev();
+                stack.extend(groups.map(|group| TextItem::Group(group, depth + 1)));
+            }
+            TextItem::Group(group, depth) => {
+                let header = match group.cardinality.as_ref() {
+                    Some(card) => cardinality_text(card),
+                    None => group.kind.clone(),
+                };
+                let _ = writeln!(out, "{}{}", "    ".repeat(depth), header);
+                stack.extend(group.children.iter().rev().map(|child| match child {
+                    ChildNode::Feature(feature) => TextItem::Feature(feature, depth + 1),
+                    ChildNode::Reference { path, .. } => TextItem::Reference(path, depth + 1),
+                }));
+            }
+            TextItem::Reference(path, depth) => {
+                let _ = writeln!(out, "{}{}", "    ".repeat(depth), path.join("."));
+            }
+        }
+    }
+}
+impl SyntaxModel {
+    //Print the model as UVL, parsing the text gives the same model up to ranges and
+    //diagnostics. Comments and the original layout are not preserved
+    pub fn to_uvl_text(&self) -> String {
+        let mut sections = Vec::new();
+        if let Some(ns) = self.namespace.as_ref() {
+            sections.push(format!("namespace {}\n", ns.join(".")));
+        }
+        if !self.includes.is_empty() {
+            let mut out = String::from("include\n");
+            for lvl in self.includes.iter() {
+                let _ = writeln!(out, "    {}", lvl);
+            }
+            sections.push(out);
+        }
+        if !self.imports.is_empty() {
+            let mut out = String::from("imports\n");
+            for import in self.imports.iter() {
+                match import.alias.as_ref() {
+                    Some(alias) => {
+                        let _ = writeln!(out, "    {} as {}", import.path.join("."), alias);
+                    }
+                    None => {
+                        let _ = writeln!(out, "    {}", import.path.join("."));
+                    }
+                }
+            }
+            sections.push(out);
+        }
+        if !self.features.is_empty() {
+            let mut out = String::from("features\n");
+            for feature in self.features.iter() {
+                feature_text(&mut out, feature, 1);
+            }
+            sections.push(out);
+        }
+        if !self.constraints.is_empty() {
+            let mut out = String::from("constraints\n");
+            for constraint in self.constraints.iter() {
+                let _ = writeln!(out, "    {}", expr_text(&constraint.expr));
+            }
+            sections.push(out);
+        }
+        sections.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::build_document;
+    use crate::parse::parse;
+    use hashbrown::HashMap;
+    use ropey::Rope;
+    use std::sync::Arc;
+    use tokio::time::Instant;
+    fn model_of(text: &str) -> SyntaxModel {
+        let uri = Url::parse("file:///export/model.uvl").unwrap();
+        let source = Rope::from_str(text);
+        let tree = parse(&source, None);
+        let doc = build_document(
+            source,
+            tree,
+            uri.clone(),
+            Instant::now(),
+            Default::default(),
+        );
+        let documents: HashMap<Url, Arc<Document>> =
+            [(uri.clone(), Arc::new(doc))].into_iter().collect();
+        let root = RootGraph::new(&documents, 0);
+        let file_id = root.file_id(&uri).unwrap();
+        let err = root.file(file_id).errors.clone();
+        export(&root, file_id, true, &err)
+    }
+    //The model without ranges and diagnostics
+    fn shape(model: &SyntaxModel) -> serde_json::Value {
+        fn strip(value: &mut serde_json::Value) {
+            match value {
+                serde_json::Value::Object(map) => {
+                    map.remove("range");
+                    map.remove("diagnostics");
+                    map.values_mut().for_each(strip);
+                }
+                serde_json::Value::Array(values) => values.iter_mut().for_each(strip),
+                _ => {}
+            }
+        }
+        let mut value = serde_json::to_value(model).unwrap();
+        strip(&mut value);
+        value
+    }
+    #[test]
+    fn round_trip() {
+        let text = "namespace Shop\ninclude\n    SMT-level.*\nimports\n    lib.Base as b\nfeatures\n    Shop cardinality [1..*] {cost 10, label 'shop', sizes [1, 2], box {weight 2.5, fragile true}, constraint Pay => Card}\n        mandatory\n            Pay\n                alternative\n                    Card\n                    \"Cash Box\" {cost 1.5}\n        [1..2]\n            Extra {cost 2}\n            Gift\nconstraints\n    !(Card & \"Cash Box\") | sum(cost) > 5\n    Card <=> (Pay & !Gift)\n    Extra.cost * 2 == Shop.cost - 6\n";
+        let errors = |model: &SyntaxModel| {
+            model
+                .diagnostics
+                .iter()
+                .filter(|d| d.severity == "error")
+                .count()
+        };
+        let model = model_of(text);
+        assert_eq!(errors(&model), 0, "{:?}", model.diagnostics);
+        let json = serde_json::to_string(&model).unwrap();
+        let back: SyntaxModel = serde_json::from_str(&json).unwrap();
+        let uvl = back.to_uvl_text();
+        let reparsed = model_of(&uvl);
+        assert_eq!(errors(&reparsed), 0, "{}", uvl);
+        assert_eq!(shape(&model), shape(&reparsed));
+        assert_eq!(reparsed.to_uvl_text(), uvl);
+    }
+    #[test]
+    fn string_quotes() {
+        let text = |value: &str| value_text(&ValueNode::String(value.into())).unwrap();
+        assert_eq!(text("shop"), "'shop'");
+        assert_eq!(text("it's"), "'it\\'s'");
+        //escapes are kept as written
+        assert_eq!(text("it\\'s"), "'it\\'s'");
+        assert_eq!(text("a\\\\"), "'a\\\\'");
+    }
+    #[test]
+    fn deep_constraints() {
+        let mut text = String::from("features\n    Car\n        optional\n");
+        for i in 0..800 {
+            text.push_str(&format!("            F{}\n", i));
+        }
+        let chain: String = (0..800).map(|i| format!("(F{} => ", i)).collect();
+        let close = ")".repeat(800);
+        text.push_str(&format!("constraints\n    {}Car{}\n", chain, close));
+        let model = model_of(&text);
+        assert_eq!(model.constraints.len(), 1);
+        let uvl = model.to_uvl_text();
+        let expected: String = (0..800).map(|i| format!("F{} => (", i)).collect();
+        let expected = format!("{}Car{}", expected.trim_end_matches('('), ")".repeat(799));
+        assert!(uvl.contains(&expected), "{}", uvl);
+    }
+}
@@ -0,0 +1,119 @@
+use crate::util::*;
+use hashbrown::HashMap;
+use ropey::Rope;
+use tower_lsp::lsp_types::*;
+use tree_sitter::Tree;
+/*
+ * The formatter only touches whitespace, features, attributes and constraints are never
+ * reordered. Lines starting a block are indented by their nesting depth, every other line
+ * (attribute blocks, expressions inside parenthesis, comments) moves by the same amount as
+ * the last block above it so relative alignment is kept. Trailing whitespace is removed and
+ * the line ending of the first line is used throughout. Formatting a formatted file changes
+ * nothing.
+ */
+static INDENT: &str = "    ";
+//Row of each block header mapped to the number of enclosing blocks
+fn header_depths(tree: &Tree) -> HashMap<usize, (usize, usize)> {
+    let mut depths = HashMap::new();
+    let mut stack = vec![(tree.root_node(), 0)];
+    while let Some((node, depth)) = stack.pop() {
+        let mut cursor = node.walk();
+        for child in node.named_children(&mut cursor) {
+            if child.kind() == "blk" {
+                if let Some(header) = child.child_by_field_name("header") {
+                    let start = header.start_position();
+                    depths.insert(start.row, (start.column, depth));
+                }
+                stack.push((child, depth + 1));
+            } else {
+                stack.push((child, depth));
+            }
+        }
+    }
+    depths
+}
+fn indent_width(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+//Formatted source, files with syntax errors are left alone
+pub fn format(source: &Rope, tree: &Tree) -> Option<String> {
+    if tree.root_node().has_error() {
+        return None;
+    }
+    let depths = header_depths(tree);
+    let eol = match source.get_line(0) {
+        Some(line) if line.to_string().ends_with("\r\n") => "\r\n",
+        _ => "\n",
+    };
+    let mut out = String::with_capacity(source.len_bytes());
+    let mut shift: isize = 0;
+    for (row, line) in source.lines().enumerate() {
+        let line = line.to_string();
+        let line = line.trim_end();
+        let indent = indent_width(line);
+        if line.is_empty() {
+            out.push_str(eol);
+            continue;
+        }
+        match depths.get(&row) {
+            Some(&(column, depth)) if column == indent => {
+                let target = INDENT.len() * depth;
+                shift = target as isize - indent as isize;
+                out.push_str(&INDENT.repeat(depth));
+            }
+            _ => {
+                let target = (indent as isize + shift).max(0) as usize;
+                out.push_str(&" ".repeat(target));
+            }
+        }
+        out.push_str(line.trim_start());
+        out.push_str(eol);
+    }
+    //exactly one newline at the end
+    let len = out.trim_end().len();
+    out.truncate(len);
+    out.push_str(eol);
+    Some(out)
+}
+pub fn format_edits(source: &Rope, tree: &Tree) -> Option<Vec<TextEdit>> {
+    let formatted = format(source, tree)?;
+    if *source == formatted.as_str() {
+        return Some(Vec::new());
+    }
+    Some(vec![TextEdit {
+        range: lsp_range(0..source.len_bytes(), source)?,
+        new_text: formatted,
+    }])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse;
+    static CORPUS: &[&str] = &[
+        "namespace Car\nfeatures\n    Car\n        mandatory\n            Engine {price 10}\n        optional\n            Radio\nconstraints\n    Radio => Engine\n",
+        "features\n  Car {weight 100, price 10}  \n    alternative   \n      Gas\n      Electric\n\n\nconstraints\n  Gas => Car.weight > 3\n",
+        "imports\n\tsub.model as s\nfeatures\n\tRoot\n\t\toptional\n\t\t\tA {\n\t\t\t\tx 1,\n\t\t\t\ty 2\n\t\t\t}\n\t\t\ts.B\nconstraints\n\t(A &\n\t\tB) => A\n",
+        "features\n    Root // root\n        or\n            A\n            // nothing here\n            B\n",
+        "features\r\n  Root \r\n    optional\r\n      A\r\n",
+    ];
+    fn content(text: &str) -> String {
+        text.chars().filter(|c| !c.is_whitespace()).collect()
+    }
+    fn format_str(text: &str) -> String {
+        let source = Rope::from_str(text);
+        let tree = parse(&source, None);
+        format(&source, &tree).unwrap()
+    }
+    #[test]
+    fn idempotent() {
+        for text in CORPUS {
+            let once = format_str(text);
+            let twice = format_str(&once);
+            assert_eq!(once, twice);
+            //only whitespace changes, nothing is reordered
+            assert_eq!(content(text), content(&once));
+            assert_eq!(text.contains("\r\n"), once.contains("\r\n"));
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use crate::ast::*;
+use crate::inherit::effective_attributes;
+use crate::util::*;
+use tower_lsp::lsp_types::*;
+/*
+ * Hover on group headers explaining which children may be selected together with their
+ * parent, computed from the group kind and the number of children. With inherited attributes
+ * enabled features list their effective attributes and where inherited ones come from.
+ * Nothing else has a hover. Clients without markdown support get the same text unstyled.
+ */
+fn styled(text: &str, mark: &str, markdown: bool) -> String {
+    if markdown {
+        format!("{}{}{}", mark, text, mark)
+    } else {
+        text.into()
+    }
+}
+fn contents(lines: Vec<String>, markdown: bool) -> HoverContents {
+    HoverContents::Markup(MarkupContent {
+        kind: if markdown {
+            MarkupKind::Markdown
+        } else {
+            MarkupKind::PlainText
+        },
+        value: lines.join("\n"),
+    })
+}
+fn selection(mode: &GroupMode, children: usize) -> String {
+    match mode {
+        GroupMode::Mandatory => format!("select all {}", children),
+        GroupMode::Optional | GroupMode::Cardinality(Cardinality::Any) => {
+            format!("select any of {}", children)
+        }
+        GroupMode::Or => format!("select at least 1 of {}", children),
+        GroupMode::Alternative => format!("select 1 of {}", children),
+        GroupMode::Cardinality(Cardinality::From(min)) => {
+            format!("select at least {} of {}", min, children)
+        }
+        GroupMode::Cardinality(Cardinality::Max(max)) => {
+            format!("select at most {} of {}", max, children)
+        }
+        GroupMode::Cardinality(Cardinality::Range(min, max)) => {
+            format!("select {} to {} of {}", min, max, children)
+        }
+    }
+}
+fn feature_attributes(file: &Document, feature: Symbol, markdown: bool) -> Option<Hover> {
+    let name = file.symbol_name(feature)?;
+    let mut lines = vec![format!("{} attributes", styled(&name, "**", markdown))];
+    for (key, e) in effective_attributes(file, feature) {
+        if matches!(file.value(e.attribute), Some(Value::Attributes)) {
+            continue;
+        }
+        let key: Vec<&str> = key.iter().map(|k| k.as_str()).collect();
+        let value: String = file
+            .value_span(e.attribute)
+            .map(|span| file.source.byte_slice(span).into())
+            .unwrap_or_default();
+        let line = format!("- {} {}", styled(&key.join("."), "`", markdown), value);
+        if e.owner == feature {
+            lines.push(line);
+        } else {
+            let owner = format!("(inherited from {})", file.symbol_name(e.owner)?);
+            lines.push(format!("{} {}", line, styled(&owner, "*", markdown)));
+        }
+    }
+    if lines.len() == 1 {
+        return None;
+    }
+    Some(Hover {
+        contents: contents(lines, markdown),
+        range: file.lsp_range(feature),
+    })
+}
+pub fn hover(
+    file: &Document,
+    pos: &Position,
+    inherit_attributes: bool,
+    markdown: bool,
+) -> Option<Hover> {
+    let offset = byte_offset(pos, &file.source);
+    if inherit_attributes {
+        if let Some(feature @ Symbol::Feature(..)) = file.find(offset) {
+            return feature_attributes(file, feature, markdown);
+        }
+    }
+    let mut group = None;
+    file.visit_children(Symbol::Root, false, |sym| match sym {
+        Symbol::Group(..) => {
+            if file.span(sym).is_some_and(|s| s.contains(&offset)) {
+                group = Some(sym);
+            }
+            group.is_none()
+        }
+        _ => group.is_none(),
+    });
+    let group = group?;
+    let children = file
+        .direct_children(group)
+        .filter(|sym| matches!(sym, Symbol::Feature(..) | Symbol::Reference(..)))
+        .count();
+    let header: String = file.source.byte_slice(file.span(group)?).into();
+    let noun = if children == 1 { "child" } else { "children" };
+    let line = format!(
+        "{} group: {} {}",
+        styled(header.trim(), "**", markdown),
+        selection(&file.group_mode(group)?, children),
+        noun
+    );
+    Some(Hover {
+        contents: contents(vec![line], markdown),
+        range: file.lsp_range(group),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::build_document;
+    use crate::parse::parse;
+    use ropey::Rope;
+    use tokio::time::Instant;
+    fn hover_text(file: &Document, line: u32, character: u32) -> Option<String> {
+        match hover(file, &Position::new(line, character), false, true)?.contents {
+            HoverContents::Markup(markup) => Some(markup.value),
+            _ => None,
+        }
+    }
+    #[test]
+    fn group_semantics() {
+        let source = Rope::from_str("features\n    Car\n        alternative\n            A\n            B\n        [1..2]\n            C\n            D\n            E\n        or\n            F\n");
+        let tree = parse(&source, None);
+        let uri = Url::parse("file:///hover.uvl").unwrap();
+        let file = build_document(source, tree, uri, Instant::now(), Default::default());
+        assert_eq!(
+            hover_text(&file, 2, 10).as_deref(),
+            Some("**alternative** group: select 1 of 2 children")
+        );
+        assert_eq!(
+            hover_text(&file, 5, 9).as_deref(),
+            Some("**[1..2]** group: select 1 to 2 of 3 children")
+        );
+        assert_eq!(
+            hover_text(&file, 9, 8).as_deref(),
+            Some("**or** group: select at least 1 of 1 child")
+        );
+        //features are no group headers
+        assert!(hover_text(&file, 1, 5).is_none());
+        assert!(hover_text(&file, 3, 12).is_none());
+        //clients without markdown support get plain text
+        let plain = hover(&file, &Position::new(2, 10), false, false).unwrap();
+        match plain.contents {
+            HoverContents::Markup(markup) => {
+                assert_eq!(markup.kind, MarkupKind::PlainText);
+                assert_eq!(markup.value, "alternative group: select 1 of 2 children");
+            }
+            _ => panic!("expected markup"),
+        }
+    }
+    #[test]
+    fn inherited_attributes() {
+        let source = Rope::from_str("features\n    Car {cost 10, owner 'a'}\n        optional\n            Engine {cost 20}\n");
+        let tree = parse(&source, None);
+        let uri = Url::parse("file:///hover.uvl").unwrap();
+        let file = build_document(source, tree, uri, Instant::now(), Default::default());
+        let text = |inherit_attributes: bool| {
+            let hover = hover(&file, &Position::new(3, 14), inherit_attributes, true)?;
+            match hover.contents {
+                HoverContents::Markup(markup) => Some(markup.value),
+                _ => None,
+            }
+        };
+        assert_eq!(
+            text(true).as_deref(),
+            Some("**Engine** attributes\n- `cost` 20\n- `owner` 'a' *(inherited from Car)*")
+        );
+        //without inheritance features have no hover
+        assert!(text(false).is_none());
+    }
+}
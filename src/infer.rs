@@ -0,0 +1,267 @@
+use crate::ast::*;
+use crate::semantic::*;
+use serde::{Deserialize, Serialize};
+use tower_lsp::lsp_types::{Range, TextDocumentIdentifier};
+use ustr::Ustr;
+/*
+ * The $/uvls/inferTypes request takes { "textDocument": { "uri": string } } and lists the
+ * types the checker assigned to the document. The format is part of the public interface,
+ * fields are only ever added.
+ *
+ * {
+ *   "uri": string,
+ *   "attributes": [{ "path": "Feature.attribute", "range": Range, "type": InferredType }],
+ *   "expressions": [{ "range": Range, "kind": "constraint" | "numeric" | "reference",
+ *                     "type": InferredType | null }],
+ *   "skipped": [Range]
+ * }
+ *
+ * Expressions are listed constraint by constraint with operands before the expression
+ * containing them. The type of a reference is the type of the symbol it resolves to and null
+ * if it does not resolve. Constraints that are too large to analyze have no types, their ranges
+ * are listed under skipped.
+ */
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InferTypesParams {
+    pub text_document: TextDocumentIdentifier,
+}
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum InferredType {
+    Bool,
+    Number,
+    String,
+    Vector,
+    Attributes,
+    Feature,
+    Void,
+}
+impl InferredType {
+    fn from_type(ty: Type) -> Option<Self> {
+        match ty {
+            Type::Number => Some(Self::Number),
+            Type::String => Some(Self::String),
+            Type::Vector => Some(Self::Vector),
+            Type::Attributes => Some(Self::Attributes),
+            Type::Feature => Some(Self::Feature),
+            Type::Void => Some(Self::Void),
+            _ => None,
+        }
+    }
+}
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ExprKind {
+    Constraint,
+    Numeric,
+    Reference,
+}
+#[derive(Serialize, Debug)]
+pub struct AttributeType {
+    pub path: String,
+    pub range: Range,
+    #[serde(rename = "type")]
+    pub ty: Option<InferredType>,
+}
+#[derive(Serialize, Debug)]
+pub struct ExprType {
+    pub range: Range,
+    pub kind: ExprKind,
+    #[serde(rename = "type")]
+    pub ty: Option<InferredType>,
+}
+#[derive(Serialize, Debug)]
+pub struct TypeInfo {
+    pub uri: String,
+    pub attributes: Vec<AttributeType>,
+    pub expressions: Vec<ExprType>,
+    pub skipped: Vec<Range>,
+}
+fn attribute_path(file: &Document, mut sym: Symbol) -> String {
+    let mut path: Vec<Ustr> = Vec::new();
+    loop {
+        if let Some(name) = file.symbol_name(sym) {
+            path.push(name);
+        }
+        match file.parent(sym, false) {
+            Some(parent) if !matches!(sym, Symbol::Feature(..)) => sym = parent,
+            _ => break,
+        }
+    }
+    path.iter()
+        .rev()
+        .map(|i| i.as_str())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+pub fn infer_types(root: &RootGraph, params: &InferTypesParams) -> Option<TypeInfo> {
+    let doc = &params.text_document;
+    let file_id = root.file_id(&doc.uri)?;
+    let file = root.file(file_id);
+    let attributes = file
+        .all_attributes()
+        .filter_map(|sym| {
+            Some(AttributeType {
+                path: attribute_path(file, sym),
+                range: file.lsp_range(sym)?,
+                ty: file.type_of(sym).and_then(InferredType::from_type),
+            })
+        })
+        .collect();
+    let expressions = file
+        .expressions()
+        .iter()
+        .filter_map(|expr| {
+            let range = crate::util::lsp_range(expr.span.clone(), &file.source)?;
+            Some(match expr.reference {
+                //references only resolve to symbols of the type the checker assigned them
+                Some(sym) => ExprType {
+                    range,
+                    kind: ExprKind::Reference,
+                    ty: root
+                        .resolve_sym(RootSymbol { file: file_id, sym })
+                        .and_then(|_| file.type_of(sym))
+                        .and_then(InferredType::from_type),
+                },
+                None if expr.numeric => ExprType {
+                    range,
+                    kind: ExprKind::Numeric,
+                    ty: Some(InferredType::Number),
+                },
+                None => ExprType {
+                    range,
+                    kind: ExprKind::Constraint,
+                    ty: Some(InferredType::Bool),
+                },
+            })
+        })
+        .collect();
+    let skipped = file
+        .oversized_constraints()
+        .iter()
+        .filter_map(|span| crate::util::lsp_range(span.clone(), &file.source))
+        .collect();
+    Some(TypeInfo {
+        uri: doc.uri.to_string(),
+        attributes,
+        expressions,
+        skipped,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::build_document;
+    use crate::parse::parse;
+    use ropey::Rope;
+    use std::sync::Arc;
+    use tokio::time::Instant;
+    use tower_lsp::lsp_types::{Position, Url};
+    fn infer(text: &str) -> TypeInfo {
+        let uri = Url::parse("file:///infer.uvl").unwrap();
+        let source = Rope::from_str(text);
+        let tree = parse(&source, None);
+        let doc = build_document(
+            source,
+            tree,
+            uri.clone(),
+            Instant::now(),
+            Default::default(),
+        );
+        let files: hashbrown::HashMap<Url, Arc<Document>> =
+            [(uri.clone(), Arc::new(doc))].into_iter().collect();
+        let root = RootGraph::new(&files, 0);
+        infer_types(
+            &root,
+            &InferTypesParams {
+                text_document: TextDocumentIdentifier { uri },
+            },
+        )
+        .unwrap()
+    }
+    //Source text of a range on a single line
+    fn slice<'a>(text: &'a str, range: &Range) -> &'a str {
+        assert_eq!(range.start.line, range.end.line);
+        let line = text.lines().nth(range.start.line as usize).unwrap();
+        &line[range.start.character as usize..range.end.character as usize]
+    }
+    #[test]
+    fn types() {
+        let text = "features\n    Car {cost 10, name 'car', extras {weight 2}}\n        optional\n            Engine {cost 5}\nconstraints\n    Engine => Car\n    Engine.cost + 2 > Car.cost\n    Missing\n";
+        let info = infer(text);
+        let mut attributes: Vec<(&str, Option<InferredType>)> = info
+            .attributes
+            .iter()
+            .map(|a| (a.path.as_str(), a.ty))
+            .collect();
+        attributes.sort_by_key(|a| a.0);
+        assert_eq!(
+            attributes,
+            [
+                ("Car.cost", Some(InferredType::Number)),
+                ("Car.extras", Some(InferredType::Attributes)),
+                ("Car.extras.weight", Some(InferredType::Number)),
+                ("Car.name", Some(InferredType::String)),
+                ("Engine.cost", Some(InferredType::Number)),
+            ]
+        );
+        let expressions: Vec<(&str, ExprKind, Option<InferredType>)> = info
+            .expressions
+            .iter()
+            .map(|e| (slice(text, &e.range), e.kind, e.ty))
+            .collect();
+        assert_eq!(
+            expressions,
+            [
+                ("Engine", ExprKind::Reference, Some(InferredType::Feature)),
+                ("Car", ExprKind::Reference, Some(InferredType::Feature)),
+                (
+                    "Engine => Car",
+                    ExprKind::Constraint,
+                    Some(InferredType::Bool)
+                ),
+                (
+                    "Engine.cost",
+                    ExprKind::Reference,
+                    Some(InferredType::Number)
+                ),
+                ("2", ExprKind::Numeric, Some(InferredType::Number)),
+                (
+                    "Engine.cost + 2",
+                    ExprKind::Numeric,
+                    Some(InferredType::Number)
+                ),
+                ("Car.cost", ExprKind::Reference, Some(InferredType::Number)),
+                (
+                    "Engine.cost + 2 > Car.cost",
+                    ExprKind::Constraint,
+                    Some(InferredType::Bool)
+                ),
+                ("Missing", ExprKind::Reference, None),
+            ]
+        );
+        assert!(info.skipped.is_empty());
+        //the serialized names are part of the interface
+        let json = serde_json::to_value(&info.expressions[0]).unwrap();
+        assert_eq!(json["kind"], "reference");
+        assert_eq!(json["type"], "feature");
+        let json = serde_json::to_value(&info.expressions[8]).unwrap();
+        assert!(json["type"].is_null());
+    }
+    #[test]
+    fn skipped_constraints() {
+        let operands: Vec<String> = (0..20000).map(|i| format!("F{}", i % 2)).collect();
+        let text = format!(
+            "features\n    F0\n        optional\n            F1\nconstraints\n    F0 => F1\n    {}\n",
+            operands.join(" | ")
+        );
+        let info = infer(&text);
+        assert_eq!(info.skipped.len(), 1);
+        assert_eq!(info.skipped[0].start, Position::new(6, 4));
+        //the oversized constraint has no expressions
+        assert!(info.expressions.iter().all(|e| e.range.start.line == 5));
+        assert_eq!(info.expressions.len(), 3);
+    }
+}
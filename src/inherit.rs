@@ -0,0 +1,265 @@
+use crate::ast::*;
+use crate::check::ErrorInfo;
+use crate::semantic::{FileID, RootGraph, RootSymbol};
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use tower_lsp::lsp_types::*;
+use ustr::Ustr;
+/*
+ * Opt-in interpretation for dialects treating the attributes of a feature as defaults of its
+ * descendants. The effective value of a key is the one declared by the feature itself or by
+ * its nearest ancestor declaring the key. The mode is stored in the RootGraph so hover, the
+ * aggregates of the solver encoding and the redundancy check agree on what a value is.
+ * Inheritance follows the feature tree of a file, features of imported models do not inherit
+ * from the feature referencing them. Checks over a whole tree pass the effective attributes
+ * down instead of walking the ancestors of every feature.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EffectiveAttribute {
+    pub attribute: Symbol,
+    //Feature declaring the attribute, the feature itself unless it is inherited
+    pub owner: Symbol,
+}
+//Attributes declared by a feature by their key, nested attributes included
+pub fn declared_attributes(file: &Document, feature: Symbol) -> BTreeMap<Vec<Ustr>, Symbol> {
+    let mut out = BTreeMap::new();
+    file.visit_named_children(feature, true, |sym, path| {
+        if matches!(sym, Symbol::Attribute(..)) {
+            out.insert(path[1..].to_vec(), sym);
+        }
+        true
+    });
+    out
+}
+pub fn effective_attributes(
+    file: &Document,
+    feature: Symbol,
+) -> BTreeMap<Vec<Ustr>, EffectiveAttribute> {
+    let mut out = BTreeMap::new();
+    for owner in file.ancestors(feature) {
+        for (key, attribute) in declared_attributes(file, owner) {
+            out.entry(key)
+                .or_insert(EffectiveAttribute { attribute, owner });
+        }
+    }
+    out
+}
+type Effective = BTreeMap<Vec<Ustr>, EffectiveAttribute>;
+//Visit the features below root with what they inherit from their parent and what they
+//declare. Features declaring nothing share the map of their parent
+fn visit_inherited<F: FnMut(Symbol, &Effective, &BTreeMap<Vec<Ustr>, Symbol>)>(
+    file: &Document,
+    root: Symbol,
+    mut f: F,
+) {
+    let inherited = file
+        .ancestors(root)
+        .find(|owner| *owner != root)
+        .map(|parent| effective_attributes(file, parent))
+        .unwrap_or_default();
+    let mut stack = vec![(root, Rc::new(inherited))];
+    while let Some((sym, inherited)) = stack.pop() {
+        let effective = match sym {
+            Symbol::Feature(..) => {
+                let declared = declared_attributes(file, sym);
+                f(sym, &inherited, &declared);
+                if declared.is_empty() {
+                    inherited
+                } else {
+                    let mut effective = (*inherited).clone();
+                    for (key, attribute) in declared {
+                        effective.insert(
+                            key,
+                            EffectiveAttribute {
+                                attribute,
+                                owner: sym,
+                            },
+                        );
+                    }
+                    Rc::new(effective)
+                }
+            }
+            _ => inherited,
+        };
+        for child in file.direct_children(sym) {
+            if matches!(child, Symbol::Feature(..) | Symbol::Group(..)) {
+                stack.push((child, effective.clone()));
+            }
+        }
+    }
+}
+//Features below root without an own attribute at key together with the number they inherit
+pub fn inherited_numbers(file: &Document, root: Symbol, key: &[Ustr]) -> Vec<(Symbol, f64)> {
+    let mut out = Vec::new();
+    visit_inherited(file, root, |feature, inherited, declared| {
+        if declared.contains_key(key) {
+            return;
+        }
+        let inherited = inherited.get(key).and_then(|e| file.value(e.attribute));
+        if let Some(Value::Number(num)) = inherited {
+            out.push((feature, *num));
+        }
+    });
+    out
+}
+//Attributes referenced through a feature inheriting them, e.g. Piston.cost for a cost
+//declared by an ancestor of Piston. Features declaring the key themselves are left to the
+//regular lookup
+pub fn resolve_inherited(
+    root: &RootGraph,
+    origin: FileID,
+    scope: Symbol,
+    path: &[Ustr],
+) -> Vec<RootSymbol> {
+    let mut out = Vec::new();
+    for split in 1..path.len() {
+        let (prefix, key) = path.split_at(split);
+        for feature in root.resolve_scoped(origin, scope, prefix) {
+            if !matches!(feature.sym, Symbol::Feature(..)) {
+                continue;
+            }
+            let file = root.file(feature.file);
+            let nearest = file.ancestors(feature.sym).find_map(|owner| {
+                file.lookup(owner, key, |sym| matches!(sym, Symbol::Attribute(..)))
+                    .next()
+                    .map(|attribute| (owner, attribute))
+            });
+            if let Some((owner, attribute)) = nearest {
+                if owner != feature.sym {
+                    out.push(RootSymbol {
+                        file: feature.file,
+                        sym: attribute,
+                    });
+                }
+            }
+        }
+    }
+    out
+}
+fn same_value(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a == b,
+        (Value::String(a), Value::String(b)) => a == b,
+        (Value::Bool(a), Value::Bool(b)) => a == b,
+        _ => false,
+    }
+}
+//Attributes repeating the value a feature would inherit anyway
+pub fn check_redundant(file: &Document) -> Vec<ErrorInfo> {
+    let mut err = Vec::new();
+    visit_inherited(file, Symbol::Root, |_, inherited, declared| {
+        for (key, attribute) in declared {
+            let inherited = match inherited.get(key) {
+                Some(inherited) => inherited,
+                None => continue,
+            };
+            let redundant = match (file.value(*attribute), file.value(inherited.attribute)) {
+                (Some(a), Some(b)) => same_value(a, b),
+                _ => false,
+            };
+            let (location, related) = match (
+                file.lsp_range(*attribute),
+                file.lsp_range(inherited.attribute),
+            ) {
+                (Some(location), Some(related)) if redundant => (location, related),
+                _ => continue,
+            };
+            let owner = file.symbol_name(inherited.owner).unwrap_or_default();
+            err.push(ErrorInfo {
+                location,
+                severity: DiagnosticSeverity::HINT,
+                weight: 10,
+                msg: format!(
+                    "{} repeats the value inherited from {}",
+                    key.iter().map(|k| k.as_str()).collect::<Vec<_>>().join("."),
+                    owner
+                ),
+                related: vec![DiagnosticRelatedInformation {
+                    location: Location {
+                        uri: file.uri.clone(),
+                        range: related,
+                    },
+                    message: "inherited value".into(),
+                }],
+                code: Some("attribute/redundant".into()),
+                tags: vec![DiagnosticTag::UNNECESSARY],
+            });
+        }
+    });
+    err
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::build_document;
+    use crate::parse::parse;
+    use crate::semantic::Resolution;
+    use hashbrown::HashMap;
+    use ropey::Rope;
+    use std::sync::Arc;
+    use tokio::time::Instant;
+    #[test]
+    fn nearest_ancestor_wins() {
+        let source = Rope::from_str("features\n    Car {cost 10, owner 'a'}\n        optional\n            Engine {cost 20}\n                optional\n                    Piston {owner 'a'}\n");
+        let tree = parse(&source, None);
+        let uri = Url::parse("file:///inherit.uvl").unwrap();
+        let file = build_document(source, tree, uri, Instant::now(), Default::default());
+        let name = |sym: Symbol| file.symbol_name(sym).unwrap().to_string();
+        let piston = file.all_features().find(|f| name(*f) == "Piston").unwrap();
+        let owners: Vec<(String, String)> = effective_attributes(&file, piston)
+            .into_iter()
+            .map(|(key, e)| (key[0].to_string(), name(e.owner)))
+            .collect();
+        assert_eq!(
+            owners,
+            vec![
+                ("cost".to_string(), "Engine".to_string()),
+                ("owner".to_string(), "Piston".to_string())
+            ]
+        );
+        assert_eq!(
+            inherited_numbers(&file, Symbol::Root, &[Ustr::from("cost")]),
+            vec![(piston, 20.0)]
+        );
+        let redundant: Vec<String> = check_redundant(&file).into_iter().map(|e| e.msg).collect();
+        assert_eq!(
+            redundant,
+            vec!["owner repeats the value inherited from Car"]
+        );
+    }
+    #[test]
+    fn inherited_references() {
+        let source = Rope::from_str("features\n    Car {cost 10}\n        optional\n            Engine\n                optional\n                    Piston {cost 3}\nconstraints\n    Engine.cost > 5\n    Piston.cost > 1\n");
+        let tree = parse(&source, None);
+        let uri = Url::parse("file:///inherit.uvl").unwrap();
+        let file = Arc::new(build_document(
+            source,
+            tree,
+            uri.clone(),
+            Instant::now(),
+            Default::default(),
+        ));
+        let files: HashMap<_, _> = [(uri, file.clone())].into_iter().collect();
+        let mut root = RootGraph::new(&files, 0);
+        let references: Vec<Symbol> = file.all_references().collect();
+        let attribute = |owner: &str| {
+            let owner = file
+                .all_features()
+                .find(|f| file.symbol_name(*f).as_deref() == Some(owner))
+                .unwrap();
+            let attribute = file.lookup(owner, &[Ustr::from("cost")], |_| true).next();
+            Resolution::Resolved(RootSymbol {
+                file: FileID(0),
+                sym: attribute.unwrap(),
+            })
+        };
+        assert_eq!(root.reference_errors(FileID(0)).len(), 1);
+        root.inherit_attributes = true;
+        assert!(root.reference_errors(FileID(0)).is_empty());
+        //Engine inherits the cost of Car, Piston declares its own
+        let resolve = |reference: Symbol| root.resolve_reference(FileID(0), reference);
+        assert_eq!(resolve(references[0]), attribute("Car"));
+        assert_eq!(resolve(references[1]), attribute("Piston"));
+    }
+}
@@ -4,10 +4,13 @@ use crate::document::Draft;
 use crate::parse::*;
 use crate::semantic::*;
 use crate::util::*;
+use crate::valueref;
 use log::info;
 use ropey::Rope;
+use serde::{Deserialize, Serialize};
 use tower_lsp::lsp_types::*;
 use tree_sitter::Node;
+use ustr::Ustr;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum TextObjectKind {
@@ -143,17 +146,100 @@ pub fn find_text_object(draft: &Draft, pos: &Position) -> Option<TextObject> {
     }
 }
 
+//Whether the cursor is inside an ERROR or MISSING node, there the tree does not describe the
+//text and its nodes may span half a line
+fn in_error_region(draft: &Draft, pos: &Position) -> bool {
+    match draft {
+        Draft::Tree { source, tree, .. } => {
+            let offset = byte_offset(pos, source);
+            let mut node = tree.root_node().descendant_for_byte_range(offset, offset);
+            while let Some(n) = node {
+                if n.is_error() || n.is_missing() {
+                    return true;
+                }
+                node = n.parent();
+            }
+            false
+        }
+        _ => false,
+    }
+}
+//Dotted identifier lexed from the source up to the end of the segment under the cursor
+fn lex_path(source: &Rope, pos: &Position) -> Option<Vec<Ustr>> {
+    let line = source.get_line(pos.line as usize)?;
+    let chars: Vec<char> = line.chars().collect();
+    let column = char_offset(pos, source) - source.line_to_char(pos.line as usize);
+    let is_name = |c: &&char| c.is_alphanumeric() || **c == '_';
+    let is_path = |c: &&char| is_name(c) || **c == '.';
+    let start = column - chars[..column].iter().rev().take_while(is_path).count();
+    let end = column + chars[column..].iter().take_while(is_name).count();
+    let token: String = chars[start..end].iter().collect();
+    let path: Vec<Ustr> = token.split('.').map(Ustr::from).collect();
+    if path.iter().any(|name| name.is_empty()) {
+        return None;
+    }
+    Some(path)
+}
+//Token level resolution for error regions: the identifier under the cursor is resolved by
+//name from the feature whose attribute block is on the same line, its ancestors and the file
+//root. The nearest scope with matches wins and only an unambiguous match is returned
+fn fallback_definitions(
+    root: &Snapshot,
+    draft: &Draft,
+    pos: &Position,
+    file_id: FileID,
+) -> Option<Vec<RootSymbol>> {
+    let path = lex_path(draft.source()?, pos)?;
+    let file = root.file(file_id);
+    let scope = file
+        .all_features()
+        .find(|f| {
+            file.lsp_range(*f)
+                .is_some_and(|r| r.start.line == pos.line && r.end.character < pos.character)
+        })
+        .unwrap_or(Symbol::Root);
+    let scoped = file.ancestors(scope).map(|feature| {
+        file.lookup(feature, &path, |_| true)
+            .map(|sym| RootSymbol { file: file_id, sym })
+            .collect::<Vec<_>>()
+    });
+    let unscoped = std::iter::once(root.resolve(file_id, &path).collect());
+    let mut found: Vec<RootSymbol> = Vec::new();
+    for candidates in scoped.chain(unscoped) {
+        for sym in candidates {
+            if matches!(sym.sym, Symbol::Feature(..) | Symbol::Attribute(..))
+                && !found.contains(&sym)
+            {
+                found.push(sym);
+            }
+        }
+        if !found.is_empty() {
+            break;
+        }
+    }
+    info!("fallback resolution of {:?}: {:?}", path, found);
+    match found[..] {
+        [sym] => Some(vec![sym]),
+        _ => None,
+    }
+}
 fn find_definitions(
     root: &Snapshot,
     draft: &Draft,
     pos: &Position,
     uri: &Url,
 ) -> Option<Vec<RootSymbol>> {
+    let file_id = root.file_id(uri)?;
+    let file = root.file(file_id);
+    if let Some(dst) = valueref::at(root, file_id, byte_offset(pos, &file.source)) {
+        return Some(vec![dst]);
+    }
+    if in_error_region(draft, pos) {
+        return fallback_definitions(root, draft, pos, file_id);
+    }
     let obj = find_text_object(draft, pos)?;
     info!("{:?}", obj);
 
-    let file_id = root.file_id(uri)?;
-    let _file = root.file(file_id);
     match obj.kind {
         TextObjectKind::ImportAlias => {
             for i in root.resolve(file_id, &obj.path.names) {
@@ -171,6 +257,19 @@ fn find_definitions(
             }]
         }),
         TextObjectKind::FeatureReference | TextObjectKind::AttributeReference => {
+            //the last segment resolves exactly like the checker resolved it
+            let offset = byte_offset(pos, &file.source);
+            let reference = file.all_references().find(|r| {
+                file.span(*r)
+                    .is_some_and(|s| s.start <= offset && offset <= s.end)
+            });
+            if let Some(r) = reference.filter(|r| obj.selected_segment + 1 >= file.path(*r).len()) {
+                match root.resolve_reference(file_id, r) {
+                    Resolution::Resolved(dst) => return Some(vec![dst]),
+                    Resolution::Ambiguous(candidates) => return Some(candidates),
+                    _ => {}
+                }
+            }
             for bind in root.resolve_with_binding(file_id, &obj.path.names) {
                 let last = bind.last().unwrap().0;
                 let dst_file = root.file(last.file);
@@ -234,10 +333,8 @@ pub fn goto_definition(
             .filter_map(|sym| {
                 let file = root.file(sym.file);
                 match sym.sym {
-                    Symbol::Root => Some(Location {
-                        uri: file.uri.clone(),
-                        range: Range::default(),
-                    }),
+                    //imports and namespaces jump to the root feature of the model
+                    Symbol::Root => Some(root.root_location(sym.file)),
                     _ => {
                         let range = file.lsp_range(sym.sym)?;
                         Some(Location {
@@ -251,38 +348,38 @@ pub fn goto_definition(
     ))
 }
 
-fn reverse_resolve(
-    root: &Snapshot,
-    dst_file: &Document,
-    dst_id: FileID,
-    tgt: Symbol,
-) -> Vec<RootSymbol> {
-    let ty = dst_file.type_of(tgt);
-
+//References in src_id resolving to dst
+fn references_to(root: &Snapshot, src_id: FileID, dst: RootSymbol) -> Vec<Symbol> {
+    let ty = root.file(dst.file).type_of(dst.sym);
+    let src_file = root.file(src_id);
+    src_file
+        .all_references()
+        .filter(|r| src_file.type_of(*r) == ty)
+        .filter(|r| {
+            root.resolve_sym(RootSymbol {
+                file: src_id,
+                sym: *r,
+            }) == Some(dst)
+        })
+        .collect()
+}
+pub fn reverse_resolve(root: &Snapshot, dst_id: FileID, tgt: Symbol) -> Vec<RootSymbol> {
+    let dst = RootSymbol {
+        file: dst_id,
+        sym: tgt,
+    };
     root.imported(dst_id)
         .iter()
         .flat_map(|&src_id| {
-            let src_file = root.file(src_id);
-            src_file
-                .all_references()
-                .filter(move |r| src_file.type_of(*r) == ty)
-                .filter(move |r| {
-                    root.resolve(src_id, src_file.path(*r)).any(|sym| {
-                        sym == RootSymbol {
-                            file: dst_id,
-                            sym: tgt,
-                        }
-                    })
-                })
-                .map(move |i| RootSymbol {
-                    file: src_id,
-                    sym: i,
-                })
+            references_to(root, src_id, dst)
+                .into_iter()
+                .map(move |sym| RootSymbol { file: src_id, sym })
         })
         .collect()
 }
 
-fn find_references_symboles(
+//Symbols whose references are searched from pos
+fn reference_targets(
     root: &Snapshot,
     draft: &Draft,
     pos: &Position,
@@ -290,58 +387,575 @@ fn find_references_symboles(
 ) -> Option<Vec<RootSymbol>> {
     let file_id = root.file_id(uri)?;
     let file = root.file(file_id);
+    if let Some(dst) = valueref::at(root, file_id, byte_offset(pos, &file.source)) {
+        return Some(vec![dst]);
+    }
+    if in_error_region(draft, pos) {
+        return fallback_definitions(root, draft, pos, file_id);
+    }
     let obj = find_text_object(draft, pos)?;
     info!("{:?}", obj);
-    match obj.kind {
-        TextObjectKind::Feature => file
-            .lookup(Symbol::Root, &obj.path.names, |sym| {
-                matches!(sym, Symbol::Feature(..))
-            })
-            .next()
-            .map(|f| reverse_resolve(root, file, file_id, f)),
-
-        TextObjectKind::Attribute => file
-            .lookup(Symbol::Root, &obj.path.names, |sym| {
-                matches!(sym, Symbol::Feature(..) | Symbol::Attribute(..))
-            })
+    let declared = |filter: fn(&Symbol) -> bool| {
+        file.lookup(Symbol::Root, &obj.path.names, |sym| filter(&sym))
             .next()
-            .map(|a| reverse_resolve(root, file, file_id, a)),
-
+            .map(|sym| vec![RootSymbol { file: file_id, sym }])
+    };
+    match obj.kind {
+        TextObjectKind::Feature => declared(|sym| matches!(sym, Symbol::Feature(..))),
+        TextObjectKind::Attribute => {
+            declared(|sym| matches!(sym, Symbol::Feature(..) | Symbol::Attribute(..)))
+        }
         TextObjectKind::FeatureReference
         | TextObjectKind::AttributeReference
-        | TextObjectKind::Aggregate(..) => find_definitions(root, draft, pos, uri).map(|defs| {
-            defs.iter()
-                .flat_map(|def| reverse_resolve(root, root.file(def.file), def.file, def.sym))
-                .collect()
-        }),
+        | TextObjectKind::Aggregate(..) => find_definitions(root, draft, pos, uri),
         _ => None,
     }
 }
+//Groups and constraints are not referenced, outside of names and paths the members of the
+//group or the declarations the constraint mentions are listed instead
+fn anchor_references(root: &Snapshot, pos: &Position, uri: &Url) -> Option<Vec<Location>> {
+    let file_id = root.file_id(uri)?;
+    let file = root.file(file_id);
+    let offset = byte_offset(pos, &file.source);
+    let node = file
+        .tree()
+        .root_node()
+        .descendant_for_byte_range(offset, offset)?;
+    let on_symbol = |n: Node| matches!(n.kind(), "name" | "path");
+    if on_symbol(node) || node.parent().is_some_and(on_symbol) {
+        return None;
+    }
+    let contains = |sym: &Symbol| {
+        file.span(*sym)
+            .is_some_and(|s| s.start <= offset && offset <= s.end)
+    };
+    let location = |file: &Document, sym: Symbol| {
+        Some(Location {
+            uri: file.uri.clone(),
+            range: file.lsp_range(sym)?,
+        })
+    };
+    if let Some(group) = file.all_groups().find(contains) {
+        return Some(
+            file.direct_children(group)
+                .filter(|sym| matches!(sym, Symbol::Feature(..) | Symbol::Reference(..)))
+                .filter_map(|sym| location(file, sym))
+                .collect(),
+        );
+    }
+    let span = file.span(file.all_constraints().find(contains)?)?;
+    let mut out: Vec<Location> = Vec::new();
+    for r in file.all_references().filter(|r| {
+        file.span(*r)
+            .is_some_and(|s| span.start <= s.start && s.end <= span.end)
+    }) {
+        let dst = root.resolve_sym(RootSymbol {
+            file: file_id,
+            sym: r,
+        });
+        if let Some(loc) = dst.and_then(|dst| location(root.file(dst.file), dst.sym)) {
+            if !out.contains(&loc) {
+                out.push(loc);
+            }
+        }
+    }
+    Some(out)
+}
+//Files searched between two partial results and progress reports
+pub const REFERENCE_BATCH: usize = 16;
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PartialReferencesParams {
+    pub token: ProgressToken,
+    pub value: Vec<Location>,
+}
+//Partial result of textDocument/references, sent with the partialResultToken of the request
+pub enum PartialReferences {}
+impl notification::Notification for PartialReferences {
+    type Params = PartialReferencesParams;
+    const METHOD: &'static str = "$/progress";
+}
+pub enum ReferenceSearch {
+    Done(Vec<Location>),
+    //References of the targets are searched in the files, one at a time so the search can be
+    //reported in batches
+    Files {
+        targets: Vec<RootSymbol>,
+        files: Vec<FileID>,
+    },
+}
+pub fn reference_search(
+    root: &Snapshot,
+    draft: &Draft,
+    pos: &Position,
+    uri: &Url,
+) -> Option<ReferenceSearch> {
+    if let Some(anchored) = anchor_references(root, pos, uri) {
+        return Some(ReferenceSearch::Done(anchored));
+    }
+    let targets = reference_targets(root, draft, pos, uri)?;
+    let mut files: Vec<FileID> = Vec::new();
+    for f in targets.iter().flat_map(|def| root.imported(def.file)) {
+        if !files.contains(&f) {
+            files.push(f);
+        }
+    }
+    Some(ReferenceSearch::Files { targets, files })
+}
+//References of the targets in one file, including strings of configured attributes, see
+//valueref.rs
+pub fn references_in(root: &Snapshot, targets: &[RootSymbol], src_id: FileID) -> Vec<Location> {
+    let file = root.file(src_id);
+    let location = |range| Location {
+        uri: file.uri.clone(),
+        range,
+    };
+    let mut out: Vec<Location> = targets
+        .iter()
+        .flat_map(|def| references_to(root, src_id, *def))
+        .filter_map(|sym| file.lsp_range(sym).map(location))
+        .collect();
+    for span in targets
+        .iter()
+        .flat_map(|def| valueref::occurrences_in(root, *def, src_id))
+    {
+        if let Some(range) = lsp_range(span, &file.source) {
+            out.push(location(range));
+        }
+    }
+    out
+}
 pub fn find_references(
     root: &Snapshot,
     draft: &Draft,
     pos: &Position,
     uri: &Url,
 ) -> Option<Vec<Location>> {
-    let refs = find_references_symboles(root, draft, pos, uri)?;
+    match reference_search(root, draft, pos, uri)? {
+        ReferenceSearch::Done(locations) => Some(locations),
+        ReferenceSearch::Files { targets, files } => Some(
+            files
+                .iter()
+                .flat_map(|f| references_in(root, &targets, *f))
+                .collect(),
+        ),
+    }
+}
+//Feature or attribute renamed from pos, either its declaration or a reference to it
+pub fn rename_target(
+    root: &Snapshot,
+    draft: &Draft,
+    pos: &Position,
+    uri: &Url,
+) -> Option<RootSymbol> {
+    let file_id = root.file_id(uri)?;
+    let file = root.file(file_id);
+    if let Some(dst) = valueref::at(root, file_id, byte_offset(pos, &file.source)) {
+        return Some(dst);
+    }
+    let obj = find_text_object(draft, pos)?;
+    let target = match obj.kind {
+        TextObjectKind::Feature | TextObjectKind::Attribute => file
+            .lookup(Symbol::Root, &obj.path.names, |sym| {
+                matches!(sym, Symbol::Feature(..) | Symbol::Attribute(..))
+            })
+            .next()
+            .map(|sym| RootSymbol { file: file_id, sym })?,
+        TextObjectKind::FeatureReference | TextObjectKind::AttributeReference => {
+            match find_definitions(root, draft, pos, uri)?.as_slice() {
+                [def] => *def,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    if matches!(target.sym, Symbol::Feature(..) | Symbol::Attribute(..)) {
+        Some(target)
+    } else {
+        None
+    }
+}
+#[derive(Deserialize, Debug, Clone)]
+pub struct ConstraintsArgs {
+    pub uri: Url,
+    //Feature declared in the model at uri
+    pub feature: String,
+}
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ConstraintReference {
+    pub location: Location,
+    pub text: String,
+}
+//Constraints mentioning a feature in any file importing it, attribute constraints included.
+//Sorted by file and position, a constraint is listed once however often it mentions the feature
+pub fn constraints_referencing(
+    root: &Snapshot,
+    file_id: FileID,
+    feature: &str,
+) -> Option<Vec<ConstraintReference>> {
+    let file = root.file(file_id);
+    let path = [Ustr::from(feature)];
+    let feature = file
+        .lookup(Symbol::Root, &path, |sym| {
+            matches!(sym, Symbol::Feature(..))
+        })
+        .next()?;
+    let mut out: Vec<(FileID, Symbol)> = reverse_resolve(root, file_id, feature)
+        .into_iter()
+        .filter_map(|r| {
+            let src = root.file(r.file);
+            let span = src.span(r.sym)?;
+            //references inside constraints are not linked to them, the spans are nested
+            src.all_constraints()
+                .find(|c| {
+                    src.span(*c)
+                        .is_some_and(|c| c.start <= span.start && span.end <= c.end)
+                })
+                .map(|c| (r.file, c))
+        })
+        .collect();
+    out.sort_by_key(|(file, c)| {
+        (
+            root.file(*file).uri.clone(),
+            root.file(*file).span(*c).map(|s| s.start),
+        )
+    });
+    out.dedup();
     Some(
-        refs.iter()
-            .filter_map(|sym| {
-                let file = root.file(sym.file);
-                match sym.sym {
-                    Symbol::Root => Some(Location {
-                        uri: file.uri.clone(),
-                        range: Range::default(),
-                    }),
-                    _ => {
-                        let range = file.lsp_range(sym.sym)?;
-                        Some(Location {
-                            uri: file.uri.clone(),
-                            range,
-                        })
-                    }
-                }
+        out.into_iter()
+            .filter_map(|(file, c)| {
+                let src = root.file(file);
+                Some(ConstraintReference {
+                    location: Location {
+                        uri: src.uri.clone(),
+                        range: src.lsp_range(c)?,
+                    },
+                    text: src.source.byte_slice(src.span(c)?).to_string(),
+                })
             })
             .collect(),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::build_document;
+    use std::sync::Arc;
+    use tokio::time::Instant;
+    //Definition ranges for the cursor placed at the first occurence of needle
+    fn definition(text: &str, needle: &str) -> Vec<Range> {
+        definition_in(text, &[], needle)
+            .into_iter()
+            .map(|l| l.range)
+            .collect()
+    }
+    //Same with other files next to the one containing the cursor
+    fn definition_in(text: &str, others: &[(&str, &str)], needle: &str) -> Vec<Location> {
+        let load = |uri: &Url, text: &str| {
+            let source = Rope::from_str(text);
+            let tree = parse(&source, None);
+            let doc = build_document(
+                source,
+                tree,
+                uri.clone(),
+                Instant::now(),
+                Default::default(),
+            );
+            (uri.clone(), Arc::new(doc))
+        };
+        let uri = Url::parse("file:///utf16.uvl").unwrap();
+        let mut files: hashbrown::HashMap<Url, Arc<Document>> = others
+            .iter()
+            .map(|(name, text)| load(&Url::parse(name).unwrap(), text))
+            .collect();
+        files.extend([load(&uri, text)]);
+        let root = tokio::sync::RwLock::new(RootGraph::new(&files, 0));
+        let source = Rope::from_str(text);
+        let tree = parse(&source, None);
+        let (line, column) = text
+            .lines()
+            .enumerate()
+            .find_map(|(i, l)| l.find(needle).map(|c| (i, l[..c].encode_utf16().count())))
+            .unwrap();
+        let draft = Draft::Tree {
+            source,
+            tree,
+            revision: Instant::now(),
+        };
+        let pos = Position::new(line as u32, column as u32 + 1);
+        let snapshot = root.try_read().unwrap();
+        match goto_definition(&snapshot, &draft, &pos, &uri) {
+            Some(GotoDefinitionResponse::Array(locations)) => locations,
+            _ => Vec::new(),
+        }
+    }
+    #[test]
+    fn multibyte_before_cursor() {
+        let text = "features\n    Äpfel {größe 3}\n    Car {größe 5}\nconstraints\n    Äpfel.größe > Car.größe\n";
+        //Ä and ö take two bytes but only one utf16 unit
+        assert_eq!(
+            definition(text, "Car.größe"),
+            vec![Range {
+                start: Position::new(2, 4),
+                end: Position::new(2, 7)
+            }]
+        );
+        assert_eq!(
+            definition(text, "größe > Car"),
+            vec![Range {
+                start: Position::new(1, 11),
+                end: Position::new(1, 16)
+            }]
+        );
+    }
+    #[test]
+    fn broken_constraint() {
+        let text = "features\n    Car {size 4}\n        optional\n            Engine\n            Wheel {size 3, constraint size > (}\nconstraints\n    Car => (Engine & \n    Wheel.size > (Car.\n";
+        let engine = Range {
+            start: Position::new(3, 12),
+            end: Position::new(3, 18),
+        };
+        assert_eq!(definition(text, "Engine & "), vec![engine]);
+        //the segment under the cursor of a half typed path
+        assert_eq!(
+            definition(text, "size > (Car"),
+            vec![Range {
+                start: Position::new(4, 19),
+                end: Position::new(4, 23)
+            }]
+        );
+        assert_eq!(
+            definition(text, "Car."),
+            vec![Range {
+                start: Position::new(1, 4),
+                end: Position::new(1, 7)
+            }]
+        );
+        //attribute constraints resolve from their feature
+        assert_eq!(
+            definition(text, "size > (}"),
+            vec![Range {
+                start: Position::new(4, 19),
+                end: Position::new(4, 23)
+            }]
+        );
+        //operators resolve to nothing instead of the expression around them
+        assert!(definition(text, "=> (Engine").is_empty());
+    }
+    #[test]
+    fn import_root() {
+        let text = "imports\n    sub as s\nfeatures\n    Main\n        optional\n            A\nconstraints\n    s.Leaf => A\n";
+        let sub = (
+            "file:///sub.uvl",
+            "features\n    Sub\n        optional\n            Leaf\n",
+        );
+        let root = Location {
+            uri: Url::parse(sub.0).unwrap(),
+            range: Range {
+                start: Position::new(1, 4),
+                end: Position::new(1, 7),
+            },
+        };
+        assert_eq!(definition_in(text, &[sub], "sub as"), vec![root.clone()]);
+        assert_eq!(definition_in(text, &[sub], " s.Leaf"), vec![root]);
+    }
+    #[test]
+    fn group_and_constraint_anchors() {
+        let text = "features\n    Car {cost 3}\n        optional\n            Engine\n            Radio\nconstraints\n    Engine & Car.cost > 2 => Radio\n";
+        let uri = Url::parse("file:///anchors.uvl").unwrap();
+        let source = Rope::from_str(text);
+        let tree = parse(&source, None);
+        let doc = build_document(
+            source.clone(),
+            tree.clone(),
+            uri.clone(),
+            Instant::now(),
+            Default::default(),
+        );
+        let files = [(uri.clone(), Arc::new(doc))].into_iter().collect();
+        let root = tokio::sync::RwLock::new(RootGraph::new(&files, 0));
+        let snapshot = root.try_read().unwrap();
+        let draft = Draft::Tree {
+            source,
+            tree,
+            revision: Instant::now(),
+        };
+        let lines = |pos: Position| {
+            find_references(&snapshot, &draft, &pos, &uri)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|l| l.range.start.line)
+                .collect::<Vec<_>>()
+        };
+        //on the group header
+        assert_eq!(lines(Position::new(2, 10)), vec![3, 4]);
+        //on the operator, the declarations of Engine, cost and Radio
+        assert_eq!(lines(Position::new(6, 12)), vec![3, 1, 4]);
+        //on a path the references of the symbol
+        assert_eq!(lines(Position::new(6, 6)), vec![6]);
+    }
+    #[test]
+    fn batched_references() {
+        let load = |uri: &Url, text: &str| {
+            let source = Rope::from_str(text);
+            let tree = parse(&source, None);
+            let doc = build_document(
+                source.clone(),
+                tree.clone(),
+                uri.clone(),
+                Instant::now(),
+                Default::default(),
+            );
+            (
+                doc,
+                Draft::Tree {
+                    source,
+                    tree,
+                    revision: Instant::now(),
+                },
+            )
+        };
+        let sub = Url::parse("file:///sub.uvl").unwrap();
+        let main = Url::parse("file:///main.uvl").unwrap();
+        let (sub_doc, draft) = load(
+            &sub,
+            "features\n    Sub\n        optional\n            Leaf\nconstraints\n    Leaf\n",
+        );
+        let (main_doc, _) = load(
+            &main,
+            "imports\n    sub\nfeatures\n    Main\nconstraints\n    sub.Leaf => Main\n",
+        );
+        let files = [
+            (sub.clone(), Arc::new(sub_doc)),
+            (main.clone(), Arc::new(main_doc)),
+        ]
+        .into_iter()
+        .collect();
+        let root = tokio::sync::RwLock::new(RootGraph::new(&files, 0));
+        let snapshot = root.try_read().unwrap();
+        let pos = Position::new(3, 14);
+        let (targets, searched) = match reference_search(&snapshot, &draft, &pos, &sub) {
+            Some(ReferenceSearch::Files { targets, files }) => (targets, files),
+            _ => panic!("expected a file search"),
+        };
+        assert_eq!(searched.len(), 2);
+        let batched: Vec<Location> = searched
+            .iter()
+            .flat_map(|f| references_in(&snapshot, &targets, *f))
+            .collect();
+        assert_eq!(batched.len(), 2);
+        assert_eq!(
+            Some(batched),
+            find_references(&snapshot, &draft, &pos, &sub)
+        );
+    }
+    #[test]
+    fn feature_constraints() {
+        let load = |uri: &str, text: &str| {
+            let uri = Url::parse(uri).unwrap();
+            let source = Rope::from_str(text);
+            let tree = parse(&source, None);
+            let doc = build_document(
+                source,
+                tree,
+                uri.clone(),
+                Instant::now(),
+                Default::default(),
+            );
+            (uri, Arc::new(doc))
+        };
+        let files: hashbrown::HashMap<Url, Arc<Document>> = [
+            load(
+                "file:///main.uvl",
+                "imports\n    sub as s\nfeatures\n    Main\n        optional\n            A\nconstraints\n    s.Leaf => A\n    A\n",
+            ),
+            load(
+                "file:///sub.uvl",
+                "features\n    Sub {constraint Leaf | !Leaf}\n        optional\n            Leaf\nconstraints\n    Leaf => Sub\n",
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let root = tokio::sync::RwLock::new(RootGraph::new(&files, 0));
+        let snapshot = root.try_read().unwrap();
+        let sub = snapshot
+            .file_id(&Url::parse("file:///sub.uvl").unwrap())
+            .unwrap();
+        let found: Vec<(String, u32, String)> = constraints_referencing(&snapshot, sub, "Leaf")
+            .unwrap()
+            .into_iter()
+            .map(|c| {
+                (
+                    c.location.uri.path().to_string(),
+                    c.location.range.start.line,
+                    c.text,
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("/main.uvl".to_string(), 7, "s.Leaf => A".to_string()),
+                ("/sub.uvl".to_string(), 1, "Leaf | !Leaf".to_string()),
+                ("/sub.uvl".to_string(), 5, "Leaf => Sub".to_string()),
+            ]
+        );
+        assert!(constraints_referencing(&snapshot, sub, "Gone").is_none());
+    }
+    #[test]
+    fn rename_preview() {
+        let load = |uri: &str, text: &str| {
+            let uri = Url::parse(uri).unwrap();
+            let source = Rope::from_str(text);
+            let tree = parse(&source, None);
+            let doc = build_document(
+                source,
+                tree,
+                uri.clone(),
+                Instant::now(),
+                Default::default(),
+            );
+            (uri, Arc::new(doc))
+        };
+        let main = "imports\n    sub as s\nfeatures\n    Main\n        optional\n            A\nconstraints\n    s.Leaf => A\n    s.Leaf.cost > 1\n";
+        let files: hashbrown::HashMap<Url, Arc<Document>> = [
+            load("file:///main.uvl", main),
+            load(
+                "file:///sub.uvl",
+                "features\n    Sub {constraint Leaf | !Leaf}\n        optional\n            Leaf {cost 2}\nconstraints\n    Leaf => Sub\n",
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let root = tokio::sync::RwLock::new(RootGraph::new(&files, 0));
+        let snapshot = root.try_read().unwrap();
+        let uri = Url::parse("file:///main.uvl").unwrap();
+        let source = Rope::from_str(main);
+        let tree = parse(&source, None);
+        let draft = Draft::Tree {
+            source,
+            tree,
+            revision: Instant::now(),
+        };
+        //the cursor on the last segment of s.Leaf
+        let pos = Position::new(7, 7);
+        let edit = crate::actions::rename(&snapshot, &draft, &pos, &uri, "Blade").unwrap();
+        let preview = crate::actions::rename_preview(edit);
+        let files: Vec<(String, usize)> = preview
+            .files
+            .iter()
+            .map(|f| (f.uri.path().to_string(), f.edits))
+            .collect();
+        assert_eq!(
+            files,
+            vec![("/main.uvl".to_string(), 2), ("/sub.uvl".to_string(), 3)]
+        );
+        assert_eq!(preview.edits, 5);
+        //the edits only touch the renamed segment
+        let main_edits = &preview.edit.changes.as_ref().unwrap()[&uri];
+        assert!(main_edits.iter().all(|e| e.range.start.character == 6));
+        //operators are not renamed
+        let operator = Position::new(7, 12);
+        assert!(crate::actions::rename(&snapshot, &draft, &operator, &uri, "X").is_none());
+    }
+}
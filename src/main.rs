@@ -3,31 +3,55 @@
 use dashmap::DashMap;
 use document::{AsyncDraft, Draft, DraftSync};
 use flexi_logger::FileSpec;
+use futures::future::{BoxFuture, FutureExt, Shared};
 
 use tokio::{join, spawn};
 
 use document::*;
 use log::info;
+use ropey::Rope;
 use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::time::Instant;
+use tokio::time::{Duration, Instant};
 use tokio_util::sync::CancellationToken;
 use tower_lsp::jsonrpc::Result;
 use tower_lsp::lsp_types::*;
-use tower_lsp::{Client, LanguageServer, LspService, Server};
+use tower_lsp::{Client, ClientSocket, LanguageServer, LspService, Server};
 mod document;
 
+mod actions;
 mod ast;
+mod cache;
+mod capabilities;
 mod check;
+mod cli;
 mod color;
 mod completion;
+mod configtree;
+mod diff;
+mod export;
+mod format;
+mod hover;
+mod infer;
+mod inherit;
 mod location;
+mod normal;
 mod parse;
+mod polling;
 mod query;
+mod scan;
+mod schema;
 mod semantic;
+mod session;
+mod settings;
 mod smt;
+mod summary;
+mod symbols;
+mod templates;
 mod util;
+mod valueref;
 use semantic::Snapshot;
 static VERSION: &str = "v0.0.10";
 //The server core, request and respones handling
@@ -36,6 +60,14 @@ struct Backend {
     coloring: Arc<color::State>,
     documents: Arc<DashMap<Url, AsyncDraft>>,
     semantic: Arc<semantic::Context>,
+    //What the client declared in initialize
+    client_support: parking_lot::RwLock<capabilities::ClientSupport>,
+    //Files on disk are polled because the client does not watch them
+    polling: Arc<AtomicBool>,
+    config: parking_lot::Mutex<settings::ConfigSources>,
+    sessions: Arc<session::Sessions>,
+    //Reloads of documents changed on disk, concurrent requests wait for the same reload
+    reloads: Arc<DashMap<Url, Shared<BoxFuture<'static, ()>>>>,
 }
 impl Backend {
     async fn sync_draft(
@@ -44,6 +76,21 @@ impl Backend {
         sync: DraftSync,
         deadline: Option<Instant>,
     ) -> Option<Draft> {
+        let state = self.documents.get(uri).map(|d| d.state)?;
+        if changed_on_disk(uri, state).await {
+            //only happens when the client requests files it never opened
+            info!(
+                "stale read: {} changed on disk without didOpen, reloading before answering",
+                uri
+            );
+            let reload_deadline = Instant::now() + Duration::from_millis(500);
+            if tokio::time::timeout_at(reload_deadline, self.reload(uri))
+                .await
+                .is_err()
+            {
+                info!("stale read: reloading {} timed out", uri);
+            }
+        }
         let mut draft = self.documents.get(uri).map(|d| d.clone())?;
         if let Some(deadline) = deadline {
             draft.sync(sync, deadline).await
@@ -51,37 +98,61 @@ impl Backend {
             draft.wait(sync).await
         }
     }
-    async fn remove(&self, uri: &Url, by_editor: bool) {
-        let time = Instant::now();
-        if self
-            .documents
-            .remove_if(uri, |_, v| {
-                by_editor || v.state != DocumentState::OwnedByEditor
+    //Reload uri from disk, joining a reload already running for it
+    fn reload(&self, uri: &Url) -> Shared<BoxFuture<'static, ()>> {
+        self.reloads
+            .entry(uri.clone())
+            .or_insert_with(|| {
+                let documents = self.documents.clone();
+                let semantic = self.semantic.clone();
+                let reloads = self.reloads.clone();
+                let uri = uri.clone();
+                let reload = tokio::task::spawn_blocking(move || {
+                    load_blocking(uri.clone(), &documents, &semantic);
+                    reloads.remove(&uri);
+                });
+                async move {
+                    let _ = reload.await;
+                }
+                .boxed()
+                .shared()
             })
-            .is_some()
-        {
-            self.semantic
-                .documents
-                .lock()
-                .send_modify(|docs| docs.delete(uri, time));
-        }
+            .clone()
+    }
+    async fn remove(&self, uri: &Url, by_editor: bool) {
+        remove_document(&self.documents, &self.semantic, uri, by_editor);
     }
     fn load(&self, uri: &Url) {
-        if self
-            .documents
-            .get(uri)
-            .map(|doc| doc.state == DocumentState::OwnedByEditor)
-            .unwrap_or(false)
-        {
+        load_document(&self.documents, &self.semantic, uri);
+    }
+    async fn start_polling(&self) {
+        if !self.semantic.settings.read().file_polling {
+            info!("file polling is disabled, changes on disk are not noticed");
             return;
         }
-        let documents = self.documents.clone();
-        let semantic = self.semantic.clone();
-        let uri = uri.clone();
-
-        tokio::task::spawn_blocking(move || {
-            load_blocking(uri, &documents, &semantic);
-        });
+        let root = self
+            .config
+            .lock()
+            .workspace_file
+            .as_ref()
+            .and_then(|file| file.parent())
+            .map(PathBuf::from);
+        self.polling.store(true, Ordering::Relaxed);
+        spawn(polling::poll_files(
+            self.documents.clone(),
+            self.semantic.clone(),
+            root,
+            self.polling.clone(),
+        ));
+        self.client
+            .show_message(
+                MessageType::INFO,
+                format!(
+                    "UVLS: the editor does not report file changes, files on disk are checked every {} seconds",
+                    polling::POLL_INTERVAL.as_secs()
+                ),
+            )
+            .await;
     }
     async fn snapshot(&self, uri: &Url, sync: bool) -> Option<(Draft, Snapshot)> {
         if let Some(draft) = self.sync_draft(uri, DraftSync::Tree, None).await {
@@ -97,6 +168,336 @@ impl Backend {
             None
         }
     }
+    //Model from its draft if the server knows it, otherwise from disk
+    async fn load_model(&self, uri: &Url) -> Option<ast::Document> {
+        let source = match self.sync_draft(uri, DraftSync::Source, None).await {
+            Some(Draft::Source { source, .. } | Draft::Tree { source, .. }) => source,
+            _ => {
+                let path = uri.to_file_path().ok()?;
+                Rope::from_str(&tokio::fs::read_to_string(path).await.ok()?)
+            }
+        };
+        let tree = parse::parse(&source, None);
+        let limits = self.semantic.settings.read().limits();
+        Some(build_document(
+            source,
+            tree,
+            uri.clone(),
+            Instant::now(),
+            limits,
+        ))
+    }
+    //Report progress on a token the client created, nothing without one
+    async fn work_done(&self, token: &Option<ProgressToken>, value: WorkDoneProgress) {
+        if let Some(token) = token {
+            self.client
+                .send_notification::<notification::Progress>(ProgressParams {
+                    token: token.clone(),
+                    value: ProgressParamsValue::WorkDone(value),
+                })
+                .await;
+        }
+    }
+    //Shared by textDocument/rename and its preview command so both compute the same edit
+    async fn rename_edit(
+        &self,
+        uri: &Url,
+        pos: &Position,
+        new_name: &str,
+    ) -> Result<Option<WorkspaceEdit>> {
+        if !actions::valid_name(new_name) {
+            return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                "expected a name of letters, digits and underscores",
+            ));
+        }
+        let edit = match self.snapshot(uri, true).await {
+            Some((draft, root)) => actions::rename(&root, &draft, pos, uri, new_name),
+            None => None,
+        };
+        let settings = self.semantic.settings.read();
+        if edit
+            .iter()
+            .flat_map(|edit| edit.changes.iter().flat_map(|c| c.keys()))
+            .any(|uri| settings.is_library(uri))
+        {
+            return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                "library models are read-only",
+            ));
+        }
+        Ok(edit)
+    }
+    //Apply the client settings merged with the workspace file, files which became library
+    //models lose their diagnostics
+    async fn apply_settings(&self) {
+        let settings = self.config.lock().effective();
+        let library: Vec<Url> = self
+            .semantic
+            .root
+            .read()
+            .await
+            .iter_files()
+            .filter(|(_, file)| settings.is_library(&file.uri))
+            .map(|(_, file)| file.uri.clone())
+            .collect();
+        let (recheck, rebuild) = {
+            let old = self.semantic.settings.read();
+            let recheck = old.schema != settings.schema
+                || old.inherit_attributes != settings.inherit_attributes
+                || old.reference_attributes != settings.reference_attributes;
+            (recheck, old.limits() != settings.limits())
+        };
+        self.semantic.set_settings(settings);
+        for uri in library {
+            self.client.publish_diagnostics(uri, vec![], None).await;
+        }
+        //syntax level diagnostics depend on the limits, documents keep the ones they were built
+        //with
+        if rebuild {
+            self.semantic.rebuild_documents().await;
+        }
+        //schema, inheritance and value reference diagnostics of unchanged files are stale
+        //otherwise
+        if recheck || rebuild {
+            self.semantic.clear_analysis().await;
+        }
+    }
+    async fn reload_workspace_settings(&self) {
+        let (diagnostics, uri) = {
+            let mut config = self.config.lock();
+            let diagnostics = config.load_workspace();
+            let uri = config
+                .workspace_file
+                .as_ref()
+                .and_then(|path| Url::from_file_path(path).ok());
+            (diagnostics, uri)
+        };
+        if let Some(uri) = uri {
+            self.client
+                .publish_diagnostics(uri, diagnostics, None)
+                .await;
+        }
+        self.apply_settings().await;
+    }
+    //custom request, current server state for debugging
+    async fn status(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::json!({
+            "version": VERSION,
+            "grammar": check::GRAMMAR_VERSION,
+            "filePolling": self.polling.load(Ordering::Relaxed),
+            "solver": smt::SOLVER_POOL.status(),
+        }))
+    }
+    //custom request, counters of the caches, for checking they are effective
+    async fn perf(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::json!({
+            "composedModels": self.semantic.composed.status(),
+        }))
+    }
+    //custom request, types assigned by the checker, the format is documented in infer.rs
+    async fn infer_types(
+        &self,
+        params: infer::InferTypesParams,
+    ) -> Result<Option<infer::TypeInfo>> {
+        if let Some((_, root)) = self.snapshot(&params.text_document.uri, true).await {
+            Ok(infer::infer_types(&root, &params))
+        } else {
+            Ok(None)
+        }
+    }
+    //custom request, serialized syntax tree, the format is documented in export.rs
+    async fn syntax_model(
+        &self,
+        params: export::SyntaxModelParams,
+    ) -> Result<Option<export::SyntaxModel>> {
+        let uri = &params.text_document.uri;
+        if let Some((_, root)) = self.snapshot(uri, true).await {
+            Ok(root.file_id(uri).map(|file_id| {
+                let mut err = root.file(file_id).errors.clone();
+                if params.resolved {
+                    err.extend(root.reference_errors(file_id));
+                }
+                export::export(&root, file_id, params.resolved, &err)
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+    //custom request, feature tree with the state of every feature in a partial configuration,
+    //the format is documented in configtree.rs
+    async fn configuration_tree(
+        &self,
+        params: configtree::TreeParams,
+    ) -> Result<configtree::ConfigurationTree> {
+        let (_, root) = self
+            .snapshot(&params.text_document.uri, true)
+            .await
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("unknown document"))?;
+        configtree::configuration_tree(root, &params, &self.semantic.composed)
+            .await
+            .map_err(tower_lsp::jsonrpc::Error::invalid_params)
+    }
+    async fn config_session_start(&self, params: session::StartParams) -> Result<session::Delta> {
+        let uri = &params.text_document.uri;
+        let (_, root) = self
+            .snapshot(uri, true)
+            .await
+            .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("unknown document"))?;
+        self.sessions
+            .start(root, uri, &self.semantic.composed)
+            .await
+            .map_err(tower_lsp::jsonrpc::Error::invalid_params)
+    }
+    async fn config_session_set(&self, params: session::SetParams) -> Result<session::Delta> {
+        self.sessions
+            .set(&self.semantic.root, &params)
+            .await
+            .map_err(tower_lsp::jsonrpc::Error::invalid_params)
+    }
+    async fn config_session_undo(&self, params: session::SessionParams) -> Result<session::Delta> {
+        self.sessions
+            .undo(&self.semantic.root, params.session)
+            .await
+            .map_err(tower_lsp::jsonrpc::Error::invalid_params)
+    }
+    async fn config_session_finish(
+        &self,
+        params: session::SessionParams,
+    ) -> Result<session::Finished> {
+        self.sessions
+            .finish(&self.semantic.root, params.session)
+            .await
+            .map_err(tower_lsp::jsonrpc::Error::invalid_params)
+    }
+}
+//Cheap check for OS owned documents modified after they were loaded, a watcher reload racing
+//with this is fine since load_blocking only replaces documents with older modification times
+async fn changed_on_disk(uri: &Url, state: DocumentState) -> bool {
+    let loaded = match state {
+        DocumentState::OwnedByOs(loaded) => loaded,
+        DocumentState::OwnedByEditor => return false,
+    };
+    let path = match uri.to_file_path() {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|meta| meta.modified())
+        .is_ok_and(|modified| modified > loaded)
+}
+fn remove_document(
+    documents: &DashMap<Url, AsyncDraft>,
+    semantic: &semantic::Context,
+    uri: &Url,
+    by_editor: bool,
+) {
+    let time = Instant::now();
+    if documents
+        .remove_if(uri, |_, v| {
+            by_editor || v.state != DocumentState::OwnedByEditor
+        })
+        .is_some()
+    {
+        semantic
+            .documents
+            .lock()
+            .send_modify(|docs| docs.delete(uri, time));
+    }
+}
+fn load_document(
+    documents: &Arc<DashMap<Url, AsyncDraft>>,
+    semantic: &Arc<semantic::Context>,
+    uri: &Url,
+) {
+    if documents
+        .get(uri)
+        .map(|doc| doc.state == DocumentState::OwnedByEditor)
+        .unwrap_or(false)
+    {
+        return;
+    }
+    let documents = documents.clone();
+    let semantic = semantic.clone();
+    let uri = uri.clone();
+
+    tokio::task::spawn_blocking(move || {
+        load_blocking(uri, &documents, &semantic);
+    });
+}
+//Models changed on disk, reported by the client or found by polling
+async fn handle_file_event(
+    documents: &Arc<DashMap<Url, AsyncDraft>>,
+    semantic: &Arc<semantic::Context>,
+    event: FileEvent,
+) {
+    match event.typ {
+        FileChangeType::CREATED | FileChangeType::CHANGED => {
+            load_document(documents, semantic, &event.uri);
+        }
+        FileChangeType::DELETED => {
+            remove_document(documents, semantic, &event.uri, false);
+        }
+        _ => {}
+    }
+}
+//Read a model unless the editor owns it. Models the server does not know yet are restored
+//from the model cache if they did not change, the cached diagnostics are shown until they are
+//checked
+fn read_model(
+    uri: &Url,
+    documents: &DashMap<Url, AsyncDraft>,
+    semantic: &Arc<semantic::Context>,
+) -> std::io::Result<Option<(String, DocumentState, Option<ast::StoredDocument>)>> {
+    let path = uri
+        .to_file_path()
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a file"))?;
+    let mut f = std::fs::File::open(&path)?;
+    let modified = f.metadata()?.modified()?;
+    let state = DocumentState::OwnedByOs(modified);
+    let known = match documents.get(uri) {
+        Some(old) if !old.state.can_update(&state) => return Ok(None),
+        old => old.is_some(),
+    };
+    let mut data = String::new();
+    f.read_to_string(&mut data)?;
+    let (errors, stored) = match semantic.model_cache.lock().as_ref() {
+        Some(cache) if !known => (
+            cache.lookup(&path, modified).map(<[_]>::to_vec),
+            cache.restore(&path, modified),
+        ),
+        _ => (None, None),
+    };
+    if let Some(errors) = errors {
+        //every check including the model is newer than the current revision
+        let revision = semantic.documents.lock().borrow().revision;
+        let _ = semantic.tx_err.blocking_send(check::DiagnosticUpdate {
+            error_state: [(uri.clone(), errors)].into_iter().collect(),
+            timestamp: revision,
+        });
+    }
+    Ok(Some((data, state, stored)))
+}
+//The editor may have opened the file since it was read, its draft is kept then
+fn insert_draft<F: FnOnce() -> AsyncDraft>(
+    documents: &DashMap<Url, AsyncDraft>,
+    uri: &Url,
+    state: DocumentState,
+    draft: F,
+) -> bool {
+    match documents.entry(uri.clone()) {
+        dashmap::mapref::entry::Entry::Vacant(e) => {
+            e.insert(draft());
+            true
+        }
+        dashmap::mapref::entry::Entry::Occupied(mut e) => {
+            if e.get().state.can_update(&state) {
+                e.insert(draft());
+                true
+            } else {
+                false
+            }
+        }
+    }
 }
 //load a file this is tricky because the editor can also load it at the same time
 fn load_blocking(
@@ -104,74 +505,91 @@ fn load_blocking(
     documents: &DashMap<Url, AsyncDraft>,
     semantic: &Arc<semantic::Context>,
 ) {
-    if std::fs::File::open(uri.path())
-        .and_then(|mut f| {
-            let meta = f.metadata()?;
-            let modified = meta.modified()?;
-            if let Some(old) = documents.get(&uri) {
-                if !old.state.can_update(&DocumentState::OwnedByOs(modified)) {
-                    return Ok(());
-                }
+    match read_model(&uri, documents, semantic) {
+        Ok(Some((data, state, Some(stored)))) => {
+            let (draft, doc) = AsyncDraft::open_stored(data, state, uri.clone(), stored);
+            if insert_draft(documents, &uri, state, || draft) {
+                semantic
+                    .documents
+                    .lock()
+                    .send_modify(|docs| docs.update_all(vec![doc]));
             }
-
-            let mut data = String::new();
-            f.read_to_string(&mut data)?;
-            match documents.entry(uri.clone()) {
-                dashmap::mapref::entry::Entry::Vacant(e) => {
-                    e.insert(AsyncDraft::open(
-                        data,
-                        DocumentState::OwnedByOs(modified),
-                        uri.clone(),
-                        semantic.clone(),
-                    ));
-                }
-                dashmap::mapref::entry::Entry::Occupied(mut e) => {
-                    if e.get()
-                        .state
-                        .can_update(&DocumentState::OwnedByOs(modified))
-                    {
-                        e.insert(AsyncDraft::open(
-                            data,
-                            DocumentState::OwnedByOs(modified),
-                            uri.clone(),
-                            semantic.clone(),
-                        ));
-                    }
-                }
-            }
-            Ok(())
-        })
-        .is_err()
-    {
-        info!("Failed to load file {}", uri);
+        }
+        Ok(Some((data, state, None))) => {
+            insert_draft(documents, &uri, state, || {
+                AsyncDraft::open(data, state, uri.clone(), semantic.clone())
+            });
+        }
+        Ok(None) => {}
+        Err(_) => info!("Failed to load file {}", uri),
     }
 }
-//load all files under given a path
+//load all files under given a path, the editor keeps parsing its own files on the runtime
+//meanwhile and wins over the scan when both load a file
 fn load_all_blocking(
     path: &Path,
     documents: Arc<DashMap<Url, AsyncDraft>>,
     semantic: Arc<semantic::Context>,
 ) {
-    for e in walkdir::WalkDir::new(path)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.path().is_file())
-        .filter(|e| {
-            e.path()
-                .extension()
-                .map(|e| e == std::ffi::OsStr::new("uvl"))
-                .unwrap_or(false)
-        })
-    {
-        let semantic = semantic.clone();
-        let documents = documents.clone();
-
-        load_blocking(
-            Url::from_file_path(e.path()).unwrap(),
-            &documents,
-            &semantic,
-        )
-    }
+    let t = Instant::now();
+    let workers = scan::workers(semantic.settings.read().scan_workers);
+    let paths = scan::discover(path);
+    let count = paths.len();
+    scan::parallel(
+        paths,
+        workers,
+        scan::BATCH,
+        |path| {
+            //the linker waits until a batch is handed over
+            let permit = semantic.parser_active.take();
+            let uri = Url::from_file_path(&path).ok()?;
+            let (data, state, stored) = match read_model(&uri, &documents, &semantic) {
+                Ok(Some(model)) => model,
+                Ok(None) => return None,
+                Err(_) => {
+                    info!("Failed to load file {}", uri);
+                    return None;
+                }
+            };
+            let (draft, doc) = match stored {
+                Some(stored) => AsyncDraft::open_stored(data, state, uri.clone(), stored),
+                None => {
+                    let limits = semantic.settings.read().limits();
+                    AsyncDraft::open_blocking(data, state, uri.clone(), limits)
+                }
+            };
+            if insert_draft(&documents, &uri, state, || draft) {
+                Some((doc, permit))
+            } else {
+                None
+            }
+        },
+        |batch| {
+            let (docs, permits): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+            drop(permits);
+            semantic
+                .documents
+                .lock()
+                .send_modify(|state| state.update_all(docs));
+        },
+    );
+    info!(
+        "scanned {} models on {} threads in {:?}",
+        count,
+        workers,
+        t.elapsed()
+    );
+}
+//The log is written to the temp dir, clients can ask for it with uvls.showLog
+fn log_file() -> FileSpec {
+    FileSpec::default()
+        .directory(std::env::temp_dir())
+        .basename("UVLS")
+        .suppress_timestamp()
+        .suffix("log")
+}
+fn log_level() -> String {
+    std::env::var("RUST_LOG").unwrap_or_else(|_| "info".into())
 }
 fn shutdown_error() -> tower_lsp::jsonrpc::Error {
     tower_lsp::jsonrpc::Error {
@@ -184,19 +602,54 @@ fn shutdown_error() -> tower_lsp::jsonrpc::Error {
 #[tower_lsp::async_trait]
 impl LanguageServer for Backend {
     async fn initialize(&self, init_params: InitializeParams) -> Result<InitializeResult> {
+        let client_support = capabilities::ClientSupport::new(&init_params.capabilities);
+        *self.client_support.write() = client_support;
+        let legend = color::Legend::new(
+            init_params
+                .capabilities
+                .text_document
+                .as_ref()
+                .and_then(|doc| doc.semantic_tokens.as_ref()),
+        );
+        self.coloring.set_legend(legend.clone());
         #[allow(deprecated)]
         let root_folder = init_params
             .root_path
             .as_deref()
             .or_else(|| init_params.root_uri.as_ref().map(|p| p.path()))
             .map(PathBuf::from);
+        //diagnostics for the workspace file are published once initialized
+        let settings = {
+            let mut config = self.config.lock();
+            config.client = init_params
+                .initialization_options
+                .clone()
+                .unwrap_or_default();
+            config.workspace_file = root_folder
+                .as_ref()
+                .map(|root| root.join(settings::WORKSPACE_FILE));
+            config.load_workspace();
+            config.effective()
+        };
+        let model_cache = settings.model_cache;
+        let limits = cache::fingerprint(&settings.limits());
+        self.semantic.set_settings(settings);
         if let Some(root_folder) = root_folder {
+            if model_cache {
+                spawn(cache::save_periodically(self.semantic.clone()));
+            }
             let documents = self.documents.clone();
             let semantic = self.semantic.clone();
             //cheap fix for better intial load, we should really use priority model to prefer
             //editor owned files
             let _ = spawn(async move {
                 tokio::task::spawn_blocking(move || {
+                    //the scan restores unchanged files from the cache
+                    if model_cache {
+                        let dir = cache::cache_dir(&root_folder);
+                        let cache = cache::ModelCache::open(&dir, limits);
+                        *semantic.model_cache.lock() = Some(cache);
+                    }
                     load_all_blocking(&root_folder, documents, semantic);
                 })
                 .await
@@ -209,13 +662,22 @@ impl LanguageServer for Backend {
                 version: None,
             }),
             capabilities: ServerCapabilities {
-                text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::INCREMENTAL,
+                //willSaveWaitUntil is answered with no edits unless formatOnSave is set, so the
+                //setting can be toggled without restarting
+                text_document_sync: Some(TextDocumentSyncCapability::Options(
+                    TextDocumentSyncOptions {
+                        open_close: Some(true),
+                        change: Some(TextDocumentSyncKind::INCREMENTAL),
+                        will_save_wait_until: Some(true),
+                        ..Default::default()
+                    },
                 )),
                 completion_provider: Some(CompletionOptions {
                     resolve_provider: Some(false),
                     all_commit_characters: None,
-                    trigger_characters: Some(vec![".".to_string()]),
+                    trigger_characters: Some(completion::trigger_characters(
+                        &self.semantic.settings.read(),
+                    )),
                     ..Default::default()
                 }),
                 definition_provider: Some(OneOf::Left(true)),
@@ -225,16 +687,23 @@ impl LanguageServer for Backend {
                             work_done_progress_options: WorkDoneProgressOptions {
                                 work_done_progress: None,
                             },
-                            legend: SemanticTokensLegend {
-                                token_types: color::token_types(),
-                                token_modifiers: Vec::new(),
-                            },
+                            legend: legend.lsp(),
                             range: None,
-                            full: Some(SemanticTokensFullOptions::Delta { delta: Some(true) }),
+                            full: Some(client_support.semantic_tokens_full()),
                         },
                     ),
                 ),
                 references_provider: Some(OneOf::Left(true)),
+                rename_provider: Some(OneOf::Left(true)),
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
+                document_symbol_provider: Some(OneOf::Left(true)),
+                folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+                document_formatting_provider: Some(OneOf::Left(true)),
+                code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: actions::commands(),
+                    ..Default::default()
+                }),
 
                 ..Default::default()
             },
@@ -249,20 +718,45 @@ impl LanguageServer for Backend {
             glob_pattern: "**/*.uvl".to_string(),
             kind: None,
         };
+        let settings_watcher = FileSystemWatcher {
+            glob_pattern: format!("**/{}", settings::WORKSPACE_FILE),
+            kind: None,
+        };
         let reg = Registration {
             id: "watcher".to_string(),
             method: "workspace/didChangeWatchedFiles".to_string(),
             register_options: serde_json::to_value(DidChangeWatchedFilesRegistrationOptions {
-                watchers: vec![watcher],
+                watchers: vec![watcher, settings_watcher],
             })
             .ok(),
         };
-        if self.client.register_capability(vec![reg]).await.is_err() {
+        if !self.client_support.read().watch_files {
+            info!("client can not watch files");
+            self.start_polling().await;
+        } else if self.client.register_capability(vec![reg]).await.is_err() {
             info!("failed to initialize file watchers");
+            self.start_polling().await;
         }
+        self.reload_workspace_settings().await;
+        spawn(session::sweep_sessions(
+            self.semantic.root.clone(),
+            self.sessions.clone(),
+            self.semantic.shutdown.clone(),
+        ));
     }
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
         info!("received did_open");
+        //the workspace file is tracked for its diagnostics, it is no model
+        if self
+            .config
+            .lock()
+            .is_workspace_file(&params.text_document.uri)
+        {
+            let draft = Rope::from_str(&params.text_document.text);
+            self.config.lock().workspace_draft = Some(draft);
+            self.reload_workspace_settings().await;
+            return;
+        }
         self.documents.insert(
             params.text_document.uri.clone(),
             AsyncDraft::open(
@@ -276,6 +770,18 @@ impl LanguageServer for Backend {
         info!("done did_open");
     }
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
+        let workspace_file = {
+            let mut config = self.config.lock();
+            let workspace_file = config.is_workspace_file(&params.text_document.uri);
+            if workspace_file {
+                config.edit_workspace(&params.content_changes);
+            }
+            workspace_file
+        };
+        if workspace_file {
+            self.reload_workspace_settings().await;
+            return;
+        }
         let mut updated = false;
         let uri = params.text_document.uri.clone();
         if let Some(mut doc) = self.documents.get_mut(&params.text_document.uri) {
@@ -294,7 +800,16 @@ impl LanguageServer for Backend {
             .await
         {
             return Ok(Some(CompletionResponse::List(
-                completion::compute_completions(root, &draft, params.text_document_position),
+                completion::compute_completions(
+                    root,
+                    &draft,
+                    params.text_document_position,
+                    params
+                        .context
+                        .as_ref()
+                        .and_then(|ctx| ctx.trigger_character.as_deref()),
+                    self.client_support.read().snippets,
+                ),
             )));
         }
         Ok(None)
@@ -315,18 +830,103 @@ impl LanguageServer for Backend {
             Ok(None)
         }
     }
+    //Clients sending a partialResultToken get the references of every batch of files as soon as
+    //it is searched and the last batch in the response, a workDoneToken gets a progress report
+    //per batch. Both wait for the next batch, so a canceled request stops there
     async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
         let uri = &params.text_document_position.text_document.uri;
-        if let Some((draft, root)) = self.snapshot(&uri, true).await {
-            Ok(location::find_references(
-                &root,
-                &draft,
-                &params.text_document_position.position,
-                uri,
-            ))
-        } else {
-            return Ok(None);
+        let pos = &params.text_document_position.position;
+        let (draft, root) = match self.snapshot(uri, true).await {
+            Some(snapshot) => snapshot,
+            None => return Ok(None),
+        };
+        let (targets, files) = match location::reference_search(&root, &draft, pos, uri) {
+            Some(location::ReferenceSearch::Files { targets, files }) => (targets, files),
+            Some(location::ReferenceSearch::Done(locations)) => return Ok(Some(locations)),
+            None => return Ok(None),
+        };
+        let partial = params.partial_result_params.partial_result_token;
+        let progress = params.work_done_progress_params.work_done_token;
+        if partial.is_none() && progress.is_none() {
+            return Ok(Some(
+                files
+                    .iter()
+                    .flat_map(|f| location::references_in(&root, &targets, *f))
+                    .collect(),
+            ));
+        }
+        self.work_done(
+            &progress,
+            WorkDoneProgress::Begin(WorkDoneProgressBegin {
+                title: "Finding references".into(),
+                cancellable: Some(true),
+                message: None,
+                percentage: Some(0),
+            }),
+        )
+        .await;
+        //the root is only locked while a batch is searched, targets and files are ids into
+        //the revision they were found in
+        let revision = root.revision;
+        drop(root);
+        let mut searched = 0;
+        let mut out = Vec::new();
+        let mut modified = false;
+        for batch in files.chunks(location::REFERENCE_BATCH) {
+            {
+                let root = self.semantic.root.read().await;
+                if root.revision != revision {
+                    modified = true;
+                    break;
+                }
+                out.extend(
+                    batch
+                        .iter()
+                        .flat_map(|f| location::references_in(&root, &targets, *f)),
+                );
+            }
+            searched += batch.len();
+            if let Some(token) = partial.as_ref() {
+                if searched < files.len() && !out.is_empty() {
+                    self.client
+                        .send_notification::<location::PartialReferences>(
+                            location::PartialReferencesParams {
+                                token: token.clone(),
+                                value: std::mem::take(&mut out),
+                            },
+                        )
+                        .await;
+                }
+            }
+            self.work_done(
+                &progress,
+                WorkDoneProgress::Report(WorkDoneProgressReport {
+                    cancellable: Some(true),
+                    message: Some(format!("searched {} of {} files", searched, files.len())),
+                    percentage: Some((searched * 100 / files.len()) as u32),
+                }),
+            )
+            .await;
+            tokio::task::yield_now().await;
         }
+        self.work_done(
+            &progress,
+            WorkDoneProgress::End(WorkDoneProgressEnd { message: None }),
+        )
+        .await;
+        if modified {
+            return Err(tower_lsp::jsonrpc::Error {
+                code: tower_lsp::jsonrpc::ErrorCode::ContentModified,
+                message: "the models changed during the search".into(),
+                data: None,
+            });
+        }
+        Ok(Some(out))
+    }
+    async fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
+        let pos = &params.text_document_position;
+        self.rename_edit(&pos.text_document.uri, &pos.position, &params.new_name)
+            .await
     }
     async fn semantic_tokens_full(
         &self,
@@ -352,8 +952,15 @@ impl LanguageServer for Backend {
         let uri = params.text_document.uri;
         if let Some((draft, root)) = self.snapshot(&uri, false).await {
             let color = self.coloring.clone();
+            let deltas = self.client_support.read().token_deltas;
             Ok(match draft {
-                Draft::Tree { source, tree, .. } => Some(color.delta(root, uri, tree, source)),
+                Draft::Tree { source, tree, .. } if deltas => {
+                    Some(color.delta(root, uri, tree, source))
+                }
+                //full responses are valid answers to delta requests
+                Draft::Tree { source, tree, .. } => Some(SemanticTokensFullDeltaResult::Tokens(
+                    color.get(root, uri, tree, source),
+                )),
                 _ => {
                     unimplemented!()
                 }
@@ -362,6 +969,306 @@ impl LanguageServer for Backend {
             Ok(None)
         }
     }
+    async fn document_symbol(
+        &self,
+        params: DocumentSymbolParams,
+    ) -> Result<Option<DocumentSymbolResponse>> {
+        let uri = &params.text_document.uri;
+        let hierarchical = self.client_support.read().hierarchical_symbols;
+        if let Some((_, root)) = self.snapshot(uri, true).await {
+            Ok(root
+                .file_by_uri(uri)
+                .map(|file| symbols::symbol_response(file, hierarchical)))
+        } else {
+            Ok(None)
+        }
+    }
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let pos = &params.text_document_position_params;
+        let uri = &pos.text_document.uri;
+        let markdown = self.client_support.read().markdown_hover;
+        if let Some((_, root)) = self.snapshot(uri, true).await {
+            Ok(root.file_by_uri(uri).and_then(|file| {
+                hover::hover(file, &pos.position, root.inherit_attributes, markdown)
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+    async fn folding_range(&self, params: FoldingRangeParams) -> Result<Option<Vec<FoldingRange>>> {
+        match self
+            .sync_draft(&params.text_document.uri, DraftSync::Tree, None)
+            .await
+        {
+            Some(Draft::Tree { tree, .. }) => Ok(Some(symbols::folding_ranges(&tree))),
+            _ => Ok(None),
+        }
+    }
+    async fn formatting(&self, params: DocumentFormattingParams) -> Result<Option<Vec<TextEdit>>> {
+        let uri = &params.text_document.uri;
+        if self.semantic.settings.read().is_library(uri) {
+            return Ok(None);
+        }
+        match self.sync_draft(uri, DraftSync::Tree, None).await {
+            Some(Draft::Tree { source, tree, .. }) => Ok(format::format_edits(&source, &tree)),
+            _ => Ok(None),
+        }
+    }
+    async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
+        let uri = &params.text_document.uri;
+        if self.semantic.settings.read().is_library(uri) {
+            return Ok(None);
+        }
+        if let Some((_, root)) = self.snapshot(uri, true).await {
+            let schema = self.semantic.settings.read().schema.clone();
+            Ok(Some(actions::code_actions(
+                &root,
+                uri,
+                params.range,
+                &schema,
+            )))
+        } else {
+            Ok(None)
+        }
+    }
+    async fn execute_command(
+        &self,
+        params: ExecuteCommandParams,
+    ) -> Result<Option<serde_json::Value>> {
+        match params.command.as_str() {
+            actions::ADD_ATTRIBUTE => {
+                let args: actions::AddAttributeArgs = params
+                    .arguments
+                    .into_iter()
+                    .next()
+                    .and_then(|arg| serde_json::from_value(arg).ok())
+                    .ok_or_else(|| {
+                        tower_lsp::jsonrpc::Error::invalid_params("expected arguments")
+                    })?;
+                if self.semantic.settings.read().is_library(&args.uri) {
+                    return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                        "library models are read-only",
+                    ));
+                }
+                let edit = match self.snapshot(&args.uri, true).await {
+                    Some((_, root)) => actions::add_attribute(&root, &args),
+                    None => Err(actions::AddAttributeError::UnknownModel),
+                };
+                let (edit, result) = edit.map_err(|e| match e {
+                    actions::AddAttributeError::InvalidKey => {
+                        tower_lsp::jsonrpc::Error::invalid_params("invalid attribute key")
+                    }
+                    actions::AddAttributeError::UnknownModel => {
+                        tower_lsp::jsonrpc::Error::invalid_params("unknown model")
+                    }
+                    actions::AddAttributeError::NoEdit(feature) => tower_lsp::jsonrpc::Error {
+                        code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+                        message: format!("could not add the attribute to {}", feature).into(),
+                        data: None,
+                    },
+                })?;
+                if !result.edited.is_empty() {
+                    let response = self.client.apply_edit(edit).await?;
+                    if !response.applied {
+                        return Err(tower_lsp::jsonrpc::Error {
+                            code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+                            message: response
+                                .failure_reason
+                                .unwrap_or_else(|| "the client did not apply the edit".into())
+                                .into(),
+                            data: None,
+                        });
+                    }
+                }
+                Ok(serde_json::to_value(result).ok())
+            }
+            actions::NEW_MODEL => {
+                let args: templates::NewModelArgs = params
+                    .arguments
+                    .into_iter()
+                    .next()
+                    .and_then(|arg| serde_json::from_value(arg).ok())
+                    .ok_or_else(|| {
+                        tower_lsp::jsonrpc::Error::invalid_params("expected arguments")
+                    })?;
+                let files = templates::instantiate(&args).ok_or_else(|| {
+                    tower_lsp::jsonrpc::Error::invalid_params(format!(
+                        "expected a file path, a valid root name and one of the templates {}",
+                        templates::template_names().join(", ")
+                    ))
+                })?;
+                if files
+                    .iter()
+                    .any(|(uri, _)| uri.to_file_path().is_ok_and(|path| path.exists()))
+                {
+                    return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                        "file already exists",
+                    ));
+                }
+                self.client
+                    .apply_edit(templates::new_model_edit(&files))
+                    .await?;
+                Ok(Some(serde_json::json!({
+                    "files": files.iter().map(|(uri, _)| uri).collect::<Vec<_>>(),
+                })))
+            }
+            actions::VALIDATE_MODEL => {
+                let args: summary::ValidateArgs = params
+                    .arguments
+                    .into_iter()
+                    .next()
+                    .and_then(|arg| serde_json::from_value(arg).ok())
+                    .ok_or_else(|| {
+                        tower_lsp::jsonrpc::Error::invalid_params("expected arguments")
+                    })?;
+                let timeout = self.semantic.settings.read().analysis_timeout;
+                let summary = match self.snapshot(&args.uri, true).await {
+                    Some((_, root)) => match root.file_id(&args.uri) {
+                        Some(file_id) => Some(
+                            summary::validate(
+                                root,
+                                file_id,
+                                &self.semantic.composed,
+                                Duration::from_millis(timeout),
+                            )
+                            .await,
+                        ),
+                        None => None,
+                    },
+                    None => None,
+                };
+                let summary = summary
+                    .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("unknown model"))?;
+                let kind = if summary.has_issues() {
+                    MessageType::WARNING
+                } else {
+                    MessageType::INFO
+                };
+                self.client.show_message(kind, summary.message()).await;
+                Ok(serde_json::to_value(summary).ok())
+            }
+            actions::DIFF => {
+                let args: diff::DiffArgs = params
+                    .arguments
+                    .into_iter()
+                    .next()
+                    .and_then(|arg| serde_json::from_value(arg).ok())
+                    .ok_or_else(|| {
+                        tower_lsp::jsonrpc::Error::invalid_params("expected arguments")
+                    })?;
+                let old = match (&args.old_text, &args.old_uri) {
+                    (Some(text), _) => {
+                        let source = Rope::from_str(text);
+                        let tree = parse::parse(&source, None);
+                        let uri = args.uri.clone();
+                        let limits = self.semantic.settings.read().limits();
+                        Some(build_document(source, tree, uri, Instant::now(), limits))
+                    }
+                    (None, Some(uri)) => self.load_model(uri).await,
+                    (None, None) => None,
+                };
+                let new = self.load_model(&args.uri).await;
+                match (old, new) {
+                    (Some(old), Some(new)) => {
+                        Ok(serde_json::to_value(diff::diff(&old, &new, args.markdown)).ok())
+                    }
+                    _ => Err(tower_lsp::jsonrpc::Error::invalid_params(
+                        "expected a model and either oldUri or oldText",
+                    )),
+                }
+            }
+            actions::FEATURE_CONSTRAINTS => {
+                let args: location::ConstraintsArgs = params
+                    .arguments
+                    .into_iter()
+                    .next()
+                    .and_then(|arg| serde_json::from_value(arg).ok())
+                    .ok_or_else(|| {
+                        tower_lsp::jsonrpc::Error::invalid_params("expected arguments")
+                    })?;
+                let constraints = match self.snapshot(&args.uri, true).await {
+                    Some((_, root)) => root.file_id(&args.uri).and_then(|file_id| {
+                        location::constraints_referencing(&root, file_id, &args.feature)
+                    }),
+                    None => None,
+                };
+                let constraints = constraints
+                    .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("unknown feature"))?;
+                Ok(serde_json::to_value(constraints).ok())
+            }
+            actions::RENAME_PREVIEW => {
+                let args: actions::RenamePreviewArgs = params
+                    .arguments
+                    .into_iter()
+                    .next()
+                    .and_then(|arg| serde_json::from_value(arg).ok())
+                    .ok_or_else(|| {
+                        tower_lsp::jsonrpc::Error::invalid_params("expected arguments")
+                    })?;
+                let edit = self
+                    .rename_edit(&args.uri, &args.position, &args.new_name)
+                    .await?
+                    .ok_or_else(|| {
+                        tower_lsp::jsonrpc::Error::invalid_params("nothing to rename")
+                    })?;
+                Ok(serde_json::to_value(actions::rename_preview(edit)).ok())
+            }
+            actions::NORMAL_FORM => {
+                let args: normal::NormalFormArgs = params
+                    .arguments
+                    .into_iter()
+                    .next()
+                    .and_then(|arg| serde_json::from_value(arg).ok())
+                    .ok_or_else(|| {
+                        tower_lsp::jsonrpc::Error::invalid_params("expected arguments")
+                    })?;
+                let normal = match self.snapshot(&args.uri, true).await {
+                    Some((_, root)) => root
+                        .file_id(&args.uri)
+                        .map(|file_id| normal::normal_form(&root, file_id, args.form)),
+                    None => None,
+                };
+                let normal = normal
+                    .ok_or_else(|| tower_lsp::jsonrpc::Error::invalid_params("unknown model"))?;
+                Ok(serde_json::to_value(normal).ok())
+            }
+            actions::CLEAR_ANALYSIS_CACHE => Ok(Some(serde_json::json!({
+                "cleared": self.semantic.clear_analysis().await,
+            }))),
+            actions::SHOW_LOG => Ok(Some(serde_json::json!({
+                "path": log_file().as_pathbuf(None),
+                "level": log_level(),
+            }))),
+            actions::EFFECTIVE_CONFIG => {
+                let config = self.config.lock();
+                Ok(Some(serde_json::json!({
+                    "settings": config.effective(),
+                    "workspaceFile": config.workspace_file,
+                    "workspaceFileLoaded": config.workspace.is_some(),
+                })))
+            }
+            _ => Err(tower_lsp::jsonrpc::Error::method_not_found()),
+        }
+    }
+    //Format on save, edits sent after didSave would leave the document modified again
+    async fn will_save_wait_until(
+        &self,
+        params: WillSaveTextDocumentParams,
+    ) -> Result<Option<Vec<TextEdit>>> {
+        let uri = &params.text_document.uri;
+        {
+            let settings = self.semantic.settings.read();
+            if !settings.format_on_save || settings.is_library(uri) {
+                return Ok(None);
+            }
+        }
+        //the client holds the save back meanwhile, a draft still parsing is saved as is
+        let deadline = Instant::now() + Duration::from_millis(500);
+        match self.sync_draft(uri, DraftSync::Tree, Some(deadline)).await {
+            Some(Draft::Tree { source, tree, .. }) => Ok(format::format_edits(&source, &tree)),
+            _ => Ok(None),
+        }
+    }
     async fn did_save(&self, _: DidSaveTextDocumentParams) {
         self.client
             .log_message(MessageType::INFO, "file saved!")
@@ -372,58 +1279,93 @@ impl LanguageServer for Backend {
         self.client
             .log_message(MessageType::INFO, "file closed!")
             .await;
+        //unsaved edits of the workspace file are discarded, the one on disk applies again
+        if self
+            .config
+            .lock()
+            .is_workspace_file(&params.text_document.uri)
+        {
+            self.config.lock().workspace_draft = None;
+            self.reload_workspace_settings().await;
+            return;
+        }
         self.remove(&params.text_document.uri, true).await;
         self.load(&params.text_document.uri);
     }
+    async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
+        info!("received did_change_configuration");
+        self.config.lock().client = params.settings;
+        self.apply_settings().await;
+    }
     async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
         info!("file change {:?}", params);
         for i in params.changes {
-            match i.typ {
-                FileChangeType::CREATED => {
-                    self.load(&i.uri);
-                }
-                FileChangeType::CHANGED => {
-                    self.load(&i.uri);
-                }
-                FileChangeType::DELETED => {
-                    self.remove(&i.uri, false).await;
-                }
-                _ => {}
+            if self.config.lock().is_workspace_file(&i.uri) {
+                self.reload_workspace_settings().await;
+                continue;
             }
+            handle_file_event(&self.documents, &self.semantic, i).await;
         }
     }
 
     async fn shutdown(&self) -> Result<()> {
         self.semantic.shutdown.cancel();
+        let semantic = self.semantic.clone();
+        let saved = tokio::task::spawn_blocking(move || cache::save(&semantic.model_cache)).await;
+        if let Ok(Err(e)) = saved {
+            info!("failed to save the model cache: {}", e);
+        }
         Ok(())
     }
 }
 
-#[tokio::main]
-async fn main() {
+//Responses for deeply nested models are serialized recursively, the default stack of the
+//runtime threads is too small for them
+const THREAD_STACK: usize = 16 * 1024 * 1024;
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_stack_size(THREAD_STACK)
+        .build()
+        .expect("Failed to start the runtime")
+}
+fn main() {
+    //block_on polls its future on the main thread, tower-lsp handles and serializes the
+    //responses inside the server future so it has to run on a worker
+    runtime()
+        .block_on(spawn(serve()))
+        .expect("the server panicked");
+}
+async fn serve() {
     let stdin = tokio::io::stdin();
     let stdout = tokio::io::stdout();
     //only needed for vscode auto update
     if std::env::args().any(|a| &a == "-v") {
         println!("{}", VERSION);
+        println!("grammar {}", check::GRAMMAR_VERSION);
         return;
     }
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(i) = args.iter().position(|a| a == "--check") {
+        std::process::exit(cli::run(&args[i + 1..]));
+    }
+    if args.get(1).is_some_and(|a| a == "parse") {
+        std::process::exit(cli::parse_model(&args[2..]));
+    }
 
-    let _logger = flexi_logger::Logger::try_with_env_or_str("info")
+    let _logger = flexi_logger::Logger::try_with_str(log_level())
         .expect("Log spec string broken")
-        .log_to_file(
-            FileSpec::default()
-                .directory(std::env::temp_dir())
-                .basename("UVLS")
-                .suppress_timestamp()
-                .suffix("log"),
-        )
+        .log_to_file(log_file())
         .write_mode(flexi_logger::WriteMode::Async)
         .start()
         .expect("Failed to start logger");
     log_panics::init();
     info!("UVLS start");
-    let (service, socket) = LspService::new(|client| {
+    let (service, socket) = service();
+    join!(Server::new(stdin, stdout, socket).serve(service));
+}
+fn service() -> (LspService<Backend>, ClientSocket) {
+    LspService::build(|client| {
         let documents = Arc::new(DashMap::new());
         let shutdown = CancellationToken::new();
         let semantic = semantic::create_handler(client.clone(), shutdown, documents.clone());
@@ -431,9 +1373,385 @@ async fn main() {
             semantic,
             documents,
             coloring: Arc::new(color::State::new()),
+            sessions: Arc::new(session::Sessions::new(client.clone())),
             client,
+            client_support: parking_lot::RwLock::new(Default::default()),
+            polling: Arc::new(AtomicBool::new(false)),
+            config: parking_lot::Mutex::new(settings::ConfigSources::default()),
+            reloads: Arc::new(DashMap::new()),
         }
-    });
+    })
+    .custom_method("uvls/status", Backend::status)
+    .custom_method("uvls/perf", Backend::perf)
+    .custom_method("$/uvls/inferTypes", Backend::infer_types)
+    .custom_method("uvls/syntaxModel", Backend::syntax_model)
+    .custom_method("uvls/configurationTree", Backend::configuration_tree)
+    .custom_method("uvls/configSession/start", Backend::config_session_start)
+    .custom_method("uvls/configSession/set", Backend::config_session_set)
+    .custom_method("uvls/configSession/undo", Backend::config_session_undo)
+    .custom_method("uvls/configSession/finish", Backend::config_session_finish)
+    .finish()
+}
 
-    join!(Server::new(stdin, stdout, socket).serve(service));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use tower::{Service, ServiceExt};
+    use tower_lsp::jsonrpc::Request;
+    //Server after the initialize handshake, without a workspace folder. Notifications to the
+    //client arrive on the socket
+    async fn initialized(options: serde_json::Value) -> (LspService<Backend>, ClientSocket) {
+        let (mut service, socket) = service();
+        let initialize = Request::build("initialize")
+            .params(serde_json::json!({
+                "capabilities": {},
+                "initializationOptions": options,
+            }))
+            .id(1)
+            .finish();
+        service
+            .ready()
+            .await
+            .unwrap()
+            .call(initialize)
+            .await
+            .unwrap();
+        (service, socket)
+    }
+    async fn open(backend: &Backend, uri: &Url, text: &str) {
+        backend
+            .did_open(DidOpenTextDocumentParams {
+                text_document: TextDocumentItem {
+                    uri: uri.clone(),
+                    language_id: "uvl".into(),
+                    version: 0,
+                    text: text.into(),
+                },
+            })
+            .await;
+    }
+    async fn change(backend: &Backend, uri: &Url, version: i32, text: &str) {
+        backend
+            .did_change(DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier {
+                    uri: uri.clone(),
+                    version,
+                },
+                content_changes: vec![TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text: text.into(),
+                }],
+            })
+            .await;
+    }
+    #[tokio::test]
+    #[ignore = "needs z3"]
+    async fn analysis_burst() {
+        let (service, _socket) = initialized(serde_json::Value::Null).await;
+        let backend = service.inner();
+        let uris: Vec<Url> = (0..4)
+            .map(|i| Url::parse(&format!("file:///burst/m{}.uvl", i)).unwrap())
+            .collect();
+        let model = "features\n    A\n        optional\n            B\n";
+        for uri in uris.iter() {
+            open(backend, uri, model).await;
+        }
+        //every change event cancels the analyses of the revision before
+        for version in 1..10 {
+            for uri in uris.iter() {
+                let text = format!(
+                    "features\n    A\n        optional\n            B\n            C{}\n",
+                    version
+                );
+                change(backend, uri, version, &text).await;
+            }
+            tokio::time::sleep(Duration::from_millis(version as u64)).await;
+        }
+        let idle = async {
+            loop {
+                let status = smt::SOLVER_POOL.status();
+                if status["running"].as_array().unwrap().is_empty()
+                    && status["queued"].as_array().unwrap().is_empty()
+                {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(30), idle)
+            .await
+            .unwrap();
+        let limit = backend.semantic.settings.read().max_solvers;
+        assert!(smt::SOLVER_POOL.peak() <= limit);
+    }
+    //The handlers run on a runtime worker like in main, tower-lsp serializes the response
+    //there
+    #[test]
+    fn deep_responses() {
+        runtime()
+            .block_on(spawn(async {
+                let (mut service, _socket) = initialized(serde_json::Value::Null).await;
+                let uri = Url::parse("file:///deep.uvl").unwrap();
+                let mut text = String::from("features\n");
+                for i in 0..1000 {
+                    text.push_str(&format!("{}F{}\n", "\t".repeat(2 * i + 1), i));
+                    text.push_str(&format!("{}optional\n", "\t".repeat(2 * i + 2)));
+                }
+                open(service.inner(), &uri, &text).await;
+                let request = Request::build("textDocument/documentSymbol")
+                    .params(serde_json::json!({"textDocument": {"uri": uri}}))
+                    .id(2)
+                    .finish();
+                let response = service
+                    .ready()
+                    .await
+                    .unwrap()
+                    .call(request)
+                    .await
+                    .unwrap()
+                    .unwrap();
+                let text = serde_json::to_string(&response).unwrap();
+                assert!(text.contains("\"F999\""));
+            }))
+            .unwrap();
+    }
+    //A client asking about a file it never opened after editing it on disk
+    #[tokio::test]
+    async fn stale_reads() {
+        let (service, _socket) = service();
+        let backend = service.inner();
+        let dir = std::env::temp_dir().join(format!("uvls-stale-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("stale model.uvl");
+        std::fs::write(&path, "features\n    A\n").unwrap();
+        let uri = Url::from_file_path(&path).unwrap();
+        backend.load(&uri);
+        let loaded = async {
+            while !backend.documents.contains_key(&uri) {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), loaded)
+            .await
+            .unwrap();
+        let source = |draft: Option<Draft>| draft.and_then(|d| d.source().map(|s| s.to_string()));
+        let edit = |text: &str| {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            std::fs::write(&path, text).unwrap();
+        };
+        edit("features\n    B\n");
+        let (first, second) = (backend.reload(&uri), backend.reload(&uri));
+        assert!(first.ptr_eq(&second));
+        first.await;
+        assert!(backend.reloads.is_empty());
+        edit("features\n    C\n");
+        let (first, second) = join!(
+            backend.sync_draft(&uri, DraftSync::Source, None),
+            backend.sync_draft(&uri, DraftSync::Source, None)
+        );
+        assert_eq!(source(first).as_deref(), Some("features\n    C\n"));
+        assert_eq!(source(second).as_deref(), Some("features\n    C\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    //A feature referenced from more files than fit into one batch
+    async fn referenced(backend: &Backend) -> (Url, usize) {
+        let uri = |name: &str| Url::parse(&format!("file:///refs/{}.uvl", name)).unwrap();
+        let importers = 2 * location::REFERENCE_BATCH + 1;
+        for i in 0..importers {
+            let text = format!(
+                "imports\n    target\nfeatures\n    M{}\nconstraints\n    target.T\n",
+                i
+            );
+            open(backend, &uri(&format!("m{}", i)), &text).await;
+        }
+        open(backend, &uri("target"), "features\n    T\n").await;
+        for i in 0..importers {
+            let linked = backend.snapshot(&uri(&format!("m{}", i)), true).await;
+            assert!(linked.is_some());
+        }
+        (uri("target"), importers)
+    }
+    fn references(id: i64, uri: &Url, tokens: serde_json::Value) -> Request {
+        let mut params = serde_json::json!({
+            "textDocument": {"uri": uri},
+            "position": {"line": 1, "character": 4},
+            "context": {"includeDeclaration": false},
+        });
+        params
+            .as_object_mut()
+            .unwrap()
+            .extend(tokens.as_object().unwrap().clone());
+        Request::build("textDocument/references")
+            .params(params)
+            .id(id)
+            .finish()
+    }
+    //$/progress notifications sent to the client until the socket is idle
+    async fn progress(socket: &mut ClientSocket) -> Vec<serde_json::Value> {
+        let mut out = Vec::new();
+        let idle = Duration::from_millis(200);
+        while let Ok(Some(msg)) = tokio::time::timeout(idle, socket.next()).await {
+            if msg.method() == "$/progress" {
+                out.push(msg.params().cloned().unwrap());
+            }
+        }
+        out
+    }
+    #[tokio::test]
+    async fn reference_batches() {
+        let (mut service, mut socket) = initialized(serde_json::Value::Null).await;
+        let (target, importers) = referenced(service.inner()).await;
+        let locations =
+            |value: serde_json::Value| -> Vec<Location> { serde_json::from_value(value).unwrap() };
+        //without tokens the result comes in one piece and nothing is reported
+        let request = references(2, &target, serde_json::json!({}));
+        let (all, reports) = join!(
+            service.ready().await.unwrap().call(request),
+            progress(&mut socket)
+        );
+        let (_, all) = all.unwrap().unwrap().into_parts();
+        assert_eq!(locations(all.unwrap()).len(), importers);
+        assert!(reports.is_empty());
+        let (response, reports) = join!(
+            service.ready().await.unwrap().call(references(
+                3,
+                &target,
+                serde_json::json!({"partialResultToken": "partial", "workDoneToken": "work"})
+            )),
+            progress(&mut socket)
+        );
+        let (_, last) = response.unwrap().unwrap().into_parts();
+        let mut found = locations(last.unwrap());
+        let mut kinds = Vec::new();
+        for report in reports {
+            match report["token"].as_str() {
+                Some("partial") => found.extend(locations(report["value"].clone())),
+                Some("work") => kinds.push(report["value"]["kind"].as_str().unwrap().to_string()),
+                _ => panic!("unexpected progress {}", report),
+            }
+        }
+        //three batches, the last one is the response
+        assert_eq!(found.len(), importers);
+        assert_eq!(kinds, ["begin", "report", "report", "report", "end"]);
+    }
+    #[tokio::test]
+    async fn reference_cancellation() {
+        let (mut service, mut socket) = initialized(serde_json::Value::Null).await;
+        let (target, _) = referenced(service.inner()).await;
+        progress(&mut socket).await;
+        let search = service.ready().await.unwrap().call(references(
+            2,
+            &target,
+            serde_json::json!({"workDoneToken": "work"}),
+        ));
+        let search = spawn(search);
+        //the client socket holds a single message, the search blocks on its next report
+        loop {
+            let msg = socket.next().await.unwrap();
+            if msg.method() == "$/progress" && msg.params().unwrap()["value"]["kind"] == "report" {
+                break;
+            }
+        }
+        let cancel = Request::build("$/cancelRequest")
+            .params(serde_json::json!({"id": 2}))
+            .finish();
+        service.ready().await.unwrap().call(cancel).await.unwrap();
+        let (_, response) = search.await.unwrap().unwrap().unwrap().into_parts();
+        assert_eq!(
+            response.unwrap_err().code,
+            tower_lsp::jsonrpc::ErrorCode::RequestCancelled
+        );
+    }
+    //Diagnostics published for uri until the server is idle
+    async fn published(socket: &mut ClientSocket, uri: &Url) -> Vec<Vec<Diagnostic>> {
+        let mut out = Vec::new();
+        let idle = Duration::from_millis(200);
+        while let Ok(Some(msg)) = tokio::time::timeout(idle, socket.next()).await {
+            if msg.method() != "textDocument/publishDiagnostics" {
+                continue;
+            }
+            let params: PublishDiagnosticsParams =
+                serde_json::from_value(msg.params().cloned().unwrap()).unwrap();
+            if params.uri == *uri {
+                out.push(params.diagnostics);
+            }
+        }
+        out
+    }
+    #[tokio::test]
+    async fn workspace_file_edits() {
+        let (service, mut socket) = initialized(serde_json::Value::Null).await;
+        let backend = service.inner();
+        let path = std::env::temp_dir()
+            .join(format!("uvls-settings-{}", std::process::id()))
+            .join(settings::WORKSPACE_FILE);
+        backend.config.lock().workspace_file = Some(path.clone());
+        let uri = Url::from_file_path(&path).unwrap();
+        let (_, broken) = join!(
+            open(backend, &uri, "{\"maxSolvers\": }"),
+            published(&mut socket, &uri)
+        );
+        assert!(broken.last().is_some_and(|d| d.len() == 1));
+        //the edit in the editor is checked, not the missing file on disk
+        let (_, fixed) = join!(
+            change(backend, &uri, 1, "{\"maxSolvers\": 3}"),
+            published(&mut socket, &uri)
+        );
+        assert!(fixed.last().is_some_and(|d| d.is_empty()));
+        assert_eq!(backend.semantic.settings.read().max_solvers, 3);
+        assert!(!backend.documents.contains_key(&uri));
+    }
+    #[tokio::test]
+    async fn format_on_save() {
+        let (mut service, socket) = service();
+        tokio::spawn(socket.for_each(|_| async {}));
+        let initialize = Request::build("initialize")
+            .params(serde_json::json!({
+                "capabilities": {},
+                "initializationOptions": {"formatOnSave": true, "library": ["lib/**"]},
+            }))
+            .id(1)
+            .finish();
+        let response = service
+            .ready()
+            .await
+            .unwrap()
+            .call(initialize)
+            .await
+            .unwrap()
+            .unwrap();
+        let result: InitializeResult =
+            serde_json::from_value(response.into_parts().1.unwrap()).unwrap();
+        //didSave is not needed and stays off
+        match result.capabilities.text_document_sync {
+            Some(TextDocumentSyncCapability::Options(options)) => {
+                assert_eq!(options.will_save_wait_until, Some(true));
+                assert_eq!(options.save, None);
+            }
+            sync => panic!("unexpected sync capability {:?}", sync),
+        }
+        let backend = service.inner();
+        let save = |uri: &str| {
+            backend.will_save_wait_until(WillSaveTextDocumentParams {
+                text_document: TextDocumentIdentifier {
+                    uri: Url::parse(uri).unwrap(),
+                },
+                reason: TextDocumentSaveReason::MANUAL,
+            })
+        };
+        let uri = Url::parse("file:///format.uvl").unwrap();
+        let text = "features\n  Car\n      optional\n        Engine\n";
+        open(backend, &uri, text).await;
+        let edits = save(uri.as_str()).await.unwrap().unwrap();
+        assert_eq!(edits.len(), 1);
+        //formatting is stable, saving again changes nothing
+        change(backend, &uri, 1, &edits[0].new_text).await;
+        assert_eq!(save(uri.as_str()).await.unwrap(), Some(Vec::new()));
+        assert_eq!(save("file:///lib/model.uvl").await.unwrap(), None);
+        backend.semantic.settings.write().format_on_save = false;
+        change(backend, &uri, 2, "features\n  Car\n").await;
+        assert_eq!(save(uri.as_str()).await.unwrap(), None);
+    }
 }
@@ -0,0 +1,469 @@
+use crate::ast::*;
+use crate::semantic::{FileID, RootGraph};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use tower_lsp::lsp_types::*;
+/*
+ * Constraints in conjunctive or disjunctive normal form for the uvls/normalForm command.
+ * Every constraint of a model and the models it imports is normalized on its own: implications
+ * and equivalences are expanded, negations are pushed to the literals and the result is
+ * distributed into clauses (CNF) or terms (DNF). Duplicate literals, clauses containing a
+ * literal and its negation and clauses subsumed by smaller ones are dropped. Comparisons are
+ * kept as atoms. Paths are printed as written, so every file gets its own section. The text is
+ * shown by the extension as a read-only virtual document under the returned uri.
+ */
+//Distribution is exponential, larger constraints are only reported
+pub const MAX_CLAUSES: usize = 4096;
+//Nested equivalences grow exponentially before distribution starts
+const MAX_NODES: usize = 16 * MAX_CLAUSES;
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Form {
+    #[default]
+    Cnf,
+    Dnf,
+}
+#[derive(Deserialize, Debug, Clone)]
+pub struct NormalFormArgs {
+    pub uri: Url,
+    #[serde(default)]
+    pub form: Form,
+}
+#[derive(Serialize, Debug)]
+pub struct NormalForm {
+    pub uri: Url,
+    pub text: String,
+}
+#[derive(Debug, Clone, PartialEq)]
+enum Nnf {
+    Const(bool),
+    Lit(String, bool),
+    And(Vec<Nnf>),
+    Or(Vec<Nnf>),
+}
+type Clause = BTreeSet<(String, bool)>;
+
+enum NumericTask<'a> {
+    Numeric(&'a Numeric),
+    Binary(&'static str),
+}
+//Constraints nest arbitrarily deep, so the walks below use explicit stacks
+fn numeric_text(num: &Numeric, name: &dyn Fn(Symbol) -> String) -> String {
+    let mut tasks = vec![NumericTask::Numeric(num)];
+    //text of every operand and whether it is a binary operation needing parentheses
+    let mut values: Vec<(String, bool)> = Vec::new();
+    let operand = |(text, binary): (String, bool)| {
+        if binary {
+            format!("({})", text)
+        } else {
+            text
+        }
+    };
+    while let Some(task) = tasks.pop() {
+        match task {
+            NumericTask::Numeric(Numeric::Number(value)) => values.push((value.to_string(), false)),
+            NumericTask::Numeric(Numeric::Ref(sym)) => values.push((name(*sym), false)),
+            NumericTask::Numeric(Numeric::Binary { op, lhs, rhs }) => {
+                let op = match op {
+                    NumericOP::Add => "+",
+                    NumericOP::Sub => "-",
+                    NumericOP::Mul => "*",
+                    NumericOP::Div => "/",
+                };
+                tasks.extend([
+                    NumericTask::Binary(op),
+                    NumericTask::Numeric(rhs),
+                    NumericTask::Numeric(lhs),
+                ]);
+            }
+            NumericTask::Numeric(Numeric::Aggregate { op, context, query }) => {
+                let op = match op {
+                    AggregateOP::Sum => "sum",
+                    AggregateOP::Avg => "avg",
+                };
+                let query = query.names.iter().map(|n| n.as_str()).collect::<Vec<_>>();
+                let text = match context {
+                    Some(context) => format!("{}({}, {})", op, name(*context), query.join(".")),
+                    None => format!("{}({})", op, query.join(".")),
+                };
+                values.push((text, false));
+            }
+            NumericTask::Binary(op) => {
+                let rhs = operand(values.pop().unwrap());
+                let lhs = operand(values.pop().unwrap());
+                values.push((format!("{} {} {}", lhs, op, rhs), true));
+            }
+        }
+    }
+    values.pop().map(|(text, _)| text).unwrap_or_default()
+}
+//Conjunction or disjunction of two formulas. Operands with the same operator are flattened
+//to keep chains shallow, the order of the items does not matter since clauses are sorted
+fn join(lhs: Nnf, rhs: Nnf, conjunction: bool) -> Nnf {
+    let flat = |f: Nnf| match f {
+        Nnf::And(items) if conjunction => items,
+        Nnf::Or(items) if !conjunction => items,
+        f => vec![f],
+    };
+    let (lhs, rhs) = (flat(lhs), flat(rhs));
+    let (mut items, rest) = if lhs.len() < rhs.len() {
+        (rhs, lhs)
+    } else {
+        (lhs, rhs)
+    };
+    items.extend(rest);
+    if conjunction {
+        Nnf::And(items)
+    } else {
+        Nnf::Or(items)
+    }
+}
+enum NnfTask<'a> {
+    Constraint(&'a Constraint, bool),
+    Join(bool),
+}
+//Negation normal form of the constraint, or of its negation if positive is false. Every
+//equivalence doubles its operands, None once more than budget nodes were built
+fn nnf(
+    c: &Constraint,
+    positive: bool,
+    name: &dyn Fn(Symbol) -> String,
+    budget: &mut usize,
+) -> Option<Nnf> {
+    let mut tasks = vec![NnfTask::Constraint(c, positive)];
+    let mut values: Vec<Nnf> = Vec::new();
+    while let Some(task) = tasks.pop() {
+        let (c, positive) = match task {
+            NnfTask::Constraint(c, positive) => (c, positive),
+            NnfTask::Join(conjunction) => {
+                let rhs = values.pop().unwrap();
+                let lhs = values.pop().unwrap();
+                values.push(join(lhs, rhs, conjunction));
+                continue;
+            }
+        };
+        if *budget == 0 {
+            return None;
+        }
+        *budget -= 1;
+        match c {
+            Constraint::Constant(value) => values.push(Nnf::Const(*value == positive)),
+            Constraint::Ref(sym) => values.push(Nnf::Lit(name(*sym), positive)),
+            Constraint::Equation { op, lhs, rhs } => {
+                let op = match op {
+                    EquationOP::Greater => ">",
+                    EquationOP::Smaller => "<",
+                    EquationOP::Equal => "==",
+                };
+                let text = format!(
+                    "({} {} {})",
+                    numeric_text(lhs, name),
+                    op,
+                    numeric_text(rhs, name)
+                );
+                values.push(Nnf::Lit(text, positive));
+            }
+            Constraint::Not(operand) => tasks.push(NnfTask::Constraint(operand, !positive)),
+            Constraint::Logic { op, lhs, rhs } => match op {
+                LogicOP::And | LogicOP::Or => tasks.extend([
+                    NnfTask::Join(matches!(op, LogicOP::And) == positive),
+                    NnfTask::Constraint(rhs, positive),
+                    NnfTask::Constraint(lhs, positive),
+                ]),
+                LogicOP::Implies => tasks.extend([
+                    NnfTask::Join(!positive),
+                    NnfTask::Constraint(rhs, positive),
+                    NnfTask::Constraint(lhs, !positive),
+                ]),
+                //a <=> b is (!a | b) & (a | !b), its negation (a & !b) | (!a & b)
+                LogicOP::Equiv => tasks.extend([
+                    NnfTask::Join(positive),
+                    NnfTask::Join(!positive),
+                    NnfTask::Constraint(rhs, !positive),
+                    NnfTask::Constraint(lhs, positive),
+                    NnfTask::Join(!positive),
+                    NnfTask::Constraint(rhs, positive),
+                    NnfTask::Constraint(lhs, !positive),
+                ]),
+            },
+        }
+    }
+    values.pop()
+}
+enum ClauseTask<'a> {
+    Nnf(&'a Nnf),
+    //fold the items of a conjunction or disjunction from the given index into the
+    //accumulator on top of the values
+    Fold(&'a [Nnf], bool, usize),
+}
+//Clauses of a CNF or terms of a DNF, the outer operator joins them and the inner one the
+//literals. None if there are more than MAX_CLAUSES
+fn clauses(f: &Nnf, form: Form) -> Option<Vec<Clause>> {
+    let outer_and = form == Form::Cnf;
+    let mut tasks = vec![ClauseTask::Nnf(f)];
+    let mut values: Vec<Vec<Clause>> = Vec::new();
+    while let Some(task) = tasks.pop() {
+        match task {
+            //an empty clause is false in a CNF and an empty term true in a DNF
+            ClauseTask::Nnf(Nnf::Const(value)) if *value == outer_and => values.push(Vec::new()),
+            ClauseTask::Nnf(Nnf::Const(_)) => values.push(vec![Clause::new()]),
+            ClauseTask::Nnf(Nnf::Lit(name, value)) => {
+                values.push(vec![[(name.clone(), *value)].into_iter().collect()])
+            }
+            ClauseTask::Nnf(f @ (Nnf::And(items) | Nnf::Or(items))) => {
+                //items of the outer operator are collected, the inner one is distributed
+                let outer = matches!(f, Nnf::And(..)) == outer_and;
+                values.push(if outer {
+                    Vec::new()
+                } else {
+                    vec![Clause::new()]
+                });
+                tasks.push(ClauseTask::Fold(items, outer, 0));
+            }
+            ClauseTask::Fold(items, outer, next) => {
+                if next > 0 {
+                    let item = values.pop().unwrap();
+                    let out = values.last_mut().unwrap();
+                    if outer {
+                        out.extend(item);
+                        if out.len() > MAX_CLAUSES {
+                            return None;
+                        }
+                    } else {
+                        if out.len() * item.len() > MAX_CLAUSES {
+                            return None;
+                        }
+                        *out = out
+                            .iter()
+                            .flat_map(|a| item.iter().map(move |b| a.union(b).cloned().collect()))
+                            .collect();
+                    }
+                }
+                if next < items.len() {
+                    tasks.extend([
+                        ClauseTask::Fold(items, outer, next + 1),
+                        ClauseTask::Nnf(&items[next]),
+                    ]);
+                }
+            }
+        }
+    }
+    values.pop()
+}
+fn simplify(mut clauses: Vec<Clause>) -> Vec<Clause> {
+    clauses.retain(|c| {
+        !c.iter()
+            .any(|(name, value)| c.contains(&(name.clone(), !value)))
+    });
+    clauses.sort_by(|a, b| a.len().cmp(&b.len()).then(a.cmp(b)));
+    clauses.dedup();
+    let mut out: Vec<Clause> = Vec::new();
+    for c in clauses {
+        if !out.iter().any(|smaller| smaller.is_subset(&c)) {
+            out.push(c);
+        }
+    }
+    out
+}
+fn literal_text((name, value): &(String, bool)) -> String {
+    if *value {
+        name.clone()
+    } else {
+        format!("!{}", name)
+    }
+}
+//Lines of a normalized constraint, None if it is too large
+pub fn normalize(
+    c: &Constraint,
+    form: Form,
+    name: &dyn Fn(Symbol) -> String,
+) -> Option<Vec<String>> {
+    let mut budget = MAX_NODES;
+    let clauses = simplify(clauses(&nnf(c, true, name, &mut budget)?, form)?);
+    let (join, empty, none) = match form {
+        Form::Cnf => (" | ", "false", "true"),
+        Form::Dnf => (" & ", "true", "false"),
+    };
+    if clauses.is_empty() {
+        return Some(vec![none.into()]);
+    }
+    Some(
+        clauses
+            .iter()
+            .map(|c| {
+                if c.is_empty() {
+                    empty.into()
+                } else {
+                    c.iter().map(literal_text).collect::<Vec<_>>().join(join)
+                }
+            })
+            .collect(),
+    )
+}
+fn file_text(out: &mut String, root: &RootGraph, file_id: FileID, form: Form) {
+    let file = root.file(file_id);
+    let name = |sym: Symbol| {
+        file.path(sym)
+            .iter()
+            .map(|n| n.as_str())
+            .collect::<Vec<_>>()
+            .join(".")
+    };
+    let _ = writeln!(out, "\n// {}", file.uri);
+    for sym in file.all_constraints() {
+        let (constraint, span, range) =
+            match (file.constraint(sym), file.span(sym), file.lsp_range(sym)) {
+                (Some(c), Some(span), Some(range)) => (c, span, range),
+                _ => continue,
+            };
+        let text = file.source.byte_slice(span).to_string();
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        let _ = writeln!(out, "// line {}: {}", range.start.line + 1, text);
+        match normalize(constraint, form, &name) {
+            Some(lines) => {
+                for line in lines {
+                    let _ = writeln!(out, "{}", line);
+                }
+            }
+            None => {
+                let _ = writeln!(
+                    out,
+                    "// too large to normalize, more than {} clauses or {} operators",
+                    MAX_CLAUSES, MAX_NODES
+                );
+            }
+        }
+    }
+}
+//Constraints of the model at file_id followed by the models it imports
+pub fn normal_form(root: &RootGraph, file_id: FileID, form: Form) -> NormalForm {
+    let file = root.file(file_id);
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "// {}, every constraint holds if {} of its lines hold",
+        match form {
+            Form::Cnf => "Conjunctive normal form",
+            Form::Dnf => "Disjunctive normal form",
+        },
+        match form {
+            Form::Cnf => "all",
+            Form::Dnf => "one",
+        }
+    );
+    let mut imported: Vec<FileID> = root
+        .importes(file_id)
+        .into_iter()
+        .filter(|&f| f != file_id)
+        .collect();
+    imported.sort_by(|a, b| root.file(*a).uri.cmp(&root.file(*b).uri));
+    for f in std::iter::once(file_id).chain(imported) {
+        file_text(&mut out, root, f, form);
+    }
+    let extension = match form {
+        Form::Cnf => "cnf",
+        Form::Dnf => "dnf",
+    };
+    NormalForm {
+        uri: Url::parse(&format!("uvls:{}.{}", file.uri.path(), extension)).unwrap(),
+        text: out,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn var(i: u32) -> Box<Constraint> {
+        Box::new(Constraint::Ref(Symbol::Reference(i)))
+    }
+    fn logic(op: LogicOP, lhs: Box<Constraint>, rhs: Box<Constraint>) -> Box<Constraint> {
+        Box::new(Constraint::Logic { op, lhs, rhs })
+    }
+    #[test]
+    fn cnf_and_dnf() {
+        let names = ["A", "B", "C"];
+        let name = |sym: Symbol| names[sym.offset() as usize].to_string();
+        //A => (B | C)
+        let implication = logic(LogicOP::Implies, var(0), logic(LogicOP::Or, var(1), var(2)));
+        assert_eq!(
+            normalize(&implication, Form::Cnf, &name),
+            Some(vec!["!A | B | C".to_string()])
+        );
+        assert_eq!(
+            normalize(&implication, Form::Dnf, &name),
+            Some(vec!["!A".to_string(), "B".to_string(), "C".to_string()])
+        );
+        //A <=> B
+        let equivalence = logic(LogicOP::Equiv, var(0), var(1));
+        assert_eq!(
+            normalize(&equivalence, Form::Cnf, &name),
+            Some(vec!["!A | B".to_string(), "A | !B".to_string()])
+        );
+        assert_eq!(
+            normalize(&equivalence, Form::Dnf, &name),
+            Some(vec!["!A & !B".to_string(), "A & B".to_string()])
+        );
+        //not (A & !A) is a tautology, (A | B) & A is absorbed to A
+        let tautology = Constraint::Not(logic(
+            LogicOP::And,
+            var(0),
+            Box::new(Constraint::Not(var(0))),
+        ));
+        assert_eq!(
+            normalize(&tautology, Form::Cnf, &name),
+            Some(vec!["true".to_string()])
+        );
+        let absorbed = logic(LogicOP::And, logic(LogicOP::Or, var(0), var(1)), var(0));
+        assert_eq!(
+            normalize(&absorbed, Form::Cnf, &name),
+            Some(vec!["A".to_string()])
+        );
+    }
+    #[test]
+    fn nested_equivalences() {
+        let name = |sym: Symbol| format!("F{}", sym.offset());
+        let mut deep = var(0);
+        for i in 1..40 {
+            deep = logic(LogicOP::Equiv, var(i), deep);
+        }
+        assert_eq!(normalize(&deep, Form::Cnf, &name), None);
+        assert_eq!(normalize(&deep, Form::Dnf, &name), None);
+    }
+    //Generated models chain thousands of implications, nothing may recurse on the operands.
+    //Runs on the small default stack of the test threads
+    #[test]
+    fn deep_chains() {
+        const DEPTH: u32 = 5000;
+        let name = |sym: Symbol| format!("F{}", sym.offset());
+        //F0 => (F1 => (... => F5000)) is a single clause, as DNF it has too many terms
+        let mut chain = var(DEPTH);
+        for i in (0..DEPTH).rev() {
+            chain = logic(LogicOP::Implies, var(i), chain);
+        }
+        let cnf = normalize(&chain, Form::Cnf, &name).unwrap();
+        assert_eq!(cnf.len(), 1);
+        assert_eq!(cnf[0].matches(" | ").count(), DEPTH as usize);
+        assert!(cnf[0].starts_with("!F0 | !F1 | "));
+        assert!(normalize(&chain, Form::Dnf, &name).is_none());
+        //F0 + (F1 + (... + F5000)) > 0 is a single comparison
+        let mut sum = Box::new(Numeric::Ref(Symbol::Reference(DEPTH)));
+        for i in (0..DEPTH).rev() {
+            sum = Box::new(Numeric::Binary {
+                op: NumericOP::Add,
+                lhs: Box::new(Numeric::Ref(Symbol::Reference(i))),
+                rhs: sum,
+            });
+        }
+        let comparison = Constraint::Equation {
+            op: EquationOP::Greater,
+            lhs: sum,
+            rhs: Box::new(Numeric::Number(0.0)),
+        };
+        let cnf = normalize(&comparison, Form::Cnf, &name).unwrap();
+        assert_eq!(cnf.len(), 1);
+        assert!(cnf[0].starts_with("(F0 + (F1 + (F2 + "));
+        let end = format!("F{}{} > 0)", DEPTH, ")".repeat(DEPTH as usize - 1));
+        assert!(cnf[0].ends_with(&end));
+    }
+}
@@ -0,0 +1,135 @@
+use crate::document::{AsyncDraft, DocumentState};
+use crate::semantic::Context;
+use dashmap::DashMap;
+use hashbrown::HashSet;
+use log::info;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::select;
+use tokio::time::{Duration, MissedTickBehavior};
+use tower_lsp::lsp_types::*;
+/*
+ * Fallback for clients that can not watch files for us. The modification times of files
+ * owned by the OS are compared every POLL_INTERVAL and the workspace is scanned for new
+ * models every RESCAN_TICKS polls. The resulting events take the same path as
+ * workspace/didChangeWatchedFiles. A slow poll delays the next one instead of queueing up.
+ */
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const RESCAN_TICKS: u32 = 15;
+//Events for known documents and, when root is given, models not known yet
+pub fn poll_changes(known: &[(Url, DocumentState)], root: Option<&Path>) -> Vec<FileEvent> {
+    let mut events = Vec::new();
+    for (uri, state) in known {
+        let loaded = match state {
+            DocumentState::OwnedByOs(loaded) => *loaded,
+            DocumentState::OwnedByEditor => continue,
+        };
+        let path = match uri.to_file_path() {
+            Ok(path) => path,
+            Err(_) => continue,
+        };
+        match std::fs::metadata(path).and_then(|meta| meta.modified()) {
+            Ok(modified) if modified > loaded => {
+                events.push(FileEvent::new(uri.clone(), FileChangeType::CHANGED))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                events.push(FileEvent::new(uri.clone(), FileChangeType::DELETED))
+            }
+            _ => {}
+        }
+    }
+    if let Some(root) = root {
+        let known: HashSet<&Url> = known.iter().map(|(uri, _)| uri).collect();
+        for e in walkdir::WalkDir::new(root)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().is_file())
+            .filter(|e| e.path().extension().is_some_and(|ext| ext == "uvl"))
+        {
+            if let Ok(uri) = Url::from_file_path(e.path()) {
+                if !known.contains(&uri) {
+                    events.push(FileEvent::new(uri, FileChangeType::CREATED));
+                }
+            }
+        }
+    }
+    events
+}
+pub async fn poll_files(
+    documents: Arc<DashMap<Url, AsyncDraft>>,
+    semantic: Arc<Context>,
+    root: Option<PathBuf>,
+    active: Arc<AtomicBool>,
+) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut tick = 0;
+    loop {
+        select! {
+            _ = semantic.shutdown.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+        //the setting can be switched off at any time
+        let enabled = semantic.settings.read().file_polling;
+        active.store(enabled, Ordering::Relaxed);
+        if !enabled {
+            continue;
+        }
+        let known: Vec<(Url, DocumentState)> = documents
+            .iter()
+            .map(|doc| (doc.key().clone(), doc.state))
+            .collect();
+        let rescan = root.clone().filter(|_| tick % RESCAN_TICKS == 0);
+        tick += 1;
+        let events = tokio::task::spawn_blocking(move || poll_changes(&known, rescan.as_deref()))
+            .await
+            .unwrap_or_default();
+        if !events.is_empty() {
+            info!("polled {} file changes", events.len());
+        }
+        for e in events {
+            crate::handle_file_event(&documents, &semantic, e).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+    #[test]
+    fn detect_changes() {
+        let dir = std::env::temp_dir().join(format!("uvls-poll-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let changed = dir.join("changed.uvl");
+        let created = dir.join("created.uvl");
+        let opened = dir.join("opened.uvl");
+        let deleted = dir.join("deleted.uvl");
+        for path in [&changed, &created, &opened] {
+            std::fs::write(path, "features\n    A\n").unwrap();
+        }
+        let uri = |path: &Path| Url::from_file_path(path).unwrap();
+        let loaded = DocumentState::OwnedByOs(SystemTime::UNIX_EPOCH);
+        let known = vec![
+            (uri(&changed), loaded),
+            (uri(&opened), DocumentState::OwnedByEditor),
+            (uri(&deleted), loaded),
+        ];
+        let events = poll_changes(&known, None);
+        assert_eq!(
+            events,
+            vec![
+                FileEvent::new(uri(&changed), FileChangeType::CHANGED),
+                FileEvent::new(uri(&deleted), FileChangeType::DELETED),
+            ]
+        );
+        let events = poll_changes(&known, Some(&dir));
+        assert_eq!(
+            events.last(),
+            Some(&FileEvent::new(uri(&created), FileChangeType::CREATED))
+        );
+        assert_eq!(events.len(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+use parking_lot::Mutex;
+use std::path::{Path, PathBuf};
+/*
+ * Initial scan of the workspace. Discovery walks the directory once, the models are then read
+ * and parsed by a bounded number of threads outside the async runtime, so requests and files
+ * opened in the editor are still served while a large workspace loads. Parsed models are
+ * handed over in batches because every update of the document store wakes the linker.
+ */
+pub const BATCH: usize = 32;
+//Threads used for a parallelism setting, 0 keeps one core free for the runtime
+pub fn workers(setting: usize) -> usize {
+    match setting {
+        0 => (num_cpus::get() - 1).max(1),
+        n => n,
+    }
+}
+pub fn discover(root: &Path) -> Vec<PathBuf> {
+    walkdir::WalkDir::new(root)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_file())
+        .filter(|e| {
+            e.path()
+                .extension()
+                .map(|e| e == std::ffi::OsStr::new("uvl"))
+                .unwrap_or(false)
+        })
+        .map(|e| e.into_path())
+        .collect()
+}
+//Load all paths on worker threads, flush receives at most batch loaded items at once
+pub fn parallel<T, L, F>(paths: Vec<PathBuf>, workers: usize, batch: usize, load: L, flush: F)
+where
+    T: Send,
+    L: Fn(PathBuf) -> Option<T> + Sync,
+    F: Fn(Vec<T>) + Sync,
+{
+    let queue = Mutex::new(paths.into_iter());
+    std::thread::scope(|s| {
+        for _ in 0..workers.max(1) {
+            s.spawn(|| {
+                let mut loaded = Vec::new();
+                loop {
+                    let next = queue.lock().next();
+                    let path = match next {
+                        Some(path) => path,
+                        None => break,
+                    };
+                    loaded.extend(load(path));
+                    if loaded.len() >= batch {
+                        flush(std::mem::take(&mut loaded));
+                    }
+                }
+                if !loaded.is_empty() {
+                    flush(loaded);
+                }
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::build_document;
+    use crate::parse::parse;
+    use ropey::Rope;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::time::Instant;
+    use tower_lsp::lsp_types::Url;
+    //Time to parse all models with a number of workers
+    fn scan(paths: &[PathBuf], workers: usize) -> std::time::Duration {
+        let parsed = AtomicUsize::new(0);
+        let start = std::time::Instant::now();
+        parallel(
+            paths.to_vec(),
+            workers,
+            BATCH,
+            |path| {
+                let source = Rope::from_str(&std::fs::read_to_string(&path).ok()?);
+                let tree = parse(&source, None);
+                let uri = Url::from_file_path(&path).ok()?;
+                Some(build_document(
+                    source,
+                    tree,
+                    uri,
+                    Instant::now(),
+                    Default::default(),
+                ))
+            },
+            |docs| {
+                parsed.fetch_add(docs.len(), Ordering::SeqCst);
+            },
+        );
+        assert_eq!(parsed.load(Ordering::SeqCst), paths.len());
+        start.elapsed()
+    }
+    //Workspace of 256 copies of the fixture, half of them nested
+    fn workspace(name: &str) -> (PathBuf, Vec<PathBuf>) {
+        let dir = std::env::temp_dir().join(format!("uvls-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        let model = include_str!("../fixtures/scan/model.uvl");
+        for i in 0..256 {
+            let sub = if i % 2 == 0 { "" } else { "nested" };
+            std::fs::write(dir.join(sub).join(format!("m{}.uvl", i)), model).unwrap();
+        }
+        std::fs::write(dir.join("notes.txt"), "not a model").unwrap();
+        let paths = discover(&dir);
+        (dir, paths)
+    }
+    #[test]
+    fn every_model_once() {
+        let (dir, paths) = workspace("scan-test");
+        assert_eq!(paths.len(), 256);
+        scan(&paths, 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    //The first loads wait for each other, so four workers are seen loading at once and never
+    //more of them
+    #[test]
+    fn bounded_concurrency() {
+        let (dir, paths) = workspace("scan-workers");
+        let barrier = std::sync::Barrier::new(4);
+        let (started, active, peak, flushed) = (
+            AtomicUsize::new(0),
+            AtomicUsize::new(0),
+            AtomicUsize::new(0),
+            AtomicUsize::new(0),
+        );
+        parallel(
+            paths.clone(),
+            4,
+            BATCH,
+            |path| {
+                let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                if started.fetch_add(1, Ordering::SeqCst) < 4 {
+                    barrier.wait();
+                }
+                let text = std::fs::read_to_string(&path).ok();
+                active.fetch_sub(1, Ordering::SeqCst);
+                text
+            },
+            |texts| {
+                assert!(texts.len() <= BATCH);
+                flushed.fetch_add(texts.len(), Ordering::SeqCst);
+            },
+        );
+        assert_eq!(peak.load(Ordering::SeqCst), 4);
+        assert_eq!(flushed.load(Ordering::SeqCst), paths.len());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    //Timing depends on the machine, run with --ignored when changing the scan
+    #[test]
+    #[ignore]
+    fn near_linear_scaling() {
+        let (dir, paths) = workspace("scan-scaling");
+        //warm up the allocator and the file cache
+        scan(&paths, 4);
+        if num_cpus::get() >= 4 {
+            let single = scan(&paths, 1);
+            let four = scan(&paths, 4);
+            assert!(
+                single.as_secs_f64() / four.as_secs_f64() > 2.5,
+                "1 worker {:?}, 4 workers {:?}",
+                single,
+                four
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
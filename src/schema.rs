@@ -0,0 +1,278 @@
+use crate::ast::*;
+use crate::check::ErrorInfo;
+use crate::util::lsp_range;
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tower_lsp::lsp_types::*;
+use ustr::Ustr;
+/*
+ * Attribute conventions declared in the workspace settings, eg. every feature needs a numeric
+ * cost and an owner. A rule applies to the features whose qualified name, the namespace
+ * followed by the features from the root down, starts with its prefix, the empty prefix
+ * applies to all features. For every key the rule with the longest prefix wins so subtrees
+ * can refine the requirements. Violations are warnings with schema codes, without rules nothing
+ * is checked.
+ */
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AttributeType {
+    Number,
+    String,
+    Bool,
+    Vector,
+    Attributes,
+}
+impl AttributeType {
+    fn name(&self) -> &'static str {
+        match self {
+            AttributeType::Number => "number",
+            AttributeType::String => "string",
+            AttributeType::Bool => "bool",
+            AttributeType::Vector => "vector",
+            AttributeType::Attributes => "attributes",
+        }
+    }
+}
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct AttributeSpec {
+    #[serde(rename = "type")]
+    pub ty: Option<AttributeType>,
+    pub required: bool,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct SchemaRule {
+    pub prefix: String,
+    //Keys may be dotted paths into nested attributes
+    pub attributes: BTreeMap<String, AttributeSpec>,
+}
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Number(..) => "number",
+        Value::String(..) => "string",
+        Value::Bool(..) => "bool",
+        Value::Vector => "vector",
+        Value::Attributes => "attributes",
+        Value::Void => "no value",
+    }
+}
+fn matches_type(ty: AttributeType, value: &Value) -> bool {
+    matches!(
+        (ty, value),
+        (AttributeType::Number, Value::Number(..))
+            | (AttributeType::String, Value::String(..))
+            | (AttributeType::Bool, Value::Bool(..))
+            | (AttributeType::Vector, Value::Vector)
+            | (AttributeType::Attributes, Value::Attributes)
+    )
+}
+//Attribute text inserted by the quick fix for a missing attribute
+fn stub(key: &str, spec: &AttributeSpec) -> String {
+    let value = match spec.ty {
+        Some(AttributeType::Number) => {
+            let n = spec.min.map_or(0.0, |min| min.max(0.0));
+            spec.max.map_or(n, |max| n.min(max)).to_string()
+        }
+        Some(AttributeType::String) => "''".into(),
+        Some(AttributeType::Bool) => "false".into(),
+        Some(AttributeType::Vector) => "[]".into(),
+        Some(AttributeType::Attributes) => "{}".into(),
+        None => return key.into(),
+    };
+    format!("{} {}", key, value)
+}
+//Qualified names of all features cut after the longest rule prefix, requirements never look
+//further. Parents are named before their children, so deeply nested models stay linear
+fn qualified_names(rules: &[SchemaRule], file: &Document) -> HashMap<Symbol, Vec<Ustr>> {
+    let limit = rules
+        .iter()
+        .map(|rule| rule.prefix.split('.').filter(|s| !s.is_empty()).count())
+        .max()
+        .unwrap_or(0);
+    let namespace: Vec<Ustr> = file
+        .namespace()
+        .iter()
+        .flat_map(|ns| ns.names.iter().cloned())
+        .collect();
+    let mut out: HashMap<Symbol, Vec<Ustr>> = HashMap::new();
+    file.visit_children(Symbol::Root, false, |sym| {
+        if matches!(sym, Symbol::Feature(..)) {
+            let mut name = file
+                .ancestors(sym)
+                .nth(1)
+                .and_then(|parent| out.get(&parent).cloned())
+                .unwrap_or_else(|| namespace.clone());
+            name.extend(file.symbol_name(sym));
+            name.truncate(limit);
+            out.insert(sym, name);
+        }
+        true
+    });
+    out
+}
+//Requirements of a feature by key, the longest matching prefix wins
+fn requirements<'a>(
+    rules: &'a [SchemaRule],
+    name: &[Ustr],
+) -> BTreeMap<&'a str, &'a AttributeSpec> {
+    let mut out: BTreeMap<&str, (usize, &AttributeSpec)> = BTreeMap::new();
+    for rule in rules {
+        let prefix: Vec<&str> = rule.prefix.split('.').filter(|s| !s.is_empty()).collect();
+        if prefix.len() > name.len() || prefix.iter().zip(name).any(|(p, n)| *p != n.as_str()) {
+            continue;
+        }
+        for (key, spec) in rule.attributes.iter() {
+            match out.get(key.as_str()) {
+                Some((len, _)) if *len > prefix.len() => {}
+                _ => {
+                    out.insert(key, (prefix.len(), spec));
+                }
+            }
+        }
+    }
+    out.into_iter()
+        .map(|(key, (_, spec))| (key, spec))
+        .collect()
+}
+fn find_attribute(file: &Document, feature: Symbol, key: &str) -> Option<Symbol> {
+    let path: Vec<Ustr> = key.split('.').map(Ustr::from).collect();
+    let attribute = file
+        .lookup(feature, &path, |sym| matches!(sym, Symbol::Attribute(..)))
+        .next();
+    attribute
+}
+//Required attributes missing on each feature with the text the quick fix inserts
+pub fn missing_attributes(rules: &[SchemaRule], file: &Document) -> Vec<(Symbol, String, String)> {
+    let mut out = Vec::new();
+    if rules.is_empty() {
+        return out;
+    }
+    let names = qualified_names(rules, file);
+    for feature in file.all_features() {
+        let name = match names.get(&feature) {
+            Some(name) => name,
+            None => continue,
+        };
+        for (key, spec) in requirements(rules, name) {
+            if spec.required && find_attribute(file, feature, key).is_none() {
+                out.push((feature, key.to_string(), stub(key, spec)));
+            }
+        }
+    }
+    out
+}
+fn warning(location: Range, code: &str, msg: String) -> ErrorInfo {
+    ErrorInfo {
+        location,
+        severity: DiagnosticSeverity::WARNING,
+        weight: 10,
+        msg,
+        related: Vec::new(),
+        code: Some(format!("schema/{}", code)),
+        tags: Vec::new(),
+    }
+}
+pub fn check_schema(rules: &[SchemaRule], file: &Document) -> Vec<ErrorInfo> {
+    let mut err = Vec::new();
+    for (feature, key, _) in missing_attributes(rules, file) {
+        let feature_name = file.symbol_name(feature).unwrap_or_default();
+        if let Some(range) = file.lsp_range(feature) {
+            err.push(warning(
+                range,
+                "missing-attribute",
+                format!("{} is missing the required attribute {}", feature_name, key),
+            ));
+        }
+    }
+    if rules.is_empty() {
+        return err;
+    }
+    let names = qualified_names(rules, file);
+    for feature in file.all_features() {
+        let name = match names.get(&feature) {
+            Some(name) => name,
+            None => continue,
+        };
+        for (key, spec) in requirements(rules, name) {
+            let attribute = match find_attribute(file, feature, key) {
+                Some(attribute) => attribute,
+                None => continue,
+            };
+            let (value, range) = match (file.value(attribute), file.value_span(attribute)) {
+                (Some(value), Some(span)) => match lsp_range(span, &file.source) {
+                    Some(range) => (value, range),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            match (spec.ty, value) {
+                (Some(ty), value) if !matches_type(ty, value) => err.push(warning(
+                    range,
+                    "wrong-type",
+                    format!(
+                        "{} should be a {}, found {}",
+                        key,
+                        ty.name(),
+                        type_name(value)
+                    ),
+                )),
+                (_, Value::Number(n)) if spec.min.is_some_and(|min| *n < min) => err.push(warning(
+                    range,
+                    "out-of-range",
+                    format!("{} is {}, expected at least {}", key, n, spec.min.unwrap()),
+                )),
+                (_, Value::Number(n)) if spec.max.is_some_and(|max| *n > max) => err.push(warning(
+                    range,
+                    "out-of-range",
+                    format!("{} is {}, expected at most {}", key, n, spec.max.unwrap()),
+                )),
+                _ => {}
+            }
+        }
+    }
+    err
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::build_document;
+    use crate::parse::parse;
+    use ropey::Rope;
+    use tokio::time::Instant;
+    #[test]
+    fn subtree_rules() {
+        let rules: Vec<SchemaRule> = serde_json::from_value(serde_json::json!([
+            {"attributes": {"cost": {"type": "number", "required": true, "min": 0}}},
+            {"prefix": "Car.Engine", "attributes": {
+                "cost": {"type": "number", "required": true, "max": 100},
+                "owner": {"type": "string"}
+            }}
+        ]))
+        .unwrap();
+        let source = Rope::from_str("features\n    Car {cost 5}\n        optional\n            Engine {cost 500, owner 3}\n                optional\n                    Piston\n            Wheel {cost -1}\n");
+        let tree = parse(&source, None);
+        let uri = Url::parse("file:///schema.uvl").unwrap();
+        let file = build_document(source, tree, uri, Instant::now(), Default::default());
+        let codes: Vec<(u32, String)> = check_schema(&rules, &file)
+            .into_iter()
+            .map(|e| (e.location.start.line, e.code.unwrap()))
+            .collect();
+        assert_eq!(
+            codes,
+            vec![
+                (5, "schema/missing-attribute".to_string()),
+                (3, "schema/out-of-range".to_string()),
+                (3, "schema/wrong-type".to_string()),
+                (6, "schema/out-of-range".to_string()),
+            ]
+        );
+        let missing = missing_attributes(&rules, &file);
+        assert_eq!(missing[0].2, "cost 0");
+        assert!(check_schema(&[], &file).is_empty());
+    }
+}
@@ -1,11 +1,16 @@
 use crate::ast::*;
+use crate::cache::ModelCache;
 use crate::check;
 use crate::check::DiagnosticUpdate;
 use crate::check::ErrorInfo;
-use crate::document::{AsyncDraft, DocumentStore};
+use crate::document::{build_document, AsyncDraft, DocumentState, DocumentStore};
+use crate::inherit;
+use crate::schema;
+use crate::settings::Settings;
 use crate::smt::check_smt;
 use crate::util::lsp_range;
 use crate::util::AtomicSemaphore;
+use crate::valueref;
 use compact_str::CompactStringExt;
 use dashmap::DashMap;
 use hashbrown::{HashMap, HashSet};
@@ -18,7 +23,7 @@ use std::collections::BTreeSet;
 use std::fmt::Debug;
 use std::ops::Index;
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio::sync::{watch, RwLock, RwLockReadGuard, Semaphore};
 use tokio::time::{Duration, Instant};
 use tokio::{select, spawn};
@@ -41,15 +46,32 @@ pub struct Context {
     //latest linked state
     pub root: Arc<RwLock<RootGraph>>,
     pub tx_err: mpsc::Sender<DiagnosticUpdate>,
+    //requests to drop all analysis results, answered with the number of dropped entries
+    pub tx_clear: mpsc::Sender<oneshot::Sender<usize>>,
     pub shutdown: CancellationToken,
     pub client: Client,
     //limit the amount of parallel background tasks to keep the server responsiv
     //TODO find a better way
     pub load_files_sema: Semaphore,
+    //solver encodings of unchanged components, see smt::ComposedCache
+    pub composed: crate::smt::ComposedCache,
     pub parser_active: AtomicSemaphore,
+    pub settings: parking_lot::RwLock<Settings>,
+    //Diagnostics of files on disk from previous runs, only set when enabled
+    pub model_cache: parking_lot::Mutex<Option<ModelCache>>,
 }
 pub type Snapshot<'a> = RwLockReadGuard<'a, RootGraph>;
 impl Context {
+    //Forget analysis results, solver encodings and cached diagnostics so every file is checked
+    //again, parse trees are kept
+    pub async fn clear_analysis(&self) -> usize {
+        self.composed.clear();
+        let (tx, rx) = oneshot::channel();
+        if self.tx_clear.send(tx).await.is_err() {
+            return 0;
+        }
+        rx.await.unwrap_or(0)
+    }
     //Make sure uri is inside the snapshot
     pub async fn snapshot(&self, uri: &Url) -> Option<Snapshot> {
         let time = Instant::now();
@@ -88,6 +110,43 @@ impl Context {
             }
         }
     }
+    //Build every document again from its text with the current limits, the parse trees are
+    //reused
+    pub async fn rebuild_documents(&self) {
+        let docs: Vec<Arc<Document>> = {
+            let documents = self.documents.lock();
+            let store = watch::Sender::borrow(&documents);
+            store.ast.values().cloned().collect()
+        };
+        let limits = self.settings.read().limits();
+        let rebuilt = tokio::task::spawn_blocking(move || {
+            docs.iter()
+                .map(|doc| {
+                    let tree = doc.tree().clone();
+                    build_document(
+                        doc.source.clone(),
+                        tree,
+                        doc.uri.clone(),
+                        doc.timestamp,
+                        limits,
+                    )
+                })
+                .collect()
+        })
+        .await;
+        if let Ok(docs) = rebuilt {
+            self.documents
+                .lock()
+                .send_modify(|state| state.update_all(docs));
+        }
+    }
+    pub fn set_settings(&self, settings: Settings) {
+        crate::smt::SOLVER_POOL.resize(settings.max_solvers);
+        if let Some(cache) = self.model_cache.lock().as_mut() {
+            cache.set_limits(crate::cache::fingerprint(&settings.limits()));
+        }
+        *self.settings.write() = settings;
+    }
     pub async fn publish_err(&self, mut err: HashMap<FileID, Vec<ErrorInfo>>, root: &RootGraph) {
         let _ = self
             .tx_err
@@ -325,6 +384,14 @@ pub struct Component {
     pub error: ComponentErrorState,
     pub dirty: bool,
 }
+//Outcome of resolving a reference
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    Resolved(RootSymbol),
+    WrongType(Type),
+    Ambiguous(Vec<RootSymbol>),
+    Unresolved,
+}
 //A fully linked version of all files, computed asynchronously
 #[derive(Debug, Clone)]
 pub struct RootGraph {
@@ -334,6 +401,10 @@ pub struct RootGraph {
     ref_map: ReferenceMap,
     index: HashMap<Url, FileID>,
     components: Vec<Component>,
+    //Attributes are inherited by descendant features, see inherit.rs
+    pub inherit_attributes: bool,
+    //Keys of attributes whose string values are references, see valueref.rs
+    pub reference_attributes: Vec<String>,
 }
 impl RootGraph {
     pub fn file_by_uri(&self, name: &Url) -> Option<&Document> {
@@ -342,17 +413,33 @@ impl RootGraph {
     pub fn file(&self, id: FileID) -> &Document {
         &self.files[id]
     }
+    //Document that outlives the snapshot
+    pub fn document(&self, id: FileID) -> Arc<Document> {
+        self.files[id].clone()
+    }
     pub fn file_id(&self, name: &Url) -> Option<FileID> {
         self.index.get(name).cloned()
     }
+    //Declaration of the root feature a model is imported as, the start of the file if it has
+    //several top level features or none
+    pub fn root_location(&self, id: FileID) -> Location {
+        let file = self.file(id);
+        let range = match file.model_root() {
+            ModelRoot::Feature(sym) => file.lsp_range(*sym),
+            _ => None,
+        };
+        Location {
+            uri: file.uri.clone(),
+            range: range.unwrap_or_default(),
+        }
+    }
     pub fn resolve_sym(&self, sym: RootSymbol) -> Option<RootSymbol> {
         match sym.sym {
             Symbol::Reference(..) => self.ref_map.resolve(sym).or_else(|| {
-                let file = self.file(sym.file);
-                let path = file.path(sym.sym);
-                let ty = file.type_of(sym.sym);
-                self.resolve(sym.file, path)
-                    .find(|&dst| self.file(dst.file).type_of(dst.sym) == ty)
+                match self.resolve_reference(sym.file, sym.sym) {
+                    Resolution::Resolved(dst) => Some(dst),
+                    _ => None,
+                }
             }),
             _ => Some(sym),
         }
@@ -435,6 +522,57 @@ impl RootGraph {
         })
         .flatten()
     }
+    //find all symbols under path relative to scope, attributes of the enclosing feature come
+    //first, then those of its ancestors and finally everything reachable from the file root
+    pub fn resolve_scoped<'a>(
+        &'a self,
+        origin: FileID,
+        scope: Symbol,
+        path: &'a [Ustr],
+    ) -> impl Iterator<Item = RootSymbol> + 'a {
+        let file = self.file(origin);
+        file.ancestors(scope)
+            .filter(move |_| !path.is_empty())
+            .flat_map(move |feature| file.lookup(feature, path, |_| true))
+            .map(move |sym| RootSymbol { file: origin, sym })
+            .chain(self.resolve(origin, path))
+    }
+    //Resolve a reference the way the checker does, navigation and the smt encoder have to
+    //agree with it. After the scoped lookup a feature may inherit the attribute from an
+    //ancestor and a bare name may still refer to an attribute of any feature in the file as
+    //long as there is only one candidate
+    pub fn resolve_reference(&self, origin: FileID, sym: Symbol) -> Resolution {
+        let file = self.file(origin);
+        let path = file.path(sym);
+        let ty = file.type_of(sym);
+        let scope = file.reference_scope(sym);
+        let mut state = Resolution::Unresolved;
+        //only walked once the regular lookup found nothing
+        let inherited = std::iter::once(scope)
+            .filter(|_| self.inherit_attributes)
+            .flat_map(|scope| inherit::resolve_inherited(self, origin, scope, path));
+        for k in self.resolve_scoped(origin, scope, path).chain(inherited) {
+            match self.file(k.file).type_of(k.sym) {
+                dst_ty if dst_ty == ty => return Resolution::Resolved(k),
+                Some(dst_ty) => state = Resolution::WrongType(dst_ty),
+                None => {}
+            }
+        }
+        if path.len() == 1 {
+            let mut candidates: Vec<RootSymbol> = file
+                .all_attributes()
+                .filter(|sym| matches!(file.parent(*sym, false), Some(Symbol::Feature(..))))
+                .filter(|sym| file.symbol_name(*sym) == Some(path[0]) && file.type_of(*sym) == ty)
+                .map(|sym| RootSymbol { file: origin, sym })
+                .collect();
+            match candidates.len() {
+                0 => {}
+                1 => return Resolution::Resolved(candidates.remove(0)),
+                _ => return Resolution::Ambiguous(candidates),
+            }
+        }
+        state
+    }
     pub fn resolve_with_binding<'a>(
         &'a self,
         origin: FileID,
@@ -549,41 +687,68 @@ impl RootGraph {
     pub fn dump(&self) {
         info!("{:#?}", &self.files);
     }
-    fn link_file(&mut self, src_file_id: FileID) -> Vec<ErrorInfo> {
-        enum ReferenceResolveState {
-            Unresolved,
-            WrongType(Type),
-            Resolved(RootSymbol),
-        }
-        let src = &self.files[src_file_id.0 as usize];
-        let mut errors = Vec::new();
-        for id in src.all_references() {
-            let path = src.path(id);
-            let r_ty = src.type_of(id);
-            let mut state = ReferenceResolveState::Unresolved;
-            for k in self.resolve(src_file_id, path) {
-                let dst = self.file(k.file);
-                if r_ty == dst.type_of(k.sym) {
-                    state = ReferenceResolveState::Resolved(k);
-                    break;
-                } else {
-                    state = ReferenceResolveState::WrongType(dst.type_of(k.sym).unwrap());
+    //Error explaining why a reference does not resolve
+    fn resolution_error(&self, file_id: FileID, id: Symbol, res: Resolution) -> Option<ErrorInfo> {
+        let src = self.file(file_id);
+        let (msg, related) = match res {
+            Resolution::Resolved(..) => return None,
+            Resolution::Unresolved => {
+                if let Some(err) = crate::check::unknown_attribute(self, file_id, id) {
+                    return Some(err);
                 }
+                ("unresolved reference".into(), Vec::new())
             }
-            match state {
-                ReferenceResolveState::Unresolved => errors.push(ErrorInfo {
-                    location: src.lsp_range(id).unwrap(),
-                    severity: DiagnosticSeverity::ERROR,
-                    weight: 30,
-                    msg: "unresolved reference".into(),
-                }),
-                ReferenceResolveState::WrongType(ty) => errors.push(ErrorInfo {
-                    location: src.lsp_range(id).unwrap(),
-                    severity: DiagnosticSeverity::ERROR,
-                    weight: 30,
-                    msg: format!("expected a {:?} got {:?}", r_ty, ty),
-                }),
-                ReferenceResolveState::Resolved(sym) => {
+            Resolution::WrongType(ty) => (
+                format!("expected a {:?} got {:?}", src.type_of(id), ty),
+                Vec::new(),
+            ),
+            Resolution::Ambiguous(candidates) => (
+                "ambiguous attribute reference".into(),
+                candidates
+                    .iter()
+                    .filter_map(|c| {
+                        let file = self.file(c.file);
+                        let feature = file.parent(c.sym, false)?;
+                        Some(DiagnosticRelatedInformation {
+                            location: Location {
+                                uri: file.uri.clone(),
+                                range: file.lsp_range(c.sym)?,
+                            },
+                            message: format!(
+                                "candidate {}.{}",
+                                file.symbol_name(feature)?,
+                                file.symbol_name(c.sym)?
+                            ),
+                        })
+                    })
+                    .collect(),
+            ),
+        };
+        Some(ErrorInfo {
+            location: src.lsp_range(id).unwrap(),
+            severity: DiagnosticSeverity::ERROR,
+            weight: 30,
+            related,
+            code: None,
+            msg,
+            tags: Vec::new(),
+        })
+    }
+    //Errors of all references of a file without linking it
+    pub fn reference_errors(&self, file_id: FileID) -> Vec<ErrorInfo> {
+        self.file(file_id)
+            .all_references()
+            .filter_map(|id| {
+                let res = self.resolve_reference(file_id, id);
+                self.resolution_error(file_id, id, res)
+            })
+            .collect()
+    }
+    fn link_file(&mut self, src_file_id: FileID) -> Vec<ErrorInfo> {
+        let mut errors = Vec::new();
+        for id in self.file(src_file_id).all_references() {
+            match self.resolve_reference(src_file_id, id) {
+                Resolution::Resolved(sym) => {
                     self.ref_map.insert(
                         RootSymbol {
                             sym: id,
@@ -592,6 +757,7 @@ impl RootGraph {
                         sym,
                     );
                 }
+                res => errors.extend(self.resolution_error(src_file_id, id, res)),
             }
         }
         errors
@@ -609,6 +775,8 @@ impl RootGraph {
             files,
             ref_map: Default::default(),
             revision,
+            inherit_attributes: false,
+            reference_attributes: Vec::new(),
         }
     }
 }
@@ -617,6 +785,9 @@ impl RootGraph {
 struct RootGraphHandler {
     check_state: HashMap<Ustr, Instant>,
     cancel_smt: Option<CancellationToken>,
+    //files importing a file directly or indirectly as of the last check
+    dependents: HashMap<Url, HashSet<Url>>,
+    interfaces: HashMap<Url, u64>,
 }
 impl RootGraphHandler {
     pub fn collect_changes(&mut self, root: &RootGraph) -> HashMap<FileID, Vec<ErrorInfo>> {
@@ -634,6 +805,49 @@ impl RootGraphHandler {
         }
         err
     }
+    //Files that have to be checked again because something they import changed, this includes
+    //files that stopped importing a changed file
+    fn dependents(&self, root: &RootGraph, changed: &HashSet<FileID>) -> HashSet<FileID> {
+        let mut out = HashSet::new();
+        for &f in changed {
+            out.extend(root.imported(f));
+            if let Some(old) = self.dependents.get(&root.file(f).uri) {
+                out.extend(old.iter().filter_map(|uri| root.file_id(uri)));
+            }
+        }
+        out
+    }
+    //Only changes to the symbols a file exports can change how importers resolve
+    fn interface_changed(&mut self, file: &Document) -> bool {
+        let hash = file.interface_hash();
+        self.interfaces.insert(file.uri.clone(), hash) != Some(hash)
+    }
+    //Drop the state of previous checks, the next check treats every file as changed
+    fn clear(&mut self) -> usize {
+        if let Some(token) = self.cancel_smt.take() {
+            token.cancel();
+        }
+        let cleared = self.check_state.len();
+        self.check_state.clear();
+        self.interfaces.clear();
+        self.dependents.clear();
+        cleared
+    }
+    fn update_dependents(&mut self, root: &RootGraph) {
+        self.dependents = root
+            .iter_file_ids()
+            .map(|f| {
+                let uri = root.file(f).uri.clone();
+                let dependents = root
+                    .imported(f)
+                    .into_iter()
+                    .filter(|&i| i != f)
+                    .map(|i| root.file(i).uri.clone())
+                    .collect();
+                (uri, dependents)
+            })
+            .collect();
+    }
     fn check_namespaces(&self, root: &RootGraph, err_out: &mut HashMap<FileID, Vec<ErrorInfo>>) {
         let mut file_paths = HashSet::new();
         for file in root.files.iter() {
@@ -646,7 +860,10 @@ impl RootGraphHandler {
                             location: lsp_range(ns.range(), &file.source).unwrap(),
                             severity: DiagnosticSeverity::ERROR,
                             weight: 100,
+                            related: Vec::new(),
+                            code: None,
                             msg: "namespace already defined".into(),
+                            tags: Vec::new(),
                         });
                     }
                 }
@@ -664,10 +881,12 @@ impl RootGraphHandler {
             .connected_components()
             .drain(..)
             .map(|files| Component {
-                error: if files
-                    .iter()
-                    .any(|f| err_out.get(f).map(|err| err.len() > 0).unwrap_or(false))
-                {
+                error: if files.iter().any(|f| {
+                    err_out
+                        .get(f)
+                        .map(|err| err.iter().any(ErrorInfo::is_error))
+                        .unwrap_or(false)
+                }) {
                     ComponentErrorState::SyntaxError
                 } else {
                     ComponentErrorState::Valid
@@ -681,12 +900,16 @@ impl RootGraphHandler {
             if c.error == ComponentErrorState::Valid && c.dirty {
                 let mut all_ok = true;
                 for f in c.members.iter() {
-                    if dirty_fs || root.importes(*f).iter().any(|im| dirty.contains(im)) {
-                        let err = root.link_file(*f);
+                    if dirty_fs || dirty.contains(f) {
+                        let mut err = root.link_file(*f);
                         if err.len() > 0 {
                             all_ok = false;
                         }
-                        err_out.insert(*f, err);
+                        //link errors are published together with the errors of the file itself
+                        err_out
+                            .entry(*f)
+                            .or_insert_with(|| root.file(*f).errors.clone())
+                            .append(&mut err);
                     }
                 }
                 if !all_ok {
@@ -696,10 +919,57 @@ impl RootGraphHandler {
         }
         root.components = components;
     }
+    //Errors of all changed files and the files depending on them
+    fn check(&mut self, root: &mut RootGraph, dirty_fs: bool) -> HashMap<FileID, Vec<ErrorInfo>> {
+        let mut err = self.collect_changes(root);
+        let changed: HashSet<FileID> = err.keys().cloned().collect();
+        let exported = changed
+            .iter()
+            .cloned()
+            .filter(|&f| self.interface_changed(root.file(f)))
+            .collect();
+        let mut dirty_files = changed;
+        for f in self.dependents(root, &exported) {
+            dirty_files.insert(f);
+            err.entry(f).or_insert_with(|| root.file(f).errors.clone());
+        }
+        self.check_namespaces(root, &mut err);
+        self.link(root, &mut err, &dirty_files, dirty_fs);
+        self.update_dependents(root);
+        err
+    }
+    //Remember the documents and diagnostics of files that are not opened in the editor for the
+    //next start
+    fn cache_errors(
+        ctx: &Context,
+        root: &RootGraph,
+        drafts: &DashMap<Url, AsyncDraft>,
+        err: &HashMap<FileID, Vec<ErrorInfo>>,
+    ) {
+        let mut cache = ctx.model_cache.lock();
+        let cache = match cache.as_mut() {
+            Some(cache) => cache,
+            None => return,
+        };
+        for (file, errors) in err.iter() {
+            let doc = &root.files[*file];
+            //the draft may be newer than the checked document
+            let state = drafts
+                .get(&doc.uri)
+                .filter(|draft| draft.revision() == doc.timestamp)
+                .map(|draft| draft.state);
+            if let (Some(DocumentState::OwnedByOs(modified)), Ok(path)) =
+                (state, doc.uri.to_file_path())
+            {
+                cache.record(path, modified, errors, doc.clone());
+            }
+        }
+    }
     pub async fn update(
         &mut self,
         ctx: &Arc<Context>,
         documents: &mut watch::Receiver<DocumentStore>,
+        drafts: &DashMap<Url, AsyncDraft>,
     ) {
         if let Some(token) = self.cancel_smt.take() {
             token.cancel();
@@ -708,32 +978,74 @@ impl RootGraphHandler {
             let docs = documents.borrow_and_update();
             RootGraph::new(&docs.ast, docs.revision)
         };
+        new_root.inherit_attributes = ctx.settings.read().inherit_attributes;
+        new_root.reference_attributes = ctx.settings.read().reference_attributes.clone();
         if ctx.parser_active.zero() {
             let timer = Instant::now();
-            let mut err = self.collect_changes(&new_root);
-            self.check_namespaces(&new_root, &mut err);
             let dirty_fs = ctx.root.read().await.file_paths() != new_root.file_paths();
-            let dirty_files = err.keys().cloned().collect();
-            self.link(&mut new_root, &mut err, &dirty_files, dirty_fs);
+            let mut err = self.check(&mut new_root, dirty_fs);
+            let schema = ctx.settings.read().schema.clone();
+            for (file, err) in err.iter_mut() {
+                err.extend(schema::check_schema(&schema, new_root.file(*file)));
+                if new_root.inherit_attributes {
+                    err.extend(inherit::check_redundant(new_root.file(*file)));
+                }
+                err.extend(valueref::check_value_refs(&new_root, *file));
+            }
+            Self::cache_errors(ctx, &new_root, drafts, &err);
             ctx.publish_err(err, &new_root).await;
             info!("linked root graph {:?}", timer.elapsed());
         }
         *ctx.root.write().await = new_root;
         if ctx.parser_active.zero() {
             let token = CancellationToken::new();
-            let _ = spawn(check_smt(ctx.clone(), token.clone()));
+            let open = drafts
+                .iter()
+                .filter(|draft| draft.state == DocumentState::OwnedByEditor)
+                .map(|draft| draft.key().clone())
+                .collect();
+            let _ = spawn(check_smt(ctx.clone(), token.clone(), open));
             self.cancel_smt = Some(token);
         }
     }
 }
 
-async fn handler_impl(ctx: Arc<Context>, mut documents: watch::Receiver<DocumentStore>) {
+//Link a set of documents without a running server, the solver is not involved
+pub fn link_documents(
+    documents: &HashMap<Url, Arc<Document>>,
+) -> (RootGraph, HashMap<FileID, Vec<ErrorInfo>>) {
+    let mut root = RootGraph::new(documents, 0);
+    let err = RootGraphHandler::default().check(&mut root, true);
+    (root, err)
+}
+//Diagnostics of a set of documents without a running server
+pub fn check_documents(documents: &HashMap<Url, Arc<Document>>) -> HashMap<Url, Vec<ErrorInfo>> {
+    let (root, err) = link_documents(documents);
+    err.into_iter()
+        .map(|(file, err)| (root.file(file).uri.clone(), err))
+        .collect()
+}
+async fn handler_impl(
+    ctx: Arc<Context>,
+    mut documents: watch::Receiver<DocumentStore>,
+    drafts: Arc<DashMap<Url, AsyncDraft>>,
+    mut clear: mpsc::Receiver<oneshot::Sender<usize>>,
+) {
     let mut handler = RootGraphHandler::default();
     loop {
         select! {
             _ = ctx.shutdown.cancelled() => return,
             _ =  documents.changed()=>{
-                handler.update(&ctx, &mut documents).await
+                handler.update(&ctx, &mut documents, &drafts).await
+            }
+            Some(reply) = clear.recv() => {
+                let mut cleared = handler.clear();
+                if let Some(cache) = ctx.model_cache.lock().as_mut() {
+                    cleared += cache.clear();
+                }
+                info!("cleared {} analysis entries", cleared);
+                let _ = reply.send(cleared);
+                handler.update(&ctx, &mut documents, &drafts).await
             }
         }
     }
@@ -742,7 +1054,7 @@ async fn handler_impl(ctx: Arc<Context>, mut documents: watch::Receiver<Document
 pub fn create_handler(
     client: Client,
     shutdown: CancellationToken,
-    _: Arc<DashMap<Url, AsyncDraft>>,
+    drafts: Arc<DashMap<Url, AsyncDraft>>,
 ) -> Arc<Context> {
     let root = Arc::new(tokio::sync::RwLock::new(RootGraph {
         components: Vec::new(),
@@ -754,20 +1066,160 @@ pub fn create_handler(
         },
         files: Default::default(),
         revision: 0,
+        inherit_attributes: false,
+        reference_attributes: Vec::new(),
     }));
     let (tx_doc, rx_doc) = watch::channel(DocumentStore::default());
     let (tx_err, rx_err) = mpsc::channel(32);
+    let (tx_clear, rx_clear) = mpsc::channel(1);
 
     let ctx = Arc::new(Context {
         load_files_sema: Semaphore::new((num_cpus::get() - 1).max(1)),
+        composed: Default::default(),
         parser_active: AtomicSemaphore::new(),
+        settings: parking_lot::RwLock::new(Settings::default()),
         tx_err,
+        tx_clear,
         shutdown,
         client,
         documents: Mutex::new(tx_doc),
         root,
+        model_cache: parking_lot::Mutex::new(None),
     });
-    spawn(handler_impl(ctx.clone(), rx_doc));
+    spawn(handler_impl(ctx.clone(), rx_doc, drafts, rx_clear));
     spawn(check::diagnostic_handler(ctx.clone(), rx_err));
     ctx
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::build_document;
+    use crate::parse::parse;
+    use ropey::Rope;
+    fn doc(uri: &Url, text: &str) -> Arc<Document> {
+        let source = Rope::from_str(text);
+        let tree = parse(&source, None);
+        Arc::new(build_document(
+            source,
+            tree,
+            uri.clone(),
+            Instant::now(),
+            Default::default(),
+        ))
+    }
+    #[test]
+    fn dependent_relinked() {
+        let a = Url::parse("file:///model/a.uvl").unwrap();
+        let b = Url::parse("file:///model/b.uvl").unwrap();
+        let b_doc = doc(
+            &b,
+            "imports\n    a\nfeatures\n    B\nconstraints\n    B => a.Engine\n",
+        );
+        //a misspells the feature b refers to
+        let broken = "features\n    A\n        optional\n            Engin\n";
+        let fixed = "features\n    A\n        optional\n            Engine\n";
+        let mut files: HashMap<Url, Arc<Document>> =
+            [(a.clone(), doc(&a, broken)), (b.clone(), b_doc)]
+                .into_iter()
+                .collect();
+        let mut handler = RootGraphHandler::default();
+        let mut root = RootGraph::new(&files, 0);
+        let err = handler.check(&mut root, true);
+        let b_id = root.file_id(&b).unwrap();
+        assert!(err[&b_id].iter().any(ErrorInfo::is_error));
+        //only a changes, b keeps its old document
+        files.insert(a.clone(), doc(&a, fixed));
+        let mut root = RootGraph::new(&files, 1);
+        let err = handler.check(&mut root, false);
+        let b_id = root.file_id(&b).unwrap();
+        assert!(err.get(&b_id).is_some_and(|err| err.is_empty()));
+    }
+    #[test]
+    fn constraint_edit_skips_importers() {
+        let a = Url::parse("file:///model/a.uvl").unwrap();
+        let model = |constraint: &str, attributes: &str| {
+            format!(
+                "features\n    A {{{}}}\n        optional\n            Engine\nconstraints\n    {}\n",
+                attributes, constraint
+            )
+        };
+        let mut files: HashMap<Url, Arc<Document>> = (0..200)
+            .map(|i| {
+                let uri = Url::parse(&format!("file:///model/b{}.uvl", i)).unwrap();
+                let text = "imports\n    a\nfeatures\n    B\nconstraints\n    B => a.Engine\n";
+                (uri.clone(), doc(&uri, text))
+            })
+            .collect();
+        let a_doc = doc(&a, &model("A => Engine", "price 1, weight 2"));
+        files.insert(a.clone(), a_doc);
+        let mut handler = RootGraphHandler::default();
+        handler.check(&mut RootGraph::new(&files, 0), true);
+        //constraints and attribute order are not part of the interface
+        let a_doc = doc(&a, &model("Engine => A", "weight 2, price 1"));
+        files.insert(a.clone(), a_doc);
+        let mut root = RootGraph::new(&files, 1);
+        let err = handler.check(&mut root, false);
+        assert_eq!(err.len(), 1);
+        assert!(err.contains_key(&root.file_id(&a).unwrap()));
+        files.insert(a.clone(), doc(&a, &model("Engine => A", "weight 2")));
+        let mut root = RootGraph::new(&files, 2);
+        assert_eq!(handler.check(&mut root, false).len(), 201);
+    }
+    #[test]
+    fn clear_rechecks_all() {
+        let files: HashMap<Url, Arc<Document>> = (0..3)
+            .map(|i| {
+                let uri = Url::parse(&format!("file:///model/f{}.uvl", i)).unwrap();
+                (uri.clone(), doc(&uri, "features\n    A\n"))
+            })
+            .collect();
+        let mut handler = RootGraphHandler::default();
+        let mut root = RootGraph::new(&files, 0);
+        handler.check(&mut root, true);
+        assert!(handler.check(&mut root, false).is_empty());
+        assert_eq!(handler.clear(), 3);
+        assert_eq!(handler.check(&mut root, false).len(), 3);
+    }
+    #[test]
+    fn attribute_resolution_order() {
+        let uri = Url::parse("file:///model/order.uvl").unwrap();
+        let text = "features\n    Car {price 10, constraint price > 5}\n        optional\n            Engine {price 3, constraint price > 1}\n                optional\n                    Piston {constraint price > 0}\n            Wheel {weight 2}\n            Seat {weight 1}\nconstraints\n    weight > 0\n";
+        let files: HashMap<Url, Arc<Document>> =
+            [(uri.clone(), doc(&uri, text))].into_iter().collect();
+        let root = RootGraph::new(&files, 0);
+        let file_id = root.file_id(&uri).unwrap();
+        let file = root.file(file_id);
+        let line = |sym: Symbol| file.lsp_range(sym).unwrap().start.line;
+        //line of the reference and of the attributes it resolves to
+        let resolved: Vec<(u32, Vec<u32>)> = file
+            .all_references()
+            .map(|sym| {
+                let targets = match root.resolve_reference(file_id, sym) {
+                    Resolution::Resolved(dst) => vec![line(dst.sym)],
+                    Resolution::Ambiguous(candidates) => {
+                        candidates.iter().map(|c| line(c.sym)).collect()
+                    }
+                    _ => Vec::new(),
+                };
+                (line(sym), targets)
+            })
+            .collect();
+        //the enclosing feature first, then its ancestors
+        assert!(resolved.contains(&(1, vec![1])));
+        assert!(resolved.contains(&(3, vec![3])));
+        assert!(resolved.contains(&(5, vec![3])));
+        //a bare name without scope matching several features is ambiguous
+        assert!(resolved.contains(&(9, vec![6, 7])));
+        let errors = root.reference_errors(file_id);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].msg, "ambiguous attribute reference");
+        assert_eq!(errors[0].location.start.line, 9);
+        let related: Vec<&str> = errors[0]
+            .related
+            .iter()
+            .map(|r| r.message.as_str())
+            .collect();
+        assert_eq!(related, ["candidate Wheel.weight", "candidate Seat.weight"]);
+    }
+}
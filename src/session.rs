@@ -0,0 +1,563 @@
+use crate::semantic::{RootGraph, RootSymbol, Snapshot};
+use crate::smt::{ComposedCache, WarmSolver};
+use hashbrown::HashMap;
+use log::info;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::select;
+use tokio::sync::RwLock;
+use tokio::time::{Duration, Instant, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tower_lsp::lsp_types::*;
+use tower_lsp::Client;
+use ustr::Ustr;
+/*
+ * Interactive configuration sessions. A session keeps a solver loaded with the component of the
+ * model it was started on and a stack of decisions. After every decision the value each feature
+ * is forced to is propagated and only the features whose state changed since the last answer
+ * are reported. Each solver is a z3 process, so sessions are limited in number, closed when
+ * they are idle for too long and closed as soon as a document of the component changes, the
+ * client is told about closed sessions with uvls/configSession/invalidated.
+ */
+pub const MAX_SESSIONS: usize = 4;
+pub const SESSION_TIMEOUT: Duration = Duration::from_secs(600);
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StartParams {
+    pub text_document: TextDocumentIdentifier,
+}
+#[derive(Deserialize, Debug)]
+pub struct SessionParams {
+    pub session: u64,
+}
+#[derive(Deserialize, Debug)]
+pub struct SetParams {
+    pub session: u64,
+    pub uri: Url,
+    //name of the feature as declared in uri
+    pub feature: String,
+    pub selected: bool,
+}
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum FeatureState {
+    Selected,
+    Deselected,
+    Open,
+}
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FeatureRef {
+    pub uri: Url,
+    pub feature: String,
+}
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FeatureChange {
+    #[serde(flatten)]
+    pub feature: FeatureRef,
+    pub range: Range,
+    pub state: FeatureState,
+}
+#[derive(Serialize, Debug)]
+pub struct Delta {
+    pub session: u64,
+    //the decision contradicts the model or earlier decisions and was not applied
+    pub conflict: bool,
+    pub changes: Vec<FeatureChange>,
+}
+#[derive(Serialize, Debug)]
+pub struct Finished {
+    //every feature is decided
+    pub complete: bool,
+    pub selected: Vec<FeatureRef>,
+    pub deselected: Vec<FeatureRef>,
+    pub open: Vec<FeatureRef>,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InvalidatedParams {
+    pub session: u64,
+    pub reason: String,
+}
+pub enum Invalidated {}
+impl notification::Notification for Invalidated {
+    type Params = InvalidatedParams;
+    const METHOD: &'static str = "uvls/configSession/invalidated";
+}
+
+struct Feature {
+    name: FeatureRef,
+    range: Range,
+}
+struct Session {
+    solver: WarmSolver,
+    cancel: CancellationToken,
+    features: HashMap<RootSymbol, Feature>,
+    lookup: HashMap<(Url, Ustr), RootSymbol>,
+    //documents of the component and the revision the session was started on
+    revisions: Vec<(Url, Instant)>,
+    decisions: Vec<(RootSymbol, bool)>,
+    //forced values of the last answer
+    state: HashMap<RootSymbol, bool>,
+    last_used: Instant,
+}
+impl Session {
+    fn stale(&self, root: &RootGraph) -> bool {
+        self.revisions
+            .iter()
+            .any(|(uri, ts)| root.file_by_uri(uri).map(|f| f.timestamp) != Some(*ts))
+    }
+    //Propagate the decisions and return what changed since the last answer, None on conflict
+    async fn update(&mut self) -> Result<Option<Vec<FeatureChange>>, String> {
+        let forced = self
+            .solver
+            .propagate(&self.decisions, &self.cancel)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(forced.map(|forced| {
+            let changes = changes(&self.features, &self.state, &forced);
+            self.state = forced;
+            changes
+        }))
+    }
+    fn finish(&self) -> Finished {
+        let mut out = Finished {
+            complete: true,
+            selected: Vec::new(),
+            deselected: Vec::new(),
+            open: Vec::new(),
+        };
+        for (sym, feature) in self.features.iter() {
+            let name = feature.name.clone();
+            match self.state.get(sym) {
+                Some(true) => out.selected.push(name),
+                Some(false) => out.deselected.push(name),
+                None => out.open.push(name),
+            }
+        }
+        out.complete = out.open.is_empty();
+        out.selected.sort();
+        out.deselected.sort();
+        out.open.sort();
+        out
+    }
+}
+fn state(value: Option<&bool>) -> FeatureState {
+    match value {
+        Some(true) => FeatureState::Selected,
+        Some(false) => FeatureState::Deselected,
+        None => FeatureState::Open,
+    }
+}
+//Features whose state differs between two propagations
+fn changes(
+    features: &HashMap<RootSymbol, Feature>,
+    old: &HashMap<RootSymbol, bool>,
+    new: &HashMap<RootSymbol, bool>,
+) -> Vec<FeatureChange> {
+    let mut changes: Vec<FeatureChange> = features
+        .iter()
+        .filter(|(sym, _)| old.get(*sym) != new.get(*sym))
+        .map(|(sym, feature)| FeatureChange {
+            feature: feature.name.clone(),
+            range: feature.range,
+            state: state(new.get(sym)),
+        })
+        .collect();
+    changes.sort_by(|a, b| (&a.feature.uri, a.range.start).cmp(&(&b.feature.uri, b.range.start)));
+    changes
+}
+
+#[derive(Default)]
+pub struct Sessions {
+    next_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, Arc<tokio::sync::Mutex<Session>>>>,
+    //told about sessions closed without a finish request
+    client: Option<Client>,
+}
+impl Sessions {
+    pub fn new(client: Client) -> Self {
+        Sessions {
+            client: Some(client),
+            ..Default::default()
+        }
+    }
+    //Start a session on the component of uri, the snapshot is released before the solver is
+    //spawned
+    pub async fn start(
+        &self,
+        root: Snapshot<'_>,
+        uri: &Url,
+        composed: &ComposedCache,
+    ) -> Result<Delta, String> {
+        if self.sessions.lock().len() >= MAX_SESSIONS {
+            return Err(format!(
+                "at most {} configuration sessions can be open",
+                MAX_SESSIONS
+            ));
+        }
+        let file_id = root.file_id(uri).ok_or("unknown document")?;
+        let comp = root
+            .components()
+            .iter()
+            .find(|comp| comp.members.contains(&file_id))
+            .ok_or("the model is not analyzed yet")?;
+        let input = WarmSolver::input(&root, comp, composed)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut features = HashMap::new();
+        let mut lookup = HashMap::new();
+        let mut revisions = Vec::new();
+        for &m in comp.members.iter() {
+            let file = root.file(m);
+            revisions.push((file.uri.clone(), file.timestamp));
+            for f in file.all_features() {
+                let (name, range) = match (file.symbol_name(f), file.lsp_range(f)) {
+                    (Some(name), Some(range)) => (name, range),
+                    _ => continue,
+                };
+                let sym = RootSymbol { file: m, sym: f };
+                lookup.insert((file.uri.clone(), name), sym);
+                features.insert(
+                    sym,
+                    Feature {
+                        name: FeatureRef {
+                            uri: file.uri.clone(),
+                            feature: name.to_string(),
+                        },
+                        range,
+                    },
+                );
+            }
+        }
+        drop(root);
+        let cancel = CancellationToken::new();
+        let solver = WarmSolver::start(input, &cancel)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut session = Session {
+            solver,
+            cancel,
+            features,
+            lookup,
+            revisions,
+            decisions: Vec::new(),
+            state: HashMap::new(),
+            last_used: Instant::now(),
+        };
+        let changes = session
+            .update()
+            .await?
+            .ok_or("the model has no valid configuration")?;
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut sessions = self.sessions.lock();
+        if sessions.len() >= MAX_SESSIONS {
+            return Err(format!(
+                "at most {} configuration sessions can be open",
+                MAX_SESSIONS
+            ));
+        }
+        sessions.insert(id, Arc::new(tokio::sync::Mutex::new(session)));
+        info!("started configuration session {}", id);
+        Ok(Delta {
+            session: id,
+            conflict: false,
+            changes,
+        })
+    }
+    fn get(&self, id: u64) -> Result<Arc<tokio::sync::Mutex<Session>>, String> {
+        self.sessions
+            .lock()
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| "unknown or closed configuration session".into())
+    }
+    async fn invalidate(&self, closed: Vec<InvalidatedParams>) {
+        for params in closed {
+            info!(
+                "closed configuration session {}: {}",
+                params.session, params.reason
+            );
+            if let Some(client) = self.client.as_ref() {
+                client.send_notification::<Invalidated>(params).await;
+            }
+        }
+    }
+    //Close a session whose model changed while it was in use
+    async fn close_stale(&self, id: u64) -> String {
+        if self.sessions.lock().remove(&id).is_some() {
+            self.invalidate(vec![InvalidatedParams {
+                session: id,
+                reason: "the model changed".into(),
+            }])
+            .await;
+        }
+        "the model changed, the session is closed".into()
+    }
+    //Decide a feature, deciding it again replaces the earlier decision
+    pub async fn set(&self, root: &RwLock<RootGraph>, params: &SetParams) -> Result<Delta, String> {
+        let session = self.get(params.session)?;
+        let mut session = session.lock().await;
+        if session.stale(&*root.read().await) {
+            return Err(self.close_stale(params.session).await);
+        }
+        session.last_used = Instant::now();
+        let feature = *session
+            .lookup
+            .get(&(params.uri.clone(), Ustr::from(&params.feature)))
+            .ok_or("unknown feature")?;
+        let previous = session.decisions.clone();
+        session.decisions.retain(|(f, _)| *f != feature);
+        session.decisions.push((feature, params.selected));
+        match session.update().await? {
+            Some(changes) => Ok(Delta {
+                session: params.session,
+                conflict: false,
+                changes,
+            }),
+            None => {
+                session.decisions = previous;
+                Ok(Delta {
+                    session: params.session,
+                    conflict: true,
+                    changes: Vec::new(),
+                })
+            }
+        }
+    }
+    //Revert the last decision
+    pub async fn undo(&self, root: &RwLock<RootGraph>, id: u64) -> Result<Delta, String> {
+        let session = self.get(id)?;
+        let mut session = session.lock().await;
+        if session.stale(&*root.read().await) {
+            return Err(self.close_stale(id).await);
+        }
+        session.last_used = Instant::now();
+        if session.decisions.pop().is_none() {
+            return Err("nothing to undo".into());
+        }
+        //fewer decisions can not conflict
+        let changes = session.update().await?.unwrap_or_default();
+        Ok(Delta {
+            session: id,
+            conflict: false,
+            changes,
+        })
+    }
+    //Close the session and return the configuration
+    pub async fn finish(&self, root: &RwLock<RootGraph>, id: u64) -> Result<Finished, String> {
+        let session = self.get(id)?;
+        let session = session.lock().await;
+        if session.stale(&*root.read().await) {
+            return Err(self.close_stale(id).await);
+        }
+        self.sessions.lock().remove(&id);
+        info!("finished configuration session {}", id);
+        Ok(session.finish())
+    }
+    //Close sessions of changed models and sessions idle for SESSION_TIMEOUT, sessions busy
+    //with a request are in use and kept
+    fn sweep(&self, root: &RootGraph, now: Instant) -> Vec<InvalidatedParams> {
+        let mut closed = Vec::new();
+        self.sessions.lock().retain(|id, session| {
+            let session = match session.try_lock() {
+                Ok(session) => session,
+                Err(_) => return true,
+            };
+            let reason = if session.stale(root) {
+                "the model changed"
+            } else if now.duration_since(session.last_used) > SESSION_TIMEOUT {
+                "the session expired"
+            } else {
+                return true;
+            };
+            closed.push(InvalidatedParams {
+                session: *id,
+                reason: reason.into(),
+            });
+            false
+        });
+        closed
+    }
+}
+pub async fn sweep_sessions(
+    root: Arc<RwLock<RootGraph>>,
+    sessions: Arc<Sessions>,
+    shutdown: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        select! {
+            _ = shutdown.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+        if sessions.sessions.lock().is_empty() {
+            continue;
+        }
+        let closed = sessions.sweep(&*root.read().await, Instant::now());
+        sessions.invalidate(closed).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{Document, Symbol};
+    use crate::document::build_document;
+    use crate::parse::parse;
+    use crate::semantic::{link_documents, FileID};
+    use ropey::Rope;
+    fn feature(name: &str, line: u32) -> Feature {
+        Feature {
+            name: FeatureRef {
+                uri: Url::parse("file:///m.uvl").unwrap(),
+                feature: name.into(),
+            },
+            range: Range::new(Position::new(line, 4), Position::new(line, 5)),
+        }
+    }
+    #[test]
+    fn delta_since_last_answer() {
+        let file = FileID(0);
+        let sym = |i| RootSymbol {
+            file,
+            sym: Symbol::Feature(i),
+        };
+        let features: HashMap<_, _> = [
+            (sym(0), feature("A", 1)),
+            (sym(1), feature("B", 3)),
+            (sym(2), feature("C", 5)),
+        ]
+        .into_iter()
+        .collect();
+        let old: HashMap<_, _> = [(sym(0), true)].into_iter().collect();
+        let new: HashMap<_, _> = [(sym(0), true), (sym(1), false), (sym(2), true)]
+            .into_iter()
+            .collect();
+        let delta = changes(&features, &old, &new);
+        let names: Vec<_> = delta.iter().map(|c| c.feature.feature.as_str()).collect();
+        assert_eq!(names, ["B", "C"]);
+        assert_eq!(delta[0].state, FeatureState::Deselected);
+        assert_eq!(delta[1].state, FeatureState::Selected);
+        let undone = changes(&features, &new, &old);
+        assert!(undone.iter().all(|c| c.state == FeatureState::Open));
+        assert_eq!(undone.len(), 2);
+    }
+    fn uri() -> Url {
+        Url::parse("file:///car.uvl").unwrap()
+    }
+    fn linked(text: &str) -> RootGraph {
+        let source = Rope::from_str(text);
+        let doc = build_document(
+            source.clone(),
+            parse(&source, None),
+            uri(),
+            Instant::now(),
+            Default::default(),
+        );
+        let files: hashbrown::HashMap<Url, Arc<Document>> =
+            [(uri(), Arc::new(doc))].into_iter().collect();
+        link_documents(&files).0
+    }
+    const CAR: &str = "features\n    Car\n        alternative\n            A\n            B\n";
+    fn set(session: u64, feature: &str, selected: bool) -> SetParams {
+        SetParams {
+            session,
+            uri: uri(),
+            feature: feature.into(),
+            selected,
+        }
+    }
+    fn states(delta: &Delta) -> Vec<(&str, FeatureState)> {
+        delta
+            .changes
+            .iter()
+            .map(|c| (c.feature.feature.as_str(), c.state))
+            .collect()
+    }
+    #[tokio::test]
+    #[ignore = "needs z3"]
+    async fn decisions() {
+        let root = RwLock::new(linked(CAR));
+        let composed = ComposedCache::default();
+        let sessions = Sessions::default();
+        let start = sessions
+            .start(root.read().await, &uri(), &composed)
+            .await
+            .unwrap();
+        let id = start.session;
+        assert_eq!(states(&start), [("Car", FeatureState::Selected)]);
+        let a = sessions.set(&root, &set(id, "A", true)).await.unwrap();
+        let expected = [
+            ("A", FeatureState::Selected),
+            ("B", FeatureState::Deselected),
+        ];
+        assert_eq!(states(&a), expected);
+        //B excludes A, the decision is not applied
+        let b = sessions.set(&root, &set(id, "B", true)).await.unwrap();
+        assert!(b.conflict && b.changes.is_empty());
+        let undo = sessions.undo(&root, id).await.unwrap();
+        assert_eq!(
+            states(&undo),
+            [("A", FeatureState::Open), ("B", FeatureState::Open)]
+        );
+        assert!(sessions.undo(&root, id).await.is_err());
+        let finished = sessions.finish(&root, id).await.unwrap();
+        assert!(!finished.complete);
+        assert_eq!(finished.open.len(), 2);
+        assert!(sessions.undo(&root, id).await.is_err());
+    }
+    #[tokio::test]
+    #[ignore = "needs z3"]
+    async fn limits() {
+        let root = RwLock::new(linked(CAR));
+        let composed = ComposedCache::default();
+        let sessions = Sessions::default();
+        let mut ids = Vec::new();
+        for _ in 0..MAX_SESSIONS {
+            let start = sessions.start(root.read().await, &uri(), &composed).await;
+            ids.push(start.unwrap().session);
+        }
+        assert!(sessions
+            .start(root.read().await, &uri(), &composed)
+            .await
+            .is_err());
+        //sessions in use are never expired
+        let busy = sessions.get(ids[0]).unwrap();
+        let busy = busy.lock().await;
+        let later = Instant::now() + SESSION_TIMEOUT + Duration::from_secs(1);
+        let closed = sessions.sweep(&*root.read().await, later);
+        assert_eq!(closed.len(), MAX_SESSIONS - 1);
+        assert!(closed.iter().all(|c| c.reason == "the session expired"));
+        drop(busy);
+        let now = Instant::now();
+        assert!(sessions.sweep(&*root.read().await, now).is_empty());
+        assert!(sessions
+            .start(root.read().await, &uri(), &composed)
+            .await
+            .is_ok());
+    }
+    #[tokio::test]
+    #[ignore = "needs z3"]
+    async fn invalidation() {
+        let root = RwLock::new(linked(CAR));
+        let composed = ComposedCache::default();
+        let sessions = Sessions::default();
+        let start = || async {
+            let delta = sessions.start(root.read().await, &uri(), &composed).await;
+            delta.unwrap().session
+        };
+        let (used, idle) = (start().await, start().await);
+        *root.write().await = linked(CAR);
+        //a request on a changed model closes the session
+        assert!(sessions.set(&root, &set(used, "A", true)).await.is_err());
+        assert!(sessions.get(used).is_err());
+        let closed = sessions.sweep(&*root.read().await, Instant::now());
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].session, idle);
+        assert_eq!(closed[0].reason, "the model changed");
+    }
+}
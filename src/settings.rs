@@ -0,0 +1,259 @@
+use crate::ast::Limits;
+use crate::schema::SchemaRule;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::info;
+use ropey::Rope;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::PathBuf;
+use tower_lsp::lsp_types::*;
+//User configuration, the client sends it as initializationOptions and on
+//workspace/didChangeConfiguration either directly or nested under "uvls"
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Settings {
+    //Paths or globs of third-party models, they are indexed for resolution but never
+    //checked or edited. Relative patterns match anywhere in the workspace
+    pub library: Vec<String>,
+    //Maximum number of solver processes running at once, the other analyses wait and the ones
+    //of documents open in the editor are let in first
+    pub max_solvers: usize,
+    //Constraints with more syntax nodes are skipped from analysis with a warning
+    pub max_constraint_size: usize,
+    //Characters triggering completion in addition to . and [, only read on initialization
+    //since the client registers them once
+    pub completion_triggers: Vec<String>,
+    //Keep the diagnostics of files on disk in a cache to show them right away on the next
+    //start, only read on initialization
+    pub model_cache: bool,
+    //Poll files on disk for changes when the client can not watch them
+    pub file_polling: bool,
+    //Time budget in milliseconds for the solver analyses of a validation summary
+    pub analysis_timeout: u64,
+    //Diagnostics published per file, the heaviest are kept and the rest summarized. 0 publishes
+    //all of them
+    pub max_diagnostics: usize,
+    //Attribute conventions checked on every feature, see schema.rs
+    pub schema: Vec<SchemaRule>,
+    //Attributes of a feature are defaults for its descendants, for dialects with inheritance
+    pub inherit_attributes: bool,
+    //Attribute keys whose string values name a feature or attribute, eg. requires. See
+    //valueref.rs
+    pub reference_attributes: Vec<String>,
+    //Format models when the editor saves them, with the same edits as document formatting
+    pub format_on_save: bool,
+    //Percentage of a file covered by syntax errors above which a single "may not be UVL" error
+    //replaces the syntax errors, 0 disables it
+    pub not_uvl_threshold: usize,
+    //Threads parsing the workspace on startup, 0 uses all cores but one. Only read on
+    //initialization
+    pub scan_workers: usize,
+    #[serde(skip)]
+    library_set: GlobSet,
+}
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            library: Vec::new(),
+            max_solvers: 2,
+            max_constraint_size: 5000,
+            completion_triggers: Vec::new(),
+            model_cache: false,
+            file_polling: true,
+            analysis_timeout: 10000,
+            max_diagnostics: 200,
+            schema: Vec::new(),
+            inherit_attributes: false,
+            reference_attributes: Vec::new(),
+            format_on_save: false,
+            not_uvl_threshold: 50,
+            scan_workers: 0,
+            library_set: GlobSet::empty(),
+        }
+    }
+}
+fn unnest(value: Value) -> Value {
+    match value {
+        Value::Object(mut map) if map.contains_key("uvls") => map.remove("uvls").unwrap(),
+        value => value,
+    }
+}
+impl Settings {
+    pub fn from_value(value: Value) -> Settings {
+        let value = unnest(value);
+        let mut settings: Settings = serde_json::from_value(value).unwrap_or_else(|e| {
+            info!("invalid settings {}", e);
+            Settings::default()
+        });
+        settings.compile();
+        settings
+    }
+    fn compile(&mut self) {
+        let mut builder = GlobSetBuilder::new();
+        for i in self.library.iter() {
+            let pattern = if i.starts_with('/') || i.starts_with("**") {
+                i.clone()
+            } else {
+                format!("**/{}", i)
+            };
+            match Glob::new(&pattern) {
+                Ok(glob) => {
+                    builder.add(glob);
+                }
+                Err(e) => info!("invalid library pattern {}: {}", i, e),
+            }
+        }
+        self.library_set = builder.build().unwrap_or_else(|_| GlobSet::empty());
+    }
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_constraint_size: self.max_constraint_size,
+            not_uvl_threshold: self.not_uvl_threshold,
+        }
+    }
+    //Library models are only used for resolution, no diagnostics or edits
+    pub fn is_library(&self, uri: &Url) -> bool {
+        uri.to_file_path()
+            .map(|path| self.library_set.is_match(path))
+            .unwrap_or(false)
+    }
+}
+//Settings committed next to the models, they are shared by everyone working on the workspace
+pub static WORKSPACE_FILE: &str = ".uvls.json";
+//Settings which only affect the editor, the client wins for these while the workspace file
+//wins for everything influencing diagnostics
+static CLIENT_SETTINGS: &[&str] = &["completionTriggers"];
+#[derive(Debug, Default)]
+pub struct ConfigSources {
+    pub client: Value,
+    //Last valid content of the workspace file
+    pub workspace: Option<Value>,
+    pub workspace_file: Option<PathBuf>,
+    //Content of the workspace file while it is open in the editor, it replaces the one on disk
+    pub workspace_draft: Option<Rope>,
+}
+impl ConfigSources {
+    pub fn merged(&self) -> Value {
+        let mut merged = match unnest(self.client.clone()) {
+            Value::Object(map) => map,
+            _ => serde_json::Map::new(),
+        };
+        if let Some(Value::Object(workspace)) = self.workspace.clone() {
+            for (key, value) in workspace {
+                if !(CLIENT_SETTINGS.contains(&key.as_str()) && merged.contains_key(&key)) {
+                    merged.insert(key, value);
+                }
+            }
+        }
+        Value::Object(merged)
+    }
+    pub fn effective(&self) -> Settings {
+        Settings::from_value(self.merged())
+    }
+    pub fn is_workspace_file(&self, uri: &Url) -> bool {
+        self.workspace_file.is_some() && uri.to_file_path().ok() == self.workspace_file
+    }
+    //Apply the edits of the editor to the open workspace file
+    pub fn edit_workspace(&mut self, changes: &[TextDocumentContentChangeEvent]) {
+        let draft = match self.workspace_draft.as_mut() {
+            Some(draft) => draft,
+            None => return,
+        };
+        for change in changes {
+            match change.range {
+                Some(range) => {
+                    let start = crate::util::char_offset(&range.start, draft);
+                    let end = crate::util::char_offset(&range.end, draft);
+                    draft.remove(start..end);
+                    draft.insert(start, &change.text);
+                }
+                None => *draft = Rope::from_str(&change.text),
+            }
+        }
+    }
+    //Reread the workspace file, a broken file keeps the previous settings and is reported
+    //with diagnostics on the file itself
+    pub fn load_workspace(&mut self) -> Vec<Diagnostic> {
+        let path = match self.workspace_file.as_ref() {
+            Some(path) => path,
+            None => return Vec::new(),
+        };
+        let text = match self.workspace_draft.as_ref() {
+            Some(draft) => draft.to_string(),
+            None => match std::fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(_) => {
+                    self.workspace = None;
+                    return Vec::new();
+                }
+            },
+        };
+        //deserialize into Settings first to report wrongly typed values with a location
+        match serde_json::from_str::<Settings>(&text)
+            .and_then(|_| serde_json::from_str::<Value>(&text))
+        {
+            Ok(value) => {
+                info!("loaded workspace settings {:?}", path);
+                self.workspace = Some(value);
+                Vec::new()
+            }
+            Err(e) => {
+                let pos = Position::new(
+                    e.line().saturating_sub(1) as u32,
+                    e.column().saturating_sub(1) as u32,
+                );
+                vec![Diagnostic {
+                    range: Range {
+                        start: pos,
+                        end: pos,
+                    },
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    message: format!("invalid workspace settings: {}", e),
+                    ..Default::default()
+                }]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn workspace_precedence() {
+        let config = ConfigSources {
+            client: serde_json::json!({"uvls": {"maxSolvers": 4, "completionTriggers": ["#"]}}),
+            workspace: Some(serde_json::json!({"maxSolvers": 1, "completionTriggers": ["@"]})),
+            workspace_file: None,
+            workspace_draft: None,
+        };
+        let settings = config.effective();
+        assert_eq!(settings.max_solvers, 1);
+        assert_eq!(settings.completion_triggers, vec!["#".to_string()]);
+    }
+    #[test]
+    fn workspace_draft() {
+        let mut config = ConfigSources {
+            workspace_file: Some(PathBuf::from("/missing/.uvls.json")),
+            workspace_draft: Some(Rope::from_str("{\"maxSolvers\": 2}")),
+            ..Default::default()
+        };
+        assert!(config.load_workspace().is_empty());
+        assert_eq!(config.effective().max_solvers, 2);
+        //a broken edit is reported and keeps the last valid settings
+        let edit = |start: u32, end: u32, text: &str| TextDocumentContentChangeEvent {
+            range: Some(Range::new(Position::new(0, start), Position::new(0, end))),
+            range_length: None,
+            text: text.into(),
+        };
+        config.edit_workspace(&[edit(15, 16, "x")]);
+        let diagnostics = config.load_workspace();
+        assert_eq!(diagnostics.len(), 1);
+        let message = &diagnostics[0].message;
+        assert!(message.starts_with("invalid workspace settings"));
+        assert_eq!(config.effective().max_solvers, 2);
+        config.edit_workspace(&[edit(15, 16, "3")]);
+        assert!(config.load_workspace().is_empty());
+        assert_eq!(config.effective().max_solvers, 3);
+    }
+}
@@ -1,16 +1,24 @@
 use crate::{
     ast::*,
     check::ErrorInfo,
+    inherit::inherited_numbers,
     semantic::{Component, ComponentErrorState, Context, FileID, RootGraph, RootSymbol},
+    settings::Settings,
     util::maybe_cancel,
 };
 use futures::future::join_all;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use lazy_static::lazy_static;
 use log::info;
+use parking_lot::Mutex;
+use serde::Serialize;
 use std::error;
 use std::fmt::{Display, Write};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use tokio::select;
+use tokio::sync::Notify;
 use tokio::{
     io::Lines,
     process::{ChildStdin, ChildStdout, Command},
@@ -20,7 +28,7 @@ use tokio::{
     process::Child,
 };
 use tokio_util::sync::CancellationToken;
-use tower_lsp::lsp_types::DiagnosticSeverity;
+use tower_lsp::lsp_types::{DiagnosticSeverity, DiagnosticTag, Url};
 use write as write_smt;
 
 #[derive(Debug)]
@@ -127,7 +135,7 @@ fn gather_clause(ctx: &Binding, file: &Document, file_id: FileID, g: Symbol) ->
 fn declare_groups(ctx: &Binding, file_id: FileID) -> Option<String> {
     let mut out = String::new();
     let file = &ctx.root.file(file_id);
-    if !file.errors.is_empty() {
+    if file.errors.iter().any(ErrorInfo::is_error) {
         return None;
     }
     for p in file.all_features() {
@@ -232,105 +240,157 @@ fn declare_groups(ctx: &Binding, file_id: FileID) -> Option<String> {
     }
     Some(out)
 }
-fn encode_numeric(ctx: &Binding, file_id: FileID, expr: &Numeric) -> Option<String> {
-    match expr {
-        Numeric::Number(num) => Some(format!("{:?}", num)),
-        Numeric::Ref(id) => Some(format!("{}", ctx.bind(*id, file_id)?)),
-        Numeric::Binary { op, rhs, lhs } => {
-            let op_str = match op {
-                NumericOP::Add => "+",
-                NumericOP::Sub => "-",
-                NumericOP::Mul => "*",
-                NumericOP::Div => "smooth_div",
-            };
-            Some(format!(
-                "({} {} {})",
-                op_str,
-                stacker::maybe_grow(32 * 1024, 1024 * 1024, || encode_numeric(ctx, file_id, lhs))?,
-                stacker::maybe_grow(32 * 1024, 1024 * 1024, || encode_numeric(ctx, file_id, rhs))?
-            ))
+//Top level features of models not imported by another member are always selected,
+//several of them are mandatory children of an implicit root
+fn declare_roots(ctx: &Binding) -> Option<String> {
+    let mut out = String::new();
+    for &file_id in ctx.members.iter() {
+        if ctx
+            .root
+            .fs
+            .imported(file_id)
+            .any(|(_, src)| ctx.index.contains_key(&src))
+        {
+            continue;
         }
-        Numeric::Aggregate { op, context, query } => {
-            let mut all_attributes = String::new();
-            let mut count_features = String::new();
-            ctx.root.resolve_attributes_with_feature(
-                file_id,
-                context
-                    .map(|context| ctx.root.file(file_id).path(context))
-                    .unwrap_or(&[]),
-                |feature, attrib, prefix, tgt_file| {
-                    if prefix == query.names.as_slice()
-                        && tgt_file.type_of(attrib.sym).unwrap() == Type::Number
-                    {
-                        let _ = write!(
-                            count_features,
-                            "(ite {}  1.0 0.0)",
-                            ctx.bind(feature.sym, feature.file).unwrap()
-                        );
-                        let _ = write!(
-                            all_attributes,
-                            " {}",
-                            ctx.bind(attrib.sym, attrib.file).unwrap()
-                        );
-                    }
-                },
-            );
-            if all_attributes.is_empty() {
-                return Some("0.0".into());
+        for f in ctx.root.file(file_id).root_features() {
+            let _ = write_smt!(out, "(assert {})", ctx.bind(f, file_id)?);
+        }
+    }
+    Some(out)
+}
+fn encode_aggregate(
+    ctx: &Binding,
+    file_id: FileID,
+    op: &AggregateOP,
+    context: Option<Symbol>,
+    query: &Path,
+) -> Option<String> {
+    let mut all_attributes = String::new();
+    let mut count_features = String::new();
+    let context = context
+        .map(|context| ctx.root.file(file_id).path(context))
+        .unwrap_or(&[]);
+    ctx.root.resolve_attributes_with_feature(
+        file_id,
+        context,
+        |feature, attrib, prefix, tgt_file| {
+            if prefix == query.names.as_slice()
+                && tgt_file.type_of(attrib.sym).unwrap() == Type::Number
+            {
+                let _ = write!(
+                    count_features,
+                    "(ite {}  1.0 0.0)",
+                    ctx.bind(feature.sym, feature.file).unwrap()
+                );
+                let _ = write!(
+                    all_attributes,
+                    " {}",
+                    ctx.bind(attrib.sym, attrib.file).unwrap()
+                );
             }
-            match op {
-                AggregateOP::Sum => Some(format!("(+ {})", all_attributes)),
-                AggregateOP::Avg => Some(format!(
-                    "(smooth_div  (+ {}) (+ {}))",
-                    all_attributes, count_features
-                )),
+        },
+    );
+    //features without the attribute take part with the value they inherit
+    if ctx.root.inherit_attributes {
+        for root in ctx.root.resolve(file_id, context) {
+            let tgt_file = ctx.root.file(root.file);
+            for (feature, num) in inherited_numbers(tgt_file, root.sym, &query.names) {
+                let feature = ctx.bind(feature, root.file)?;
+                let _ = write!(count_features, "(ite {}  1.0 0.0)", feature);
+                let _ = write!(all_attributes, " (ite {} {:?} 0.0)", feature, num);
             }
         }
     }
-}
-
-fn encode_constraint(ctx: &Binding, file_id: FileID, constraint: &Constraint) -> Option<String> {
-    match constraint {
-        Constraint::Constant(val) => Some(format!("{}", val)),
-        Constraint::Ref(id) => Some(format!("{}", ctx.bind(*id, file_id)?)),
-        Constraint::Not(lhs) => Some(format!(
-            "(not {})",
-            stacker::maybe_grow(32 * 1024, 1024 * 1024, || encode_constraint(
-                ctx, file_id, lhs
-            ))?,
+    if all_attributes.is_empty() {
+        return Some("0.0".into());
+    }
+    match op {
+        AggregateOP::Sum => Some(format!("(+ {})", all_attributes)),
+        AggregateOP::Avg => Some(format!(
+            "(smooth_div  (+ {}) (+ {}))",
+            all_attributes, count_features
         )),
-        Constraint::Logic { op, lhs, rhs } => {
-            let op_str = match op {
-                LogicOP::Or => "or",
-                LogicOP::And => "and",
-                LogicOP::Equiv => "=",
-                LogicOP::Implies => "=>",
-            };
-            Some(format!(
-                "({} {} {})",
-                op_str,
-                stacker::maybe_grow(32 * 1024, 1024 * 1024, || encode_constraint(
-                    ctx, file_id, lhs
-                ))?,
-                stacker::maybe_grow(32 * 1024, 1024 * 1024, || encode_constraint(
-                    ctx, file_id, rhs
-                ))?,
-            ))
-        }
-        Constraint::Equation { op, lhs, rhs } => {
-            let op_str = match op {
-                EquationOP::Equal => "=",
-                EquationOP::Greater => ">",
-                EquationOP::Smaller => "<",
-            };
-            Some(format!(
-                "({} {} {})",
-                op_str,
-                stacker::maybe_grow(32 * 1024, 1024 * 1024, || encode_numeric(ctx, file_id, lhs))?,
-                stacker::maybe_grow(32 * 1024, 1024 * 1024, || encode_numeric(ctx, file_id, rhs))?
-            ))
+    }
+}
+enum Encode<'a> {
+    Constraint(&'a Constraint),
+    Numeric(&'a Numeric),
+    Text(&'static str),
+}
+//Expressions are encoded with an explicit stack into a single buffer, deeply nested
+//constraints would otherwise overflow the stack and copy their operands at every level
+fn encode_expr(ctx: &Binding, file_id: FileID, root: Encode) -> Option<String> {
+    let mut out = String::new();
+    let mut stack = vec![root];
+    while let Some(next) = stack.pop() {
+        match next {
+            Encode::Text(text) => out.push_str(text),
+            Encode::Numeric(expr) => match expr {
+                Numeric::Number(num) => {
+                    let _ = write!(out, "{:?}", num);
+                }
+                Numeric::Ref(id) => {
+                    let _ = write!(out, "{}", ctx.bind(*id, file_id)?);
+                }
+                Numeric::Binary { op, rhs, lhs } => {
+                    out.push_str(match op {
+                        NumericOP::Add => "(+ ",
+                        NumericOP::Sub => "(- ",
+                        NumericOP::Mul => "(* ",
+                        NumericOP::Div => "(smooth_div ",
+                    });
+                    stack.push(Encode::Text(")"));
+                    stack.push(Encode::Numeric(rhs));
+                    stack.push(Encode::Text(" "));
+                    stack.push(Encode::Numeric(lhs));
+                }
+                Numeric::Aggregate { op, context, query } => {
+                    out.push_str(&encode_aggregate(ctx, file_id, op, *context, query)?);
+                }
+            },
+            Encode::Constraint(constraint) => match constraint {
+                Constraint::Constant(val) => {
+                    let _ = write!(out, "{}", val);
+                }
+                Constraint::Ref(id) => {
+                    let _ = write!(out, "{}", ctx.bind(*id, file_id)?);
+                }
+                Constraint::Not(lhs) => {
+                    out.push_str("(not ");
+                    stack.push(Encode::Text(")"));
+                    stack.push(Encode::Constraint(lhs));
+                }
+                Constraint::Logic { op, lhs, rhs } => {
+                    out.push_str(match op {
+                        LogicOP::Or => "(or ",
+                        LogicOP::And => "(and ",
+                        LogicOP::Equiv => "(= ",
+                        LogicOP::Implies => "(=> ",
+                    });
+                    stack.push(Encode::Text(")"));
+                    stack.push(Encode::Constraint(rhs));
+                    stack.push(Encode::Text(" "));
+                    stack.push(Encode::Constraint(lhs));
+                }
+                Constraint::Equation { op, lhs, rhs } => {
+                    out.push_str(match op {
+                        EquationOP::Equal => "(= ",
+                        EquationOP::Greater => "(> ",
+                        EquationOP::Smaller => "(< ",
+                    });
+                    stack.push(Encode::Text(")"));
+                    stack.push(Encode::Numeric(rhs));
+                    stack.push(Encode::Text(" "));
+                    stack.push(Encode::Numeric(lhs));
+                }
+            },
         }
     }
+    Some(out)
+}
+fn encode_constraint(ctx: &Binding, file_id: FileID, constraint: &Constraint) -> Option<String> {
+    encode_expr(ctx, file_id, Encode::Constraint(constraint))
 }
 
 fn encode_constraints(ctx: &Binding, file_id: FileID) -> Option<String> {
@@ -366,7 +426,7 @@ async fn smtlib_model<'a>(ctx: &'a Binding<'a>) -> Option<String> {
     if ctx
         .members
         .iter()
-        .any(|f| ctx.root.file(*f).errors.len() > 0)
+        .any(|f| ctx.root.file(*f).errors.iter().any(ErrorInfo::is_error))
     {
         return None;
     }
@@ -387,6 +447,7 @@ async fn smtlib_model<'a>(ctx: &'a Binding<'a>) -> Option<String> {
             .map(|f| declare_attributes(ctx, *f))
             .collect::<String>()
     );
+    let _ = write_smt!(out, "{}", declare_roots(ctx)?);
     for file in ctx.members.iter() {
         let _ = write_smt!(out, "{}", declare_groups(ctx, *file)?);
     }
@@ -396,6 +457,78 @@ async fn smtlib_model<'a>(ctx: &'a Binding<'a>) -> Option<String> {
     let _ = write_smt!(out, "{}", "\n");
     Some(out)
 }
+//Encoded component, the member index of a binding is its position in the member list
+pub struct Composed {
+    source: String,
+    //Every feature with the name it has in the source
+    features: Vec<(u16, Symbol, String)>,
+}
+const MAX_COMPOSED: usize = 32;
+//Encodings of components by a fingerprint of their members, so analyses, validation and
+//configuration sessions of a component that did not change skip the encoding. A change in any
+//member, however deep in the import chain, changes the fingerprint of every component
+//containing it. The least recently used entries are dropped
+#[derive(Default)]
+pub struct ComposedCache {
+    entries: Mutex<HashMap<u64, (u64, Arc<Composed>)>>,
+    clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+impl ComposedCache {
+    fn fingerprint(root: &RootGraph, members: &[FileID]) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        root.inherit_attributes.hash(&mut hasher);
+        for m in members {
+            root.file(*m).composition_hash().hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+    async fn get(&self, ctx: &Binding<'_>) -> Option<Arc<Composed>> {
+        let key = Self::fingerprint(ctx.root, ctx.members);
+        let now = self.clock.fetch_add(1, Ordering::SeqCst);
+        if let Some((used, hit)) = self.entries.lock().get_mut(&key) {
+            *used = now;
+            self.hits.fetch_add(1, Ordering::SeqCst);
+            return Some(hit.clone());
+        }
+        self.misses.fetch_add(1, Ordering::SeqCst);
+        let source = smtlib_model(ctx).await?;
+        let mut features = Vec::new();
+        for &m in ctx.members.iter() {
+            for f in ctx.root.file(m).all_features() {
+                features.push((ctx.index[&m], f, ctx.bind(f, m)?.to_string()));
+            }
+        }
+        let composed = Arc::new(Composed { source, features });
+        let mut entries = self.entries.lock();
+        if entries.len() >= MAX_COMPOSED {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(k, _)| *k);
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key, (now, composed.clone()));
+        Some(composed)
+    }
+    //Drop all encodings, the next analysis of every component encodes it again
+    pub fn clear(&self) -> usize {
+        let mut entries = self.entries.lock();
+        let cleared = entries.len();
+        entries.clear();
+        cleared
+    }
+    pub fn status(&self) -> serde_json::Value {
+        serde_json::json!({
+            "hits": self.hits.load(Ordering::SeqCst),
+            "misses": self.misses.load(Ordering::SeqCst),
+            "entries": self.entries.lock().len(),
+        })
+    }
+}
 #[derive(Debug)]
 enum Reason {
     Single(Bind),
@@ -419,23 +552,29 @@ impl Reason {
     }
 }
 
-fn parse_core(ctx: &Binding, core: String) -> HashMap<FileID, Vec<ErrorInfo>> {
+fn parse_core(
+    members: &[(FileID, Arc<Document>)],
+    core: String,
+) -> HashMap<FileID, Vec<ErrorInfo>> {
     let mut out = HashMap::new();
     for r in core[1..core.len() - 1].split(" ").map(Reason::parse) {
         //info!("Reason: {:?}",r );
         match r {
             Some(Reason::Single(Bind { file, sym })) => {
-                let file = ctx.members[file as usize];
+                let (file, doc) = (members[file as usize].0, &members[file as usize].1);
                 match sym {
                     Symbol::Group(..) => {
                         insert_multi(
                             &mut out,
                             file,
                             ErrorInfo {
-                                location: ctx.root.file(file).lsp_range(sym).unwrap(),
+                                location: doc.lsp_range(sym).unwrap(),
                                 severity: DiagnosticSeverity::WARNING,
                                 msg: "unsatisfiable group".into(),
                                 weight: 20,
+                                related: Vec::new(),
+                                code: None,
+                                tags: Vec::new(),
                             },
                         );
                     }
@@ -444,10 +583,13 @@ fn parse_core(ctx: &Binding, core: String) -> HashMap<FileID, Vec<ErrorInfo>> {
                             &mut out,
                             file,
                             ErrorInfo {
-                                location: ctx.root.file(file).lsp_range(sym).unwrap(),
+                                location: doc.lsp_range(sym).unwrap(),
                                 severity: DiagnosticSeverity::WARNING,
                                 msg: "unsatisfiable constraint".into(),
                                 weight: 20,
+                                related: Vec::new(),
+                                code: None,
+                                tags: Vec::new(),
                             },
                         );
                     }
@@ -455,41 +597,50 @@ fn parse_core(ctx: &Binding, core: String) -> HashMap<FileID, Vec<ErrorInfo>> {
                 }
             }
             Some(Reason::GroupMin(Bind { file, sym })) => {
-                let file = ctx.members[file as usize];
+                let (file, doc) = (members[file as usize].0, &members[file as usize].1);
                 insert_multi(
                     &mut out,
                     file,
                     ErrorInfo {
-                        location: ctx.root.file(file).lsp_range(sym).unwrap(),
+                        location: doc.lsp_range(sym).unwrap(),
                         severity: DiagnosticSeverity::WARNING,
                         msg: "unsatisfiable group minimum".into(),
                         weight: 20,
+                        related: Vec::new(),
+                        code: None,
+                        tags: Vec::new(),
                     },
                 );
             }
             Some(Reason::GroupMax(Bind { file, sym })) => {
-                let file = ctx.members[file as usize];
+                let (file, doc) = (members[file as usize].0, &members[file as usize].1);
                 insert_multi(
                     &mut out,
                     file,
                     ErrorInfo {
-                        location: ctx.root.file(file).lsp_range(sym).unwrap(),
+                        location: doc.lsp_range(sym).unwrap(),
                         severity: DiagnosticSeverity::WARNING,
                         msg: "unsatisfiable group maximum".into(),
                         weight: 20,
+                        related: Vec::new(),
+                        code: None,
+                        tags: Vec::new(),
                     },
                 );
             }
             Some(Reason::GroubMember(Bind { .. }, Bind { file, sym })) => {
-                let file = ctx.members[file as usize];
+                let (file, doc) = (members[file as usize].0, &members[file as usize].1);
                 insert_multi(
                     &mut out,
                     file,
                     ErrorInfo {
-                        location: ctx.root.file(file).lsp_range(sym).unwrap(),
+                        location: doc.lsp_range(sym).unwrap(),
                         severity: DiagnosticSeverity::WARNING,
                         msg: "unsatisfiable group member".into(),
                         weight: 20,
+                        related: Vec::new(),
+                        code: None,
+                        tags: Vec::new(),
                     },
                 );
             }
@@ -500,13 +651,135 @@ fn parse_core(ctx: &Binding, core: String) -> HashMap<FileID, Vec<ErrorInfo>> {
 }
 type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
 
+#[derive(Clone, Debug, Serialize)]
+pub struct AnalysisInfo {
+    #[serde(skip)]
+    id: u64,
+    pub model: String,
+    pub revision: u64,
+    //a document of the model is open in the editor
+    pub open: bool,
+}
+//Every solver eats a core and hundreds of MB, so all analyses share a fixed amount of
+//solver instances. Waiting analyses are dropped when their model revision becomes stale, a
+//burst of edits cancels the analyses of older revisions but they keep their solver until they
+//notice. Waiting analyses of documents open in the editor are let in first.
+pub struct SolverPool {
+    size: AtomicUsize,
+    next_id: AtomicU64,
+    queue: Mutex<Vec<AnalysisInfo>>,
+    running: Mutex<Vec<AnalysisInfo>>,
+    peak: AtomicUsize,
+    changed: Notify,
+}
+pub struct SolverPermit<'a> {
+    pool: &'a SolverPool,
+    id: u64,
+}
+impl<'a> Drop for SolverPermit<'a> {
+    fn drop(&mut self) {
+        self.pool.running.lock().retain(|i| i.id != self.id);
+        self.pool.changed.notify_waiters();
+    }
+}
+impl SolverPool {
+    pub fn new(size: usize) -> Self {
+        SolverPool {
+            size: AtomicUsize::new(size.max(1)),
+            next_id: AtomicU64::new(0),
+            queue: Mutex::new(Vec::new()),
+            running: Mutex::new(Vec::new()),
+            peak: AtomicUsize::new(0),
+            changed: Notify::new(),
+        }
+    }
+    //Take a solver if the analysis is the next in line
+    fn try_enter(&self, id: u64) -> bool {
+        let mut queue = self.queue.lock();
+        let next = queue
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, i)| (!i.open, i.id))
+            .map(|(pos, _)| pos);
+        let next = match next {
+            Some(next) if queue[next].id == id => next,
+            _ => return false,
+        };
+        let mut running = self.running.lock();
+        if running.len() >= self.size.load(Ordering::SeqCst) {
+            return false;
+        }
+        running.push(queue.remove(next));
+        self.peak.fetch_max(running.len(), Ordering::SeqCst);
+        true
+    }
+    //Wait for a free solver, fails if the analysis was canceled while waiting
+    pub async fn acquire(
+        &self,
+        model: String,
+        revision: u64,
+        open: bool,
+        cancel: &CancellationToken,
+    ) -> Result<SolverPermit<'_>> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.queue.lock().push(AnalysisInfo {
+            id,
+            model,
+            revision,
+            open,
+        });
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            if cancel.is_cancelled() {
+                break;
+            }
+            if self.try_enter(id) {
+                return Ok(SolverPermit { pool: self, id });
+            }
+            select! {
+                _ = cancel.cancelled() => break,
+                _ = changed => {}
+            }
+        }
+        self.queue.lock().retain(|i| i.id != id);
+        self.changed.notify_waiters();
+        Err("stale analysis".into())
+    }
+    pub fn resize(&self, size: usize) {
+        self.size.store(size.max(1), Ordering::SeqCst);
+        self.changed.notify_waiters();
+    }
+    //highest number of solvers that were alive at once
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::SeqCst)
+    }
+    pub fn status(&self) -> serde_json::Value {
+        let running = self.running.lock().clone();
+        let queued = self.queue.lock().clone();
+        serde_json::json!({
+            "permits": self.size.load(Ordering::SeqCst),
+            "running": running,
+            "queued": queued,
+        })
+    }
+}
+
 struct SmtModel {
     proc: Child,
     stdin: BufWriter<ChildStdin>,
     stdout: Lines<BufReader<ChildStdout>>,
 }
 impl SmtModel {
-    async fn new(model: String, cancel: &CancellationToken) -> Result<Self> {
+    async fn new(
+        model: String,
+        _permit: &SolverPermit<'_>,
+        cancel: &CancellationToken,
+    ) -> Result<Self> {
+        Self::spawn(model, cancel).await
+    }
+    async fn spawn(model: String, cancel: &CancellationToken) -> Result<Self> {
         let mut proc = Command::new("z3")
             .arg("-in")
             .arg("-smt2")
@@ -534,6 +807,15 @@ impl SmtModel {
             .unwrap()
             == "sat")
     }
+    async fn check_assuming(&mut self, literals: &str, cancel: &CancellationToken) -> Result<bool> {
+        let cmd = format!("(check-sat-assuming ({}))\n", literals);
+        maybe_cancel(cancel, self.stdin.write_all(cmd.as_bytes())).await??;
+        self.stdin.flush().await?;
+        Ok(maybe_cancel(cancel, self.stdout.next_line())
+            .await??
+            .unwrap()
+            == "sat")
+    }
     async fn get_unsat_core(&mut self, cancel: &CancellationToken) -> Result<String> {
         maybe_cancel(&cancel, self.stdin.write("(get-unsat-core)\n".as_bytes())).await??;
         self.stdin.flush().await?;
@@ -548,14 +830,32 @@ impl SmtModel {
     }
 }
 
-pub async fn run_z3(
+//Outcome of the solver for one component
+pub struct SolverResult {
+    //No configuration exists, the errors explain the unsat core. Otherwise they are the dead
+    //features
+    pub void: bool,
+    pub errors: HashMap<FileID, Vec<ErrorInfo>>,
+}
+//Encoding of a component and the documents of its members, built while the snapshot is held
+//so waiting for a solver and running it does not block the linker
+pub struct SolveInput {
+    composed: Arc<Composed>,
+    members: Vec<(FileID, Arc<Document>)>,
+    model: String,
+    revision: u64,
+}
+pub async fn solve_input(
     root: &RootGraph,
     comp: &Component,
-    sema: Arc<Context>,
-    cancel: CancellationToken,
-) -> Result<()> {
-    if !comp.dirty || comp.error != ComponentErrorState::Valid {
-        Err("dirty or syntax errors")?
+    composed: &ComposedCache,
+    cancel: &CancellationToken,
+) -> Result<SolveInput> {
+    if !*HAS_Z3 {
+        Err("z3 is not installed")?
+    }
+    if comp.error != ComponentErrorState::Valid {
+        Err("syntax errors")?
     }
     //info!("SMT check {:#?}", comp);
     let ctx = Binding {
@@ -568,39 +868,171 @@ pub async fn run_z3(
             .map(|(i, f)| (*f, i as u16))
             .collect(),
     };
-    let source = maybe_cancel(&cancel, smtlib_model(&ctx))
+    let composed = maybe_cancel(cancel, composed.get(&ctx))
         .await?
         .ok_or("model generation failure")?;
+    Ok(SolveInput {
+        composed,
+        members: comp
+            .members
+            .iter()
+            .map(|m| (*m, root.document(*m)))
+            .collect(),
+        model: root.file(comp.members[0]).uri.to_string(),
+        revision: root.revision,
+    })
+}
+pub async fn solve(
+    input: SolveInput,
+    open: bool,
+    cancel: &CancellationToken,
+) -> Result<SolverResult> {
     //info!("{}",source);
-    let mut model = SmtModel::new(source, &cancel).await?;
-    if !model.check_sat(&cancel).await? {
-        let core = model.get_unsat_core(&cancel).await?;
-        sema.publish_err(parse_core(&ctx, core), root).await;
+    let permit = SOLVER_POOL
+        .acquire(input.model.clone(), input.revision, open, cancel)
+        .await?;
+    let mut model = SmtModel::new(input.composed.source.clone(), &permit, cancel).await?;
+    if !model.check_sat(cancel).await? {
+        let core = model.get_unsat_core(cancel).await?;
+        Ok(SolverResult {
+            void: true,
+            errors: parse_core(&input.members, core),
+        })
     } else {
         let mut err = HashMap::new();
-        for m in ctx.members.iter() {
-            let file = root.file(*m);
-            for f in file.all_features() {
-                model
-                    .push(format!("(push 1)(assert {})\n", ctx.bind(f, *m).unwrap()))
-                    .await?;
-                if !model.check_sat(&cancel).await? {
-                    insert_multi(
-                        &mut err,
-                        *m,
-                        ErrorInfo {
-                            location: file.lsp_range(f).unwrap(),
-                            severity: DiagnosticSeverity::WARNING,
-                            weight: 20,
-                            msg: "dead feature".into(),
-                        },
-                    );
-                }
-                model.push(format!("(pop 1)\n")).await?;
+        for (i, f, name) in input.composed.features.iter() {
+            let (m, file) = &input.members[*i as usize];
+            model.push(format!("(push 1)(assert {})\n", name)).await?;
+            if !model.check_sat(cancel).await? {
+                insert_multi(
+                    &mut err,
+                    *m,
+                    ErrorInfo {
+                        location: file.lsp_range(*f).unwrap(),
+                        severity: DiagnosticSeverity::WARNING,
+                        weight: 20,
+                        related: Vec::new(),
+                        code: None,
+                        msg: "dead feature".into(),
+                        tags: vec![DiagnosticTag::UNNECESSARY],
+                    },
+                );
             }
+            model.push(format!("(pop 1)\n")).await?;
+        }
+        Ok(SolverResult {
+            void: false,
+            errors: err,
+        })
+    }
+}
+//Solver kept loaded with a component for configuration sessions. Sessions have their own
+//limit and do not take a solver from the pool, otherwise an idle session would block analysis
+pub struct WarmSolver {
+    model: SmtModel,
+    features: Vec<(RootSymbol, String)>,
+}
+//Encoding and feature names a warm solver is started with, built while the snapshot is held
+pub struct SolverInput {
+    composed: Arc<Composed>,
+    features: Vec<(RootSymbol, String)>,
+}
+impl WarmSolver {
+    pub async fn input(
+        root: &RootGraph,
+        comp: &Component,
+        composed: &ComposedCache,
+    ) -> Result<SolverInput> {
+        if !*HAS_Z3 {
+            Err("z3 is not installed")?
         }
-        sema.publish_err(err, root).await;
+        if comp.error != ComponentErrorState::Valid {
+            Err("syntax errors")?
+        }
+        let ctx = Binding {
+            members: &comp.members,
+            root,
+            index: comp
+                .members
+                .iter()
+                .enumerate()
+                .map(|(i, f)| (*f, i as u16))
+                .collect(),
+        };
+        let composed = composed.get(&ctx).await.ok_or("model generation failure")?;
+        let features = composed
+            .features
+            .iter()
+            .map(|(i, sym, name)| {
+                let file = comp.members[*i as usize];
+                (RootSymbol { file, sym: *sym }, name.clone())
+            })
+            .collect();
+        Ok(SolverInput { composed, features })
     }
+    //Spawn z3 for input, does not need the snapshot
+    pub async fn start(input: SolverInput, cancel: &CancellationToken) -> Result<Self> {
+        let SolverInput { composed, features } = input;
+        let mut model = SmtModel::spawn(composed.source.clone(), cancel).await?;
+        if !model.check_sat(cancel).await? {
+            Err("the model has no valid configuration")?
+        }
+        Ok(WarmSolver { model, features })
+    }
+    //Value every feature is forced to by the decisions, None if the decisions contradict
+    //each other or the model
+    pub async fn propagate(
+        &mut self,
+        decisions: &[(RootSymbol, bool)],
+        cancel: &CancellationToken,
+    ) -> Result<Option<HashMap<RootSymbol, bool>>> {
+        let literal = |name: &str, value: bool| {
+            if value {
+                name.to_string()
+            } else {
+                format!("(not {})", name)
+            }
+        };
+        let WarmSolver { model, features } = self;
+        let assumed: Vec<String> = decisions
+            .iter()
+            .filter_map(|(sym, value)| {
+                let (_, name) = features.iter().find(|(f, _)| f == sym)?;
+                Some(literal(name, *value))
+            })
+            .collect();
+        let assumed = assumed.join(" ");
+        if !model.check_assuming(&assumed, cancel).await? {
+            return Ok(None);
+        }
+        let mut forced = HashMap::new();
+        for (sym, name) in features.iter() {
+            let deselected = format!("{} {}", assumed, literal(name, false));
+            if !model.check_assuming(&deselected, cancel).await? {
+                forced.insert(*sym, true);
+                continue;
+            }
+            let selected = format!("{} {}", assumed, literal(name, true));
+            if !model.check_assuming(&selected, cancel).await? {
+                forced.insert(*sym, false);
+            }
+        }
+        Ok(Some(forced))
+    }
+}
+pub async fn run_z3(
+    root: &RootGraph,
+    comp: &Component,
+    sema: Arc<Context>,
+    cancel: CancellationToken,
+    open: bool,
+) -> Result<()> {
+    if !comp.dirty {
+        Err("not dirty")?
+    }
+    let input = solve_input(root, comp, &sema.composed, &cancel).await?;
+    let result = solve(input, open, &cancel).await?;
+    sema.publish_err(result.errors, root).await;
     Ok(())
 }
 
@@ -609,21 +1041,304 @@ pub fn can_run_z3() -> bool {
 }
 lazy_static! {
     static ref HAS_Z3: bool = can_run_z3();
+    pub static ref SOLVER_POOL: SolverPool = SolverPool::new(Settings::default().max_solvers);
 }
-pub async fn check_smt(ctx: Arc<Context>, cancel: CancellationToken) {
+//Analyse all dirty components, components with a document in open go first
+pub async fn check_smt(ctx: Arc<Context>, cancel: CancellationToken, open: HashSet<Url>) {
     if *HAS_Z3 {
         info!("start smt");
         let root = ctx.root.read().await;
-        let _results =
-            maybe_cancel(
-                &cancel,
-                join_all(root.components().iter().map(|c| async {
-                    if let Err(e) = run_z3(&root, c, ctx.clone(), cancel.clone()).await {
-                        info!("failed to run z3 {}",e);
+        let _results = maybe_cancel(
+            &cancel,
+            join_all(root.components().iter().map(|c| async {
+                let open = c.members.iter().any(|m| open.contains(&root.file(*m).uri));
+                if let Err(e) = run_z3(&root, c, ctx.clone(), cancel.clone(), open).await {
+                    info!("failed to run z3 {}", e);
+                }
+            })),
+        )
+        .await;
+    }
+}
 
-                    }
-                })),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::build_document;
+    use crate::parse::parse;
+    use ropey::Rope;
+    use tokio::time::Instant;
+    use tower_lsp::lsp_types::Url;
+    use ustr::Ustr;
+    #[test]
+    fn synthetic_root() {
+        let source = Rope::from_str("features\n    A\n        optional\n            B\n    C\n");
+        let uri = Url::parse("file:///synthetic.uvl").unwrap();
+        let doc = build_document(
+            source.clone(),
+            parse(&source, None),
+            uri.clone(),
+            Instant::now(),
+            Default::default(),
+        );
+        assert_eq!(
+            doc.model_root(),
+            &ModelRoot::Synthetic(vec![Symbol::Feature(0), Symbol::Feature(2)])
+        );
+        //the implicit root is pointed out without keeping the model from the analysis
+        assert!(!doc.errors.iter().any(ErrorInfo::is_error));
+        assert_eq!(
+            doc.errors
+                .iter()
+                .filter(|e| e.code.as_deref() == Some("model/implicit-root"))
+                .count(),
+            2
+        );
+        let files = [(uri, Arc::new(doc))].into_iter().collect();
+        let root = RootGraph::new(&files, 0);
+        let ctx = Binding {
+            root: &root,
+            index: [(FileID(0), 0)].into_iter().collect(),
+            members: &[FileID(0)],
+        };
+        assert_eq!(declare_roots(&ctx).unwrap(), "(assert f0_0)(assert f2_0)");
+    }
+    #[tokio::test]
+    async fn solver_limit() {
+        let pool = Arc::new(SolverPool::new(2));
+        let cancel = CancellationToken::new();
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let pool = pool.clone();
+                let cancel = cancel.clone();
+                tokio::spawn(async move {
+                    let _permit = pool
+                        .acquire(format!("model{}", i), 0, false, &cancel)
+                        .await
+                        .unwrap();
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                })
+            })
+            .collect();
+        for i in tasks {
+            i.await.unwrap();
+        }
+        assert_eq!(pool.peak(), 2);
+        assert_eq!(pool.status()["running"].as_array().unwrap().len(), 0);
+    }
+    #[tokio::test]
+    async fn composed_invalidation() {
+        let uri = |name: &str| Url::parse(&format!("file:///model/{}.uvl", name)).unwrap();
+        let doc = |name: &str, text: &str| {
+            let source = Rope::from_str(text);
+            let tree = parse(&source, None);
+            let doc = build_document(source, tree, uri(name), Instant::now(), Default::default());
+            (uri(name), Arc::new(doc))
+        };
+        //a and e both reach d through c
+        let mut files: HashMap<Url, Arc<Document>> = [
+            doc(
+                "a",
+                "imports\n    b\nfeatures\n    A\nconstraints\n    A => b.B\n",
+            ),
+            doc(
+                "b",
+                "imports\n    c\nfeatures\n    B\nconstraints\n    B => c.C\n",
+            ),
+            doc(
+                "c",
+                "imports\n    d\nfeatures\n    C\nconstraints\n    C => d.D\n",
+            ),
+            doc(
+                "d",
+                "features\n    D\n        optional\n            Deep\nconstraints\n    D => Deep\n",
+            ),
+            doc("e", "imports\n    c\nfeatures\n    E\n"),
+        ]
+        .into_iter()
+        .collect();
+        let cache = ComposedCache::default();
+        let compose_all = |files: &HashMap<Url, Arc<Document>>| {
+            let root = RootGraph::new(files, 0);
+            let cache = &cache;
+            async move {
+                for name in ["a", "e"] {
+                    let mut members = root.importes(root.file_id(&uri(name)).unwrap());
+                    members.sort_by_key(|m| root.file(*m).uri.clone());
+                    let ctx = Binding {
+                        root: &root,
+                        index: members
+                            .iter()
+                            .enumerate()
+                            .map(|(i, f)| (*f, i as u16))
+                            .collect(),
+                        members: &members,
+                    };
+                    assert!(cache.get(&ctx).await.is_some());
+                }
+            }
+        };
+        let counts = || {
+            (
+                cache.hits.load(Ordering::SeqCst),
+                cache.misses.load(Ordering::SeqCst),
             )
-            .await;
+        };
+        compose_all(&files).await;
+        compose_all(&files).await;
+        assert_eq!(counts(), (2, 2));
+        //layout does not matter
+        files.extend([doc(
+            "d",
+            "features\n    D\n        optional\n            Deep\nconstraints\n    D   =>   Deep\n",
+        )]);
+        compose_all(&files).await;
+        assert_eq!(counts(), (4, 2));
+        //a constraint three imports below a invalidates both roots
+        files.extend([doc(
+            "d",
+            "features\n    D\n        optional\n            Deep\nconstraints\n    Deep => D\n",
+        )]);
+        compose_all(&files).await;
+        assert_eq!(counts(), (4, 4));
+        assert_eq!(cache.clear(), 4);
+        compose_all(&files).await;
+        assert_eq!(counts(), (4, 6));
+    }
+    #[tokio::test]
+    async fn open_documents_first() {
+        let pool = SolverPool::new(1);
+        let cancel = CancellationToken::new();
+        let running = pool.acquire("a".into(), 0, false, &cancel).await.unwrap();
+        let order = Mutex::new(Vec::new());
+        tokio::join!(
+            async {
+                let _permit = pool
+                    .acquire("closed".into(), 0, false, &cancel)
+                    .await
+                    .unwrap();
+                order.lock().push("closed");
+            },
+            async {
+                tokio::task::yield_now().await;
+                let _permit = pool.acquire("open".into(), 0, true, &cancel).await.unwrap();
+                order.lock().push("open");
+            },
+            async {
+                tokio::task::yield_now().await;
+                tokio::task::yield_now().await;
+                drop(running);
+            }
+        );
+        assert_eq!(*order.lock(), ["open", "closed"]);
+        assert_eq!(pool.peak(), 1);
+    }
+    #[tokio::test]
+    async fn stale_analysis() {
+        let pool = SolverPool::new(1);
+        let cancel = CancellationToken::new();
+        let running = pool.acquire("a".into(), 0, false, &cancel).await.unwrap();
+        let stale = CancellationToken::new();
+        let (waiting, _) = tokio::join!(pool.acquire("b".into(), 0, false, &stale), async {
+            assert_eq!(pool.status()["queued"].as_array().unwrap().len(), 1);
+            stale.cancel();
+        });
+        assert!(waiting.is_err());
+        assert_eq!(pool.status()["queued"].as_array().unwrap().len(), 0);
+        drop(running);
+        assert!(pool.acquire("c".into(), 1, false, &cancel).await.is_ok());
+    }
+    #[test]
+    fn pathological_constraints() {
+        let timer = std::time::Instant::now();
+        let mut text = "features\n    Root\n        optional\n".to_string();
+        for i in 0..1000 {
+            let _ = writeln!(text, "            F{}", i);
+        }
+        text.push_str("constraints\n");
+        let _ = writeln!(text, "    {}F0{}", "(".repeat(400), ")".repeat(400));
+        text.push_str("    (F0");
+        for i in 1..1000 {
+            let _ = write!(text, "\n        | F{}", i);
+        }
+        text.push_str(")\n    F0");
+        for i in 1..20000 {
+            let _ = write!(text, " | F{}", i % 1000);
+        }
+        text.push('\n');
+        let source = Rope::from_str(&text);
+        let uri = Url::parse("file:///stress.uvl").unwrap();
+        let doc = build_document(
+            source.clone(),
+            parse(&source, None),
+            uri.clone(),
+            Instant::now(),
+            Default::default(),
+        );
+        assert!(!doc.errors.iter().any(ErrorInfo::is_error));
+        assert_eq!(
+            doc.errors
+                .iter()
+                .filter(|e| e.msg == "constraint too large to analyze")
+                .count(),
+            1
+        );
+        assert_eq!(doc.all_constraints().count(), 2);
+        let files = [(uri, Arc::new(doc))].into_iter().collect();
+        let root = RootGraph::new(&files, 0);
+        let ctx = Binding {
+            root: &root,
+            index: [(FileID(0), 0)].into_iter().collect(),
+            members: &[FileID(0)],
+        };
+        assert!(encode_constraints(&ctx, FileID(0)).is_some());
+        assert!(timer.elapsed() < std::time::Duration::from_secs(10));
+    }
+    #[test]
+    fn inherited_aggregates() {
+        let source = Rope::from_str("features\n    Car {cost 10}\n        optional\n            Engine\n            Wheel {cost 5}\nconstraints\n    sum(cost) > 0\n");
+        let uri = Url::parse("file:///sum.uvl").unwrap();
+        let doc = build_document(
+            source.clone(),
+            parse(&source, None),
+            uri.clone(),
+            Instant::now(),
+            Default::default(),
+        );
+        let engine = find_feature(&doc, "Engine");
+        let wheel = find_feature(&doc, "Wheel");
+        let files = [(uri, Arc::new(doc))].into_iter().collect();
+        let mut root = RootGraph::new(&files, 0);
+        let query = Path {
+            names: vec![Ustr::from("cost")],
+            spans: Vec::new(),
+        };
+        let sum = |root: &RootGraph| {
+            let ctx = Binding {
+                root,
+                index: [(FileID(0), 0)].into_iter().collect(),
+                members: &[FileID(0)],
+            };
+            let engine = ctx.bind(engine, FileID(0)).unwrap();
+            let wheel = ctx.bind(wheel, FileID(0)).unwrap();
+            let sum = encode_aggregate(&ctx, FileID(0), &AggregateOP::Sum, None, &query).unwrap();
+            (
+                sum,
+                format!("(ite {} 10.0 0.0)", engine),
+                format!("(ite {} ", wheel),
+            )
+        };
+        let (plain, engine_cost, _) = sum(&root);
+        assert!(!plain.contains(&engine_cost));
+        //Engine takes part with the cost of Car, Wheel with its own
+        root.inherit_attributes = true;
+        let (inherited, engine_cost, wheel_cost) = sum(&root);
+        assert!(inherited.contains(&engine_cost));
+        assert!(!inherited.contains(&wheel_cost));
+    }
+    fn find_feature(doc: &Document, name: &str) -> Symbol {
+        doc.all_features()
+            .find(|f| doc.symbol_name(*f).as_deref() == Some(name))
+            .unwrap()
     }
 }
@@ -0,0 +1,220 @@
+use crate::check::ErrorInfo;
+use crate::semantic::{ComponentErrorState, FileID, Snapshot};
+use crate::smt;
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tower_lsp::lsp_types::*;
+/*
+ * One-shot health check of a model for the uvls.validateModel command. Syntax and reference
+ * checks always run, the solver analyses share the analysis timeout and are reported as
+ * skipped when it runs out, when the model has errors or when no solver is installed.
+ * The solver only detects void models and dead features, false optional features and
+ * redundant constraints are reported as unsupported.
+ */
+const TOP_ISSUES: usize = 10;
+#[derive(Deserialize, Debug, Clone)]
+pub struct ValidateArgs {
+    pub uri: Url,
+}
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub syntax: usize,
+    pub references: usize,
+    pub void: usize,
+    pub dead: usize,
+}
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Issue {
+    pub category: &'static str,
+    pub message: String,
+    pub uri: Url,
+    pub range: Range,
+    pub severity: DiagnosticSeverity,
+}
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Skipped {
+    pub analysis: &'static str,
+    pub reason: String,
+}
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Summary {
+    pub uri: Url,
+    pub counts: Counts,
+    pub issues: Vec<Issue>,
+    pub skipped: Vec<Skipped>,
+}
+impl Summary {
+    //Single line for window/showMessage
+    pub fn message(&self) -> String {
+        let name = self.uri.path_segments().and_then(|mut s| s.next_back());
+        let void = if self.counts.void > 0 {
+            "void"
+        } else {
+            "not void"
+        };
+        let mut msg = format!(
+            "{}: {} syntax, {} reference issues, {}, {} dead features",
+            name.unwrap_or(""),
+            self.counts.syntax,
+            self.counts.references,
+            void,
+            self.counts.dead
+        );
+        if !self.skipped.is_empty() {
+            let skipped: Vec<String> = self
+                .skipped
+                .iter()
+                .map(|s| format!("{} ({})", s.analysis, s.reason))
+                .collect();
+            msg = format!("{}, skipped {}", msg, skipped.join(", "));
+        }
+        msg
+    }
+    pub fn has_issues(&self) -> bool {
+        self.counts != Counts::default()
+    }
+    fn add(&mut self, category: &'static str, uri: &Url, errors: &[ErrorInfo]) {
+        self.issues.extend(errors.iter().map(|e| Issue {
+            category,
+            message: e.msg.clone(),
+            uri: uri.clone(),
+            range: e.location,
+            severity: e.severity,
+        }));
+    }
+    fn skip(&mut self, analyses: &[&'static str], reason: &str) {
+        self.skipped.extend(analyses.iter().map(|analysis| Skipped {
+            analysis,
+            reason: reason.into(),
+        }));
+    }
+}
+//The snapshot is released before waiting for a solver, the timeout covers the encoding too
+pub async fn validate(
+    root: Snapshot<'_>,
+    file_id: FileID,
+    composed: &smt::ComposedCache,
+    timeout: Duration,
+) -> Summary {
+    let deadline = Instant::now() + timeout;
+    let file = root.file(file_id);
+    let mut summary = Summary {
+        uri: file.uri.clone(),
+        counts: Counts::default(),
+        issues: Vec::new(),
+        skipped: Vec::new(),
+    };
+    let syntax: Vec<ErrorInfo> = file.errors.clone();
+    let references = root.reference_errors(file_id);
+    summary.counts.syntax = syntax.len();
+    summary.counts.references = references.len();
+    summary.add("syntax", &file.uri, &syntax);
+    summary.add("references", &file.uri, &references);
+    let component = root
+        .components()
+        .iter()
+        .find(|c| c.members.contains(&file_id));
+    let broken = syntax
+        .iter()
+        .chain(references.iter())
+        .any(ErrorInfo::is_error);
+    //dropping the analysis on timeout kills the solver
+    let cancel = CancellationToken::new();
+    let input = match component {
+        _ if broken => Err("model has errors".to_string()),
+        Some(c) if c.error == ComponentErrorState::Valid => {
+            let uris: HashMap<FileID, Url> = c
+                .members
+                .iter()
+                .map(|m| (*m, root.file(*m).uri.clone()))
+                .collect();
+            let input = smt::solve_input(&root, c, composed, &cancel);
+            match tokio::time::timeout_at(deadline, input).await {
+                Ok(Ok(input)) => Ok((input, uris)),
+                Ok(Err(e)) => Err(e.to_string()),
+                Err(_) => Err("timeout".to_string()),
+            }
+        }
+        Some(_) => Err("imported models have errors".to_string()),
+        None => Err("model is not linked yet".to_string()),
+    };
+    drop(root);
+    match input {
+        Ok((input, uris)) => {
+            let analysis = smt::solve(input, true, &cancel);
+            match tokio::time::timeout_at(deadline, analysis).await {
+                Ok(Ok(result)) => {
+                    for (f, errors) in result.errors.iter() {
+                        let uri = &uris[f];
+                        if result.void {
+                            summary.add("void", uri, errors);
+                        } else {
+                            summary.counts.dead += errors.len();
+                            summary.add("dead", uri, errors);
+                        }
+                    }
+                    if result.void {
+                        summary.counts.void = 1;
+                        summary.skip(&["dead"], "model is void");
+                    }
+                }
+                Ok(Err(e)) => summary.skip(&["void", "dead"], &e.to_string()),
+                Err(_) => summary.skip(&["void", "dead"], "timeout"),
+            }
+        }
+        Err(reason) => summary.skip(&["void", "dead"], &reason),
+    }
+    summary.skip(&["falseOptional", "redundant"], "unsupported");
+    //errors first, heavier issues mask consequential ones
+    summary
+        .issues
+        .sort_by_key(|i| (i.severity, i.category != "void"));
+    summary.issues.truncate(TOP_ISSUES);
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Document;
+    use crate::document::build_document;
+    use crate::parse::parse;
+    use crate::semantic::RootGraph;
+    use ropey::Rope;
+    use std::sync::Arc;
+    use tokio::time::Instant;
+    #[tokio::test]
+    async fn broken_model() {
+        let uri = Url::parse("file:///summary.uvl").unwrap();
+        let source = Rope::from_str("features\n    Car\n        optional\n            Engine\nconstraints\n    Car => Wheel\n");
+        let tree = parse(&source, None);
+        let doc = build_document(
+            source,
+            tree,
+            uri.clone(),
+            Instant::now(),
+            Default::default(),
+        );
+        let files: HashMap<Url, Arc<Document>> =
+            [(uri.clone(), Arc::new(doc))].into_iter().collect();
+        let root = tokio::sync::RwLock::new(RootGraph::new(&files, 0));
+        let root = root.read().await;
+        let file_id = root.file_id(&uri).unwrap();
+        let summary = validate(root, file_id, &Default::default(), Duration::from_secs(1)).await;
+        assert_eq!(summary.counts.syntax, 0);
+        assert_eq!(summary.counts.references, 1);
+        assert_eq!(summary.issues[0].category, "references");
+        assert!(summary.skipped.contains(&Skipped {
+            analysis: "dead",
+            reason: "model has errors".into()
+        }));
+        assert!(summary.has_issues());
+        assert!(summary
+            .message()
+            .starts_with("summary.uvl: 0 syntax, 1 reference issues"));
+    }
+}
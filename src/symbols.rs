@@ -0,0 +1,377 @@
+use crate::ast::{Document, Span, Symbol};
+use crate::util::*;
+use hashbrown::HashMap;
+use tower_lsp::lsp_types::*;
+use tree_sitter::Tree;
+//Document outline and folding, both have to cope with partially broken files.
+//Symbols are build from the AST which never contains ERROR nodes and their ranges only cover
+//valid children, folding ranges ignore ERROR subtrees of the syntax tree.
+//Features can be nested thousands of levels deep, so nothing here recurses.
+
+fn union(a: Span, b: &Span) -> Span {
+    a.start.min(b.start)..a.end.max(b.end)
+}
+#[allow(deprecated)]
+fn make_symbol(
+    file: &Document,
+    name: String,
+    kind: SymbolKind,
+    selection: Span,
+    range: Span,
+    children: Vec<DocumentSymbol>,
+) -> Option<DocumentSymbol> {
+    Some(DocumentSymbol {
+        name,
+        detail: None,
+        kind,
+        tags: None,
+        deprecated: None,
+        range: lsp_range(range, &file.source)?,
+        selection_range: lsp_range(selection, &file.source)?,
+        children: if children.is_empty() {
+            None
+        } else {
+            Some(children)
+        },
+    })
+}
+//Returns the symbol and the span covered by it and its children
+fn finish_symbol(
+    file: &Document,
+    sym: Symbol,
+    children: Vec<(DocumentSymbol, Span)>,
+) -> Option<(DocumentSymbol, Span)> {
+    let selection = file.span(sym)?;
+    let mut range = selection.clone();
+    for (_, span) in children.iter() {
+        range = union(range, span);
+    }
+    let children = children.into_iter().map(|(child, _)| child).collect();
+    let (name, kind) = match sym {
+        Symbol::Feature(..) => (file.symbol_name(sym)?.to_string(), SymbolKind::CLASS),
+        Symbol::Attribute(..) => (file.symbol_name(sym)?.to_string(), SymbolKind::FIELD),
+        Symbol::Group(..) => (
+            file.source.byte_slice(selection.clone()).to_string(),
+            SymbolKind::ENUM,
+        ),
+        _ => return None,
+    };
+    Some((
+        make_symbol(file, name, kind, selection, range.clone(), children)?,
+        range,
+    ))
+}
+//Symbols are finished bottom up, each stack entry holds the children still to visit and the
+//finished ones
+fn tree_symbol(file: &Document, sym: Symbol) -> Option<(DocumentSymbol, Span)> {
+    let pending = |sym| {
+        let mut children: Vec<Symbol> = file.direct_children(sym).collect();
+        children.reverse();
+        (sym, children, Vec::new())
+    };
+    let mut stack = vec![pending(sym)];
+    loop {
+        let (_, children, _) = stack.last_mut().unwrap();
+        if let Some(child) = children.pop() {
+            stack.push(pending(child));
+            continue;
+        }
+        let (sym, _, finished) = stack.pop().unwrap();
+        let symbol = finish_symbol(file, sym, finished);
+        match stack.last_mut() {
+            Some((_, _, finished)) => finished.extend(symbol),
+            None => return symbol,
+        }
+    }
+}
+pub fn document_symbols(file: &Document) -> Vec<DocumentSymbol> {
+    let mut out = Vec::new();
+    if let Some(ns) = file.namespace() {
+        let name = ns
+            .names
+            .iter()
+            .map(|i| i.as_str())
+            .collect::<Vec<_>>()
+            .join(".");
+        out.extend(make_symbol(
+            file,
+            name,
+            SymbolKind::NAMESPACE,
+            ns.range(),
+            ns.range(),
+            Vec::new(),
+        ));
+    }
+    for sym in file.all_imports() {
+        if let (Some(name), Some(span)) = (file.symbol_name(sym), file.span(sym)) {
+            out.extend(make_symbol(
+                file,
+                name.to_string(),
+                SymbolKind::MODULE,
+                span.clone(),
+                span,
+                Vec::new(),
+            ));
+        }
+    }
+    for sym in file.direct_children(Symbol::Root) {
+        if let Some((symbol, _)) = tree_symbol(file, sym) {
+            out.push(symbol);
+        }
+    }
+    out
+}
+//Outline for clients without hierarchical symbol support, parents become container names
+#[allow(deprecated)]
+fn flatten(uri: &Url, symbols: Vec<DocumentSymbol>, out: &mut Vec<SymbolInformation>) {
+    let mut stack: Vec<(DocumentSymbol, Option<String>)> =
+        symbols.into_iter().rev().map(|i| (i, None)).collect();
+    while let Some((i, container)) = stack.pop() {
+        out.push(SymbolInformation {
+            name: i.name.clone(),
+            kind: i.kind,
+            tags: None,
+            deprecated: None,
+            location: Location::new(uri.clone(), i.range),
+            container_name: container,
+        });
+        let container = Some(i.name.clone());
+        let children = i.children.unwrap_or_default().into_iter().rev();
+        stack.extend(children.map(|c| (c, container.clone())));
+    }
+}
+pub fn symbol_response(file: &Document, hierarchical: bool) -> DocumentSymbolResponse {
+    let symbols = document_symbols(file);
+    if hierarchical {
+        DocumentSymbolResponse::Nested(symbols)
+    } else {
+        let mut out = Vec::new();
+        flatten(&file.uri, symbols, &mut out);
+        DocumentSymbolResponse::Flat(out)
+    }
+}
+//Last row covered by each node without trailing ERROR nodes, children are finished before
+//their parent so every node is visited once
+fn valid_end_rows(tree: &Tree) -> HashMap<usize, usize> {
+    let mut rows = HashMap::new();
+    let mut stack = vec![(tree.root_node(), false)];
+    while let Some((node, finished)) = stack.pop() {
+        let mut cursor = node.walk();
+        if !finished {
+            stack.push((node, true));
+            stack.extend(
+                node.children(&mut cursor)
+                    .filter(|child| !child.is_error() && child.child_count() > 0)
+                    .map(|child| (child, false)),
+            );
+            continue;
+        }
+        let row = node
+            .children(&mut cursor)
+            .filter(|child| !child.is_error())
+            .map(|child| {
+                if child.child_count() > 0 {
+                    rows[&child.id()]
+                } else {
+                    child.end_position().row
+                }
+            })
+            .max()
+            .unwrap_or(node.start_position().row);
+        rows.insert(node.id(), row);
+    }
+    rows
+}
+pub fn folding_ranges(tree: &Tree) -> Vec<FoldingRange> {
+    let mut out = Vec::new();
+    let end_rows = valid_end_rows(tree);
+    ts_filterd_visit(tree.root_node(), |node| {
+        if node.is_error() {
+            return false;
+        }
+        if node.kind() == "blk" {
+            let start = node.start_position().row;
+            let end = end_rows[&node.id()];
+            if end > start {
+                out.push(FoldingRange {
+                    start_line: start as u32,
+                    end_line: end as u32,
+                    ..Default::default()
+                });
+            }
+        }
+        true
+    });
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::build_document;
+    use crate::parse::parse;
+    use ropey::Rope;
+    use tokio::time::Instant;
+    fn names(symbols: &[DocumentSymbol], out: &mut Vec<String>) {
+        for i in symbols {
+            out.push(i.name.clone());
+            names(i.children.as_deref().unwrap_or(&[]), out);
+        }
+    }
+    fn find<'a>(symbols: &'a [DocumentSymbol], name: &str) -> Option<&'a DocumentSymbol> {
+        symbols.iter().find_map(|i| {
+            if i.name == name {
+                Some(i)
+            } else {
+                find(i.children.as_deref().unwrap_or(&[]), name)
+            }
+        })
+    }
+    fn timed<T>(name: &str, f: impl FnOnce() -> T) -> T {
+        let start = std::time::Instant::now();
+        let out = f();
+        let elapsed = start.elapsed();
+        assert!(elapsed.as_secs() < 5, "{} took {:?}", name, elapsed);
+        out
+    }
+    //Models generated from decision trees nest features very deep, every request has to
+    //finish without running out of stack or walking the ancestors of every node. Responses
+    //are serialized like the server does, on a thread with the stack of the runtime workers
+    #[test]
+    fn deep_nesting() {
+        std::thread::Builder::new()
+            .stack_size(crate::THREAD_STACK)
+            .spawn(deep_requests)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+    fn deep_requests() {
+        use crate::document::Draft;
+        use crate::semantic::RootGraph;
+        use std::fmt::Write;
+        use std::sync::Arc;
+        const DEPTH: usize = 1000;
+        fn respond<T: serde::Serialize>(name: &str, f: impl FnOnce() -> T) -> String {
+            timed(name, || serde_json::to_string(&f()).unwrap())
+        }
+        let mut text = String::from("features\n");
+        for i in 0..DEPTH {
+            let _ = writeln!(text, "{}F{} {{depth {}}}", "\t".repeat(2 * i + 1), i, i);
+            let _ = writeln!(text, "{}optional", "\t".repeat(2 * i + 2));
+        }
+        let _ = writeln!(text, "{}Leaf", "\t".repeat(2 * DEPTH + 1));
+        text.push_str("constraints\n\tLeaf => F0\n");
+        let uri = Url::parse("file:///deep.uvl").unwrap();
+        let source = Rope::from_str(&text);
+        let tree = timed("parse", || parse(&source, None));
+        let doc = timed("check", || {
+            build_document(
+                source.clone(),
+                tree.clone(),
+                uri.clone(),
+                Instant::now(),
+                Default::default(),
+            )
+        });
+        let files = [(uri.clone(), Arc::new(doc))].into_iter().collect();
+        let root = timed("link", || {
+            tokio::sync::RwLock::new(RootGraph::new(&files, 0))
+        });
+        let snapshot = root.try_read().unwrap();
+        let file = snapshot.file_by_uri(&uri).unwrap();
+        let file_id = snapshot.file_id(&uri).unwrap();
+        let draft = Draft::Tree {
+            source: source.clone(),
+            tree: tree.clone(),
+            revision: Instant::now(),
+        };
+        //the innermost declared feature and the reference in the constraint
+        let deepest = Position::new(2 * DEPTH as u32 - 1, 2 * DEPTH as u32);
+        let reference = Position::new(2 * DEPTH as u32 + 3, 2);
+        let symbols = respond("symbols", || symbol_response(file, true));
+        assert!(symbols.contains("\"Leaf\""));
+        respond("flat symbols", || symbol_response(file, false));
+        respond("folding", || folding_ranges(&tree));
+        respond("hover", || crate::hover::hover(file, &deepest, true, true));
+        respond("definition", || {
+            crate::location::goto_definition(&snapshot, &draft, &reference, &uri)
+        });
+        respond("references", || {
+            crate::location::find_references(&snapshot, &draft, &deepest, &uri)
+        });
+        respond("rename", || {
+            crate::actions::rename(&snapshot, &draft, &deepest, &uri, "Renamed")
+        });
+        respond("code actions", || {
+            crate::actions::code_actions(&snapshot, &uri, Range::new(deepest, deepest), &[])
+        });
+        respond("format", || crate::format::format_edits(&source, &tree));
+        let model = timed("export", || {
+            crate::export::export(&snapshot, file_id, true, &file.errors)
+        });
+        timed("export text", || model.to_uvl_text());
+        respond("syntax model", || model);
+        respond("semantic tokens", || {
+            crate::color::State::new().get(
+                root.try_read().unwrap(),
+                uri.clone(),
+                tree.clone(),
+                source.clone(),
+            )
+        });
+        respond("completion", || {
+            crate::completion::compute_completions(
+                root.try_read().unwrap(),
+                &draft,
+                TextDocumentPositionParams {
+                    text_document: TextDocumentIdentifier { uri: uri.clone() },
+                    position: reference,
+                },
+                None,
+                true,
+            )
+        });
+    }
+    #[test]
+    fn broken_file() {
+        let source = Rope::from_str(
+            "namespace Test\nfeatures\n    Root\n        optional\n            A\n            ) => (\n            B\nconstraints\n    A => B\n",
+        );
+        let tree = parse(&source, None);
+        let doc = build_document(
+            source.clone(),
+            tree.clone(),
+            Url::parse("file:///broken.uvl").unwrap(),
+            Instant::now(),
+            Default::default(),
+        );
+        let symbols = document_symbols(&doc);
+        let mut found = Vec::new();
+        names(&symbols, &mut found);
+        for i in ["Test", "Root", "A", "B"] {
+            assert!(found.iter().any(|k| k == i), "missing {}", i);
+        }
+        //the features block and Root fold up to B, the ERROR line inside does not cut them short
+        let folds: Vec<(u32, u32)> = folding_ranges(&tree)
+            .iter()
+            .map(|i| (i.start_line, i.end_line))
+            .collect();
+        assert!(folds.contains(&(1, 6)), "{:?}", folds);
+        assert!(folds.contains(&(2, 6)), "{:?}", folds);
+        assert!(folds.iter().all(|(_, end)| *end != 5), "{:?}", folds);
+        match symbol_response(&doc, false) {
+            DocumentSymbolResponse::Flat(flat) => {
+                assert_eq!(flat.len(), found.len());
+                let a = flat.iter().find(|i| i.name == "A").unwrap();
+                assert_eq!(a.container_name.as_deref(), Some("optional"));
+                //flat symbols cover the whole declaration like nested ones
+                let root = flat.iter().find(|i| i.name == "Root").unwrap();
+                let nested = find(&symbols, "Root").unwrap();
+                assert_eq!(root.location.range, nested.range);
+                assert_ne!(root.location.range, nested.selection_range);
+            }
+            _ => panic!("expected flat symbols"),
+        }
+    }
+}
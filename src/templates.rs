@@ -0,0 +1,229 @@
+use serde::Deserialize;
+use tower_lsp::lsp_types::*;
+/*
+ * Starter models for the uvls/newModel command. $ROOT is replaced with the root feature name
+ * and $NAMESPACE with the stem of the created file. Additional files of a template are
+ * created next to the first one as <namespace>_<suffix>.uvl and are referenced with
+ * $NAMESPACE_<suffix>, so the generated models check without errors right away.
+ */
+struct Template {
+    name: &'static str,
+    //(suffix, content) the first file has no suffix
+    files: &'static [(&'static str, &'static str)],
+}
+static TEMPLATES: &[Template] = &[
+    Template {
+        name: "minimal",
+        files: &[(
+            "",
+            "namespace $NAMESPACE
+
+features
+    $ROOT
+        mandatory
+            Base
+        optional
+            Extra
+        alternative
+            Small
+            Large
+
+constraints
+    Extra => Large
+",
+        )],
+    },
+    Template {
+        name: "attributes",
+        files: &[(
+            "",
+            "namespace $NAMESPACE
+
+features
+    $ROOT {budget 100}
+        mandatory
+            Base {price 10, weight 5}
+        optional
+            Extra {price 20, weight 3}
+
+constraints
+    Base.price + Extra.price < $ROOT.budget
+    Base.weight + Extra.weight < 10
+",
+        )],
+    },
+    Template {
+        name: "multiFile",
+        files: &[
+            (
+                "",
+                "namespace $NAMESPACE
+
+imports
+    $NAMESPACE_parts as parts
+
+features
+    $ROOT
+        mandatory
+            parts.Parts
+        optional
+            Extra
+
+constraints
+    Extra => parts.Engine
+",
+            ),
+            (
+                "parts",
+                "namespace $NAMESPACE_parts
+
+features
+    Parts
+        alternative
+            Engine
+            Battery
+",
+            ),
+        ],
+    },
+];
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct NewModelArgs {
+    //The first file of the template
+    pub uri: Url,
+    #[serde(default)]
+    pub template: Option<String>,
+    //Name of the root feature
+    #[serde(default)]
+    pub root: Option<String>,
+}
+pub fn template_names() -> Vec<&'static str> {
+    TEMPLATES.iter().map(|t| t.name).collect()
+}
+fn is_identifier(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+//Uri and content of every file of a template
+pub fn instantiate(args: &NewModelArgs) -> Option<Vec<(Url, String)>> {
+    let name = args.template.as_deref().unwrap_or("minimal");
+    let template = TEMPLATES.iter().find(|t| t.name == name)?;
+    let root = args.root.as_deref().unwrap_or("Root");
+    let path = args.uri.to_file_path().ok()?;
+    let namespace = path.file_stem()?.to_str()?;
+    if !is_identifier(root) || !is_identifier(namespace) {
+        return None;
+    }
+    template
+        .files
+        .iter()
+        .map(|(suffix, content)| {
+            let uri = if suffix.is_empty() {
+                args.uri.clone()
+            } else {
+                let file = path.with_file_name(format!("{}_{}.uvl", namespace, suffix));
+                Url::from_file_path(file).ok()?
+            };
+            let text = content
+                .replace("$NAMESPACE", namespace)
+                .replace("$ROOT", root);
+            Some((uri, text))
+        })
+        .collect()
+}
+pub fn new_model_edit(files: &[(Url, String)]) -> WorkspaceEdit {
+    let mut operations = Vec::new();
+    for (uri, text) in files {
+        let create = CreateFile {
+            uri: uri.clone(),
+            options: Some(CreateFileOptions {
+                overwrite: Some(false),
+                ignore_if_exists: Some(false),
+            }),
+            annotation_id: None,
+        };
+        operations.push(DocumentChangeOperation::Op(ResourceOp::Create(create)));
+        operations.push(DocumentChangeOperation::Edit(TextDocumentEdit {
+            text_document: OptionalVersionedTextDocumentIdentifier {
+                uri: uri.clone(),
+                version: None,
+            },
+            edits: vec![OneOf::Left(TextEdit {
+                range: Range::default(),
+                new_text: text.clone(),
+            })],
+        }));
+    }
+    WorkspaceEdit {
+        document_changes: Some(DocumentChanges::Operations(operations)),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check::ErrorInfo;
+    use crate::document::build_document;
+    use crate::parse::parse;
+    use crate::semantic::*;
+    use ropey::Rope;
+    use std::sync::Arc;
+    use tokio::time::Instant;
+    #[test]
+    fn templates_check_cleanly() {
+        for name in template_names() {
+            let args = NewModelArgs {
+                uri: Url::parse("file:///models/car.uvl").unwrap(),
+                template: Some(name.into()),
+                root: Some("Car".into()),
+            };
+            let files = instantiate(&args).unwrap();
+            assert_eq!(files[0].0, args.uri);
+            let docs = files
+                .iter()
+                .map(|(uri, text)| {
+                    let source = Rope::from_str(text);
+                    let tree = parse(&source, None);
+                    let doc = build_document(
+                        source,
+                        tree,
+                        uri.clone(),
+                        Instant::now(),
+                        Default::default(),
+                    );
+                    assert!(!doc.errors.iter().any(ErrorInfo::is_error), "{}", name);
+                    (uri.clone(), Arc::new(doc))
+                })
+                .collect();
+            let root = RootGraph::new(&docs, 0);
+            for (uri, _) in files.iter() {
+                let file_id = root.file_id(uri).unwrap();
+                let file = root.file(file_id);
+                assert_eq!(
+                    root.fs.imports(file_id).count(),
+                    file.all_imports().count(),
+                    "{}",
+                    name
+                );
+                for sym in file.all_references() {
+                    assert!(
+                        root.resolve_sym(RootSymbol { file: file_id, sym })
+                            .is_some(),
+                        "{}: {:?}",
+                        name,
+                        file.path(sym)
+                    );
+                }
+            }
+        }
+        //root names have to be valid identifiers
+        let args = NewModelArgs {
+            uri: Url::parse("file:///models/car.uvl").unwrap(),
+            template: None,
+            root: Some("my car".into()),
+        };
+        assert!(instantiate(&args).is_none());
+    }
+}
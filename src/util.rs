@@ -3,7 +3,7 @@ use std::sync::atomic::{AtomicU64, Ordering};
 use crate::query::Queries;
 use futures::Future;
 use lazy_static::lazy_static;
-use ropey::Rope;
+use ropey::{Rope, RopeSlice};
 use tokio::select;
 use tokio_util::sync::CancellationToken;
 use tower_lsp::lsp_types::{Position, Range};
@@ -61,13 +61,26 @@ pub fn lsp_range(span: std::ops::Range<usize>, source: &Rope) -> Option<Range> {
     lsp_position(span.start, source)
         .and_then(|start| lsp_position(span.end, source).map(|end| Range { start, end }))
 }
+//Chars of a line without its line break, \r\n counts as one break
+fn content_chars(line: RopeSlice) -> usize {
+    let mut len = line.len_chars();
+    while len > 0 && matches!(line.char(len - 1), '\n' | '\r') {
+        len -= 1;
+    }
+    len
+}
+pub fn line_len_utf16(source: &Rope, line: usize) -> usize {
+    let line = source.line(line);
+    line.char_to_utf16_cu(content_chars(line))
+}
+//Positions past the end of a line are clamped to its end, never into the line break
 pub fn char_offset(pos: &Position, source: &Rope) -> usize {
     if let Some(line) = source.get_line(pos.line as usize) {
-        if let Ok(end) = line.try_utf16_cu_to_char(pos.character as usize) {
-            source.line_to_char(pos.line as usize) + end
-        } else {
-            source.line_to_char(pos.line as usize) + line.len_chars()
-        }
+        let len = content_chars(line);
+        let end = line
+            .try_utf16_cu_to_char(pos.character as usize)
+            .map_or(len, |end| end.min(len));
+        source.line_to_char(pos.line as usize) + end
     } else {
         source.len_chars()
     }
@@ -86,6 +99,35 @@ pub fn containing_blk(mut node: Node) -> Option<Node> {
 pub fn header_kind(node: Node) -> &str {
     node.child_by_field_name("header").unwrap().kind()
 }
+//Walk the syntax tree and only go "down" if F is true
+pub fn ts_filterd_visit<F: FnMut(Node) -> bool>(root: Node, mut f: F) {
+    let mut reached_root = false;
+    let mut cursor = root.walk();
+    if !cursor.goto_first_child() {
+        return;
+    }
+    while !reached_root {
+        if f(cursor.node()) && cursor.goto_first_child() {
+            continue;
+        }
+        if cursor.goto_next_sibling() {
+            continue;
+        }
+        loop {
+            if !cursor.goto_parent() {
+                reached_root = true;
+                break;
+            }
+            if cursor.node() == root {
+                reached_root = true;
+                break;
+            }
+            if cursor.goto_next_sibling() {
+                break;
+            }
+        }
+    }
+}
 
 pub async fn maybe_cancel<'a, F: Future + 'a>(
     token: &CancellationToken,
@@ -121,3 +163,30 @@ impl<'a> Drop for AtomicSemaphorePermit<'a> {
         self.owner.counter.fetch_sub(1, Ordering::SeqCst);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn crlf_positions() {
+        let source = Rope::from_str("features\r\n    Äb\r\n    C\r\n");
+        assert_eq!(line_len_utf16(&source, 0), 8);
+        assert_eq!(line_len_utf16(&source, 1), 6);
+        //the end of a line is before \r
+        assert_eq!(byte_offset(&Position::new(0, 8), &source), 8);
+        assert_eq!(byte_offset(&Position::new(0, 9), &source), 8);
+        assert_eq!(byte_offset(&Position::new(1, 100), &source), 17);
+        let c = source.to_string().find('C').unwrap();
+        assert_eq!(
+            lsp_range(c..c + 1, &source),
+            Some(Range {
+                start: Position::new(2, 4),
+                end: Position::new(2, 5)
+            })
+        );
+        assert_eq!(
+            lsp_position(source.len_bytes(), &source),
+            Some(Position::new(3, 0))
+        );
+    }
+}
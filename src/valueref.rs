@@ -0,0 +1,260 @@
+use crate::ast::*;
+use crate::check::ErrorInfo;
+use crate::semantic::*;
+use crate::util::lsp_range;
+use tower_lsp::lsp_types::*;
+use ustr::Ustr;
+/*
+ * Opt-in references inside string attribute values, eg. {requires "Engine.Turbo"}. Only the
+ * keys listed in the referenceAttributes setting are read as references, other strings stay
+ * plain text. The content between the quotes is a dotted path resolved from the root of the
+ * file like an unscoped reference, so import prefixes and Feature.attribute work. A missing
+ * closing quote or a trailing dot while typing is tolerated. The keys are stored in the
+ * RootGraph so diagnostics, definition, references and rename agree on what a reference is.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueRef {
+    pub attribute: Symbol,
+    pub path: Vec<Ustr>,
+    //Byte span of every segment in the source
+    pub spans: Vec<Span>,
+}
+//Segments of a quoted string starting at offset, None for an empty path
+fn parse_value(text: &str, offset: usize) -> Option<(Vec<Ustr>, Vec<Span>)> {
+    let quote = text.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let inner = &text[1..];
+    let inner = inner.strip_suffix(quote).unwrap_or(inner);
+    let mut path = Vec::new();
+    let mut spans = Vec::new();
+    let mut start = offset + 1;
+    for segment in inner.split('.') {
+        let trimmed = segment.trim();
+        if !trimmed.is_empty() {
+            let lead = segment.len() - segment.trim_start().len();
+            path.push(Ustr::from(trimmed));
+            spans.push(start + lead..start + lead + trimmed.len());
+        } else if !path.is_empty() {
+            break;
+        }
+        start += segment.len() + 1;
+    }
+    if path.is_empty() {
+        None
+    } else {
+        Some((path, spans))
+    }
+}
+//References of all configured keys in a file
+pub fn value_refs(file: &Document, keys: &[String]) -> Vec<ValueRef> {
+    if keys.is_empty() {
+        return Vec::new();
+    }
+    file.all_attributes()
+        .filter(|sym| {
+            file.symbol_name(*sym)
+                .is_some_and(|name| keys.iter().any(|k| k.as_str() == name.as_str()))
+        })
+        .filter_map(|attribute| {
+            let span = match file.value(attribute)? {
+                Value::String(..) => file.value_span(attribute)?,
+                _ => return None,
+            };
+            let text = file.source.byte_slice(span.clone()).to_string();
+            let (path, spans) = parse_value(&text, span.start)?;
+            Some(ValueRef {
+                attribute,
+                path,
+                spans,
+            })
+        })
+        .collect()
+}
+pub fn resolve(root: &RootGraph, file_id: FileID, path: &[Ustr]) -> Option<RootSymbol> {
+    root.resolve(file_id, path)
+        .find(|dst| matches!(dst.sym, Symbol::Feature(..) | Symbol::Attribute(..)))
+}
+pub fn check_value_refs(root: &RootGraph, file_id: FileID) -> Vec<ErrorInfo> {
+    let file = root.file(file_id);
+    value_refs(file, &root.reference_attributes)
+        .into_iter()
+        .filter(|r| resolve(root, file_id, &r.path).is_none())
+        .filter_map(|r| {
+            let span = r.spans[0].start..r.spans.last()?.end;
+            Some(ErrorInfo {
+                location: lsp_range(span, &file.source)?,
+                severity: DiagnosticSeverity::WARNING,
+                weight: 20,
+                msg: format!("unresolved reference {}", r.path.join(".")),
+                related: Vec::new(),
+                code: Some("attribute/unresolved-reference".into()),
+                tags: Vec::new(),
+            })
+        })
+        .collect()
+}
+//Symbol named by the segment under offset and the segments before it
+pub fn at(root: &RootGraph, file_id: FileID, offset: usize) -> Option<RootSymbol> {
+    let file = root.file(file_id);
+    value_refs(file, &root.reference_attributes)
+        .into_iter()
+        .find_map(|r| {
+            let i = r
+                .spans
+                .iter()
+                .position(|s| s.start <= offset && offset <= s.end)?;
+            resolve(root, file_id, &r.path[..=i])
+        })
+}
+//Segments naming target in the files importing it, the segment after which the path resolves
+//to target
+pub fn occurrences(root: &RootGraph, target: RootSymbol) -> Vec<(FileID, Span)> {
+    if root.reference_attributes.is_empty() {
+        return Vec::new();
+    }
+    root.imported(target.file)
+        .into_iter()
+        .flat_map(|src_id| {
+            occurrences_in(root, target, src_id)
+                .into_iter()
+                .map(move |span| (src_id, span))
+        })
+        .collect()
+}
+pub fn occurrences_in(root: &RootGraph, target: RootSymbol, src_id: FileID) -> Vec<Span> {
+    let mut out = Vec::new();
+    for r in value_refs(root.file(src_id), &root.reference_attributes) {
+        for i in 0..r.path.len() {
+            if resolve(root, src_id, &r.path[..=i]) == Some(target) {
+                out.push(r.spans[i].clone());
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::build_document;
+    use crate::parse::parse;
+    use ropey::Rope;
+    use std::sync::Arc;
+    use tokio::time::Instant;
+    #[test]
+    fn quoted_paths() {
+        assert_eq!(
+            parse_value("\"Engine.Turbo\"", 10),
+            Some((vec!["Engine".into(), "Turbo".into()], vec![11..17, 18..23]))
+        );
+        //still typing
+        assert_eq!(
+            parse_value("'Engine.", 0),
+            Some((vec!["Engine".into()], vec![Span { start: 1, end: 7 }]))
+        );
+        assert_eq!(parse_value("\"\"", 0), None);
+    }
+    #[test]
+    fn configured_keys_only() {
+        let uri = Url::parse("file:///refs.uvl").unwrap();
+        let source = Rope::from_str("features\n    Car\n        optional\n            Engine {turbo true}\n            Radio {requires 'Engine.turbo', note 'Engine'}\n            Seat {requires 'Wheel'}\n");
+        let doc = build_document(
+            source.clone(),
+            parse(&source, None),
+            uri.clone(),
+            Instant::now(),
+            Default::default(),
+        );
+        let files = [(uri.clone(), Arc::new(doc))].into_iter().collect();
+        let mut root = RootGraph::new(&files, 0);
+        root.reference_attributes = vec!["requires".into()];
+        let file_id = root.file_id(&uri).unwrap();
+        let refs = value_refs(root.file(file_id), &root.reference_attributes);
+        assert_eq!(refs.len(), 2);
+        let err = check_value_refs(&root, file_id);
+        assert_eq!(err.len(), 1);
+        assert_eq!(err[0].location.start, Position::new(5, 28));
+        let engine = resolve(&root, file_id, &["Engine".into()]).unwrap();
+        assert_eq!(occurrences(&root, engine).len(), 1);
+    }
+    //Rename and definition go through the string segments of configured keys only
+    #[test]
+    fn rename_and_definition() {
+        use crate::document::Draft;
+        let uri = Url::parse("file:///refs.uvl").unwrap();
+        let text = "features\n    Car\n        optional\n            Engine\n                optional\n                    Turbo\n            Radio {requires 'Engine.Turbo', note 'Engine.Turbo'}\n";
+        let source = Rope::from_str(text);
+        let doc = build_document(
+            source.clone(),
+            parse(&source, None),
+            uri.clone(),
+            Instant::now(),
+            Default::default(),
+        );
+        let files = [(uri.clone(), Arc::new(doc))].into_iter().collect();
+        let draft = Draft::Tree {
+            source: source.clone(),
+            tree: parse(&source, None),
+            revision: Instant::now(),
+        };
+        let renamed = |keys: &[&str], pos: Position| {
+            let mut root = RootGraph::new(&files, 0);
+            root.reference_attributes = keys.iter().map(|k| k.to_string()).collect();
+            let root = tokio::sync::RwLock::new(root);
+            let snapshot = root.try_read().unwrap();
+            let edit = crate::actions::rename(&snapshot, &draft, &pos, &uri, "Boost")?;
+            let mut edits = edit.changes?.remove(&uri)?;
+            edits.sort_by_key(|e| std::cmp::Reverse(e.range.start));
+            let mut out = source.clone();
+            for e in edits {
+                let start = out.line_to_char(e.range.start.line as usize)
+                    + e.range.start.character as usize;
+                let end =
+                    out.line_to_char(e.range.end.line as usize) + e.range.end.character as usize;
+                out.remove(start..end);
+                out.insert(start, &e.new_text);
+            }
+            Some(out.to_string())
+        };
+        //from the declaration and from the segment inside the quotes
+        let expected = text.replacen("Turbo\n", "Boost\n", 1).replacen(
+            "'Engine.Turbo',",
+            "'Engine.Boost',",
+            1,
+        );
+        assert_eq!(
+            renamed(&["requires"], Position::new(5, 22)),
+            Some(expected.clone())
+        );
+        assert_eq!(renamed(&["requires"], Position::new(6, 38)), Some(expected));
+        //without the key the string is plain text
+        assert_eq!(
+            renamed(&[], Position::new(5, 22)),
+            Some(text.replacen("Turbo\n", "Boost\n", 1))
+        );
+        let mut root = RootGraph::new(&files, 0);
+        root.reference_attributes = vec!["requires".into()];
+        let root = tokio::sync::RwLock::new(root);
+        let snapshot = root.try_read().unwrap();
+        let definition =
+            |pos: Position| match crate::location::goto_definition(&snapshot, &draft, &pos, &uri) {
+                Some(GotoDefinitionResponse::Array(locations)) => {
+                    locations.into_iter().map(|l| l.range).collect()
+                }
+                _ => Vec::new(),
+            };
+        //the cursor on Engine inside 'Engine.Turbo'
+        assert_eq!(
+            definition(Position::new(6, 31)),
+            vec![Range::new(Position::new(3, 12), Position::new(3, 18))]
+        );
+        assert_eq!(
+            definition(Position::new(6, 38)),
+            vec![Range::new(Position::new(5, 20), Position::new(5, 25))]
+        );
+        //note is not a reference key
+        let file_id = snapshot.file_id(&uri).unwrap();
+        let note = text.lines().nth(6).unwrap().rfind("Engine").unwrap();
+        let offset = source.line_to_byte(6) + note + 1;
+        assert_eq!(at(&snapshot, file_id, offset), None);
+    }
+}